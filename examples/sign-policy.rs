@@ -0,0 +1,49 @@
+//! A mux that only signs with keys whose fingerprints are listed, one per line, in a file that is
+//! read again on every sign request, so that access can be granted and revoked while it runs:
+//!
+//! ```console
+//! $ cargo run --example sign-policy -- <listen path> <allowlist file> <upstream agent socket>...
+//! ```
+
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use ssh_agent_lib::{error::AgentError, ssh_key::Fingerprint};
+use ssh_agent_mux::{
+    policy::{async_trait, Decision, Peer, RequestPolicy},
+    MuxAgent, MuxOptions, UpstreamAgent,
+};
+
+#[derive(Debug)]
+struct Allowlist(PathBuf);
+
+#[async_trait]
+impl RequestPolicy for Allowlist {
+    async fn allow_sign(&self, fingerprint: &Fingerprint, peer: Option<&Peer>) -> Decision {
+        let listed = fs::read_to_string(&self.0).is_ok_and(|text| {
+            text.lines()
+                .any(|line| line.trim().parse::<Fingerprint>().ok() == Some(*fingerprint))
+        });
+        if listed {
+            return Decision::Allow;
+        }
+        let client = peer.map_or("an unknown client".into(), |p| format!("uid {}", p.uid));
+        Decision::Deny(format!("not in {} (asked by {})", self.0.display(), client))
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), AgentError> {
+    let mut args = env::args_os().skip(1);
+    let (Some(listen_path), Some(allowlist)) = (args.next(), args.next()) else {
+        eprintln!("usage: sign-policy <listen path> <allowlist file> <upstream agent socket>...");
+        std::process::exit(2);
+    };
+    let agents = args
+        .enumerate()
+        .map(|(i, socket_path)| UpstreamAgent::new(format!("agent-{}", i + 1), socket_path));
+    let options = MuxOptions {
+        policy: Arc::new(Allowlist(allowlist.into())),
+        ..Default::default()
+    };
+    MuxAgent::run(listen_path, agents, vec![], options).await
+}
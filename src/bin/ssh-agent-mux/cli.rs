@@ -1,4 +1,15 @@
-use std::{env, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    path::{self, Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use clap_serde_derive::{
     clap::{self, Parser, ValueEnum},
@@ -8,8 +19,15 @@ use clap_serde_derive::{
 use color_eyre::eyre::Result as EyreResult;
 use expand_tilde::ExpandTilde;
 use log::LevelFilter;
+use regex::Regex;
+use ssh_agent_lib::ssh_key::{Algorithm, Fingerprint};
+use ssh_agent_mux::{
+    policy::AllowAll, CommentFilter, ExtensionFilter, KeyFilter, MuxOptions, UpstreamAgent,
+    UpstreamKind, UpstreamTransport,
+};
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::service;
+use crate::{import, logging, route, service, sign};
 
 fn default_config_path() -> EyreResult<PathBuf> {
     let config_dir = env::var_os("XDG_CONFIG_HOME")
@@ -23,36 +41,634 @@ fn default_config_path() -> EyreResult<PathBuf> {
         .join(concat!(env!("CARGO_PKG_NAME"), ".toml")))
 }
 
+/// Make `path` absolute, resolving `.`, `..`, and symlinks in as much of it as exists, so that
+/// different spellings of the same socket path compare equal
+fn canonicalize_path(path: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            // The part resolved so far has no symlinks, so this is where `..` leads
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => {
+                resolved.push(component);
+                if let Ok(canonical) = fs::canonicalize(&resolved) {
+                    resolved = canonical;
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Expand environment variables in `text`; `$$` stands for a literal `$`
+fn expand_env_vars(text: &str, undefined: EnvUndefined) -> EyreResult<String> {
+    let lookup = |name: &str| match (env::var(name), undefined) {
+        (Ok(value), _) => Ok(Some(value)),
+        (Err(e), EnvUndefined::Error) => Err(e),
+        (Err(_), EnvUndefined::Keep) => Ok(None),
+        (Err(_), EnvUndefined::Empty) => Ok(Some(String::new())),
+    };
+    let parts = text
+        .split("$$")
+        .map(|part| Ok(shellexpand::env_with_context(part, lookup)?.into_owned()))
+        .collect::<EyreResult<Vec<_>>>()?;
+    Ok(parts.join("$"))
+}
+
+/// Expand environment variables in the string values of the configuration file contents `value`
+/// at `path`, except in the settings listed in `no-env-expansion`
+fn expand_config_env_vars(
+    value: &mut toml::Value,
+    path: &ConfigPath,
+    undefined: EnvUndefined,
+    skip: &[String],
+) -> EyreResult<()> {
+    if skip.contains(&path.setting) {
+        return Ok(());
+    }
+    match value {
+        toml::Value::String(s) => {
+            let expanded = expand_env_vars(s, undefined).map_err(|e| {
+                color_eyre::eyre::eyre!("Failed to expand environment variables in {}: {}", path, e)
+            })?;
+            s.zeroize();
+            *s = expanded;
+        }
+        toml::Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                expand_config_env_vars(value, &path.index(i), undefined, skip)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                expand_config_env_vars(value, &path.key(key), undefined, skip)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Overwrite the strings in configuration file contents, which may include lock passphrases
+fn zeroize_config_strings(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(s) => s.zeroize(),
+        toml::Value::Array(values) => values.iter_mut().for_each(zeroize_config_strings),
+        toml::Value::Table(table) => table
+            .iter_mut()
+            .for_each(|(_, v)| zeroize_config_strings(v)),
+        _ => {}
+    }
+}
+
+/// Location of a value in the configuration file
+#[derive(Default)]
+struct ConfigPath {
+    /// Full TOML path, e.g. `agents[2].socket-path`
+    full: String,
+    /// Path without array indices, e.g. `agents.socket-path`, as used by `no-env-expansion`
+    setting: String,
+}
+
+impl ConfigPath {
+    fn key(&self, key: &str) -> Self {
+        let join = |path: &str| {
+            if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{path}.{key}")
+            }
+        };
+        Self {
+            full: join(&self.full),
+            setting: join(&self.setting),
+        }
+    }
+
+    fn index(&self, i: usize) -> Self {
+        Self {
+            full: format!("{}[{i}]", self.full),
+            setting: self.setting.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.full)
+    }
+}
+
+/// Parse configuration file contents into TOML, expanding environment variables in its string
+/// values as set by the file's own `env-undefined` and `no-env-expansion`
+fn read_config_value(text: &str) -> EyreResult<toml::Value> {
+    let mut value = toml::Value::Table(toml::from_str(text)?);
+    // The expansion settings themselves are read before expansion
+    let setting = |key: &str| value.get(key).cloned();
+    let undefined: EnvUndefined = setting("env-undefined")
+        .map(|v| v.try_into())
+        .transpose()?
+        .unwrap_or_default();
+    let skip: Vec<String> = setting("no-env-expansion")
+        .map(|v| v.try_into())
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Err(e) = expand_config_env_vars(&mut value, &ConfigPath::default(), undefined, &skip) {
+        zeroize_config_strings(&mut value);
+        return Err(e);
+    }
+    Ok(value)
+}
+
+/// Merge the contents of a later configuration file into those of the earlier ones: its
+/// `[[agents]]` and `[[routes]]` are appended, and its other settings replace the earlier values
+fn merge_config_values(merged: &mut toml::Value, later: toml::Value) {
+    let (toml::Value::Table(merged), toml::Value::Table(later)) = (merged, later) else {
+        unreachable!("configuration files parse to tables");
+    };
+    for (key, value) in later {
+        match (merged.get_mut(&key), value) {
+            (Some(toml::Value::Array(tables)), toml::Value::Array(more))
+                if key == "agents" || key == "routes" =>
+            {
+                tables.extend(more)
+            }
+            (_, value) => {
+                if let Some(mut replaced) = merged.insert(key, value) {
+                    zeroize_config_strings(&mut replaced);
+                }
+            }
+        }
+    }
+}
+
+/// Read and merge the configuration files that exist among `paths`, in order
+fn read_config_files(paths: &[PathBuf]) -> EyreResult<Option<<Config as ClapSerde>::Opt>> {
+    let mut merged: Option<toml::Value> = None;
+    for path in paths {
+        let Ok(mut f) = File::open(path) else {
+            continue;
+        };
+        log::info!("Read configuration from {}", path.display());
+        // The file may contain lock passphrases
+        let mut config_text = Zeroizing::new(String::new());
+        f.read_to_string(&mut config_text)?;
+        let value = read_config_value(&config_text).map_err(|e| {
+            e.wrap_err(format!(
+                "Failed to read configuration from {}",
+                path.display()
+            ))
+        })?;
+        match merged {
+            Some(ref mut merged) => merge_config_values(merged, value),
+            None => merged = Some(value),
+        }
+    }
+    let Some(mut merged) = merged else {
+        return Ok(None);
+    };
+    let result = merged.clone().try_into().map_err(Into::into);
+    zeroize_config_strings(&mut merged);
+    result.map(Some)
+}
+
+/// Parse key fingerprints; those that don't parse are rejected by validation
+fn parse_fingerprints(fingerprints: &[String]) -> Vec<Fingerprint> {
+    fingerprints.iter().filter_map(|f| f.parse().ok()).collect()
+}
+
+/// Check that each of the key fingerprints of the setting at `path` parses
+fn check_fingerprints(path: &str, fingerprints: &[String], issues: &mut Vec<ConfigIssue>) {
+    for (i, fingerprint) in fingerprints.iter().enumerate() {
+        if let Err(e) = fingerprint.parse::<Fingerprint>() {
+            issues.push(ConfigIssue::new(
+                format!("{path}[{i}]"),
+                format!("{:?} is not a key fingerprint: {}", fingerprint, e),
+            ));
+        }
+    }
+}
+
+/// Check that each key type of `allowed-key-types` is one an upstream agent can list
+fn check_key_types(key_types: &[String], issues: &mut Vec<ConfigIssue>) {
+    for (i, key_type) in key_types.iter().enumerate() {
+        let message = match key_type.parse::<Algorithm>() {
+            Err(e) => format!("{:?} is not a key type: {}", key_type, e),
+            Ok(Algorithm::Rsa { hash: Some(_) }) => format!(
+                "{:?} is a signature algorithm; RSA keys are of type \"ssh-rsa\"",
+                key_type
+            ),
+            Ok(_) => continue,
+        };
+        issues.push(ConfigIssue::new(format!("allowed-key-types[{i}]"), message));
+    }
+}
+
+/// Check the `host:port` of a `tcp://` socket path; unless `allow_remote`, the host must be a
+/// loopback address or `localhost`
+fn check_tcp_address(path: &str, address: &str, allow_remote: bool, issues: &mut Vec<ConfigIssue>) {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => host,
+        _ => {
+            issues.push(ConfigIssue::new(
+                path.into(),
+                format!("{:?} is not a TCP address; use tcp://host:port", address),
+            ));
+            return;
+        }
+    };
+    let is_loopback = host == "localhost"
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    if !is_loopback && !allow_remote {
+        issues.push(ConfigIssue::new(
+            path.into(),
+            format!(
+                "{:?} is not a loopback address; the agent protocol is unencrypted, so set \
+                 allow-remote-tcp = true to connect to another host anyway",
+                address
+            ),
+        ));
+    }
+}
+
+/// Whether `name` is a vendor extension name (`name@domain`, RFC 4251 section 6)
+fn is_extension_name(name: &str) -> bool {
+    let valid_chars = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_graphic() && c != ',' && c != '@')
+    };
+    name.len() <= 64
+        && name
+            .split_once('@')
+            .is_some_and(|(local, domain)| valid_chars(local) && valid_chars(domain))
+}
+
+/// Check that each of the extension names of the setting at `path` is of the form name@domain
+fn check_extension_names(path: &str, names: &[String], issues: &mut Vec<ConfigIssue>) {
+    for (i, name) in names.iter().enumerate() {
+        if !is_extension_name(name) {
+            issues.push(ConfigIssue::new(
+                format!("{path}[{i}]"),
+                format!("{:?} is not of the form name@domain", name),
+            ));
+        }
+    }
+}
 
-fn expand_env_vars(text: &str) -> EyreResult<String> {
-    Ok(shellexpand::env(text)?.into_owned())
+/// The first `{...}` placeholder in a default-comment template that isn't a supported one
+fn unknown_comment_placeholder(template: &str) -> Option<&str> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}')? + start;
+        let placeholder = &rest[start..=end];
+        if !["{agent}", "{fingerprint}"].contains(&placeholder) {
+            return Some(placeholder);
+        }
+        rest = &rest[end + 1..];
+    }
+    None
 }
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Args {
-    /// Config file
+    /// Config file; may be repeated, later files overriding earlier ones
     #[arg(short, long = "config")]
-    config_path: Option<PathBuf>,
+    config_paths: Vec<PathBuf>,
+
+    /// Serve a single client connection, then exit
+    #[arg(long)]
+    once: bool,
+
+    /// Check that every enabled agent can be reached, then exit without listening; the exit
+    /// code is the result
+    #[arg(long, conflicts_with = "once")]
+    probe_only: bool,
 
     /// Config from file or args
     #[command(flatten)]
     config: <Config as ClapSerde>::Opt,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Clone)]
+pub enum Command {
+    /// Ask each configured agent for its keys directly and report whether it's reachable, how many
+    /// keys it lists, and how quickly it answers (the mux needn't be running)
+    Doctor,
+    /// Suggest [[agents]] configuration for the agent sockets found in the environment
+    Import(import::ImportArgs),
+    /// Print every key the configured agents list, with its fingerprint and owning agent, as
+    /// JSON (the mux needn't be running)
+    Inventory,
+    /// Report which upstream agent would sign with a public key, asking the configured agents
+    /// directly (the mux needn't be running)
+    Route(route::RouteArgs),
+    /// Sign a file through the running mux and report which agent signed it (for diagnostics;
+    /// not a general-purpose signing tool)
+    Sign(sign::SignArgs),
+    /// Show the outcome of the running mux's latest identity request to each upstream agent
+    Status,
+}
+
+/// One agent name, or several in order of preference
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AgentNames {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl AgentNames {
+    pub fn names(&self) -> &[String] {
+        match self {
+            Self::One(name) => std::slice::from_ref(name),
+            Self::Many(names) => names,
+        }
+    }
+}
+
+/// Parse a file mode written in octal, with or without a leading `0o`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8).map_err(|e| format!("{:?} is not an octal mode: {}", mode, e))
 }
 
 fn default_enabled() -> bool {
     true
 }
 
+fn is_empty_path(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AgentConfig {
     pub name: String,
+    /// Required, except for builtin agents, which have no socket
+    #[serde(default, skip_serializing_if = "is_empty_path")]
     pub socket_path: PathBuf,
     #[serde(default = "default_enabled")]
     pub enabled: bool,
+    /// Tags clients can select with the `select-tags@ssh-agent-mux` extension
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Passphrase to lock and unlock this agent with, instead of the client's
+    #[serde(default, skip_serializing)]
+    pub lock_passphrase: Option<Zeroizing<String>>,
+    /// File containing the lock passphrase; a trailing newline is ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_passphrase_file: Option<PathBuf>,
+    /// Implementation of the agent, to enable workarounds for its quirks
+    #[serde(default)]
+    pub kind: AgentKind,
+    /// Seconds to keep retrying to connect to the agent the first time it's used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub startup_grace: Option<u64>,
+    /// Fingerprints (e.g. `SHA256:...`) of the only keys of this agent to expose
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expose_fingerprints: Vec<String>,
+    /// Fingerprints of keys of this agent to hide
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hide_fingerprints: Vec<String>,
+    /// Ask the agent for its keys on every identity request, even under lazy-connect
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub always_refresh: bool,
+    /// Names of the only extensions to forward to the agent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forward_extensions: Vec<String>,
+    /// Names of extensions not to forward to the agent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_forward_extensions: Vec<String>,
+    /// Agents with a lower rank have their keys offered to clients first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offer_rank: Option<i32>,
+    /// Send the agent one request at a time
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub serialize: bool,
+    /// Program and arguments to start the agent with when its socket can't be connected to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// Environment variables to start the agent with, on top of the mux's own
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Ask for approval with confirm-command before forwarding sign requests to the agent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub confirm: bool,
+    /// Only list keys and sign with the agent: don't lock it, or add or remove keys
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+}
+
+/// Pins sign requests for one key to one agent
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RouteConfig {
+    /// Fingerprint of the key, e.g. `SHA256:...`
+    pub fingerprint: String,
+    /// Name of the agent to send sign requests for the key to
+    pub agent: String,
+}
+
+impl AgentConfig {
+    fn is_builtin(&self) -> bool {
+        matches!(self.kind, AgentKind::Builtin)
+    }
+
+    /// Socket path the mux knows the agent by; a builtin agent's is only a unique name
+    fn upstream_socket_path(&self) -> PathBuf {
+        match self.kind {
+            AgentKind::Builtin => UpstreamAgent::builtin(&self.name).socket_path,
+            _ => self.socket_path.clone(),
+        }
+    }
+
+    /// How the agent is reached, from the form of its socket path
+    fn transport(&self) -> UpstreamTransport<'_> {
+        UpstreamTransport::of(&self.socket_path)
+    }
+
+    /// Read `lock-passphrase-file` into `lock_passphrase`
+    fn resolve_lock_passphrase(&mut self) -> EyreResult<()> {
+        let Some(ref path) = self.lock_passphrase_file else {
+            return Ok(());
+        };
+        if self.lock_passphrase.is_some() {
+            return Err(color_eyre::eyre::eyre!(
+                "Agent {:?} sets both lock-passphrase and lock-passphrase-file",
+                self.name
+            ));
+        }
+        let path = path.expand_tilde_owned()?;
+        let mut passphrase = Zeroizing::new(String::new());
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut passphrase))
+            .map_err(|e| {
+                color_eyre::eyre::eyre!(
+                    "Failed to read lock passphrase for agent {:?} from {}: {}",
+                    self.name,
+                    path.display(),
+                    e
+                )
+            })?;
+        let len = passphrase.trim_end_matches(['\r', '\n']).len();
+        passphrase.truncate(len);
+        self.lock_passphrase = Some(passphrase);
+        Ok(())
+    }
+
+    /// Filter of the agent's keys
+    fn key_filter(&self) -> KeyFilter {
+        if !self.expose_fingerprints.is_empty() {
+            KeyFilter::Only(parse_fingerprints(&self.expose_fingerprints))
+        } else if !self.hide_fingerprints.is_empty() {
+            KeyFilter::Except(parse_fingerprints(&self.hide_fingerprints))
+        } else {
+            KeyFilter::All
+        }
+    }
+
+    /// Filter of the extensions forwarded to the agent
+    fn extension_filter(&self) -> ExtensionFilter {
+        if !self.forward_extensions.is_empty() {
+            ExtensionFilter::Only(self.forward_extensions.clone())
+        } else if !self.no_forward_extensions.is_empty() {
+            ExtensionFilter::Except(self.no_forward_extensions.clone())
+        } else {
+            ExtensionFilter::All
+        }
+    }
+}
+
+/// Prefix of the environment variables that define agents, `SSH_AGENT_MUX_AGENT_<index>_<FIELD>`
+const ENV_AGENT_PREFIX: &str = "SSH_AGENT_MUX_AGENT_";
+
+/// Agents defined by `SSH_AGENT_MUX_AGENT_<index>_<FIELD>` variables among `vars`, in index order
+fn env_agents(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+    issues: &mut Vec<ConfigIssue>,
+) -> Vec<AgentConfig> {
+    let mut tables: BTreeMap<u32, toml::Table> = BTreeMap::new();
+    for (var, value) in vars {
+        let Some(rest) = var.to_str().and_then(|v| v.strip_prefix(ENV_AGENT_PREFIX)) else {
+            continue;
+        };
+        let var = var.to_string_lossy().into_owned();
+        let Some((index, field)) = rest
+            .split_once('_')
+            .and_then(|(index, field)| Some((index.parse::<u32>().ok()?, field)))
+        else {
+            issues.push(ConfigIssue::new(
+                var,
+                format!("not of the form {ENV_AGENT_PREFIX}<index>_<field>"),
+            ));
+            continue;
+        };
+        let Some(value) = value.to_str() else {
+            issues.push(ConfigIssue::new(var, "not valid UTF-8".into()));
+            continue;
+        };
+        let string = || toml::Value::String(value.into());
+        let (key, value) = match field {
+            "NAME" => ("name", string()),
+            "SOCKET" => ("socket-path", string()),
+            "KIND" => ("kind", string()),
+            "ENABLED" => match value {
+                "true" | "1" => ("enabled", toml::Value::Boolean(true)),
+                "false" | "0" => ("enabled", toml::Value::Boolean(false)),
+                _ => {
+                    issues.push(ConfigIssue::new(var, "expected true or false".into()));
+                    continue;
+                }
+            },
+            _ => {
+                issues.push(ConfigIssue::new(
+                    var,
+                    format!("unknown field {field}; expected NAME, SOCKET, ENABLED, or KIND"),
+                ));
+                continue;
+            }
+        };
+        tables.entry(index).or_default().insert(key.into(), value);
+    }
+    tables
+        .into_iter()
+        .filter_map(|(index, table)| {
+            toml::Value::Table(table)
+                .try_into()
+                .map_err(|e: toml::de::Error| {
+                    let path = format!("{ENV_AGENT_PREFIX}{index}_*");
+                    issues.push(ConfigIssue::new(path, e.message().into()));
+                })
+                .ok()
+        })
+        .collect()
+}
+
+/// Add agents defined in the environment; one named like an agent from a configuration file
+/// replaces it, in its position
+fn merge_env_agents(agents: &mut Vec<AgentConfig>, env_agents: Vec<AgentConfig>) {
+    for agent in env_agents {
+        match agents.iter_mut().find(|a| a.name == agent.name) {
+            Some(existing) => *existing = agent,
+            None => agents.push(agent),
+        }
+    }
+}
+
+/// A configuration setting that breaks a validation rule
+#[derive(Debug)]
+pub struct ConfigIssue {
+    /// TOML path of the setting, e.g. `agents[2].name`
+    pub path: String,
+    /// The rule that was broken
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(path: String, message: String) -> Self {
+        Self { path, message }
+    }
+}
+
+/// Every validation rule broken by a configuration
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigIssue>);
+
+impl ConfigErrors {
+    fn from_issues(issues: Vec<ConfigIssue>) -> Result<(), Self> {
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(Self(issues))
+        }
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for issue in &self.0 {
+            write!(f, "\n  {}: {}", issue.path, issue.message)?;
+        }
+        Ok(())
+    }
 }
 
+impl std::error::Error for ConfigErrors {}
+
 #[derive(ClapSerde, Clone, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
@@ -61,36 +677,284 @@ pub struct Config {
     #[arg(long = "listen-path")]
     pub listen_path: PathBuf,
 
+    /// Permissions of the listening socket, in octal, e.g. 0660
+    #[default(0o600)]
+    #[arg(long = "listen-mode", value_parser = parse_mode)]
+    pub listen_mode: u32,
+
     /// Log level for agent
     #[default(LogLevel::Warn)]
     #[arg(long = "log-level", value_enum)]
     pub log_level: LogLevel,
 
-    /// Optional log file for agent (logs to standard output, otherwise)
+    /// Timestamp to start each log line with
+    #[default(LogTimestamp::None)]
+    #[arg(long = "log-timestamp", value_enum)]
+    pub log_timestamp: LogTimestamp,
+
+    /// Format of log records: lines of text, or one JSON object per record
+    #[default(LogFormat::Text)]
+    #[arg(long = "log-format", value_enum)]
+    pub log_format: LogFormat,
+
+    /// What to log of each sign request for auditing, even below log-level info
+    #[default(AuditVerbosity::None)]
+    #[arg(long = "audit-verbosity", value_enum)]
+    pub audit_verbosity: AuditVerbosity,
+
+    /// Optional log file for agent (logs to standard output, otherwise); `-` logs to standard
+    /// output even if the configuration file sets a log file
     #[arg(long = "log-file", num_args = 1)]
     pub log_file: Option<PathBuf>,
 
+    /// Optional file to append a JSON line to for each sign request, whatever the log level;
+    /// records at least the outcome, even with audit-verbosity none
+    #[arg(long = "audit-file", num_args = 1)]
+    pub audit_file: Option<PathBuf>,
+
     /// Timeout in seconds for upstream agent operations (default: 5)
     #[default(5)]
     #[arg(long = "agent-timeout")]
     pub agent_timeout: u64,
 
+    /// Retry a failed sign once after asking upstream agents which of them now holds the key, if
+    /// the agent holding it no longer lists it or can't be reached
+    #[default(true)]
+    #[arg(long = "retry-sign", action = clap::ArgAction::Set)]
+    pub retry_sign: bool,
+
+    /// Refresh identities from upstream agents when asked to sign with an unknown key
+    #[default(true)]
+    #[arg(long = "refresh-on-sign-miss", action = clap::ArgAction::Set)]
+    pub refresh_on_sign_miss: bool,
+
+    /// Seconds after a refresh during which sign requests for unknown keys don't refresh again
+    #[default(0)]
+    #[arg(long = "cache-ttl")]
+    pub cache_ttl: u64,
+
+    /// List the known keys instead of connecting to every upstream agent on each identity
+    /// request, refreshing them only occasionally
+    #[default(false)]
+    #[arg(long = "lazy-connect", action = clap::ArgAction::Set)]
+    pub lazy_connect: bool,
+
+    /// Keep connections to upstream agents open between requests, instead of connecting for each
+    #[default(false)]
+    #[arg(long = "reuse-connections", action = clap::ArgAction::Set)]
+    pub reuse_connections: bool,
+
+    /// Answer the sign-check@ssh-agent-mux debugging extension, which makes every upstream agent
+    /// holding a key sign with it
+    #[default(false)]
+    #[arg(long = "sign-check", action = clap::ArgAction::Set)]
+    pub sign_check: bool,
+
+    /// Prefix for the comment of every key the mux lists (e.g. "[mux] ")
+    #[default(String::new())]
+    #[arg(long = "comment-prefix")]
+    pub comment_prefix: String,
+
+    /// Comment for keys listed without one; {agent} and {fingerprint} are replaced with the name
+    /// of the agent holding the key and its fingerprint
+    #[arg(long = "default-comment")]
+    pub default_comment: Option<String>,
+
+    /// Append the name of the agent holding each key to its listed comment (e.g. "user@host
+    /// [yubikey]")
+    #[default(false)]
+    #[arg(long = "annotate-comments", action = clap::ArgAction::Set)]
+    pub annotate_comments: bool,
+
+    /// Only list keys whose comment, as their upstream agent lists it, matches this regular
+    /// expression (e.g. "work@.*")
+    #[arg(long = "comment-filter")]
+    pub comment_filter: Option<String>,
+
+    /// List the keys whose comment doesn't match comment-filter, instead of those whose does
+    #[default(false)]
+    #[arg(long = "comment-filter-invert", action = clap::ArgAction::Set)]
+    pub comment_filter_invert: bool,
+
+    /// Refresh identities from upstream agents every this many seconds, in the background
+    #[arg(long = "background-refresh")]
+    pub background_refresh: Option<u64>,
+
+    /// Exit when the configuration can't be reloaded on SIGHUP, instead of keeping the previous one
+    #[default(false)]
+    #[arg(long = "strict-reload", action = clap::ArgAction::Set)]
+    pub strict_reload: bool,
+
+    /// Reload the configuration when a configuration file changes, as on SIGHUP
+    #[default(false)]
+    #[arg(long = "watch-config", action = clap::ArgAction::Set)]
+    pub watch_config: bool,
+
+    /// Make socket paths absolute and resolve symlinks in them when loading the configuration
+    #[default(true)]
+    #[arg(long = "canonicalize-paths", action = clap::ArgAction::Set)]
+    pub canonicalize_paths: bool,
+
+    /// Accept connections from processes of other users, not only the mux's own user and root
+    #[default(false)]
+    #[arg(long = "allow-any-peer", action = clap::ArgAction::Set)]
+    pub allow_any_peer: bool,
+
+    /// Client requests the mux handles; others fail (default: all of them)
+    #[arg(skip)]
+    #[default(Operation::ALL.to_vec())]
+    pub allowed_operations: Vec<Operation>,
+
+    /// Serve Prometheus metrics over HTTP on this address (e.g. 127.0.0.1:9898)
+    #[arg(long = "metrics-http")]
+    pub metrics_http: Option<SocketAddr>,
+
+    /// Cache known keys in this file across restarts, to route sign requests sooner at startup
+    #[arg(long = "known-keys-cache")]
+    pub known_keys_cache: Option<PathBuf>,
+
+    /// Ignore a known keys cache older than this many seconds at startup (default: 300)
+    #[default(300)]
+    #[arg(long = "known-keys-cache-max-age")]
+    pub known_keys_cache_max_age: u64,
+
+    /// Extension names to advertise in responses to `query`, besides the built-in ones
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub advertise_extensions: Vec<String>,
+
     /// Upstream agents to multiplex
     #[arg(skip)]
     #[default(Vec::new())]
     pub agents: Vec<AgentConfig>,
 
-    /// Name of agent to forward add_identity requests to
+    /// Allow agents' socket-path to be a TCP address on another host, over which the agent
+    /// protocol is sent unencrypted
+    #[default(false)]
+    #[arg(long = "allow-remote-tcp", action = clap::ArgAction::Set)]
+    pub allow_remote_tcp: bool,
+
+    /// Agents to send sign requests for keys to, by key fingerprint
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub routes: Vec<RouteConfig>,
+
+    /// Name of agent to forward add_identity requests to, or names of agents to try in order
+    #[arg(skip)]
+    pub add_new_keys_to: Option<AgentNames>,
+
+    /// Add keys to every add-new-keys-to agent, instead of the first that accepts them
+    #[default(false)]
+    #[arg(long = "add-to-all", action = clap::ArgAction::Set)]
+    pub add_to_all: bool,
+
+    /// What to do when adding a key that the add-new-keys-to agent already holds
+    #[default(AddIfPresent::Replace)]
+    #[arg(long = "add-if-present", value_enum)]
+    pub add_if_present: AddIfPresent,
+
+    /// Which agent signs with a key that several agents list
+    #[default(DuplicateKeyPolicy::LastWins)]
+    #[arg(long = "duplicate-key-policy", value_enum)]
+    pub duplicate_key_policy: DuplicateKeyPolicy,
+
+    /// Timeout in seconds for adding a key to the add-new-keys-to agent (default: agent-timeout)
+    #[arg(long = "add-timeout")]
+    pub add_timeout: Option<u64>,
+
+    /// Times to retry adding a key to the add-new-keys-to agent after a timeout
+    #[default(0)]
+    #[arg(long = "add-retries")]
+    pub add_retries: u32,
+
+    /// Refuse to sign with keys added without a lifetime or confirmation constraint
+    #[default(RequireConstraints::Off)]
+    #[arg(long = "require-constraints-for-sign", value_enum)]
+    pub require_constraints_for_sign: RequireConstraints,
+
+    /// Ask for approval with confirm-command before forwarding any sign request
+    #[default(false)]
+    #[arg(long = "confirm-sign", action = clap::ArgAction::Set)]
+    pub confirm_sign: bool,
+
+    /// Program and arguments to ask for approval of a sign request with, run like ssh-askpass
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub confirm_command: Vec<String>,
+
+    /// Seconds to wait for approval of a sign request before refusing it
+    #[default(30)]
+    #[arg(long = "confirm-timeout")]
+    pub confirm_timeout: u64,
+
+    /// Name of agent to send sign requests to when no upstream agent has the key
+    #[arg(skip)]
+    pub default_agent: Option<String>,
+
+    /// Ask every upstream agent in turn to sign with a key none of them lists, when there's no
+    /// default-agent
+    #[default(false)]
+    #[arg(long = "sign-fallback-broadcast", action = clap::ArgAction::Set)]
+    pub sign_fallback_broadcast: bool,
+
+    /// Whether keys are visible to clients without being listed in visible-fingerprints
+    #[default(Visibility::All)]
+    #[arg(long = "default-visibility", value_enum)]
+    pub default_visibility: Visibility,
+
+    /// Fingerprints of the keys visible to clients when default-visibility is "none"
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub visible_fingerprints: Vec<String>,
+
+    /// Types of the keys visible to clients (e.g. "ssh-ed25519"), or empty for every type
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub allowed_key_types: Vec<String>,
+
+    /// How to expand references to undefined environment variables in the configuration file
     #[arg(skip)]
-    pub add_new_keys_to: Option<String>,
+    #[default(EnvUndefined::Error)]
+    pub env_undefined: EnvUndefined,
+
+    /// Settings whose values are used as written, without expanding environment variables
+    /// (e.g. `agents.socket-path`, for the socket path of every agent)
+    #[arg(skip)]
+    #[default(Vec::new())]
+    pub no_env_expansion: Vec<String>,
 
     // Following are part of command line args, but
     // not in configuration file
-    /// Config file path (not an arg; copied from struct Args)
+    /// Config file path, the last one if several were given (not an arg; copied from struct Args)
     #[arg(skip)]
     #[serde(skip_deserializing, skip_serializing)]
     pub config_path: PathBuf,
 
+    /// Every config file path, in order (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub config_paths: Vec<PathBuf>,
+
+    /// Problems with the configuration that don't prevent using it, to log once logging is set up
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub warnings: Vec<String>,
+
+    /// Subcommand to run instead of the agent (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub command: Option<Command>,
+
+    /// Serve a single client connection, then exit (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub once: bool,
+
+    /// Check the agents instead of serving (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub probe_only: bool,
+
     #[serde(skip_deserializing, skip_serializing)]
     #[command(flatten)]
     pub service: service::ServiceArgs,
@@ -98,88 +962,587 @@ pub struct Config {
 
 impl Config {
     pub fn parse() -> EyreResult<Self> {
-        let mut args = Args::parse();
-
-        let config_path = args.config_path.or_else(|| default_config_path().ok());
-
-        let mut config = if let Some(ref path) = config_path {
-            if let Ok(mut f) = File::open(path) {
-                log::info!("Read configuration from {}", path.display());
-                let mut config_text = String::new();
-                f.read_to_string(&mut config_text)?;
-                let expanded_config_text = expand_env_vars(&config_text)?;
-                let file_config = toml::from_str::<<Config as ClapSerde>::Opt>(&expanded_config_text)?;
-                Config::from(file_config).merge(&mut args.config)
-            } else {
-                Config::from(&mut args.config)
-            }
+        Self::from_args(Args::parse())
+    }
+
+    fn from_args(mut args: Args) -> EyreResult<Self> {
+        let config_paths = if args.config_paths.is_empty() {
+            default_config_path().into_iter().collect()
         } else {
-            Config::from(&mut args.config)
+            args.config_paths
+        };
+
+        let mut config = match read_config_files(&config_paths)? {
+            Some(file_config) => Config::from(file_config).merge(&mut args.config),
+            None => Config::from(&mut args.config),
         };
+        let mut issues = vec![];
+        merge_env_agents(&mut config.agents, env_agents(env::vars_os(), &mut issues));
 
-        config.config_path = config_path.unwrap_or_default();
+        config.config_path = config_paths.last().cloned().unwrap_or_default();
+        config.config_paths = config_paths;
+        config.command = args.command;
+        config.once = args.once;
+        config.probe_only = args.probe_only;
         config.listen_path = config.listen_path.expand_tilde_owned()?;
-        config.log_file = config.log_file
+        config.log_file = config
+            .log_file
+            .map(|p| {
+                if logging::is_stdout_log_file(&p) {
+                    Ok(p)
+                } else {
+                    p.expand_tilde_owned()
+                }
+            })
+            .transpose()?;
+        config.audit_file = config
+            .audit_file
             .map(|p| p.expand_tilde_owned())
             .transpose()?;
-        config.agents = config
-            .agents
-            .into_iter()
-            .map(|mut a| {
-                a.socket_path = a.socket_path.expand_tilde_owned()?;
-                Ok(a)
-            })
-            .collect::<EyreResult<Vec<_>>>()?;
-
-        // Validate agent names are unique
-        let mut seen_names = std::collections::HashSet::new();
-        for agent in &config.agents {
-            if !seen_names.insert(&agent.name) {
-                return Err(color_eyre::eyre::eyre!(
-                    "Duplicate agent name: {:?}",
-                    agent.name
+        config.known_keys_cache = config
+            .known_keys_cache
+            .map(|p| p.expand_tilde_owned())
+            .transpose()?;
+        for (i, agent) in config.agents.iter_mut().enumerate() {
+            agent.socket_path = agent.socket_path.expand_tilde_owned()?;
+            if let Err(e) = agent.resolve_lock_passphrase() {
+                issues.push(ConfigIssue::new(
+                    format!("agents[{i}].lock-passphrase-file"),
+                    e.to_string(),
                 ));
             }
         }
-
-        // Validate add-new-keys-to references an existing, enabled agent
-        if let Some(ref name) = config.add_new_keys_to {
-            match config.agents.iter().find(|a| a.name == *name) {
-                None => {
-                    return Err(color_eyre::eyre::eyre!(
-                        "add-new-keys-to references unknown agent: {:?}",
-                        name
-                    ));
-                }
-                Some(agent) if !agent.enabled => {
-                    return Err(color_eyre::eyre::eyre!(
-                        "add-new-keys-to references disabled agent: {:?}",
-                        name
-                    ));
-                }
-                _ => {}
-            }
+        if config.canonicalize_paths {
+            config.canonicalize_socket_paths()?;
+        }
+        if let Err(ConfigErrors(more_issues)) = config.validate() {
+            issues.extend(more_issues);
         }
+        ConfigErrors::from_issues(issues)?;
 
         Ok(config)
     }
 
-    pub fn enabled_agent_socket_paths(&self) -> Vec<PathBuf> {
-        self.agents
-            .iter()
-            .filter(|a| a.enabled)
-            .map(|a| a.socket_path.clone())
-            .collect()
+    /// Canonicalize the listen path and agent socket paths, warning about relative ones, which
+    /// depend on the directory the mux is started in
+    fn canonicalize_socket_paths(&mut self) -> EyreResult<()> {
+        let mut canonicalize = |option: String, path: &mut PathBuf| -> EyreResult<()> {
+            if path.is_relative() {
+                self.warnings.push(format!(
+                    "{option}: relative path {:?} is resolved against the working directory",
+                    path
+                ));
+            }
+            *path = canonicalize_path(path)
+                .map_err(|e| color_eyre::eyre::eyre!("{option}: {}: {}", path.display(), e))?;
+            Ok(())
+        };
+        canonicalize("listen-path".into(), &mut self.listen_path)?;
+        for (i, agent) in self.agents.iter_mut().enumerate() {
+            if agent.is_builtin() || !matches!(agent.transport(), UpstreamTransport::Unix(_)) {
+                continue;
+            }
+            canonicalize(format!("agents[{i}].socket-path"), &mut agent.socket_path)?;
+        }
+        Ok(())
     }
 
-    pub fn added_keys_socket_path(&self) -> Option<PathBuf> {
-        self.add_new_keys_to.as_ref().and_then(|name| {
-            self.agents
-                .iter()
-                .find(|a| a.name == *name)
-                .map(|a| a.socket_path.clone())
+    /// Check the rules a configuration must satisfy beyond parsing, reporting every violation
+    fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut issues = vec![];
+
+        // Validate agent names are unique
+        let mut seen_names = std::collections::HashMap::new();
+        for (i, agent) in self.agents.iter().enumerate() {
+            if let Some(first) = seen_names.insert(&agent.name, i) {
+                issues.push(ConfigIssue::new(
+                    format!("agents[{i}].name"),
+                    format!(
+                        "duplicate agent name {:?}, already used by agents[{first}]",
+                        agent.name
+                    ),
+                ));
+            }
+        }
+
+        for (i, agent) in self.agents.iter().enumerate() {
+            match (agent.is_builtin(), is_empty_path(&agent.socket_path)) {
+                (true, false) => issues.push(ConfigIssue::new(
+                    format!("agents[{i}].socket-path"),
+                    "builtin agents have no socket".into(),
+                )),
+                (false, true) => issues.push(ConfigIssue::new(
+                    format!("agents[{i}].socket-path"),
+                    "missing; required unless kind = \"builtin\"".into(),
+                )),
+                _ => {}
+            }
+            match agent.command.first() {
+                Some(_) if agent.is_builtin() => issues.push(ConfigIssue::new(
+                    format!("agents[{i}].command"),
+                    "builtin agents are run by the mux itself".into(),
+                )),
+                Some(program) if program.is_empty() => issues.push(ConfigIssue::new(
+                    format!("agents[{i}].command"),
+                    "the program to run is empty".into(),
+                )),
+                _ => {}
+            }
+            if !agent.env.is_empty() && agent.command.is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("agents[{i}].env"),
+                    "only applies with command".into(),
+                ));
+            }
+            for name in agent.env.keys() {
+                if name.is_empty() || name.contains(['=', '\0']) {
+                    issues.push(ConfigIssue::new(
+                        format!("agents[{i}].env"),
+                        format!("{:?} is not an environment variable name", name),
+                    ));
+                }
+            }
+            let option = format!("agents[{i}].socket-path");
+            match agent.transport() {
+                UpstreamTransport::Unix(path) if path == self.listen_path => {
+                    issues.push(ConfigIssue::new(
+                        option,
+                        "is the mux's own listen-path, so requests would loop back to the mux"
+                            .into(),
+                    ));
+                }
+                UpstreamTransport::Unix(_) => {}
+                UpstreamTransport::Tcp(address) => {
+                    check_tcp_address(&option, address, self.allow_remote_tcp, &mut issues);
+                }
+            }
+            if !agent.expose_fingerprints.is_empty() && !agent.hide_fingerprints.is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("agents[{i}].hide-fingerprints"),
+                    "cannot be combined with expose-fingerprints".into(),
+                ));
+            }
+            for (option, fingerprints) in [
+                ("expose-fingerprints", &agent.expose_fingerprints),
+                ("hide-fingerprints", &agent.hide_fingerprints),
+            ] {
+                check_fingerprints(&format!("agents[{i}].{option}"), fingerprints, &mut issues);
+            }
+
+            if !agent.forward_extensions.is_empty() && !agent.no_forward_extensions.is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("agents[{i}].no-forward-extensions"),
+                    "cannot be combined with forward-extensions".into(),
+                ));
+            }
+            for (option, names) in [
+                ("forward-extensions", &agent.forward_extensions),
+                ("no-forward-extensions", &agent.no_forward_extensions),
+            ] {
+                check_extension_names(&format!("agents[{i}].{option}"), names, &mut issues);
+            }
+        }
+
+        check_fingerprints(
+            "visible-fingerprints",
+            &self.visible_fingerprints,
+            &mut issues,
+        );
+        check_key_types(&self.allowed_key_types, &mut issues);
+        if matches!(self.default_visibility, Visibility::All)
+            && !self.visible_fingerprints.is_empty()
+        {
+            issues.push(ConfigIssue::new(
+                "visible-fingerprints".into(),
+                "only applies with default-visibility = \"none\"".into(),
+            ));
+        }
+
+        check_extension_names(
+            "advertise-extensions",
+            &self.advertise_extensions,
+            &mut issues,
+        );
+
+        let confirming = self.confirm_sign || self.agents.iter().any(|a| a.enabled && a.confirm);
+        match self.confirm_command.first() {
+            None if confirming => issues.push(ConfigIssue::new(
+                "confirm-command".into(),
+                "missing; required by confirm-sign or an agent's confirm".into(),
+            )),
+            Some(program) if program.is_empty() => issues.push(ConfigIssue::new(
+                "confirm-command".into(),
+                "the program to run is empty".into(),
+            )),
+            _ => {}
+        }
+
+        if let Some(ref template) = self.default_comment {
+            if let Some(placeholder) = unknown_comment_placeholder(template) {
+                issues.push(ConfigIssue::new(
+                    "default-comment".into(),
+                    format!(
+                        "unknown placeholder {:?}; use {{agent}} or {{fingerprint}}",
+                        placeholder
+                    ),
+                ));
+            }
+        }
+
+        match self.comment_filter {
+            Some(ref pattern) => {
+                if let Err(e) = Regex::new(pattern) {
+                    issues.push(ConfigIssue::new(
+                        "comment-filter".into(),
+                        format!("{:?} is not a valid regular expression: {}", pattern, e),
+                    ));
+                }
+            }
+            None if self.comment_filter_invert => issues.push(ConfigIssue::new(
+                "comment-filter-invert".into(),
+                "only applies with comment-filter".into(),
+            )),
+            None => {}
+        }
+
+        if self.listen_mode & !0o777 != 0 {
+            issues.push(ConfigIssue::new(
+                "listen-mode".into(),
+                format!(
+                    "{} isn't a permission mode; write it in octal, e.g. 0o600",
+                    self.listen_mode
+                ),
+            ));
+        }
+
+        if self.background_refresh == Some(0) {
+            issues.push(ConfigIssue::new(
+                "background-refresh".into(),
+                "must be at least 1 second".into(),
+            ));
+        }
+
+        match self.add_new_keys_to {
+            Some(AgentNames::One(ref name)) => {
+                issues.extend(self.check_add_target("add-new-keys-to", name));
+            }
+            Some(AgentNames::Many(ref names)) => {
+                for (i, name) in names.iter().enumerate() {
+                    let option = format!("add-new-keys-to[{i}]");
+                    issues.extend(self.check_add_target(&option, name));
+                }
+            }
+            None => {}
+        }
+        if let Some(ref name) = self.default_agent {
+            issues.extend(self.check_agent_reference("default-agent", name));
+        }
+        let mut routed = std::collections::HashMap::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            match route.fingerprint.parse::<Fingerprint>() {
+                Err(e) => issues.push(ConfigIssue::new(
+                    format!("routes[{i}].fingerprint"),
+                    format!("{:?} is not a key fingerprint: {}", route.fingerprint, e),
+                )),
+                Ok(fingerprint) => {
+                    if let Some(first) = routed.insert(fingerprint.to_string(), i) {
+                        issues.push(ConfigIssue::new(
+                            format!("routes[{i}].fingerprint"),
+                            format!(
+                                "duplicate route for {}, already in routes[{first}]",
+                                fingerprint
+                            ),
+                        ));
+                    }
+                }
+            }
+            issues.extend(self.check_agent_reference(&format!("routes[{i}].agent"), &route.agent));
+        }
+
+        ConfigErrors::from_issues(issues)
+    }
+
+    /// Check that an option naming an agent references an existing, enabled agent
+    fn check_agent_reference(&self, option: &str, name: &str) -> Option<ConfigIssue> {
+        let message = match self.agents.iter().find(|a| a.name == name) {
+            None => format!("references unknown agent {:?}", name),
+            Some(agent) if !agent.enabled => format!("references disabled agent {:?}", name),
+            _ => return None,
+        };
+        Some(ConfigIssue::new(option.into(), message))
+    }
+
+    /// Check that an add-new-keys-to entry references an agent keys can be added to
+    fn check_add_target(&self, option: &str, name: &str) -> Option<ConfigIssue> {
+        self.check_agent_reference(option, name).or_else(|| {
+            let agent = self.agents.iter().find(|a| a.name == name)?;
+            agent.read_only.then(|| {
+                ConfigIssue::new(
+                    option.into(),
+                    format!("references read-only agent {:?}", name),
+                )
+            })
         })
     }
+
+    pub fn enabled_upstream_agents(&self) -> Vec<UpstreamAgent> {
+        self.agents
+            .iter()
+            .filter(|a| a.enabled)
+            .map(|a| UpstreamAgent {
+                tags: a.tags.clone(),
+                lock_passphrase: a.lock_passphrase.clone(),
+                kind: a.kind.into(),
+                startup_grace: Duration::from_secs(a.startup_grace.unwrap_or_default()),
+                key_filter: a.key_filter(),
+                always_refresh: a.always_refresh,
+                extension_filter: a.extension_filter(),
+                offer_rank: a.offer_rank.unwrap_or_default(),
+                serialize: a.serialize,
+                command: a.command.clone(),
+                env: a.env.clone(),
+                confirm: a.confirm,
+                read_only: a.read_only,
+                ..UpstreamAgent::new(&a.name, a.upstream_socket_path())
+            })
+            .collect()
+    }
+
+    pub fn mux_options(&self) -> MuxOptions {
+        MuxOptions {
+            agent_timeout: Duration::from_secs(self.agent_timeout),
+            retry_sign: self.retry_sign,
+            refresh_on_sign_miss: self.refresh_on_sign_miss,
+            cache_ttl: Duration::from_secs(self.cache_ttl),
+            lazy_connect: self.lazy_connect,
+            reuse_connections: self.reuse_connections,
+            sign_check: self.sign_check,
+            comment_prefix: self.comment_prefix.clone(),
+            default_comment: self.default_comment.clone(),
+            annotate_comments: self.annotate_comments,
+            comment_filter: self
+                .comment_filter
+                .as_ref()
+                .and_then(|pattern| Regex::new(pattern).ok())
+                .map(|pattern| CommentFilter {
+                    pattern,
+                    invert: self.comment_filter_invert,
+                }),
+            background_refresh: self.background_refresh.map(Duration::from_secs),
+            allowed_operations: self
+                .allowed_operations
+                .iter()
+                .map(|&op| op.into())
+                .collect(),
+            metrics_http: self.metrics_http,
+            default_agent_sock: self.default_agent_socket_path(),
+            sign_fallback_broadcast: self.sign_fallback_broadcast,
+            key_routes: self
+                .routes
+                .iter()
+                .filter_map(|route| {
+                    let fingerprint = route.fingerprint.parse().ok()?;
+                    Some((fingerprint, self.agent_socket_path(&route.agent)?))
+                })
+                .collect(),
+            known_keys_cache: self.known_keys_cache.clone(),
+            known_keys_cache_max_age: Duration::from_secs(self.known_keys_cache_max_age),
+            extra_extensions: self.advertise_extensions.clone(),
+            add_if_present: self.add_if_present.into(),
+            add_to_all: self.add_to_all,
+            duplicate_key_policy: self.duplicate_key_policy.into(),
+            add_timeout: self.add_timeout.map(Duration::from_secs),
+            add_retries: self.add_retries,
+            audit: match (self.audit_verbosity, &self.audit_file) {
+                (AuditVerbosity::None, Some(_)) => ssh_agent_mux::AuditVerbosity::Outcome,
+                (verbosity, _) => verbosity.into(),
+            },
+            require_constraints: self.require_constraints_for_sign.into(),
+            confirm_sign: self.confirm_sign,
+            confirm_command: self.confirm_command.clone(),
+            confirm_timeout: Duration::from_secs(self.confirm_timeout),
+            once: self.once,
+            policy: Arc::new(AllowAll),
+            // Set by the caller, which outlives reloads
+            identity_cache: None,
+            spawned_agents: None,
+            allow_any_peer: self.allow_any_peer,
+            listen_mode: self.listen_mode,
+            visible_keys: match self.default_visibility {
+                Visibility::All => KeyFilter::All,
+                Visibility::None => KeyFilter::Only(parse_fingerprints(&self.visible_fingerprints)),
+            },
+            allowed_key_types: self
+                .allowed_key_types
+                .iter()
+                .filter_map(|t| t.parse().ok())
+                .collect(),
+        }
+    }
+
+    pub fn added_keys_socket_paths(&self) -> Vec<PathBuf> {
+        self.add_new_keys_to
+            .iter()
+            .flat_map(AgentNames::names)
+            .filter_map(|name| self.agent_socket_path(name))
+            .collect()
+    }
+
+    pub fn default_agent_socket_path(&self) -> Option<PathBuf> {
+        self.default_agent
+            .as_ref()
+            .and_then(|name| self.agent_socket_path(name))
+    }
+
+    fn agent_socket_path(&self, name: &str) -> Option<PathBuf> {
+        self.agents
+            .iter()
+            .find(|a| a.name == name)
+            .map(AgentConfig::upstream_socket_path)
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AgentKind {
+    #[default]
+    SshAgent,
+    /// gpg-agent's SSH socket; see [`UpstreamKind::GpgAgent`] for the behaviors this enables
+    GpgAgent,
+    /// An in-memory agent run by the mux; see [`UpstreamKind::Builtin`]
+    Builtin,
+}
+
+impl From<AgentKind> for UpstreamKind {
+    fn from(value: AgentKind) -> Self {
+        match value {
+            AgentKind::SshAgent => UpstreamKind::Standard,
+            AgentKind::GpgAgent => UpstreamKind::GpgAgent,
+            AgentKind::Builtin => UpstreamKind::Builtin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    RequestIdentities,
+    Sign,
+    AddIdentity,
+    RemoveIdentity,
+    Lock,
+    Unlock,
+    Extension,
+}
+
+impl Operation {
+    const ALL: [Operation; 7] = [
+        Operation::RequestIdentities,
+        Operation::Sign,
+        Operation::AddIdentity,
+        Operation::RemoveIdentity,
+        Operation::Lock,
+        Operation::Unlock,
+        Operation::Extension,
+    ];
+}
+
+impl From<Operation> for ssh_agent_mux::Operation {
+    fn from(value: Operation) -> Self {
+        match value {
+            Operation::RequestIdentities => ssh_agent_mux::Operation::RequestIdentities,
+            Operation::Sign => ssh_agent_mux::Operation::Sign,
+            Operation::AddIdentity => ssh_agent_mux::Operation::AddIdentity,
+            Operation::RemoveIdentity => ssh_agent_mux::Operation::RemoveIdentity,
+            Operation::Lock => ssh_agent_mux::Operation::Lock,
+            Operation::Unlock => ssh_agent_mux::Operation::Unlock,
+            Operation::Extension => ssh_agent_mux::Operation::Extension,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddIfPresent {
+    /// Add the key again, replacing its constraints
+    Replace,
+    /// Keep the existing key and report success
+    Skip,
+    /// Keep the existing key and report failure
+    Error,
+}
+
+impl From<AddIfPresent> for ssh_agent_mux::AddIfPresent {
+    fn from(value: AddIfPresent) -> Self {
+        match value {
+            AddIfPresent::Replace => ssh_agent_mux::AddIfPresent::Replace,
+            AddIfPresent::Skip => ssh_agent_mux::AddIfPresent::Skip,
+            AddIfPresent::Error => ssh_agent_mux::AddIfPresent::Error,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateKeyPolicy {
+    /// The last configured agent listing the key signs with it
+    LastWins,
+    /// The first configured agent listing the key signs with it
+    FirstWins,
+    /// Refuse to sign with the key
+    Error,
+}
+
+impl From<DuplicateKeyPolicy> for ssh_agent_mux::DuplicateKeyPolicy {
+    fn from(value: DuplicateKeyPolicy) -> Self {
+        match value {
+            DuplicateKeyPolicy::LastWins => ssh_agent_mux::DuplicateKeyPolicy::LastWins,
+            DuplicateKeyPolicy::FirstWins => ssh_agent_mux::DuplicateKeyPolicy::FirstWins,
+            DuplicateKeyPolicy::Error => ssh_agent_mux::DuplicateKeyPolicy::Error,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequireConstraints {
+    /// Sign with any key
+    Off,
+    /// Refuse keys added through the mux without a constraint, but allow keys it didn't see added
+    Added,
+    /// Refuse every key not added through the mux with a constraint
+    All,
+}
+
+impl From<RequireConstraints> for ssh_agent_mux::RequireConstraints {
+    fn from(value: RequireConstraints) -> Self {
+        match value {
+            RequireConstraints::Off => ssh_agent_mux::RequireConstraints::Off,
+            RequireConstraints::Added => ssh_agent_mux::RequireConstraints::AddedKeys,
+            RequireConstraints::All => ssh_agent_mux::RequireConstraints::AllKeys,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvUndefined {
+    /// Fail to load the configuration
+    #[default]
+    Error,
+    /// Leave the reference as written
+    Keep,
+    /// Expand to an empty string
+    Empty,
+}
+
+#[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Clients see every key of the upstream agents
+    All,
+    /// Clients only see the keys listed in visible-fingerprints
+    None,
 }
 
 #[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
@@ -193,6 +1556,50 @@ pub enum LogLevel {
     Trace = 5,
 }
 
+/// Timestamp format of log lines
+#[derive(ValueEnum, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTimestamp {
+    /// RFC 3339 in UTC, e.g. `2024-05-01T12:34:56.789Z`, for log aggregation
+    Rfc3339,
+    /// Local time with its UTC offset, for reading
+    Local,
+    /// No timestamp, e.g. for journald, which adds its own
+    None,
+}
+
+/// Format of log records
+#[derive(ValueEnum, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines of text, for reading
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+/// What is logged of each sign request for auditing
+#[derive(ValueEnum, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditVerbosity {
+    /// Nothing beyond the usual logging
+    None,
+    /// The key and the outcome
+    Outcome,
+    /// The key, the outcome, and how the request was routed
+    Routing,
+}
+
+impl From<AuditVerbosity> for ssh_agent_mux::AuditVerbosity {
+    fn from(value: AuditVerbosity) -> Self {
+        match value {
+            AuditVerbosity::None => ssh_agent_mux::AuditVerbosity::Off,
+            AuditVerbosity::Outcome => ssh_agent_mux::AuditVerbosity::Outcome,
+            AuditVerbosity::Routing => ssh_agent_mux::AuditVerbosity::Routing,
+        }
+    }
+}
+
 impl From<LogLevel> for LevelFilter {
     fn from(value: LogLevel) -> Self {
         match value {
@@ -210,20 +1617,25 @@ mod tests {
     use super::*;
     use std::env;
 
+    /// Parse configuration file contents, expanding environment variables in its string values
+    fn parse_config_text(text: &str) -> EyreResult<<Config as ClapSerde>::Opt> {
+        Ok(read_config_value(text)?.try_into()?)
+    }
+
     #[test]
     fn test_env_var_expansion() -> EyreResult<()> {
         // Test basic environment variable expansion
         env::set_var("TEST_VAR", "test_value");
-        let result = expand_env_vars("${TEST_VAR}")?;
+        let result = expand_env_vars("${TEST_VAR}", EnvUndefined::Error)?;
         assert_eq!(result, "test_value");
 
         // Test expansion in middle of string
-        let result = expand_env_vars("/path/${TEST_VAR}/file")?;
+        let result = expand_env_vars("/path/${TEST_VAR}/file", EnvUndefined::Error)?;
         assert_eq!(result, "/path/test_value/file");
 
         // Test multiple variables
         env::set_var("TEST_VAR2", "another");
-        let result = expand_env_vars("${TEST_VAR}_${TEST_VAR2}")?;
+        let result = expand_env_vars("${TEST_VAR}_${TEST_VAR2}", EnvUndefined::Error)?;
         assert_eq!(result, "test_value_another");
 
         Ok(())
@@ -257,13 +1669,250 @@ socket-path = "/tmp/ssh-agent-${TEST_USER}.sock"
         std::io::Write::write_all(&mut temp_file, config_content.as_bytes())?;
 
         // Test that our expansion function works on the config content
-        let expanded = expand_env_vars(config_content)?;
+        let config = Config::from(parse_config_text(config_content)?);
 
         // Verify environment variables were expanded
-        assert!(expanded.contains("/test/home/.ssh/agent-mux.sock"));
-        assert!(expanded.contains("/test/home/logs/ssh-agent-mux.log"));
-        assert!(expanded.contains("/tmp/test.sock"));
-        assert!(expanded.contains("/tmp/ssh-agent-testuser.sock"));
+        assert_eq!(
+            config.listen_path,
+            PathBuf::from("/test/home/.ssh/agent-mux.sock")
+        );
+        assert_eq!(
+            config.log_file,
+            Some(PathBuf::from("/test/home/logs/ssh-agent-mux.log"))
+        );
+        assert_eq!(
+            config.agents[0].socket_path,
+            PathBuf::from("/tmp/test.sock")
+        );
+        assert_eq!(
+            config.agents[1].socket_path,
+            PathBuf::from("/tmp/ssh-agent-testuser.sock")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_file_dash_overrides_config_file() -> EyreResult<()> {
+        let mut config_file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut config_file, b"log-file = \"~/mux.log\"\n")?;
+        let config_path = config_file.path().to_str().unwrap();
+
+        let args = Args::try_parse_from(["ssh-agent-mux", "--config", config_path])?;
+        let config = Config::from_args(args)?;
+        assert_eq!(
+            config.log_file,
+            Some(PathBuf::from("~/mux.log").expand_tilde_owned()?)
+        );
+
+        let args =
+            Args::try_parse_from(["ssh-agent-mux", "--config", config_path, "--log-file", "-"])?;
+        let config = Config::from_args(args)?;
+        assert_eq!(
+            config.log_file,
+            Some(PathBuf::from(logging::STDOUT_LOG_FILE))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_config_files_merged_in_order() -> EyreResult<()> {
+        let dir = tempfile::tempdir()?;
+        let base = dir.path().join("base.toml");
+        fs::write(
+            &base,
+            "agent-timeout = 10\nlog-level = \"info\"\n\
+             advertise-extensions = [\"a@example.com\"]\n\
+             [[agents]]\nname = \"a\"\nsocket-path = \"/tmp/a.sock\"\n",
+        )?;
+        let host = dir.path().join("host.toml");
+        fs::write(
+            &host,
+            "agent-timeout = 20\nadvertise-extensions = [\"b@example.com\"]\n\
+             [[agents]]\nname = \"b\"\nsocket-path = \"/tmp/b.sock\"\n",
+        )?;
+        let missing = dir.path().join("missing.toml");
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+
+        let args = Args::try_parse_from([
+            "mux".into(),
+            format!("--config={}", path(&base)),
+            format!("--config={}", path(&missing)),
+            format!("--config={}", path(&host)),
+        ])?;
+        let config = Config::from_args(args)?;
+        // Scalars and other lists are overridden, agents concatenated
+        assert_eq!(config.agent_timeout, 20);
+        assert!(matches!(config.log_level, LogLevel::Info));
+        assert_eq!(config.advertise_extensions, ["b@example.com"]);
+        let names: Vec<_> = config.agents.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(config.config_path, host);
+
+        // Agent names must be unique across every file
+        let args = Args::try_parse_from([
+            "mux".into(),
+            format!("--config={}", path(&base)),
+            format!("--config={}", path(&base)),
+        ])?;
+        let err = Config::from_args(args).err().unwrap().to_string();
+        assert!(
+            err.contains("agents[1].name: duplicate agent name \"a\""),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalize_path() -> EyreResult<()> {
+        let dir = tempfile::tempdir()?;
+        let real = fs::canonicalize(dir.path())?.join("real");
+        fs::create_dir(&real)?;
+        std::os::unix::fs::symlink(&real, dir.path().join("link"))?;
+
+        // Symlinks are followed, and `..` applies to their targets
+        let path = dir.path().join("link/./agent.sock");
+        assert_eq!(canonicalize_path(&path)?, real.join("agent.sock"));
+        let path = dir.path().join("link/../real/agent.sock");
+        assert_eq!(canonicalize_path(&path)?, real.join("agent.sock"));
+        // Parts that don't exist yet are normalized without resolving them
+        let path = dir.path().join("link/missing/../agent.sock");
+        assert_eq!(canonicalize_path(&path)?, real.join("agent.sock"));
+
+        // Relative paths are resolved against the working directory
+        let cwd = fs::canonicalize(env::current_dir()?)?;
+        assert_eq!(
+            canonicalize_path(Path::new("agent.sock"))?,
+            cwd.join("agent.sock")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_socket_paths_canonicalized_at_load() -> EyreResult<()> {
+        let dir = tempfile::tempdir()?;
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("link"))?;
+        let canonical_dir = fs::canonicalize(dir.path())?;
+        let mut config_file = tempfile::NamedTempFile::new()?;
+        let config_text = format!(
+            "listen-path = \"{}/link/mux.sock\"\n[[agents]]\nname = \"a\"\nsocket-path = \"a.sock\"\n",
+            dir.path().display()
+        );
+        std::io::Write::write_all(&mut config_file, config_text.as_bytes())?;
+        let config_path = config_file.path().to_str().unwrap();
+
+        let config = Config::from_args(Args::try_parse_from(["mux", "--config", config_path])?)?;
+        assert_eq!(config.listen_path, canonical_dir.join("mux.sock"));
+        let cwd = fs::canonicalize(env::current_dir()?)?;
+        assert_eq!(config.agents[0].socket_path, cwd.join("a.sock"));
+        assert_eq!(config.warnings.len(), 1);
+        assert!(config.warnings[0].starts_with("agents[0].socket-path: relative path"));
+
+        let args = Args::try_parse_from([
+            "mux",
+            "--config",
+            config_path,
+            "--canonicalize-paths",
+            "false",
+        ])?;
+        let config = Config::from_args(args)?;
+        assert_eq!(config.listen_path, dir.path().join("link/mux.sock"));
+        assert_eq!(config.agents[0].socket_path, PathBuf::from("a.sock"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_agent_socket_is_listen_path() -> EyreResult<()> {
+        let dir = tempfile::tempdir()?;
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("link"))?;
+        let mut config_file = tempfile::NamedTempFile::new()?;
+        let config_text = format!(
+            "listen-path = \"{0}/mux.sock\"\n\
+             [[agents]]\nname = \"a\"\nsocket-path = \"{0}/link/mux.sock\"\n",
+            dir.path().display()
+        );
+        std::io::Write::write_all(&mut config_file, config_text.as_bytes())?;
+        let config_path = config_file.path().to_str().unwrap();
+
+        let args = Args::try_parse_from(["mux", "--config", config_path])?;
+        let Err(err) = Config::from_args(args) else {
+            panic!("a mux listed as its own upstream is accepted");
+        };
+        assert!(
+            format!("{:?}", err).contains("agents[0].socket-path: is the mux's own listen-path"),
+            "{:?}",
+            err
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undefined_env_var_modes() -> EyreResult<()> {
+        env::remove_var("TEST_UNDEFINED_VAR");
+        let text = "/run/${TEST_UNDEFINED_VAR}/agent.sock";
+
+        let err = expand_env_vars(text, EnvUndefined::Error).unwrap_err();
+        assert!(err.to_string().contains("TEST_UNDEFINED_VAR"), "{err}");
+        assert_eq!(expand_env_vars(text, EnvUndefined::Keep)?, text);
+        assert_eq!(
+            expand_env_vars(text, EnvUndefined::Empty)?,
+            "/run//agent.sock"
+        );
+
+        // The mode comes from the file itself
+        let socket_path = |mode: &str| -> EyreResult<PathBuf> {
+            let config_text = format!(
+                "env-undefined = \"{mode}\"\n[[agents]]\nname = \"a\"\nsocket-path = \"{text}\"\n"
+            );
+            Ok(Config::from(parse_config_text(&config_text)?).agents[0]
+                .socket_path
+                .clone())
+        };
+        let err = socket_path("error").unwrap_err();
+        assert!(err.to_string().contains("agents[0].socket-path"), "{err}");
+        assert_eq!(socket_path("keep")?, PathBuf::from(text));
+        assert_eq!(socket_path("empty")?, PathBuf::from("/run//agent.sock"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_expansion_escapes_and_exclusions() -> EyreResult<()> {
+        env::set_var("TEST_ESCAPED_VAR", "expanded");
+        assert_eq!(
+            expand_env_vars("$$TEST_ESCAPED_VAR-$TEST_ESCAPED_VAR", EnvUndefined::Error)?,
+            "$TEST_ESCAPED_VAR-expanded"
+        );
+
+        let config_text = r#"
+# Comments aren't expanded: ${TEST_UNDEFINED_COMMENT_VAR}
+no-env-expansion = ["agents.socket-path"]
+log-file = "/logs/$TEST_ESCAPED_VAR"
+
+[[agents]]
+name = "first-$TEST_ESCAPED_VAR"
+socket-path = "/run/$TEST_ESCAPED_VAR"
+
+[[agents]]
+name = "second"
+socket-path = "/run/${NOT_AN_ENV_VAR}"
+"#;
+        let config = Config::from(parse_config_text(config_text)?);
+        assert_eq!(config.log_file, Some(PathBuf::from("/logs/expanded")));
+        assert_eq!(config.agents[0].name, "first-expanded");
+        assert_eq!(
+            config.agents[0].socket_path,
+            PathBuf::from("/run/$TEST_ESCAPED_VAR")
+        );
+        assert_eq!(
+            config.agents[1].socket_path,
+            PathBuf::from("/run/${NOT_AN_ENV_VAR}")
+        );
 
         Ok(())
     }
@@ -304,12 +1953,184 @@ socket-path = "/tmp/a.sock"
         let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
         let config = Config::from(parsed);
 
-        let valid = config.add_new_keys_to.as_ref().map_or(true, |name| {
-            config.agents.iter().any(|a| a.name == *name)
+        let valid = config.add_new_keys_to.as_ref().map_or(true, |names| {
+            names
+                .names()
+                .iter()
+                .all(|name| config.agents.iter().any(|a| a.name == *name))
         });
         assert!(!valid, "Should reject reference to nonexistent agent");
     }
 
+    #[test]
+    fn test_add_new_keys_to_read_only_rejected() {
+        let config_text = r#"
+add-new-keys-to = ["writable", "archive"]
+
+[[agents]]
+name = "writable"
+socket-path = "/tmp/writable.sock"
+
+[[agents]]
+name = "archive"
+socket-path = "/tmp/archive.sock"
+read-only = true
+"#;
+
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("add-new-keys-to[1]: references read-only agent"),
+            "{}",
+            err
+        );
+
+        config.add_new_keys_to = Some(AgentNames::One("writable".into()));
+        assert!(config.validate().is_ok());
+        assert!(config.enabled_upstream_agents()[1].read_only);
+    }
+
+    #[test]
+    fn test_default_agent_validation() {
+        let config_text = r#"
+default-agent = "fallback"
+
+[[agents]]
+name = "primary"
+socket-path = "/tmp/primary.sock"
+
+[[agents]]
+name = "fallback"
+socket-path = "/tmp/fallback.sock"
+"#;
+
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.default_agent_socket_path(),
+            Some(PathBuf::from("/tmp/fallback.sock"))
+        );
+
+        config.agents[1].enabled = false;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("default-agent: references disabled agent"),
+            "{}",
+            err
+        );
+
+        config.default_agent = Some("nonexistent".into());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("default-agent: references unknown agent"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        let config_text = r#"
+[[agents]]
+name = "yubikey"
+socket-path = "/tmp/yubikey.sock"
+
+[[agents]]
+name = "laptop"
+socket-path = "/tmp/laptop.sock"
+
+[[routes]]
+fingerprint = "SHA256:dbdXukhYlXo7U5VXfYeihSego8ipe2rt+tevCE0z0YU"
+agent = "yubikey"
+"#;
+
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+        let fingerprint = config.routes[0].fingerprint.parse().unwrap();
+        assert_eq!(
+            config.mux_options().key_routes,
+            [(fingerprint, PathBuf::from("/tmp/yubikey.sock"))]
+        );
+
+        config.agents[0].enabled = false;
+        config.routes.push(RouteConfig {
+            fingerprint: "invalid".into(),
+            agent: "nonexistent".into(),
+        });
+        config.routes.push(config.routes[0].clone());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("routes[0].agent: references disabled agent"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("routes[1].fingerprint: \"invalid\" is not"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("routes[1].agent: references unknown agent"),
+            "{}",
+            err
+        );
+        assert!(err.contains("already in routes[0]"), "{}", err);
+    }
+
+    #[test]
+    fn test_tcp_socket_paths() {
+        let config_text = r#"
+[[agents]]
+name = "relay"
+socket-path = "tcp://127.0.0.1:8022"
+
+[[agents]]
+name = "tunnel"
+socket-path = "tcp://[::1]:8023"
+
+[[agents]]
+name = "local"
+socket-path = "tcp://localhost:8024"
+"#;
+
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+        config.canonicalize_socket_paths().unwrap();
+        let agents = config.enabled_upstream_agents();
+        assert_eq!(agents[0].socket_path, PathBuf::from("tcp://127.0.0.1:8022"));
+        assert_eq!(agents[1].transport(), UpstreamTransport::Tcp("[::1]:8023"));
+
+        config.agents[0].socket_path = "tcp://192.0.2.1:8022".into();
+        config.agents[1].socket_path = "tcp://agent.example.com:8023".into();
+        config.agents[2].socket_path = "tcp://localhost".into();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("agents[0].socket-path: \"192.0.2.1:8022\" is not a"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("\"agent.example.com:8023\" is not a loopback"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("\"localhost\" is not a TCP address"),
+            "{}",
+            err
+        );
+
+        config.allow_remote_tcp = true;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(!err.contains("agents[0]"), "{}", err);
+        assert!(!err.contains("agents[1]"), "{}", err);
+        assert!(err.contains("agents[2].socket-path"), "{}", err);
+    }
+
     #[test]
     fn test_enabled_filtering() {
         let config_text = r#"
@@ -326,9 +2147,9 @@ enabled = false
         let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
         let config = Config::from(parsed);
 
-        let enabled_paths = config.enabled_agent_socket_paths();
-        assert_eq!(enabled_paths.len(), 1);
-        assert_eq!(enabled_paths[0], PathBuf::from("/tmp/active.sock"));
+        let enabled = config.enabled_upstream_agents();
+        assert_eq!(enabled.len(), 1);
+        assert_eq!(enabled[0].socket_path, PathBuf::from("/tmp/active.sock"));
     }
 
     #[test]
@@ -348,7 +2169,533 @@ socket-path = "/tmp/target.sock"
         let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
         let config = Config::from(parsed);
 
-        let resolved = config.added_keys_socket_path();
-        assert_eq!(resolved, Some(PathBuf::from("/tmp/target.sock")));
+        let resolved = config.added_keys_socket_paths();
+        assert_eq!(resolved, [PathBuf::from("/tmp/target.sock")]);
+
+        // A list of agents to try in order, each of which must exist
+        let config_text = r#"
+add-new-keys-to = ["target", "other"]
+
+[[agents]]
+name = "other"
+socket-path = "/tmp/other.sock"
+
+[[agents]]
+name = "target"
+socket-path = "/tmp/target.sock"
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+        let resolved = config.added_keys_socket_paths();
+        assert_eq!(
+            resolved,
+            [
+                PathBuf::from("/tmp/target.sock"),
+                PathBuf::from("/tmp/other.sock")
+            ]
+        );
+
+        config.add_new_keys_to = Some(AgentNames::Many(vec!["target".into(), "missing".into()]));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("add-new-keys-to[1]: references unknown agent"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_lock_passphrase_file() -> EyreResult<()> {
+        use std::io::Write;
+        use tempfile::NamedTempFile;
+
+        let mut passphrase_file = NamedTempFile::new()?;
+        writeln!(passphrase_file, "secret")?;
+
+        let config_text = format!(
+            r#"
+[[agents]]
+name = "locked"
+socket-path = "/tmp/locked.sock"
+lock-passphrase-file = "{}"
+"#,
+            passphrase_file.path().display()
+        );
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(&config_text)?;
+        let mut config = Config::from(parsed);
+
+        config.agents[0].resolve_lock_passphrase()?;
+        assert_eq!(
+            config.agents[0]
+                .lock_passphrase
+                .as_deref()
+                .map(String::as_str),
+            Some("secret")
+        );
+
+        // The passphrase is now set, as well as the file
+        assert!(config.agents[0].resolve_lock_passphrase().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_advertise_extensions_validation() {
+        assert!(is_extension_name("routing-table@ssh-agent-mux"));
+        assert!(is_extension_name("session-bind@openssh.com"));
+        for invalid in [
+            "query",
+            "@openssh.com",
+            "name@",
+            "a@b@c",
+            "has space@x",
+            "a,b@x",
+        ] {
+            assert!(!is_extension_name(invalid), "{:?} accepted", invalid);
+        }
+
+        let config_text = r#"
+advertise-extensions = ["no-domain"]
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let config = Config::from(parsed);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("advertise-extensions[0]: \"no-domain\" is not of the form name@domain"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_listen_mode() {
+        assert_eq!(parse_mode("0660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert!(parse_mode("rw-------").is_err());
+
+        let parse =
+            |text| Config::from(toml::from_str::<<Config as ClapSerde>::Opt>(text).unwrap());
+        assert_eq!(parse("").listen_mode, 0o600);
+        assert_eq!(parse("listen-mode = 0o660").listen_mode, 0o660);
+        // Written in decimal by mistake
+        let err = parse("listen-mode = 660")
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("listen-mode: 660 isn't a permission mode"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_key_fingerprint_filters() {
+        let fingerprint = "SHA256:dbdXukhYlXo7U5VXfYeihSego8ipe2rt+tevCE0z0YU";
+        let config_text = format!(
+            r#"
+[[agents]]
+name = "exposing"
+socket-path = "/tmp/exposing.sock"
+expose-fingerprints = ["{fingerprint}"]
+
+[[agents]]
+name = "hiding"
+socket-path = "/tmp/hiding.sock"
+hide-fingerprints = ["{fingerprint}"]
+
+[[agents]]
+name = "all"
+socket-path = "/tmp/all.sock"
+"#
+        );
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(&config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+
+        let fingerprint: Fingerprint = fingerprint.parse().unwrap();
+        let filters: Vec<_> = config
+            .enabled_upstream_agents()
+            .into_iter()
+            .map(|a| a.key_filter)
+            .collect();
+        assert_eq!(
+            filters,
+            [
+                KeyFilter::Only(vec![fingerprint]),
+                KeyFilter::Except(vec![fingerprint]),
+                KeyFilter::All
+            ]
+        );
+
+        config.agents[0].hide_fingerprints = vec!["MD5:not-a-fingerprint".into()];
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "agents[0].hide-fingerprints",
+                "agents[0].hide-fingerprints[0]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extension_forwarding_filters() {
+        let config_text = r#"
+[[agents]]
+name = "team"
+socket-path = "/tmp/team.sock"
+no-forward-extensions = ["session-bind@openssh.com"]
+
+[[agents]]
+name = "personal"
+socket-path = "/tmp/personal.sock"
+forward-extensions = ["session-bind@openssh.com"]
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+
+        let filters: Vec<_> = config
+            .enabled_upstream_agents()
+            .into_iter()
+            .map(|a| a.extension_filter)
+            .collect();
+        assert_eq!(
+            filters,
+            [
+                ExtensionFilter::Except(vec!["session-bind@openssh.com".into()]),
+                ExtensionFilter::Only(vec!["session-bind@openssh.com".into()]),
+            ]
+        );
+        assert!(!filters[0].forwards("session-bind@openssh.com"));
+        assert!(filters[0].forwards("other@example.com"));
+        assert!(!filters[1].forwards("other@example.com"));
+
+        config.agents[1].no_forward_extensions = vec!["session-bind".into()];
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "agents[1].no-forward-extensions",
+                "agents[1].no-forward-extensions[0]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builtin_agent_has_no_socket_path() {
+        let config_text = r#"
+add-new-keys-to = "memory"
+
+[[agents]]
+name = "memory"
+kind = "builtin"
+
+[[agents]]
+name = "missing"
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["agents[1].socket-path"]);
+
+        config.agents.pop();
+        assert!(config.validate().is_ok());
+        let agents = config.enabled_upstream_agents();
+        assert_eq!(agents[0].kind, UpstreamKind::Builtin);
+        assert_eq!(agents[0].socket_path, PathBuf::from("builtin:memory"));
+        assert_eq!(
+            config.added_keys_socket_paths(),
+            [PathBuf::from("builtin:memory")]
+        );
+
+        config.agents[0].socket_path = "/tmp/memory.sock".into();
+        let errors = config.validate().unwrap_err();
+        assert!(
+            errors.to_string().contains("builtin agents have no socket"),
+            "{}",
+            errors
+        );
+    }
+
+    #[test]
+    fn test_agent_command() {
+        let config_text = r#"
+[[agents]]
+name = "ssh-agent"
+socket-path = "/tmp/spawned.sock"
+command = ["ssh-agent", "-D", "-a", "/tmp/spawned.sock"]
+
+[[agents]]
+name = "memory"
+kind = "builtin"
+command = ["ssh-agent"]
+
+[[agents]]
+name = "empty"
+socket-path = "/tmp/empty.sock"
+command = [""]
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let config = Config::from(parsed);
+        let agents = config.enabled_upstream_agents();
+        assert_eq!(
+            agents[0].command,
+            ["ssh-agent", "-D", "-a", "/tmp/spawned.sock"]
+        );
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["agents[1].command", "agents[2].command"]);
+    }
+
+    #[test]
+    fn test_agent_env() {
+        let config_text = r#"
+[[agents]]
+name = "piv"
+socket-path = "/tmp/piv.sock"
+command = ["piv-agent", "-a", "/tmp/piv.sock"]
+env = { SSH_ASKPASS = "/usr/bin/ssh-askpass", "BAD=NAME" = "" }
+
+[[agents]]
+name = "external"
+socket-path = "/tmp/external.sock"
+env = { DISPLAY = ":0" }
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let config = Config::from(parsed);
+        assert_eq!(
+            config.enabled_upstream_agents()[0].env["SSH_ASKPASS"],
+            "/usr/bin/ssh-askpass"
+        );
+        let errors = config.validate().unwrap_err();
+        let messages: Vec<_> = errors
+            .0
+            .iter()
+            .map(|i| format!("{}: {}", i.path, i.message))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "agents[0].env: \"BAD=NAME\" is not an environment variable name",
+                "agents[1].env: only applies with command",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_confirm_command_required() {
+        let config_text = r#"
+[[agents]]
+name = "yubikey"
+socket-path = "/tmp/yubikey.sock"
+confirm = true
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let config = Config::from(parsed);
+        assert!(config.enabled_upstream_agents()[0].confirm);
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["confirm-command"]);
+
+        let config_text = format!("confirm-command = [\"ssh-askpass\"]\n{config_text}");
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(&config_text).unwrap();
+        let config = Config::from(parsed);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.mux_options().confirm_command, ["ssh-askpass"]);
+    }
+
+    #[test]
+    fn test_env_agents() {
+        let vars = [
+            ("SSH_AGENT_MUX_AGENT_10_NAME", "yubikey"),
+            ("SSH_AGENT_MUX_AGENT_10_SOCKET", "/run/yubikey.sock"),
+            ("SSH_AGENT_MUX_AGENT_2_NAME", "office"),
+            ("SSH_AGENT_MUX_AGENT_2_SOCKET", "/run/office.sock"),
+            ("SSH_AGENT_MUX_AGENT_2_ENABLED", "0"),
+            ("SSH_AGENT_MUX_AGENT_3_SOCKET", "/run/nameless.sock"),
+            ("SSH_AGENT_MUX_AGENT_4_NAME", "typo"),
+            ("SSH_AGENT_MUX_AGENT_4_SOCKETT", "/run/typo.sock"),
+            ("SSH_AGENT_MUX_AGENT_5_ENABLED", "yes"),
+            ("SSH_AGENT_MUX_AGENT_X_NAME", "bad-index"),
+            ("SSH_AUTH_SOCK", "/run/unrelated.sock"),
+        ]
+        .map(|(var, value)| (var.into(), value.into()));
+        let mut issues = vec![];
+        let agents = env_agents(vars, &mut issues);
+
+        let names: Vec<_> = agents.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["office", "typo", "yubikey"]);
+        assert!(!agents[0].enabled);
+        assert!(agents[2].enabled);
+        assert_eq!(agents[2].socket_path, PathBuf::from("/run/yubikey.sock"));
+        let mut paths: Vec<_> = issues.iter().map(|i| i.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "SSH_AGENT_MUX_AGENT_3_*",
+                "SSH_AGENT_MUX_AGENT_4_SOCKETT",
+                "SSH_AGENT_MUX_AGENT_5_ENABLED",
+                "SSH_AGENT_MUX_AGENT_X_NAME",
+            ]
+        );
+
+        let config_text = r#"
+[[agents]]
+name = "yubikey"
+socket-path = "/tmp/old-yubikey.sock"
+
+[[agents]]
+name = "laptop"
+socket-path = "/tmp/laptop.sock"
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        merge_env_agents(&mut config.agents, agents);
+        let names: Vec<_> = config.agents.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["yubikey", "laptop", "office", "typo"]);
+        assert_eq!(
+            config.agents[0].socket_path,
+            PathBuf::from("/run/yubikey.sock")
+        );
+        // The agent with a misspelt field has no socket, which validation reports
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["agents[3].socket-path"]);
+    }
+
+    #[test]
+    fn test_default_comment_placeholders() {
+        assert_eq!(
+            unknown_comment_placeholder("{agent} key {fingerprint}"),
+            None
+        );
+        assert_eq!(unknown_comment_placeholder("no placeholders"), None);
+        assert_eq!(
+            unknown_comment_placeholder("{agent} {name}"),
+            Some("{name}")
+        );
+
+        let parsed =
+            toml::from_str::<<Config as ClapSerde>::Opt>(r#"default-comment = "{host}""#).unwrap();
+        let err = Config::from(parsed).validate().unwrap_err().to_string();
+        assert!(
+            err.contains("default-comment: unknown placeholder \"{host}\""),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_default_visibility() {
+        let config_text = r#"
+visible-fingerprints = ["SHA256:dbdXukhYlXo7U5VXfYeihSego8ipe2rt+tevCE0z0YU", "invalid"]
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        assert_eq!(config.mux_options().visible_keys, KeyFilter::All);
+
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["visible-fingerprints[1]", "visible-fingerprints"]);
+
+        config.default_visibility = Visibility::None;
+        config.visible_fingerprints.pop();
+        assert!(config.validate().is_ok());
+        assert!(matches!(
+            config.mux_options().visible_keys,
+            KeyFilter::Only(fingerprints) if fingerprints.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_allowed_key_types() {
+        let config_text = r#"
+allowed-key-types = ["ssh-ed25519", "rsa-sha2-256", "ed25519", "ecdsa-sha2-nistp256"]
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["allowed-key-types[1]", "allowed-key-types[2]"]);
+
+        config.allowed_key_types.drain(1..3);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.mux_options().allowed_key_types,
+            [
+                Algorithm::Ed25519,
+                Algorithm::Ecdsa {
+                    curve: ssh_agent_lib::ssh_key::EcdsaCurve::NistP256
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comment_filter() {
+        let config_text = r#"
+comment-filter = "work@(.*"
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let mut config = Config::from(parsed);
+        let err = config.validate().unwrap_err().to_string();
+        let message = "comment-filter: \"work@(.*\" is not a valid regular expression";
+        assert!(err.contains(message), "{}", err);
+
+        config.comment_filter = None;
+        config.comment_filter_invert = true;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("comment-filter-invert: only applies with comment-filter"),
+            "{}",
+            err
+        );
+
+        config.comment_filter = Some("work@.*".into());
+        assert!(config.validate().is_ok());
+        let filter = config.mux_options().comment_filter.unwrap();
+        assert!(filter.exposes("me@home") && !filter.exposes("me@work@example.com"));
+    }
+
+    #[test]
+    fn test_validation_reports_every_issue_with_its_path() {
+        let config_text = r#"
+add-new-keys-to = "missing"
+advertise-extensions = ["valid@example.com", "invalid"]
+
+[[agents]]
+name = "first"
+socket-path = "/tmp/first.sock"
+
+[[agents]]
+name = "second"
+socket-path = "/tmp/second.sock"
+
+[[agents]]
+name = "first"
+socket-path = "/tmp/third.sock"
+"#;
+        let parsed = toml::from_str::<<Config as ClapSerde>::Opt>(config_text).unwrap();
+        let config = Config::from(parsed);
+
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<_> = errors.0.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "agents[2].name",
+                "advertise-extensions[1]",
+                "add-new-keys-to"
+            ]
+        );
+        assert!(errors.0[0].message.contains("already used by agents[0]"));
     }
 }
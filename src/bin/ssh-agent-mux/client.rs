@@ -0,0 +1,28 @@
+//! Connections to agents from subcommands, under the same timeouts the mux uses
+
+use std::{future::Future, path::Path, time::Duration};
+
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_lib::{agent::Session, client, error::AgentError};
+use tokio::time::timeout;
+
+/// Run `request` under the agent timeout, like the mux does for its own upstream requests
+pub async fn with_timeout<T>(
+    agent_timeout: Duration,
+    what: &str,
+    request: impl Future<Output = Result<T, AgentError>>,
+) -> Result<T> {
+    timeout(agent_timeout, request)
+        .await
+        .map_err(|_| eyre!("{} timed out after {:?}", what, agent_timeout))?
+        .map_err(|e| eyre!("{} failed: {}", what, e))
+}
+
+pub async fn connect(socket_path: &Path, agent_timeout: Duration) -> Result<Box<dyn Session>> {
+    let what = format!("Connecting to {}", socket_path.display());
+    let stream = with_timeout(agent_timeout, &what, async {
+        Ok(tokio::net::UnixStream::connect(socket_path).await?)
+    })
+    .await?;
+    client::connect(stream.into_std()?.into()).map_err(|e| eyre!("{}: {}", what, e))
+}
@@ -0,0 +1,80 @@
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_mux::{extensions::RefreshOutcome, MuxAgent, UpstreamCheck};
+
+use crate::{cli::Config, exit};
+
+fn describe(check: &UpstreamCheck) -> [String; 5] {
+    let reachable = check.outcome == RefreshOutcome::Ok;
+    let mut state = if reachable {
+        "reachable"
+    } else {
+        "unreachable"
+    }
+    .to_string();
+    if !reachable {
+        state.push_str(&format!(" ({})", check.outcome));
+        if !check.detail.is_empty() {
+            state.push_str(&format!(": {}", check.detail));
+        }
+    }
+    [
+        check.name.clone(),
+        check.socket_path.display().to_string(),
+        if reachable {
+            check.keys.to_string()
+        } else {
+            "-".into()
+        },
+        format!("{:.1?}", check.latency),
+        state,
+    ]
+}
+
+/// Ask each enabled upstream agent for its identities directly, without the mux, and print how
+/// each answered; fails if any couldn't be reached
+pub async fn handle_doctor_command(config: &Config) -> Result<()> {
+    let checks =
+        MuxAgent::check_upstreams(config.enabled_upstream_agents(), config.mux_options()).await;
+
+    let header = ["AGENT", "SOCKET", "KEYS", "LATENCY", "STATUS"].map(String::from);
+    let rows: Vec<_> = [header]
+        .into_iter()
+        .chain(checks.iter().map(describe))
+        .collect();
+    let widths: Vec<_> = (0..4)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in &rows {
+        println!(
+            "{:w0$}  {:w1$}  {:>w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3]
+        );
+    }
+
+    let unreachable: Vec<_> = checks
+        .iter()
+        .filter(|c| c.outcome != RefreshOutcome::Ok)
+        .map(|c| &c.name)
+        .collect();
+    if unreachable.is_empty() {
+        return Ok(());
+    }
+    Err(eyre!(
+        "Couldn't list the keys of upstream agents {:?}",
+        unreachable
+    )
+    .wrap_err(exit::AgentsUnreachable))
+}
@@ -0,0 +1,59 @@
+//! Exit codes of the process, so that supervisors and scripts can tell failure modes apart; where
+//! one fits, the code is the one `sysexits.h` defines
+
+use std::{fmt, io};
+
+use color_eyre::eyre::Report;
+
+use crate::cli::ConfigErrors;
+
+/// Any failure without a more specific code
+pub const FAILURE: u8 = 1;
+/// An upstream agent couldn't be reached by `--probe-only` or `doctor` (`EX_UNAVAILABLE`)
+pub const UNAVAILABLE: u8 = 69;
+/// The listening socket path is in use, e.g. by another mux; retrying later may succeed
+/// (`EX_TEMPFAIL`)
+pub const ADDR_IN_USE: u8 = 75;
+/// Permission denied, e.g. to create the listening socket (`EX_NOPERM`)
+pub const PERMISSION_DENIED: u8 = 77;
+/// The configuration couldn't be read, parsed, or validated (`EX_CONFIG`)
+pub const CONFIG: u8 = 78;
+
+/// Context of errors loading the configuration, by which [`code`] tells them apart
+#[derive(Debug)]
+pub struct ConfigLoadFailed;
+
+impl fmt::Display for ConfigLoadFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Failed to load configuration")
+    }
+}
+
+/// Context of `--probe-only` or `doctor` failing because an upstream agent couldn't be reached
+#[derive(Debug)]
+pub struct AgentsUnreachable;
+
+impl fmt::Display for AgentsUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Not ready")
+    }
+}
+
+/// Exit code for the error `report`; a clean shutdown, including on SIGTERM or SIGINT, exits
+/// with 0
+pub fn code(report: &Report) -> u8 {
+    if report.downcast_ref::<ConfigLoadFailed>().is_some()
+        || report.downcast_ref::<ConfigErrors>().is_some()
+    {
+        return CONFIG;
+    }
+    if report.downcast_ref::<AgentsUnreachable>().is_some() {
+        return UNAVAILABLE;
+    }
+    let io_error = report.chain().find_map(|e| e.downcast_ref::<io::Error>());
+    match io_error.map(io::Error::kind) {
+        Some(io::ErrorKind::AddrInUse) => ADDR_IN_USE,
+        Some(io::ErrorKind::PermissionDenied) => PERMISSION_DENIED,
+        _ => FAILURE,
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashSet,
+    env,
+    fmt::Write as _,
+    fs,
+    io::Write as _,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap_serde_derive::clap::{self, Args};
+use color_eyre::eyre::Result;
+use expand_tilde::ExpandTilde;
+use ssh_agent_lib::{client, error::AgentError};
+use tokio::time::timeout;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone)]
+pub struct ImportArgs {
+    /// Also suggest every agent socket in this directory (may be repeated)
+    #[arg(long = "dir")]
+    pub dirs: Vec<PathBuf>,
+
+    /// Append the suggestions to the config file, instead of only printing them
+    #[arg(long)]
+    pub write: bool,
+}
+
+/// A socket that might be an SSH agent, and where it was found
+struct Candidate {
+    name: String,
+    socket_path: PathBuf,
+    source: String,
+    gpg_agent: bool,
+}
+
+/// Result of connecting to a candidate socket and listing its identities
+enum Probe {
+    Reachable(usize),
+    Unreachable(String),
+}
+
+/// Well-known agent socket locations, as (name, path, is gpg-agent)
+fn well_known_sockets() -> Vec<(&'static str, PathBuf, bool)> {
+    let mut sockets = vec![];
+    let home = |p: &str| PathBuf::from(p).expand_tilde_owned().ok();
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+
+    if let Some(dir) = &runtime_dir {
+        sockets.push(("gpg-agent", dir.join("gnupg/S.gpg-agent.ssh"), true));
+        sockets.push(("ssh-agent", dir.join("ssh-agent.socket"), false));
+        sockets.push(("gnome-keyring", dir.join("keyring/ssh"), false));
+        sockets.push((
+            "yubikey-agent",
+            dir.join("yubikey-agent/yubikey-agent.sock"),
+            false,
+        ));
+    }
+    for (name, path, gpg_agent) in [
+        ("gpg-agent", "~/.gnupg/S.gpg-agent.ssh", true),
+        ("1password", "~/.1password/agent.sock", false),
+        (
+            "1password",
+            "~/Library/Group Containers/2BUA8C4S2C.com.1password/t/agent.sock",
+            false,
+        ),
+        (
+            "secretive",
+            "~/Library/Containers/com.maxgoedjen.Secretive.SecretAgent/Data/socket.ssh",
+            false,
+        ),
+    ] {
+        if let Some(path) = home(path) {
+            sockets.push((name, path, gpg_agent));
+        }
+    }
+    sockets
+}
+
+fn find_candidates(args: &ImportArgs) -> Result<Vec<Candidate>> {
+    let mut candidates = vec![];
+
+    if let Some(path) = env::var_os("SSH_AUTH_SOCK") {
+        candidates.push(Candidate {
+            name: "ssh-auth-sock".into(),
+            socket_path: path.into(),
+            source: "SSH_AUTH_SOCK".into(),
+            gpg_agent: false,
+        });
+    }
+    // GPG_AGENT_INFO is "<socket>:<pid>:<protocol version>"; the SSH socket sits beside the
+    // main one
+    if let Some(info) = env::var_os("GPG_AGENT_INFO") {
+        let info = info.to_string_lossy();
+        if let Some(dir) = info.split(':').next().map(Path::new).and_then(Path::parent) {
+            candidates.push(Candidate {
+                name: "gpg-agent".into(),
+                socket_path: dir.join("S.gpg-agent.ssh"),
+                source: "GPG_AGENT_INFO".into(),
+                gpg_agent: true,
+            });
+        }
+    }
+    for (name, path, gpg_agent) in well_known_sockets() {
+        if path.exists() {
+            candidates.push(Candidate {
+                name: name.into(),
+                socket_path: path,
+                source: "well-known location".into(),
+                gpg_agent,
+            });
+        }
+    }
+    for dir in &args.dirs {
+        let dir = dir.expand_tilde_owned()?;
+        let mut entries = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_socket()))
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        for path in entries {
+            candidates.push(Candidate {
+                name: path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "agent".into()),
+                socket_path: path,
+                source: format!("directory {}", dir.display()),
+                gpg_agent: false,
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+async fn probe(socket_path: &Path, agent_timeout: Duration) -> Probe {
+    let result = timeout(agent_timeout, async {
+        let stream = tokio::net::UnixStream::connect(socket_path).await?;
+        let mut client = client::connect(stream.into_std()?.into())
+            .map_err(|e| AgentError::Other(e.to_string().into()))?;
+        client.request_identities().await
+    })
+    .await;
+    match result {
+        Ok(Ok(identities)) => Probe::Reachable(identities.len()),
+        Ok(Err(e)) => Probe::Unreachable(e.to_string()),
+        Err(_) => Probe::Unreachable("timed out".into()),
+    }
+}
+
+/// Resolve symlinks, so that the same socket found via different paths is only suggested once
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+pub async fn handle_import_command(config: &Config, args: &ImportArgs) -> Result<()> {
+    let mut seen_paths: HashSet<PathBuf> = config
+        .agents
+        .iter()
+        .map(|a| canonical(&a.socket_path))
+        .collect();
+    // Don't suggest the mux to itself, e.g. when SSH_AUTH_SOCK already points at it
+    seen_paths.insert(canonical(&config.listen_path));
+    let mut names: HashSet<String> = config.agents.iter().map(|a| a.name.clone()).collect();
+
+    let mut suggestions = String::new();
+    for candidate in find_candidates(args)? {
+        if !seen_paths.insert(canonical(&candidate.socket_path)) {
+            continue;
+        }
+
+        let mut name = candidate.name.clone();
+        let mut n = 2;
+        while !names.insert(name.clone()) {
+            name = format!("{}-{}", candidate.name, n);
+            n += 1;
+        }
+
+        let agent_timeout = Duration::from_secs(config.agent_timeout);
+        let probe = probe(&candidate.socket_path, agent_timeout).await;
+        let status = match &probe {
+            Probe::Reachable(1) => "reachable, 1 identity".to_string(),
+            Probe::Reachable(n) => format!("reachable, {n} identities"),
+            Probe::Unreachable(e) => format!("unreachable ({e})"),
+        };
+        let socket_path = toml::Value::String(candidate.socket_path.display().to_string());
+
+        writeln!(suggestions, "# From {}: {}", candidate.source, status)?;
+        writeln!(suggestions, "[[agents]]")?;
+        writeln!(suggestions, "name = {}", toml::Value::String(name))?;
+        writeln!(suggestions, "socket-path = {}", socket_path)?;
+        if candidate.gpg_agent {
+            writeln!(suggestions, "kind = \"gpg-agent\"")?;
+        }
+        if let Probe::Unreachable(_) = probe {
+            writeln!(suggestions, "enabled = false")?;
+        }
+        writeln!(suggestions)?;
+    }
+
+    if suggestions.is_empty() {
+        eprintln!("No new agent sockets found");
+        return Ok(());
+    }
+    print!("{}", suggestions);
+
+    if args.write {
+        if let Some(parent) = config.config_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.config_path)?;
+        write!(file, "\n{}", suggestions)?;
+        eprintln!("Appended suggestions to {}", config.config_path.display());
+    }
+
+    Ok(())
+}
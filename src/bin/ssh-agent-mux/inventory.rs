@@ -0,0 +1,68 @@
+use std::fmt::Write as _;
+
+use color_eyre::eyre::Result;
+use ssh_agent_lib::ssh_key::{HashAlg, PublicKey};
+use ssh_agent_mux::{InventoryKey, MuxAgent};
+
+use crate::cli::Config;
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn key_json(key: &InventoryKey) -> Result<String> {
+    let pubkey = &key.identity.pubkey;
+    let openssh = PublicKey::new(pubkey.clone(), "").to_openssh()?;
+    Ok(format!(
+        concat!(
+            r#"{{"agent": {}, "fingerprint": {}, "type": {}, "comment": {}, "#,
+            r#""public-key": {}, "routed": {}}}"#
+        ),
+        json_string(&key.agent),
+        json_string(&pubkey.fingerprint(HashAlg::Sha256).to_string()),
+        json_string(pubkey.algorithm().as_str()),
+        json_string(&key.identity.comment),
+        json_string(openssh.trim()),
+        key.routed
+    ))
+}
+
+/// Print every key the configured agents list as a JSON array, asking the agents directly; only
+/// lists keys, so it's safe to run against live agents
+pub async fn handle_inventory_command(config: &Config) -> Result<()> {
+    let inventory =
+        MuxAgent::inventory(config.enabled_upstream_agents(), config.mux_options()).await?;
+    for name in &inventory.unreachable {
+        eprintln!("Couldn't list the keys of upstream agent {:?}", name);
+    }
+
+    let keys = inventory
+        .keys
+        .iter()
+        .map(key_json)
+        .collect::<Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        println!("[]");
+    } else {
+        println!("[\n  {}\n]", keys.join(",\n  "));
+    }
+
+    Ok(())
+}
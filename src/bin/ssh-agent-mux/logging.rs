@@ -1,11 +1,29 @@
 use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::sync::{Mutex, PoisonError};
 
 use flexi_logger::{
     filter::{LogLineFilter, LogLineWriter},
-    FileSpec, FlexiLoggerError, LogSpecification, Logger, LoggerHandle,
+    DeferredNow, FileSpec, FlexiLoggerError, FormatFunction, LogSpecBuilder, LogSpecification,
+    Logger, LoggerHandle, TS_DASHES_BLANK_COLONS_DOT_BLANK,
 };
-use log::LevelFilter;
+use log::{
+    kv::{self, VisitSource},
+    LevelFilter, Record,
+};
+use ssh_agent_mux::AUDIT_LOG_TARGET;
+
+use crate::cli::{LogFormat, LogTimestamp};
+
+/// `log-file` value that selects standard output, overriding a log file set elsewhere
+pub const STDOUT_LOG_FILE: &str = "-";
+
+pub fn is_stdout_log_file(path: &Path) -> bool {
+    path == Path::new(STDOUT_LOG_FILE)
+}
 
 /// Suppress upstream extension failures by default, because we probe agents for the
 /// session-bind@openssh.com extension and ignore failure. We'd like to keep the ability log
@@ -40,25 +58,200 @@ impl LogLineFilter for SuppressExtensionFailure {
     }
 }
 
+/// Copies audit records to the audit file, one JSON object per line, then passes every record
+/// on to the log; the file gets them whatever the log level, as they're always let through
+struct AuditFile {
+    file: Mutex<BufWriter<File>>,
+    suppress_extension_failure: bool,
+}
+
+impl AuditFile {
+    fn open(path: &Path, suppress_extension_failure: bool) -> io::Result<Self> {
+        // Audit records tell which keys were used when, so only the user may read them
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+            suppress_extension_failure,
+        })
+    }
+}
+
+impl LogLineFilter for AuditFile {
+    fn write(
+        &self,
+        now: &mut DeferredNow,
+        record: &Record,
+        log_line_writer: &dyn LogLineWriter,
+    ) -> io::Result<()> {
+        if record.target() == AUDIT_LOG_TARGET {
+            let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            json_utc_format(&mut *file, now, record)?;
+            file.write_all(b"\n")?;
+            // Each record is on disk once the request is answered, even if the mux is killed
+            file.flush()?;
+        }
+        if self.suppress_extension_failure {
+            SuppressExtensionFailure.write(now, record, log_line_writer)
+        } else {
+            log_line_writer.write(now, record)
+        }
+    }
+}
+
+fn rfc3339_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    write!(
+        w,
+        "{} ",
+        now.now_utc_owned().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+    )?;
+    flexi_logger::default_format(w, now, record)
+}
+
+fn local_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    write!(w, "{} ", now.now().format(TS_DASHES_BLANK_COLONS_DOT_BLANK))?;
+    flexi_logger::default_format(w, now, record)
+}
+
+impl LogTimestamp {
+    fn format(self) -> FormatFunction {
+        match self {
+            Self::Rfc3339 => rfc3339_format,
+            Self::Local => local_format,
+            Self::None => flexi_logger::default_format,
+        }
+    }
+}
+
+/// `s` as a JSON string
+fn write_json_string(w: &mut dyn Write, s: &str) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            '\r' => w.write_all(b"\\r")?,
+            '\t' => w.write_all(b"\\t")?,
+            c if c.is_control() => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// Writes a record's key-values as more fields of its JSON object, with string values
+struct JsonFields<'a>(&'a mut dyn Write);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.write_all(b",")?;
+        write_json_string(self.0, key.as_str())?;
+        self.0.write_all(b":")?;
+        write_json_string(self.0, &value.to_string())?;
+        Ok(())
+    }
+}
+
+fn write_json_record(w: &mut dyn Write, timestamp: &str, record: &Record) -> io::Result<()> {
+    w.write_all(b"{\"timestamp\":")?;
+    write_json_string(w, timestamp)?;
+    w.write_all(b",\"level\":")?;
+    write_json_string(w, record.level().as_str())?;
+    w.write_all(b",\"target\":")?;
+    write_json_string(w, record.target())?;
+    w.write_all(b",\"message\":")?;
+    write_json_string(w, &record.args().to_string())?;
+    record
+        .key_values()
+        .visit(&mut JsonFields(w))
+        .map_err(io::Error::other)?;
+    w.write_all(b"}")
+}
+
+fn json_utc_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    let timestamp = now
+        .now_utc_owned()
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string();
+    write_json_record(w, &timestamp, record)
+}
+
+fn json_local_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    let timestamp = now.now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string();
+    write_json_record(w, &timestamp, record)
+}
+
+impl LogFormat {
+    /// Records as text, starting with `timestamp`, or as JSON objects, whose timestamp is in
+    /// local time with `local` and in UTC otherwise, as log pipelines need one
+    fn format(self, timestamp: LogTimestamp) -> FormatFunction {
+        match (self, timestamp) {
+            (Self::Text, timestamp) => timestamp.format(),
+            (Self::Json, LogTimestamp::Local) => json_local_format,
+            (Self::Json, LogTimestamp::Rfc3339 | LogTimestamp::None) => json_utc_format,
+        }
+    }
+}
+
+/// Set up logging to `log_file`, or else to standard output, or to standard error with
+/// `to_stderr`, e.g. for subcommands whose output is on standard output; audit records are also
+/// appended to `audit_file`
 pub fn setup_logger(
     level: LevelFilter,
     log_file: Option<&Path>,
+    audit_file: Option<&Path>,
+    timestamp: LogTimestamp,
+    format: LogFormat,
+    to_stderr: bool,
 ) -> Result<LoggerHandle, FlexiLoggerError> {
-    // If RUST_LOG is in the environment, follow its directives;
-    // otherwise, use the configuration file, command line args, or defaults.
-    let logger = if env::var_os("RUST_LOG").is_some() {
-        Logger::try_with_env()?
+    // If RUST_LOG is in the environment, follow its directives; otherwise, use the configuration
+    // file, command line args, or defaults.
+    let from_env = env::var_os("RUST_LOG").is_some();
+    let logger = if from_env {
+        let spec = LogSpecification::env()?;
+        let audit_level = spec
+            .module_filters()
+            .iter()
+            .find(|f| f.module_name.as_deref() == Some(AUDIT_LOG_TARGET))
+            .map_or(LevelFilter::Off, |f| f.level_filter);
+        // Audit records only reach the audit file if they're let through, whatever the directives
+        let spec = LogSpecBuilder::from_module_filters(spec.module_filters())
+            .module(AUDIT_LOG_TARGET, audit_level.max(LevelFilter::Info))
+            .build_with_textfilter(spec.text_filter().cloned());
+        Logger::with(spec)
     } else {
         let logspec = LogSpecification::builder()
             .default(LevelFilter::Error)
             .module(env!("CARGO_CRATE_NAME"), level)
+            // Audit records are only written when enabled, so always let them through
+            .module(AUDIT_LOG_TARGET, level.max(LevelFilter::Info))
             .build();
-        Logger::with(logspec).filter(Box::new(SuppressExtensionFailure))
+        Logger::with(logspec)
     };
+    let logger = match audit_file {
+        Some(path) => logger.filter(Box::new(AuditFile::open(path, !from_env)?)),
+        None if from_env => logger,
+        None => logger.filter(Box::new(SuppressExtensionFailure)),
+    }
+    .format(format.format(timestamp));
 
-    if let Some(f) = log_file {
+    if let Some(f) = log_file.filter(|f| !is_stdout_log_file(f)) {
         let file_spec = FileSpec::try_from(f)?;
         logger.log_to_file(file_spec).start()
+    } else if to_stderr {
+        logger.log_to_stderr().start()
     } else {
         logger.log_to_stdout().start()
     }
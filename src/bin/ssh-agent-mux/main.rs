@@ -1,13 +1,23 @@
-use std::time::Duration;
+use std::process::ExitCode;
 
-use color_eyre::eyre::Result as EyreResult;
-use ssh_agent_mux::MuxAgent;
+use color_eyre::eyre::{Result as EyreResult, WrapErr};
+use ssh_agent_mux::{IdentityCache, MuxAgent, MuxOptions, SpawnedAgents};
 use tokio::select;
 use tokio::signal::{self, unix::SignalKind};
 
 mod cli;
+mod client;
+mod doctor;
+mod exit;
+mod import;
+mod inventory;
 mod logging;
+mod probe;
+mod route;
 mod service;
+mod sign;
+mod status;
+mod watch;
 
 #[cfg(debug_assertions)]
 fn install_eyre_hook() -> EyreResult<()> {
@@ -23,29 +33,88 @@ fn install_eyre_hook() -> EyreResult<()> {
         .install()
 }
 
-// Use current_thread to keep our resource utilization down; this program will generally be
-// accessed by only one user, at the start of each SSH session, so it doesn't need tokio's powerful
-// async multithreading
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> EyreResult<()> {
+fn main() -> ExitCode {
+    // Like sd_listen_fds, so that they don't reach the agents the mux starts; before the runtime
+    // starts threads, as changing the environment isn't safe once other threads run
+    ssh_agent_mux::init_socket_activation();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    // Use current_thread to keep our resource utilization down; this program will generally be
+    // accessed by only one user, at the start of each SSH session, so it doesn't need tokio's
+    // powerful async multithreading
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed building the Runtime");
+    match runtime.block_on(run()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            eprintln!("Error: {:?}", report);
+            ExitCode::from(exit::code(&report))
+        }
+    }
+}
+
+async fn run() -> EyreResult<()> {
     install_eyre_hook()?;
 
-    let mut config = cli::Config::parse()?;
+    let mut config = cli::Config::parse().wrap_err(exit::ConfigLoadFailed)?;
 
-    // Create parent directory for log file if it doesn't exist
-    if let Some(ref log_file) = config.log_file {
+    // Create parent directories for log and audit files if they don't exist
+    if let Some(log_file) = config
+        .log_file
+        .as_deref()
+        .filter(|f| !logging::is_stdout_log_file(f))
+    {
         if let Some(parent) = log_file.parent() {
             std::fs::create_dir_all(parent)?;
         }
     }
+    if let Some(audit_file) = config.audit_file.as_deref() {
+        if let Some(parent) = audit_file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
 
     // LoggerHandle must be held until program termination so file logging takes place
-    let _logger = logging::setup_logger(config.log_level.into(), config.log_file.as_deref())?;
+    let _logger = logging::setup_logger(
+        config.log_level.into(),
+        config.log_file.as_deref(),
+        config.audit_file.as_deref(),
+        config.log_timestamp,
+        config.log_format,
+        // Keep subcommands' output on standard output apart from their logs
+        config.command.is_some(),
+    )?;
+    for warning in &config.warnings {
+        log::warn!("{}", warning);
+    }
 
     if config.service.any() {
         return service::handle_service_command(&config);
     }
 
+    match config.command {
+        Some(cli::Command::Doctor) => return doctor::handle_doctor_command(&config).await,
+        Some(cli::Command::Import(ref args)) => {
+            return import::handle_import_command(&config, args).await
+        }
+        Some(cli::Command::Inventory) => return inventory::handle_inventory_command(&config).await,
+        Some(cli::Command::Route(ref args)) => {
+            return route::handle_route_command(&config, args).await
+        }
+        Some(cli::Command::Sign(ref args)) => {
+            return sign::handle_sign_command(&config, args).await
+        }
+        Some(cli::Command::Status) => return status::handle_status_command(&config).await,
+        None => {}
+    }
+    if config.probe_only {
+        return probe::handle_probe_only(&config).await;
+    }
+
     // TODO: detect and remove stale socket before binding. If
     // listen_path exists but no process is listening (connect returns
     // ECONNREFUSED), unlink it so MuxAgent::run doesn't fail with
@@ -53,22 +122,59 @@ async fn main() -> EyreResult<()> {
 
     let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
     let mut sighup = signal::unix::signal(SignalKind::hangup())?;
+    // Lets a reload that leaves the upstream agents as they were keep the keys known so far
+    let identity_cache = IdentityCache::default();
+    // Likewise keeps the agents the mux started running; they're stopped when it exits
+    let spawned_agents = SpawnedAgents::default();
+    let mut config_stamps = watch::stamps(&config.config_paths);
 
     loop {
-        let agent_paths = config.enabled_agent_socket_paths();
-        let added_keys_path = config.added_keys_socket_path();
+        let agents = config.enabled_upstream_agents();
+        let added_keys_paths = config.added_keys_socket_paths();
+        let options = MuxOptions {
+            identity_cache: Some(identity_cache.clone()),
+            spawned_agents: Some(spawned_agents.clone()),
+            ..config.mux_options()
+        };
         select! {
-            res = MuxAgent::run(&config.listen_path, &agent_paths, added_keys_path, Duration::from_secs(config.agent_timeout)) => { res?; break },
+            res = MuxAgent::run(&config.listen_path, agents, added_keys_paths, options) => { res?; break },
             // Cleanly exit on interrupt and SIGTERM, allowing
             // MuxAgent to clean up
             _ = signal::ctrl_c() => { log::info!("Exiting on SIGINT"); break },
             Some(_) = sigterm.recv() => { log::info!("Exiting on SIGTERM"); break },
             Some(_) = sighup.recv() => {
                 log::info!("Reloading configuration");
-                config = cli::Config::parse()?;
+                config_stamps = watch::stamps(&config.config_paths);
+                let strict = config.strict_reload;
+                reload_config(&mut config, strict)?;
+            }
+            _ = watch::changed(&config.config_paths, &mut config_stamps), if config.watch_config => {
+                log::info!("Configuration file changed; reloading it");
+                // Likely mid-edit, so never fatal
+                reload_config(&mut config, false)?;
             }
         }
     }
 
     Ok(())
 }
+
+/// Replace `config` with the configuration loaded again; if it can't be loaded, keep serving with
+/// `config`, unless `strict`
+fn reload_config(config: &mut cli::Config, strict: bool) -> EyreResult<()> {
+    match cli::Config::parse() {
+        Ok(new_config) => {
+            *config = new_config;
+            for warning in &config.warnings {
+                log::warn!("{}", warning);
+            }
+        }
+        Err(e) if strict => return Err(e.wrap_err(exit::ConfigLoadFailed)),
+        // An accidental bad edit shouldn't take down the agent mid-session
+        Err(e) => log::error!(
+            "Failed to reload configuration; still serving with the previous one: {:#}",
+            e
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_mux::MuxAgent;
+
+use crate::{cli::Config, exit};
+
+/// Check that every enabled upstream agent answers an identity request, without binding the
+/// listening socket; prints nothing when they all do, so the exit code is the result
+pub async fn handle_probe_only(config: &Config) -> Result<()> {
+    let inventory =
+        MuxAgent::inventory(config.enabled_upstream_agents(), config.mux_options()).await?;
+    if inventory.unreachable.is_empty() {
+        return Ok(());
+    }
+    Err(eyre!(
+        "Couldn't list the keys of upstream agents {:?}",
+        inventory.unreachable
+    )
+    .wrap_err(exit::AgentsUnreachable))
+}
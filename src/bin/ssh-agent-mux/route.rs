@@ -0,0 +1,64 @@
+use std::{fs, path::PathBuf};
+
+use clap_serde_derive::clap::{self, Args};
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_lib::ssh_key::PublicKey;
+use ssh_agent_mux::{MuxAgent, Route};
+
+use crate::cli::Config;
+
+#[derive(Args, Clone)]
+pub struct RouteArgs {
+    /// OpenSSH public key file of the key to route
+    pub pubkey_file: PathBuf,
+}
+
+/// Report which upstream agent the mux would ask to sign with a key, by asking the configured
+/// agents directly; the mux itself doesn't need to be running
+pub async fn handle_route_command(config: &Config, args: &RouteArgs) -> Result<()> {
+    let text = fs::read_to_string(&args.pubkey_file).map_err(|e| {
+        eyre!(
+            "Failed to read public key file {}: {}",
+            args.pubkey_file.display(),
+            e
+        )
+    })?;
+    let pubkey = PublicKey::from_openssh(text.trim())?;
+    let fingerprint = pubkey.fingerprint(Default::default());
+
+    let route = MuxAgent::route(
+        config.enabled_upstream_agents(),
+        config.mux_options(),
+        pubkey.key_data(),
+    )
+    .await?;
+    match route {
+        Route::Agent(name) => println!("{}", name),
+        Route::DefaultAgent(name) => {
+            println!("{}", name);
+            eprintln!(
+                "No upstream agent lists key {}; it goes to default-agent",
+                fingerprint
+            );
+        }
+        Route::Hidden(name) => {
+            return Err(eyre!(
+                "Key {} would go to upstream agent {:?}, but key filters hide it, so the mux \
+                 refuses to sign with it",
+                fingerprint,
+                name
+            ))
+        }
+        Route::Duplicate(names) => {
+            return Err(eyre!(
+                "Key {} is listed by several upstream agents ({}), and duplicate-key-policy is \
+                 error, so the mux refuses to sign with it",
+                fingerprint,
+                names.join(", ")
+            ))
+        }
+        Route::Unlisted => return Err(eyre!("No agent holds key {}", fingerprint)),
+    }
+
+    Ok(())
+}
@@ -127,6 +127,22 @@ fn write_new_config_file(config: &Config) -> Result<()> {
                     name: "default".into(),
                     socket_path: v.into(),
                     enabled: true,
+                    tags: Vec::new(),
+                    lock_passphrase: None,
+                    lock_passphrase_file: None,
+                    kind: Default::default(),
+                    startup_grace: None,
+                    expose_fingerprints: Vec::new(),
+                    hide_fingerprints: Vec::new(),
+                    always_refresh: false,
+                    forward_extensions: Vec::new(),
+                    no_forward_extensions: Vec::new(),
+                    offer_rank: None,
+                    serialize: false,
+                    command: Vec::new(),
+                    env: Default::default(),
+                    confirm: false,
+                    read_only: false,
                 });
             }
             Err(e) => {
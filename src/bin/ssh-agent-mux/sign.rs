@@ -0,0 +1,113 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use clap_serde_derive::clap::{self, Args};
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_lib::{
+    agent::Session,
+    proto::{signature, SignRequest},
+    ssh_encoding::{
+        base64::{Base64, Encoding},
+        Encode,
+    },
+    ssh_key::{public::KeyData, Algorithm, Fingerprint, PublicKey},
+};
+use ssh_agent_mux::{MuxAgent, Route};
+
+use crate::{
+    cli::Config,
+    client::{connect, with_timeout},
+};
+
+#[derive(Args, Clone)]
+pub struct SignArgs {
+    /// Key to sign with: an OpenSSH public key file, or the fingerprint of a key the mux lists
+    #[arg(long)]
+    pub key: String,
+
+    /// File containing the data to sign
+    #[arg(long)]
+    pub data: PathBuf,
+
+    /// Write the signature to this file in SSH wire encoding, instead of printing it as base64
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+async fn resolve_key(
+    key: &str,
+    mux: &mut Box<dyn Session>,
+    agent_timeout: Duration,
+) -> Result<KeyData> {
+    let Ok(fingerprint) = key.parse::<Fingerprint>() else {
+        let text = fs::read_to_string(key)
+            .map_err(|e| eyre!("Failed to read public key file {}: {}", key, e))?;
+        return Ok(PublicKey::from_openssh(text.trim())?.key_data().clone());
+    };
+    let identities = with_timeout(agent_timeout, "Listing keys", mux.request_identities()).await?;
+    identities
+        .into_iter()
+        .map(|id| id.pubkey)
+        .find(|pubkey| pubkey.fingerprint(fingerprint.algorithm()) == fingerprint)
+        .ok_or_else(|| {
+            eyre!(
+                "The mux doesn't list a key with fingerprint {}",
+                fingerprint
+            )
+        })
+}
+
+/// Sign a file through the running mux, to check signing end to end without `ssh`. A diagnostic
+/// tool: the signature is over the raw data, not in any format another program verifies.
+pub async fn handle_sign_command(config: &Config, args: &SignArgs) -> Result<()> {
+    let agent_timeout = Duration::from_secs(config.agent_timeout);
+    let data = fs::read(&args.data)
+        .map_err(|e| eyre!("Failed to read data file {}: {}", args.data.display(), e))?;
+
+    let mut mux = connect(&config.listen_path, agent_timeout).await?;
+    let pubkey = resolve_key(&args.key, &mut mux, agent_timeout).await?;
+    let fingerprint = pubkey.fingerprint(Default::default());
+    let flags = match pubkey.algorithm() {
+        Algorithm::Rsa { .. } => signature::RSA_SHA2_256,
+        _ => 0,
+    };
+    let request = SignRequest {
+        pubkey: pubkey.clone(),
+        data,
+        flags,
+    };
+    let signature = with_timeout(agent_timeout, "Signing", mux.sign(request)).await?;
+
+    // Asks the agents directly, so it's the agent the mux routed to unless their keys changed in
+    // between
+    let route = MuxAgent::route(
+        config.enabled_upstream_agents(),
+        config.mux_options(),
+        &pubkey,
+    )
+    .await;
+    match route {
+        Ok(Route::Agent(name)) => {
+            eprintln!(
+                "Signed with key {} by upstream agent {:?}",
+                fingerprint, name
+            )
+        }
+        Ok(Route::DefaultAgent(name)) => eprintln!(
+            "Signed with key {}; no upstream agent lists it, so default-agent {:?} likely signed",
+            fingerprint, name
+        ),
+        _ => eprintln!(
+            "Signed with key {}; no configured agent lists it",
+            fingerprint
+        ),
+    }
+
+    let mut encoded = Vec::new();
+    signature.encode(&mut encoded)?;
+    match &args.output {
+        Some(path) => fs::write(path, encoded)?,
+        None => println!("{}", Base64::encode_string(&encoded)),
+    }
+
+    Ok(())
+}
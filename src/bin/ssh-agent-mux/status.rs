@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result};
+use ssh_agent_lib::proto::{extension::MessageExtension, Extension};
+use ssh_agent_mux::extensions::{AgentRefreshStatus, RefreshOutcome, RefreshStatus};
+
+use crate::{
+    cli::Config,
+    client::{connect, with_timeout},
+};
+
+fn describe(agent: &AgentRefreshStatus) -> String {
+    let mut description = match agent.outcome {
+        RefreshOutcome::Ok if agent.keys == 1 => "ok, 1 key".to_string(),
+        RefreshOutcome::Ok => format!("ok, {} keys", agent.keys),
+        outcome => outcome.to_string(),
+    };
+    if agent.outcome != RefreshOutcome::NotRefreshed {
+        description.push_str(&format!(" ({}s ago)", agent.age_secs));
+    }
+    if !agent.detail.is_empty() {
+        description.push_str(&format!(": {}", agent.detail));
+    }
+    description
+}
+
+/// Print the outcome of the running mux's latest identity request to each upstream agent
+pub async fn handle_status_command(config: &Config) -> Result<()> {
+    let agent_timeout = Duration::from_secs(config.agent_timeout);
+    let mut mux = connect(&config.listen_path, agent_timeout).await?;
+    let request = Extension {
+        name: RefreshStatus::NAME.into(),
+        details: Vec::new().into(),
+    };
+    let response = with_timeout(agent_timeout, "Requesting status", mux.extension(request))
+        .await?
+        .ok_or_else(|| eyre!("The mux sent no refresh status"))?;
+    let RefreshStatus { agents } = response
+        .parse_message::<RefreshStatus>()?
+        .ok_or_else(|| eyre!("Unexpected response to {}", RefreshStatus::NAME))?;
+
+    let name_width = agents
+        .iter()
+        .map(|a| a.name.len())
+        .max()
+        .unwrap_or_default();
+    for agent in &agents {
+        println!("{:name_width$}  {}", agent.name, describe(agent));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Polling of the configuration files for `watch-config`
+//!
+//! This polls each file's metadata every second instead of watching it with `notify`
+//! (inotify/kqueue). It only runs with `watch-config` set, and a few `stat` calls a second cost
+//! next to nothing. It also follows each path again on every poll, so it sees a file replaced
+//! by repointing a symlink (as home-manager does, into the Nix store), which a watch on the old
+//! target would miss unless every directory along the way were watched too. It also needs no
+//! extra dependency.
+
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// How often to look for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a changed file must stay unchanged before it's reloaded, so that an editor's
+/// write-then-rename, or several saves in a row, cause a single reload
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// What tells versions of a file apart: replacing it by a rename changes the inode, writing to it
+/// in place the modification time or length. `None` if it doesn't exist.
+type Stamp = Option<(u64, u64, SystemTime, u64)>;
+
+/// Stamps of `paths`, for [`changed`]
+pub fn stamps(paths: &[PathBuf]) -> Vec<Stamp> {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            let modified = metadata.modified().ok()?;
+            Some((metadata.dev(), metadata.ino(), modified, metadata.len()))
+        })
+        .collect()
+}
+
+/// Wait until any of `paths` is created, changed, or removed since `seen` was taken, and has
+/// then stayed unchanged for a moment; `seen` is updated to match
+pub async fn changed(paths: &[PathBuf], seen: &mut Vec<Stamp>) {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let mut latest = stamps(paths);
+        if latest == *seen {
+            continue;
+        }
+        loop {
+            tokio::time::sleep(DEBOUNCE).await;
+            let settled = stamps(paths);
+            if settled == latest {
+                break;
+            }
+            latest = settled;
+        }
+        *seen = latest;
+        return;
+    }
+}
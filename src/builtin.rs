@@ -0,0 +1,183 @@
+//! Upstream agent run by the mux itself, holding keys added through it in memory; see
+//! [`UpstreamKind::Builtin`](crate::UpstreamKind::Builtin)
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rsa::{
+    pkcs1v15,
+    sha2::{Sha256, Sha512},
+    signature::{SignatureEncoding, Signer},
+};
+use ssh_agent_lib::{
+    agent::Session,
+    error::AgentError,
+    proto::{
+        signature, AddIdentity, AddIdentityConstrained, Credential, Extension, Identity,
+        RemoveIdentity, SignRequest,
+    },
+    ssh_key::{
+        private::{KeypairData, RsaKeypair},
+        public::KeyData as PubKeyData,
+        Algorithm, HashAlg, Mpint, Signature,
+    },
+};
+use zeroize::Zeroizing;
+
+/// A key held by the agent; the private half is zeroized when dropped
+struct Key {
+    keypair: KeypairData,
+    pubkey: PubKeyData,
+    comment: String,
+}
+
+#[derive(Default)]
+struct Store {
+    keys: Vec<Key>,
+    lock_passphrase: Option<Zeroizing<String>>,
+}
+
+/// A minimal in-memory agent. Clones share the same keys, so the mux hands each request a clone
+/// instead of connecting to a socket.
+///
+/// Deliberately limited: it refuses certificates, key constraints (it has no way to ask for
+/// confirmation or to expire keys), legacy SHA-1 `ssh-rsa` signatures, and every extension.
+#[derive(Clone, Default)]
+pub(crate) struct BuiltinAgent {
+    store: Arc<Mutex<Store>>,
+}
+
+impl BuiltinAgent {
+    fn store(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The store, unless the agent is locked
+    fn unlocked_store(&self) -> Result<std::sync::MutexGuard<'_, Store>, AgentError> {
+        let store = self.store();
+        match store.lock_passphrase {
+            Some(_) => Err(AgentError::Failure),
+            None => Ok(store),
+        }
+    }
+}
+
+/// Like ssh-key's own conversion, which as of 0.6.7 passes `p` for both primes and so fails
+fn rsa_private_key(keypair: &RsaKeypair) -> Result<rsa::RsaPrivateKey, AgentError> {
+    let uint = |m: &Mpint| rsa::BigUint::try_from(m).map_err(AgentError::other);
+    rsa::RsaPrivateKey::from_components(
+        uint(&keypair.public.n)?,
+        uint(&keypair.public.e)?,
+        uint(&keypair.private.d)?,
+        vec![uint(&keypair.private.p)?, uint(&keypair.private.q)?],
+    )
+    .map_err(AgentError::other)
+}
+
+fn sign_with(keypair: &KeypairData, request: &SignRequest) -> Result<Signature, AgentError> {
+    let KeypairData::Rsa(keypair) = keypair else {
+        return keypair.try_sign(&request.data).map_err(AgentError::other);
+    };
+    let private_key = rsa_private_key(keypair)?;
+    let (hash, data) = if request.flags & signature::RSA_SHA2_512 != 0 {
+        let signer = pkcs1v15::SigningKey::<Sha512>::new(private_key);
+        (HashAlg::Sha512, signer.try_sign(&request.data))
+    } else if request.flags & signature::RSA_SHA2_256 != 0 {
+        let signer = pkcs1v15::SigningKey::<Sha256>::new(private_key);
+        (HashAlg::Sha256, signer.try_sign(&request.data))
+    } else {
+        log::warn!("Built-in agent refused a legacy ssh-rsa (SHA-1) signature");
+        return Err(AgentError::Failure);
+    };
+    let data = data.map_err(AgentError::other)?.to_vec();
+    Signature::new(Algorithm::Rsa { hash: Some(hash) }, data).map_err(AgentError::other)
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for BuiltinAgent {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        let store = self.store();
+        if store.lock_passphrase.is_some() {
+            return Ok(vec![]);
+        }
+        Ok(store
+            .keys
+            .iter()
+            .map(|key| Identity {
+                pubkey: key.pubkey.clone(),
+                comment: key.comment.clone(),
+            })
+            .collect())
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let store = self.unlocked_store()?;
+        let key = store
+            .keys
+            .iter()
+            .find(|key| key.pubkey == request.pubkey)
+            .ok_or(AgentError::Failure)?;
+        sign_with(&key.keypair, &request)
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        let Credential::Key { privkey, comment } = identity.credential else {
+            log::warn!("Built-in agent refused to add a certificate");
+            return Err(AgentError::Failure);
+        };
+        let pubkey = PubKeyData::try_from(&privkey).map_err(AgentError::other)?;
+        let mut store = self.unlocked_store()?;
+        // Replace an existing copy, like ssh-agent, so that a re-added key gets its new comment
+        store.keys.retain(|key| key.pubkey != pubkey);
+        store.keys.push(Key {
+            keypair: privkey,
+            pubkey,
+            comment,
+        });
+        Ok(())
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        _identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        // Adding the key without enforcing its constraints would be worse than not adding it
+        log::warn!("Built-in agent refused a key with constraints, which it can't enforce");
+        Err(AgentError::Failure)
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        let mut store = self.unlocked_store()?;
+        let count = store.keys.len();
+        store.keys.retain(|key| key.pubkey != identity.pubkey);
+        if store.keys.len() == count {
+            return Err(AgentError::Failure);
+        }
+        Ok(())
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        self.unlocked_store()?.keys.clear();
+        Ok(())
+    }
+
+    async fn lock(&mut self, key: String) -> Result<(), AgentError> {
+        let key = Zeroizing::new(key);
+        let mut store = self.unlocked_store()?;
+        store.lock_passphrase = Some(key);
+        Ok(())
+    }
+
+    async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
+        let key = Zeroizing::new(key);
+        let mut store = self.store();
+        if store.lock_passphrase.as_ref() != Some(&key) {
+            return Err(AgentError::Failure);
+        }
+        store.lock_passphrase = None;
+        Ok(())
+    }
+
+    async fn extension(&mut self, _extension: Extension) -> Result<Option<Extension>, AgentError> {
+        Err(AgentError::ExtensionFailure)
+    }
+}
@@ -0,0 +1,211 @@
+//! Persisted routing of public keys to upstream agents, so that a restarted [`MuxAgent`] can route
+//! sign requests without first refreshing every upstream agent
+//!
+//! [`MuxAgent`]: crate::MuxAgent
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::ssh_key::PublicKey;
+
+use crate::{CommentFilter, KnownPubKeys, KnownPubKeysMap, UpstreamAgent};
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CacheFile {
+    /// Socket paths of the upstream agents the cache was written for
+    agents: Vec<PathBuf>,
+    /// When the cache was written, in seconds since the Unix epoch
+    #[serde(default)]
+    written_at: Option<u64>,
+    /// Comment filter the keys were listed under, as the cache doesn't record their comments to
+    /// check them against another one
+    #[serde(default)]
+    comment_filter: Option<CachedCommentFilter>,
+    #[serde(default)]
+    keys: Vec<CachedKey>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CachedCommentFilter {
+    pattern: String,
+    invert: bool,
+}
+
+impl CachedCommentFilter {
+    fn of(filter: Option<&CommentFilter>) -> Option<Self> {
+        filter.map(|f| Self {
+            pattern: f.pattern.as_str().to_owned(),
+            invert: f.invert,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CachedKey {
+    /// Public key, in OpenSSH format
+    key: String,
+    /// Socket path of the agent that last listed the key
+    agent: PathBuf,
+}
+
+fn agent_set(agents: &[UpstreamAgent]) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = agents.iter().map(|a| a.socket_path.clone()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Load the cached routing at `path`; anything unreadable, written for a different set of
+/// upstream agents or under a different comment filter, or written more than `max_age` ago, is
+/// ignored
+pub(crate) fn load(
+    path: &Path,
+    agents: &[UpstreamAgent],
+    comment_filter: Option<&CommentFilter>,
+    max_age: Duration,
+) -> KnownPubKeysMap {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Default::default(),
+        Err(e) => {
+            log::warn!("Failed to read known keys cache {}: {}", path.display(), e);
+            return Default::default();
+        }
+    };
+    let mut cache = match toml::from_str::<CacheFile>(&text) {
+        Ok(cache) => cache,
+        Err(e) => {
+            log::warn!(
+                "Ignoring invalid known keys cache {}: {}",
+                path.display(),
+                e
+            );
+            return Default::default();
+        }
+    };
+    cache.agents.sort();
+    cache.agents.dedup();
+    if cache.agents != agent_set(agents) {
+        log::info!(
+            "Ignoring known keys cache {}: upstream agents have changed",
+            path.display()
+        );
+        return Default::default();
+    }
+    if cache.comment_filter != CachedCommentFilter::of(comment_filter) {
+        log::info!(
+            "Ignoring known keys cache {}: comment-filter has changed",
+            path.display()
+        );
+        return Default::default();
+    }
+    // Caches without a timestamp, or from the future (the clock was set back), are of unknown age
+    let age = cache
+        .written_at
+        .and_then(|written_at| unix_time().checked_sub(written_at))
+        .map(Duration::from_secs);
+    if !age.is_some_and(|age| age <= max_age) {
+        log::info!(
+            "Ignoring known keys cache {}: written more than {:?} ago",
+            path.display(),
+            max_age
+        );
+        return Default::default();
+    }
+
+    let known_keys: KnownPubKeysMap = cache
+        .keys
+        .into_iter()
+        .filter(|k| cache.agents.contains(&k.agent))
+        .filter_map(|k| match PublicKey::from_openssh(&k.key) {
+            Ok(key) => Some((key.key_data().clone(), k.agent)),
+            Err(e) => {
+                log::debug!("Skipping unparseable key in known keys cache: {}", e);
+                None
+            }
+        })
+        .collect();
+    log::info!(
+        "Loaded {} known keys from cache {}",
+        known_keys.len(),
+        path.display()
+    );
+    known_keys
+}
+
+/// Write `known_keys` to `path`, replacing any existing cache
+pub(crate) fn save(
+    path: &Path,
+    agents: &[UpstreamAgent],
+    comment_filter: Option<&CommentFilter>,
+    known_keys: &KnownPubKeysMap,
+) -> io::Result<()> {
+    let keys = known_keys
+        .iter()
+        .filter_map(|(key_data, agent)| {
+            let key = PublicKey::from(key_data.clone()).to_openssh().ok()?;
+            Some(CachedKey {
+                key,
+                agent: agent.clone(),
+            })
+        })
+        .collect();
+    let cache = CacheFile {
+        agents: agent_set(agents),
+        written_at: Some(unix_time()),
+        comment_filter: CachedCommentFilter::of(comment_filter),
+        keys,
+    };
+    let text = toml::to_string(&cache).map_err(io::Error::other)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write then rename, so a crash mid-write can't leave a truncated cache behind
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, text)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Saves the known keys to the cache file when dropped, i.e. when the agent stops
+pub(crate) struct SaveOnDrop {
+    pub path: PathBuf,
+    pub agents: Vec<UpstreamAgent>,
+    pub comment_filter: Option<CommentFilter>,
+    pub known_keys: KnownPubKeys,
+}
+
+impl Drop for SaveOnDrop {
+    fn drop(&mut self) {
+        let Ok(known_keys) = self.known_keys.try_lock() else {
+            log::warn!("Known keys busy at shutdown; not updating cache");
+            return;
+        };
+        let comment_filter = self.comment_filter.as_ref();
+        match save(&self.path, &self.agents, comment_filter, &known_keys) {
+            Ok(()) => log::debug!(
+                "Saved {} known keys to cache {}",
+                known_keys.len(),
+                self.path.display()
+            ),
+            Err(e) => log::warn!(
+                "Failed to write known keys cache {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
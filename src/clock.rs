@@ -0,0 +1,72 @@
+//! Time source for time-dependent behavior, so that tests can control time instead of sleeping
+
+use std::{fmt, time::Instant};
+
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+pub(crate) trait Clock: fmt::Debug + Send + Sync {
+    /// Current monotonic time
+    fn now(&self) -> Instant;
+}
+
+/// The real clock
+#[derive(Debug, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct TestClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Default::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_only_moves_when_advanced() {
+        let clock = TestClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(clock.now() - start, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_system_clock_is_monotonic() {
+        let clock = SystemClock;
+        let start = clock.now();
+        assert!(clock.now() >= start);
+    }
+}
@@ -0,0 +1,33 @@
+//! Asking the user to approve a sign request before it's forwarded; see
+//! [`MuxOptions::confirm_command`](crate::MuxOptions::confirm_command)
+
+use std::{process::Stdio, time::Duration};
+
+use tokio::{process::Command, time::timeout};
+
+/// Run `command` with `prompt` as its last argument, as ssh-agent runs `ssh-askpass` for keys
+/// added with `ssh-add -c`; whether the user approved, by the program exiting successfully within
+/// `wait`, or why they couldn't be asked
+pub(crate) async fn ask(command: &[String], prompt: &str, wait: Duration) -> Result<bool, String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err("no confirm-command is set".into());
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .arg(prompt)
+        // Tells ssh-askpass to ask yes or no, rather than for a passphrase
+        .env("SSH_ASKPASS_PROMPT", "confirm")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to run {:?}: {}", program, e))?;
+    match timeout(wait, child.wait()).await {
+        Ok(Ok(status)) => Ok(status.success()),
+        Ok(Err(e)) => Err(format!("failed to wait for {:?}: {}", program, e)),
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(format!("no answer within {:?}", wait))
+        }
+    }
+}
@@ -0,0 +1,642 @@
+//! Agent protocol extensions implemented by the mux itself, rather than forwarded upstream
+
+use ssh_agent_lib::{
+    proto::{extension::MessageExtension, ProtoError},
+    ssh_encoding::{self, CheckedSum, Decode, Encode, Reader, Writer},
+    ssh_key::public::KeyData,
+};
+
+/// `select-tags@ssh-agent-mux` message extension.
+///
+/// Restricts the rest of the client's connection to upstream agents carrying at least one of
+/// `tags`: only their identities are listed and only they are asked to sign. An empty list
+/// restores the default of every upstream agent being in scope.
+///
+/// Wire format: a single `name-list`-style sequence of strings, as used by the `query` response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectTags {
+    pub tags: Vec<String>,
+}
+
+impl Encode for SelectTags {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.tags.encoded_len()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.tags.encode(writer)
+    }
+}
+
+impl Decode for SelectTags {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let tags = Vec::<String>::decode(reader)?;
+        Ok(Self { tags })
+    }
+}
+
+impl MessageExtension for SelectTags {
+    const NAME: &'static str = "select-tags@ssh-agent-mux";
+}
+
+/// `refresh-status@ssh-agent-mux` message extension.
+///
+/// Sent with empty contents; the response lists every upstream agent, in configured order, with
+/// the outcome of the latest attempt to request its identities, so a partially working mux can
+/// be diagnosed at a glance.
+///
+/// Wire format: a `uint32` count of agents, then for each: `string` name, `string` outcome (see
+/// [`RefreshOutcome`]), `uint32` number of keys, `string` detail (e.g. an error message), and
+/// `uint32` seconds since the attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshStatus {
+    pub agents: Vec<AgentRefreshStatus>,
+}
+
+/// Outcome of the latest identity request to one upstream agent
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentRefreshStatus {
+    pub name: String,
+    pub outcome: RefreshOutcome,
+    /// Number of keys the agent contributed; zero unless the outcome is [`RefreshOutcome::Ok`]
+    pub keys: u32,
+    /// Reason for a failure, or empty
+    pub detail: String,
+    /// Seconds since the attempt; zero if there wasn't one
+    pub age_secs: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshOutcome {
+    /// The agent hasn't been asked for its identities yet
+    NotRefreshed,
+    Ok,
+    /// Connecting to the agent's socket failed, e.g. because it doesn't exist
+    ConnectFailed,
+    /// The agent didn't answer within the agent timeout
+    TimedOut,
+    /// The agent answered the identity request with an error
+    RequestFailed,
+}
+
+impl RefreshOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefreshOutcome::NotRefreshed => "not-refreshed",
+            RefreshOutcome::Ok => "ok",
+            RefreshOutcome::ConnectFailed => "connect-failed",
+            RefreshOutcome::TimedOut => "timed-out",
+            RefreshOutcome::RequestFailed => "request-failed",
+        }
+    }
+}
+
+impl std::str::FromStr for RefreshOutcome {
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            RefreshOutcome::NotRefreshed,
+            RefreshOutcome::Ok,
+            RefreshOutcome::ConnectFailed,
+            RefreshOutcome::TimedOut,
+            RefreshOutcome::RequestFailed,
+        ]
+        .into_iter()
+        .find(|o| o.as_str() == s)
+        .ok_or_else(|| {
+            ProtoError::IO(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown refresh outcome {:?}", s),
+            ))
+        })
+    }
+}
+
+impl std::fmt::Display for RefreshOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Encode for AgentRefreshStatus {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        [
+            self.name.encoded_len()?,
+            self.outcome.as_str().encoded_len()?,
+            self.keys.encoded_len()?,
+            self.detail.encoded_len()?,
+            self.age_secs.encoded_len()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.name.encode(writer)?;
+        self.outcome.as_str().encode(writer)?;
+        self.keys.encode(writer)?;
+        self.detail.encode(writer)?;
+        self.age_secs.encode(writer)
+    }
+}
+
+impl Decode for AgentRefreshStatus {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            outcome: String::decode(reader)?.parse()?,
+            keys: u32::decode(reader)?,
+            detail: String::decode(reader)?,
+            age_secs: u32::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for RefreshStatus {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.agents
+            .iter()
+            .map(Encode::encoded_len)
+            .try_fold(4usize, |len, agent| [len, agent?].checked_sum())
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        u32::try_from(self.agents.len())?.encode(writer)?;
+        for agent in &self.agents {
+            agent.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for RefreshStatus {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let count = u32::decode(reader)?;
+        let agents = (0..count)
+            .map(|_| AgentRefreshStatus::decode(reader))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { agents })
+    }
+}
+
+impl MessageExtension for RefreshStatus {
+    const NAME: &'static str = "refresh-status@ssh-agent-mux";
+}
+
+/// `list-upstreams@ssh-agent-mux` message extension.
+///
+/// Sent with empty contents; the mux refreshes its identities first, and the response lists the
+/// upstream agents in the client's scope, in configured order, each with the keys the mux routes
+/// sign requests for to it. A key that several agents list appears only under the one it's routed
+/// to, and an agent that couldn't be reached has no keys.
+///
+/// Wire format: a `uint32` count of agents, then for each: `string` name, `string` socket path,
+/// and a `name-list`-style sequence of the SHA256 fingerprints of its keys, in the order it lists
+/// them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListUpstreams {
+    pub agents: Vec<UpstreamKeys>,
+}
+
+/// Keys routed to one upstream agent
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamKeys {
+    pub name: String,
+    /// `builtin:<name>` for a builtin agent
+    pub socket_path: String,
+    /// In the form `SHA256:<base64>`
+    pub fingerprints: Vec<String>,
+}
+
+impl Encode for UpstreamKeys {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        [
+            self.name.encoded_len()?,
+            self.socket_path.encoded_len()?,
+            self.fingerprints.encoded_len()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.name.encode(writer)?;
+        self.socket_path.encode(writer)?;
+        self.fingerprints.encode(writer)
+    }
+}
+
+impl Decode for UpstreamKeys {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            socket_path: String::decode(reader)?,
+            fingerprints: Vec::<String>::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for ListUpstreams {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.agents
+            .iter()
+            .map(Encode::encoded_len)
+            .try_fold(4usize, |len, agent| [len, agent?].checked_sum())
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        u32::try_from(self.agents.len())?.encode(writer)?;
+        for agent in &self.agents {
+            agent.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for ListUpstreams {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let count = u32::decode(reader)?;
+        let agents = (0..count)
+            .map(|_| UpstreamKeys::decode(reader))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { agents })
+    }
+}
+
+impl MessageExtension for ListUpstreams {
+    const NAME: &'static str = "list-upstreams@ssh-agent-mux";
+}
+
+/// `info@ssh-agent-mux` message extension.
+///
+/// Sent with empty contents; the response identifies the mux, so that tools can tell they're
+/// talking to ssh-agent-mux and which of its own extensions they can use.
+///
+/// Wire format: `string` crate name, `string` version, then a `name-list`-style sequence of the
+/// names of the mux's own extensions it answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuxInfo {
+    pub name: String,
+    pub version: String,
+    pub extensions: Vec<String>,
+}
+
+impl Encode for MuxInfo {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        [
+            self.name.encoded_len()?,
+            self.version.encoded_len()?,
+            self.extensions.encoded_len()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.name.encode(writer)?;
+        self.version.encode(writer)?;
+        self.extensions.encode(writer)
+    }
+}
+
+impl Decode for MuxInfo {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            version: String::decode(reader)?,
+            extensions: Vec::<String>::decode(reader)?,
+        })
+    }
+}
+
+impl MessageExtension for MuxInfo {
+    const NAME: &'static str = "info@ssh-agent-mux";
+}
+
+/// `mux-identity@ssh-agent-mux` message extension, with which a mux finds out whether its
+/// upstream agents lead back to it through other muxes.
+///
+/// Sent with the `chain` of instance ids of the muxes the probe has passed through, starting
+/// with the one that sent it. A mux whose id isn't in the chain adds it and passes the probe on
+/// to each of its own upstream agents, answering with the first response that closes a cycle,
+/// or else with the chain it passed on; one whose id is already in it answers at once with the
+/// chain ending in its id again. A response whose last id appears earlier in it is a cycle.
+///
+/// Wire format: a single `name-list`-style sequence of instance ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuxIdentity {
+    pub chain: Vec<String>,
+}
+
+impl MuxIdentity {
+    /// The mux at which the chain loops back on itself, if it does
+    pub fn cycle_at(&self) -> Option<&str> {
+        let (last, rest) = self.chain.split_last()?;
+        rest.contains(last).then_some(last.as_str())
+    }
+}
+
+impl Encode for MuxIdentity {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.chain.encoded_len()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.chain.encode(writer)
+    }
+}
+
+impl Decode for MuxIdentity {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let chain = Vec::<String>::decode(reader)?;
+        Ok(Self { chain })
+    }
+}
+
+impl MessageExtension for MuxIdentity {
+    const NAME: &'static str = "mux-identity@ssh-agent-mux";
+}
+
+/// `sign-check@ssh-agent-mux` message extension, for troubleshooting routing of keys that more
+/// than one upstream agent holds.
+///
+/// Asks every upstream agent that lists `pubkey` to sign a fixed test message with it; the
+/// response is a [`SignCheckResults`]. Each of those agents really signs, which may ask for
+/// confirmation or a hardware token touch. Only answered when enabled in
+/// [`MuxOptions::sign_check`](crate::MuxOptions::sign_check); otherwise the reply is a
+/// [`Denied`].
+///
+/// Wire format: `string` public key blob.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignCheck {
+    pub pubkey: KeyData,
+}
+
+impl Encode for SignCheck {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.pubkey.encoded_len_prefixed()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.pubkey.encode_prefixed(writer)
+    }
+}
+
+impl Decode for SignCheck {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let pubkey = reader.read_prefixed(KeyData::decode)?;
+        Ok(Self { pubkey })
+    }
+}
+
+impl MessageExtension for SignCheck {
+    const NAME: &'static str = "sign-check@ssh-agent-mux";
+}
+
+/// Response to [`SignCheck`]: the upstream agents that list the key, in configured order.
+///
+/// Wire format: a `uint32` count of agents, then for each: `string` name, `boolean` whether it
+/// returned a signature, `string` detail (e.g. an error message), and `uint32` milliseconds the
+/// sign request took; then `string` denied, which older versions of the mux don't send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignCheckResults {
+    pub agents: Vec<AgentSignCheck>,
+    /// Why the mux itself would refuse to sign with the key, as `policy denied (<tag>): <detail>`
+    /// (see [`DenialReason`](crate::policy::DenialReason)), or empty; when set, no agent is asked
+    pub denied: String,
+}
+
+/// Result of one upstream agent's test signature
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentSignCheck {
+    pub name: String,
+    /// Whether the agent returned a signature; the signature itself isn't verified
+    pub ok: bool,
+    /// Reason for a failure, or empty
+    pub detail: String,
+    pub millis: u32,
+}
+
+impl Encode for AgentSignCheck {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        [
+            self.name.encoded_len()?,
+            u8::from(self.ok).encoded_len()?,
+            self.detail.encoded_len()?,
+            self.millis.encoded_len()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.name.encode(writer)?;
+        u8::from(self.ok).encode(writer)?;
+        self.detail.encode(writer)?;
+        self.millis.encode(writer)
+    }
+}
+
+impl Decode for AgentSignCheck {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            ok: u8::decode(reader)? != 0,
+            detail: String::decode(reader)?,
+            millis: u32::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for SignCheckResults {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        self.agents
+            .iter()
+            .map(Encode::encoded_len)
+            .try_fold(4usize, |len, agent| [len, agent?].checked_sum())
+            .and_then(|len| [len, self.denied.encoded_len()?].checked_sum())
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        u32::try_from(self.agents.len())?.encode(writer)?;
+        for agent in &self.agents {
+            agent.encode(writer)?;
+        }
+        self.denied.encode(writer)
+    }
+}
+
+impl Decode for SignCheckResults {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let count = u32::decode(reader)?;
+        let agents = (0..count)
+            .map(|_| AgentSignCheck::decode(reader))
+            .collect::<Result<_, _>>()?;
+        let denied = if reader.is_finished() {
+            String::new()
+        } else {
+            String::decode(reader)?
+        };
+        Ok(Self { agents, denied })
+    }
+}
+
+impl MessageExtension for SignCheckResults {
+    const NAME: &'static str = SignCheck::NAME;
+}
+
+/// `denied@ssh-agent-mux` extension response, with which the mux answers a request for one of its
+/// own extensions that it refuses, rather than with a failure that gives no reason.
+///
+/// Wire format: `string` reason tag (see [`DenialReason`](crate::policy::DenialReason)), then
+/// `string` detail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Denied {
+    pub reason: String,
+    pub detail: String,
+}
+
+impl Encode for Denied {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        [self.reason.encoded_len()?, self.detail.encoded_len()?].checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        self.reason.encode(writer)?;
+        self.detail.encode(writer)
+    }
+}
+
+impl Decode for Denied {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            reason: String::decode(reader)?,
+            detail: String::decode(reader)?,
+        })
+    }
+}
+
+impl MessageExtension for Denied {
+    const NAME: &'static str = "denied@ssh-agent-mux";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refresh_status_round_trip() -> Result<(), ProtoError> {
+        let status = RefreshStatus {
+            agents: vec![
+                AgentRefreshStatus {
+                    name: "token".into(),
+                    outcome: RefreshOutcome::ConnectFailed,
+                    keys: 0,
+                    detail: "No such file or directory".into(),
+                    age_secs: 12,
+                },
+                AgentRefreshStatus {
+                    name: "openssh".into(),
+                    outcome: RefreshOutcome::Ok,
+                    keys: 3,
+                    detail: String::new(),
+                    age_secs: 0,
+                },
+            ],
+        };
+        let mut encoded = Vec::new();
+        status.encode(&mut encoded)?;
+        assert_eq!(encoded.len(), status.encoded_len()?);
+        assert_eq!(RefreshStatus::decode(&mut encoded.as_slice())?, status);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_upstreams_round_trip() -> Result<(), ProtoError> {
+        let upstreams = ListUpstreams {
+            agents: vec![
+                UpstreamKeys {
+                    name: "token".into(),
+                    socket_path: "/run/user/1000/token.sock".into(),
+                    fingerprints: vec![],
+                },
+                UpstreamKeys {
+                    name: "openssh".into(),
+                    socket_path: "/tmp/ssh-XXXX/agent.1".into(),
+                    fingerprints: vec![
+                        "SHA256:+DiY3wvvV6TuJJhbpZisF/zLDA0zPMSvHdkr4UvCOqU".into(),
+                        "SHA256:ZL7QP2SZ0mhkqRhmH0CWxeXIpA5BdElKwWIhNnieiBU".into(),
+                    ],
+                },
+            ],
+        };
+        let mut encoded = Vec::new();
+        upstreams.encode(&mut encoded)?;
+        assert_eq!(encoded.len(), upstreams.encoded_len()?);
+        assert_eq!(ListUpstreams::decode(&mut encoded.as_slice())?, upstreams);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mux_info_round_trip() -> Result<(), ProtoError> {
+        let info = MuxInfo {
+            name: "ssh-agent-mux".into(),
+            version: "1.2.3".into(),
+            extensions: vec![SelectTags::NAME.into(), MuxInfo::NAME.into()],
+        };
+        let mut encoded = Vec::new();
+        info.encode(&mut encoded)?;
+        assert_eq!(encoded.len(), info.encoded_len()?);
+        assert_eq!(MuxInfo::decode(&mut encoded.as_slice())?, info);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_check_results_round_trip() -> Result<(), ProtoError> {
+        let results = SignCheckResults {
+            agents: vec![
+                AgentSignCheck {
+                    name: "token".into(),
+                    ok: false,
+                    detail: "agent refused".into(),
+                    millis: 1500,
+                },
+                AgentSignCheck {
+                    name: "openssh".into(),
+                    ok: true,
+                    detail: String::new(),
+                    millis: 2,
+                },
+            ],
+            denied: String::new(),
+        };
+        let mut encoded = Vec::new();
+        results.encode(&mut encoded)?;
+        assert_eq!(encoded.len(), results.encoded_len()?);
+        assert_eq!(SignCheckResults::decode(&mut encoded.as_slice())?, results);
+
+        // As sent by a mux from before `denied`
+        encoded.truncate(encoded.len() - 4);
+        assert_eq!(SignCheckResults::decode(&mut encoded.as_slice())?, results);
+        Ok(())
+    }
+}
@@ -1,78 +1,422 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
+use regex::Regex;
 use ssh_agent_lib::{
     agent::{self, Agent, ListeningSocket, Session},
-    client,
     error::AgentError,
-    proto::{extension::QueryResponse, Credential, Extension, Identity, SignRequest},
-    ssh_key::{public::KeyData as PubKeyData, Signature},
+    proto::{
+        extension::{MessageExtension, QueryResponse},
+        signature, AddIdentity, AddIdentityConstrained, Credential, Extension, Identity,
+        KeyConstraint, ProtoError, RemoveIdentity, Request, Response, SignRequest,
+    },
+    ssh_encoding::Encode,
+    ssh_key::{public::KeyData as PubKeyData, Algorithm, Fingerprint, Signature},
 };
 use tokio::{
     net::UnixListener,
-    sync::{Mutex, OwnedMutexGuard},
+    sync::{oneshot, Mutex, OwnedMutexGuard},
     time::timeout,
 };
+use zeroize::{Zeroize, Zeroizing};
+
+mod builtin;
+mod cache;
+mod clock;
+mod confirm;
+pub mod extensions;
+mod metrics;
+pub mod policy;
+mod pool;
+mod serialize;
+mod socket;
+mod spawn;
+
+use builtin::BuiltinAgent;
+use clock::{Clock, SystemClock};
+use extensions::{
+    AgentRefreshStatus, AgentSignCheck, Denied, ListUpstreams, MuxIdentity, MuxInfo,
+    RefreshOutcome, RefreshStatus, SelectTags, SignCheck, SignCheckResults, UpstreamKeys,
+};
+use metrics::Metrics;
+use policy::{Decision, DenialReason, Peer, RequestPolicy};
+use pool::{Connection, ConnectionPool, PooledClient};
+use serialize::SerializedClient;
+use socket::UpstreamStream;
+pub use spawn::SpawnedAgents;
+
+// OpenSSH refuses RSA keys with a smaller modulus (SSH_RSA_MINIMUM_MODULUS_SIZE)
+const MIN_RSA_MODULUS_BITS: usize = 1024;
+// gpg-agent is commonly started on demand by the first connection to its socket
+const GPG_AGENT_MIN_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+// Interval between connection attempts to an agent within its startup grace period
+const STARTUP_GRACE_POLL: Duration = Duration::from_millis(100);
+// With lazy connection, how long known keys are advertised before identities are refreshed again
+const LAZY_CONNECT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+// Longest wait between background refreshes while no upstream agent is reachable, unless the
+// configured interval is longer
+const BACKGROUND_REFRESH_MAX_BACKOFF: Duration = Duration::from_secs(600);
+// Data upstream agents sign for sign-check@ssh-agent-mux
+const SIGN_CHECK_DATA: &[u8] = b"ssh-agent-mux sign-check";
+// Longest chain of muxes a mux-identity@ssh-agent-mux probe is passed along
+const MUX_CHAIN_MAX: usize = 8;
+
+/// Log target of the records written for [`MuxOptions::audit`]
+pub const AUDIT_LOG_TARGET: &str = "ssh_agent_mux::audit";
+
+/// Prefix of an [`UpstreamAgent::socket_path`] that makes it a TCP address, as in
+/// `tcp://127.0.0.1:8022`
+pub const TCP_SOCKET_PREFIX: &str = "tcp://";
 
 type KnownPubKeysMap = HashMap<PubKeyData, PathBuf>;
 type KnownPubKeys = Arc<Mutex<KnownPubKeysMap>>;
 
-/// Only the `request_identities`, `sign`, `add_identity`, `lock`, `unlock`, and `extension`
-/// commands are implemented.
-/// For `extension`, only the `session-bind@openssh.com` and `query` extensions are supported.
+/// How a sign request was routed and answered, for [`AuditVerbosity::Routing`]; holds neither
+/// the data signed nor the signature
+#[derive(Debug, Default)]
+struct SignTrace {
+    /// Names of the agents in scope that listed the key at their latest identity request, as of
+    /// when it was first routed
+    candidates: Option<Vec<String>>,
+    /// Each routing decision and upstream answer, in order
+    steps: Vec<String>,
+    /// Name of the last agent asked to sign: the one that signed, if the request succeeded
+    agent: Option<String>,
+    /// Whether the user approved the request, if asked, so that a retry doesn't ask again
+    confirmed: Option<bool>,
+}
+
+/// A key added through the mux, for [`MuxOptions::require_constraints`]
+#[derive(Debug)]
+struct AddedKey {
+    /// Whether it was added with a lifetime or confirmation constraint
+    constrained: bool,
+}
+
+/// Outcome of the latest identity request to an upstream agent
+#[derive(Debug)]
+struct AgentOutcome {
+    at: Instant,
+    outcome: RefreshOutcome,
+    keys: usize,
+    detail: String,
+}
+
+/// Sweeps of the upstream agents, so that sessions that waited on the known keys lock for a
+/// refresh can take its result instead of repeating it; a burst of connections that all need a
+/// refresh then queries each agent once
+#[derive(Debug, Default)]
+struct Refreshes {
+    /// Number of refreshes completed
+    generation: u64,
+    /// Number of changes made through the mux to upstream agents' keys
+    changes: u64,
+    /// Tags the latest refresh was scoped to, and its result; cleared when a change is made, as
+    /// the result may be outdated
+    latest: Option<(Option<Vec<String>>, Vec<Identity>)>,
+    /// When the latest refresh completed, or the known keys cache was loaded; cleared when a
+    /// change is made. Used by [`MuxOptions::lazy_connect`].
+    swept_at: Option<Instant>,
+    /// Tags of the latest refresh that every agent in its scope answered, and when it completed;
+    /// cleared when a change is made. Used by [`MuxOptions::cache_ttl`].
+    answered_at: Option<(Option<Vec<String>>, Instant)>,
+    /// Identities each upstream agent listed in its latest successful identity request, by
+    /// socket path
+    listed: HashMap<PathBuf, Vec<Identity>>,
+    /// Number of upstream agents that answered the latest refresh
+    reachable: usize,
+}
+
+/// Only the `request_identities`, `sign`, `add_identity`, `add_identity_constrained`,
+/// `remove_identity`, `lock`, `unlock`, and `extension` commands are implemented.
+/// `remove_identity` goes to the agent that sign requests for the key would go to.
+/// `remove_all_identities`, like `lock` and `unlock`, goes to every upstream agent, skipping
+/// those that fail, and only fails if no agent succeeds.
+/// For `extension`, the mux answers `query` and its own extensions in [`extensions`] itself, and
+/// sends `session-bind@openssh.com` to every upstream agent. Other extensions are forwarded to
+/// one upstream agent after another, and answered with the first response that isn't a failure,
+/// or `SSH_AGENT_FAILURE` if there is none; failures of the mux's own extensions are answered
+/// with `SSH_AGENT_EXTENSION_FAILURE`, so that clients can tell the two apart, and the mux's own
+/// refusals of them with a [`Denied`] reply.
+/// `lock` and `unlock`, like `session-bind@openssh.com`, go to every upstream agent, skipping
+/// those that fail, and only fail if no agent succeeds.
 #[ssh_agent_lib::async_trait]
 impl Session for MuxAgent {
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        let request = request_name(&message);
+        // A panic would otherwise end the client's connection without a response, and may leave
+        // other sessions' shared state half-updated; the panic itself is reported by the hook
+        match CatchUnwind(Box::pin(self.dispatch(message))).await {
+            Some(result) => result,
+            None => {
+                log::error!(
+                    session:% = self.session_id;
+                    "Panicked handling {} request; answering with failure",
+                    request
+                );
+                Err(AgentError::Failure)
+            }
+        }
+    }
+
     async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
-        log::trace!("incoming: request_identities");
+        log::trace!(session:% = self.session_id; "incoming: request_identities");
+        self.check_allowed(Operation::RequestIdentities)?;
+        let generation = self.refresh_generation();
         let mut known_keys = self.known_keys.clone().lock_owned().await;
-        self.refresh_identities(&mut known_keys).await
+        let mut lazy_identities = if self.options.lazy_connect {
+            self.lazy_identities(&known_keys)
+        } else {
+            None
+        };
+        if lazy_identities.is_some() && self.refresh_volatile_agents(&mut known_keys).await {
+            lazy_identities = self.lazy_identities(&known_keys);
+        }
+        let mut identities = match lazy_identities {
+            Some(identities) => identities,
+            None => {
+                self.refresh_identities_once(&mut known_keys, generation)
+                    .await?
+            }
+        };
+        // The default comment fills in empty comments before the prefix is added to every one
+        if let Some(ref template) = self.options.default_comment {
+            for id in identities.iter_mut().filter(|id| id.comment.is_empty()) {
+                let agent = known_keys
+                    .get(&id.pubkey)
+                    .map(|sock_path| self.agent_name(sock_path))
+                    .unwrap_or_default();
+                id.comment = expand_comment_template(template, &agent, &id.pubkey);
+            }
+        }
+        if self.options.annotate_comments {
+            for id in &mut identities {
+                if let Some(sock_path) = known_keys.get(&id.pubkey) {
+                    id.comment = annotate_comment(&id.comment, &self.agent_name(sock_path));
+                }
+            }
+        }
+        if !self.options.comment_prefix.is_empty() {
+            for id in &mut identities {
+                id.comment.insert_str(0, &self.options.comment_prefix);
+            }
+        }
+        Ok(identities)
     }
 
-    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+    async fn sign(&mut self, mut request: SignRequest) -> Result<Signature, AgentError> {
         let fingerprint = request.pubkey.fingerprint(Default::default());
-        log::trace!("incoming: sign({})", &fingerprint);
-
-        if let Some(agent_sock_path) = self.get_agent_sock_for_pubkey(&request.pubkey).await? {
-            log::info!(
-                "Requesting signature with key {} from upstream agent <{}>",
-                &fingerprint,
-                agent_sock_path.display()
-            );
+        log::trace!(
+            session:% = self.session_id, fingerprint:% = fingerprint;
+            "incoming: sign({})",
+            &fingerprint
+        );
+        Metrics::increment(&self.metrics.sign_requests);
+        let mut trace = SignTrace::default();
+        if let Some(denial) = self.sign_denial(&request.pubkey, &fingerprint).await {
+            let e = self.refuse(&format!("to sign with key {}", fingerprint), &denial);
+            request.data.zeroize();
+            Metrics::increment(&self.metrics.sign_failures);
+            trace.steps.push(denial.to_string());
+            self.audit_sign(&fingerprint, &trace, Err(&e));
+            return Err(e);
+        }
 
-            let mut client = self.connect_upstream_agent(&agent_sock_path).await?;
-            timeout(self.agent_timeout, client.sign(request))
-                .await
-                .map_err(|_| {
-                    AgentError::Other(
-                        format!(
-                            "Sign request timed out on upstream agent: {}",
-                            agent_sock_path.display()
-                        )
-                        .into(),
-                    )
-                })?
-        } else {
-            log::error!("No upstream agent found for public key {}", &fingerprint);
-            log::trace!("Known keys:\n{:#?}", self.known_keys);
-            Err(AgentError::Other(
-                format!("No agent found for public key: {}", &fingerprint).into(),
-            ))
+        let result = match self.route_and_sign(&request, &mut trace).await {
+            // The owning agent may have dropped the key between the refresh that located it and
+            // the sign request (e.g. a hardware token being swapped, or the agent restarted);
+            // find its current owner and try once more. Only retry if an owner was recorded for
+            // the key; otherwise the request went to the default agent, which would just be asked
+            // again, as would the agent a key is pinned to. An owner that still lists the key
+            // refused to sign with it (e.g. a denied confirmation), and isn't asked again.
+            Err(e)
+                if (self.options.retry_sign || self.routing_from_cache.load(Ordering::Relaxed))
+                    && is_upstream_failure(&e)
+                    && self.pinned_agent(&request.pubkey).is_none()
+                    && self.known_keys.lock().await.contains_key(&request.pubkey) =>
+            {
+                let owner = self.known_keys.lock().await.get(&request.pubkey).cloned();
+                if self.routing_from_cache.load(Ordering::Relaxed) {
+                    // Routing from the cache may be stale for every key, not just this one
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Upstream agent failed to sign with key {} routed from the known keys \
+                         cache; refreshing identities and retrying",
+                        &fingerprint
+                    );
+                    trace
+                        .steps
+                        .push("retrying after refreshing identities".into());
+                    let mut known_keys = self.known_keys.clone().lock_owned().await;
+                    let _ = self.refresh_identities(&mut known_keys).await?;
+                } else {
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Upstream agent failed to sign with key {}; locating it and retrying",
+                        &fingerprint
+                    );
+                    trace.steps.push("retrying after locating the key".into());
+                    self.relocate_key(&request.pubkey).await;
+                }
+                if self.known_keys.lock().await.get(&request.pubkey) == owner.as_ref() {
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Upstream agent still lists key {}; not retrying",
+                        &fingerprint
+                    );
+                    trace
+                        .steps
+                        .push("not retrying: the agent still lists the key".into());
+                    Err(e)
+                } else {
+                    self.route_and_sign(&request, &mut trace).await
+                }
+            }
+            // The agents the key was routed to are gone (e.g. stopped since the refresh that
+            // found the key on them); a refresh drops their keys and finds where it is now
+            Err(AgentError::Other(e)) if self.options.retry_sign && e.is::<AgentUnreachable>() => {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Couldn't reach upstream agent to sign with key {}: {}; refreshing identities \
+                     and retrying",
+                    &fingerprint,
+                    e
+                );
+                trace
+                    .steps
+                    .push("retrying after refreshing identities".into());
+                {
+                    let mut known_keys = self.known_keys.clone().lock_owned().await;
+                    let _ = self.refresh_identities(&mut known_keys).await?;
+                }
+                self.route_and_sign(&request, &mut trace).await
+            }
+            result => result,
+        };
+        request.data.zeroize();
+        if result.is_err() {
+            Metrics::increment(&self.metrics.sign_failures);
         }
+        self.audit_sign(&fingerprint, &trace, result.as_ref().map(|_| ()));
+        result
     }
 
     async fn extension(&mut self, request: Extension) -> Result<Option<Extension>, AgentError> {
-        log::trace!("incoming: extension({})", request.name);
+        log::trace!(session:% = self.session_id; "incoming: extension({})", request.name);
+        if let Some(denial) = self.operation_denial(Operation::Extension) {
+            return self.refuse_extension(&request, &denial);
+        }
         match request.name.as_str() {
-            "query" => Ok(Some(Extension::new_message(QueryResponse {
-                extensions: ["session-bind@openssh.com"].map(String::from).to_vec(),
+            "query" => {
+                let mut extensions = vec!["session-bind@openssh.com".to_string()];
+                extensions.extend(self.native_extensions());
+                for name in &self.options.extra_extensions {
+                    if !extensions.contains(name) {
+                        extensions.push(name.clone());
+                    }
+                }
+                Ok(Some(Extension::new_message(QueryResponse { extensions })?))
+            }
+            RefreshStatus::NAME => Ok(Some(Extension::new_message(self.refresh_status())?)),
+            ListUpstreams::NAME => Ok(Some(Extension::new_message(self.list_upstreams().await?)?)),
+            MuxInfo::NAME => Ok(Some(Extension::new_message(MuxInfo {
+                name: env!("CARGO_PKG_NAME").into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+                extensions: self.native_extensions(),
             })?)),
+            MuxIdentity::NAME => {
+                let MuxIdentity { chain } = request
+                    .parse_message::<MuxIdentity>()
+                    .map_err(|e| {
+                        log::warn!(
+                            session:% = self.session_id;
+                            "Invalid {} request: {}",
+                            MuxIdentity::NAME,
+                            e
+                        );
+                        AgentError::ExtensionFailure
+                    })?
+                    .expect("extension name already matched");
+                let response = self.answer_mux_identity(chain).await;
+                Ok(Some(Extension::new_message(response)?))
+            }
+            SignCheck::NAME if self.options.sign_check => {
+                let SignCheck { pubkey } = request
+                    .parse_message::<SignCheck>()
+                    .map_err(|e| {
+                        log::warn!(
+                            session:% = self.session_id;
+                            "Invalid {} request: {}",
+                            SignCheck::NAME,
+                            e
+                        );
+                        AgentError::ExtensionFailure
+                    })?
+                    .expect("extension name already matched");
+                Ok(Some(Extension::new_message(
+                    self.sign_check(&pubkey).await,
+                )?))
+            }
+            SelectTags::NAME => {
+                let SelectTags { tags } = request
+                    .parse_message::<SelectTags>()
+                    .map_err(|e| {
+                        log::warn!(
+                            session:% = self.session_id;
+                            "Invalid {} request: {}",
+                            SelectTags::NAME,
+                            e
+                        );
+                        AgentError::ExtensionFailure
+                    })?
+                    .expect("extension name already matched");
+                for tag in &tags {
+                    if !self.agents.iter().any(|a| a.tags.contains(tag)) {
+                        log::warn!(
+                            session:% = self.session_id;
+                            "Client selected tag {:?}, but no upstream agent has it",
+                            tag
+                        );
+                    }
+                }
+                log::debug!(
+                    session:% = self.session_id;
+                    "Client selected upstream agent tags: {:?}",
+                    &tags
+                );
+                self.selected_tags = if tags.is_empty() { None } else { Some(tags) };
+                Ok(None)
+            }
             "session-bind@openssh.com" => {
                 let mut session_bind_suceeded = false;
-                for sock_path in &self.socket_paths {
+                for agent in &self.agents {
+                    if agent.kind == UpstreamKind::GpgAgent {
+                        continue;
+                    }
+                    if !agent.extension_filter.forwards(&request.name) {
+                        log::debug!(
+                            session:% = self.session_id;
+                            "Not forwarding {} to upstream agent <{}>",
+                            request.name,
+                            agent.name
+                        );
+                        continue;
+                    }
+                    let sock_path = &agent.socket_path;
                     // Try extension on upstream agents; discard any upstream failures from agents
                     // that don't support the extension (but the default is Failure if there are no
                     // successful upstream responses)
@@ -80,10 +424,17 @@ impl Session for MuxAgent {
                         Ok(c) => c,
                         Err(_) => continue,
                     };
-                    let result = match timeout(self.agent_timeout, client.extension(request.clone())).await {
+                    let result = match timeout(
+                        self.options.agent_timeout,
+                        client.extension(request.clone()),
+                    )
+                    .await
+                    {
                         Ok(r) => r,
                         Err(_) => {
+                            Metrics::increment(&self.metrics.upstream_timeouts);
                             log::warn!(
+                                session:% = self.session_id;
                                 "Extension request timed out on upstream agent: {}",
                                 sock_path.display()
                             );
@@ -95,14 +446,14 @@ impl Session for MuxAgent {
                         Ok(v) => {
                             session_bind_suceeded = true;
                             if v.is_some() {
-                                log::warn!("session-bind@openssh.com request succeeded on socket <{}>, but an invalid response was received", sock_path.display());
+                                log::warn!(session:% = self.session_id; "session-bind@openssh.com request succeeded on socket <{}>, but an invalid response was received", sock_path.display());
                             }
                         }
                         // Don't propagate upstream lack of extension support
-                        Err(AgentError::Failure) => continue,
+                        Err(e) if is_upstream_failure(&e) => continue,
                         // Report but ignore any unexpected errors
                         Err(e) => {
-                            log::error!("Unexpected error on socket <{}> when requesting session-bind@openssh.com extension: {}", sock_path.display(), e);
+                            log::error!(session:% = self.session_id; "Unexpected error on socket <{}> when requesting session-bind@openssh.com extension: {}", sock_path.display(), e);
                             continue;
                         }
                     }
@@ -110,107 +461,330 @@ impl Session for MuxAgent {
                 if session_bind_suceeded {
                     Ok(None)
                 } else {
-                    Err(AgentError::Failure)
+                    Err(AgentError::ExtensionFailure)
                 }
             }
-            _ => Err(AgentError::Failure),
+            // The mux's own, when disabled, aren't for upstream agents to answer
+            SignCheck::NAME => {
+                let denial =
+                    Denial::new(DenialReason::NotEnabled, "sign-check isn't enabled".into());
+                self.refuse_extension(&request, &denial)
+            }
+            _ => self.forward_extension(&request).await,
         }
     }
 
+    // Passphrases and sign payloads are zeroized once the mux is done with its own copies. The
+    // copies owned by ssh-agent-lib aren't: the `String`/`Vec` each upstream client consumes and
+    // the read and write buffers of both the server and client connections. Its debug logging of
+    // requests also includes lock passphrases, so don't run with `debug` logging while locking.
     async fn lock(&mut self, key: String) -> Result<(), AgentError> {
-        log::trace!("incoming: lock");
-        for sock_path in &self.socket_paths {
-            let mut client = self.connect_upstream_agent(sock_path).await?;
-            timeout(self.agent_timeout, client.lock(key.clone()))
-                .await
-                .map_err(|_| {
-                    AgentError::Other(
-                        format!(
-                            "Lock request timed out on upstream agent: {}",
-                            sock_path.display()
-                        )
-                        .into(),
-                    )
-                })??;
-            log::info!(
-                "Locked upstream agent <{}>",
-                sock_path.display()
-            );
+        log::trace!(session:% = self.session_id; "incoming: lock");
+        let key = Zeroizing::new(key);
+        self.check_allowed(Operation::Lock)?;
+        // An agent that fails to lock doesn't keep the others unlocked
+        let mut locked = false;
+        for agent in self.writable_agents() {
+            let passphrase = agent.lock_passphrase.as_ref().unwrap_or(&key);
+            locked |= self
+                .try_forward_lock(&agent.socket_path, passphrase, true)
+                .await;
+        }
+        if !locked && self.writable_agents().next().is_some() {
+            return Err(AgentError::Failure);
         }
+        *self.lock_passphrase.lock().await = Some(key);
+        self.note_keys_changed();
         Ok(())
     }
 
     async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
-        log::trace!("incoming: unlock");
-        for sock_path in &self.socket_paths {
-            let mut client = self.connect_upstream_agent(sock_path).await?;
-            timeout(self.agent_timeout, client.unlock(key.clone()))
-                .await
-                .map_err(|_| {
-                    AgentError::Other(
-                        format!(
-                            "Unlock request timed out on upstream agent: {}",
-                            sock_path.display()
-                        )
-                        .into(),
-                    )
-                })??;
-            log::info!(
-                "Unlocked upstream agent <{}>",
-                sock_path.display()
+        log::trace!(session:% = self.session_id; "incoming: unlock");
+        let key = Zeroizing::new(key);
+        self.check_allowed(Operation::Unlock)?;
+        // Agents with their own lock passphrase are only unlocked once the client's passphrase is
+        // known to be right: it's the one the mux was locked with, or it unlocks an agent that
+        // shares it. Otherwise any passphrase would unlock them.
+        let mut verified = self.lock_passphrase.lock().await.as_ref() == Some(&key);
+        let (shared, overridden): (Vec<_>, Vec<_>) = self
+            .writable_agents()
+            .partition(|a| a.lock_passphrase.is_none());
+        let mut unlocked = false;
+        for agent in shared {
+            if self.try_forward_lock(&agent.socket_path, &key, false).await {
+                verified = true;
+                unlocked = true;
+            }
+        }
+        if !overridden.is_empty() && !verified {
+            log::warn!(
+                session:% = self.session_id;
+                "Refusing to unlock agents with their own lock passphrase: the passphrase doesn't match the one the mux was locked with"
             );
+            return Err(AgentError::Failure);
+        }
+        for agent in &overridden {
+            if let Some(passphrase) = &agent.lock_passphrase {
+                unlocked |= self
+                    .try_forward_lock(&agent.socket_path, passphrase, false)
+                    .await;
+            }
+        }
+        if !unlocked && self.writable_agents().next().is_some() {
+            return Err(AgentError::Failure);
         }
+        *self.lock_passphrase.lock().await = None;
+        self.note_keys_changed();
         Ok(())
     }
 
-    async fn add_identity(
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        log::trace!(session:% = self.session_id; "incoming: add_identity");
+        self.check_allowed(Operation::AddIdentity)?;
+        self.add_to_targets(AddIdentityConstrained {
+            identity,
+            constraints: vec![],
+        })
+        .await
+    }
+
+    async fn add_identity_constrained(
         &mut self,
-        identity: ssh_agent_lib::proto::AddIdentity,
+        identity: AddIdentityConstrained,
     ) -> Result<(), AgentError> {
-        log::trace!("incoming: add_identity");
+        log::trace!(session:% = self.session_id; "incoming: add_identity_constrained");
+        self.check_allowed(Operation::AddIdentity)?;
+        self.add_to_targets(identity).await
+    }
 
-        if let Some(added_keys_sock) = &self.added_keys_sock {
-            log::info!(
-                "Forwarding add_identity request to upstream agent <{}>",
-                added_keys_sock.display()
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        log::trace!(session:% = self.session_id; "incoming: remove_identity");
+        self.check_allowed(Operation::RemoveIdentity)?;
+        let pubkey = identity.pubkey.clone();
+        let fingerprint = pubkey.fingerprint(Default::default());
+        let Some(sock_path) = self.get_agent_sock_for_pubkey(&pubkey).await? else {
+            log::error!(
+                session:% = self.session_id, fingerprint:% = fingerprint;
+                "No upstream agent found for public key {}",
+                &fingerprint
             );
+            return Err(AgentError::Failure);
+        };
+        if self.is_hidden(&pubkey, &sock_path) {
+            log::warn!(
+                session:% = self.session_id, fingerprint:% = fingerprint,
+                socket:% = sock_path.display();
+                "Refusing to remove key {} hidden by key filters (upstream agent <{}>)",
+                &fingerprint,
+                sock_path.display()
+            );
+            return Err(AgentError::Failure);
+        }
+        if self.upstream_agent(&sock_path).is_some_and(|a| a.read_only) {
+            log::warn!(
+                session:% = self.session_id, fingerprint:% = fingerprint,
+                socket:% = sock_path.display();
+                "Refusing to remove key {} from read-only upstream agent <{}>",
+                &fingerprint,
+                sock_path.display()
+            );
+            return Err(AgentError::Failure);
+        }
+        log::info!(
+            session:% = self.session_id, fingerprint:% = fingerprint,
+            socket:% = sock_path.display();
+            "Removing key {} from upstream agent <{}>",
+            &fingerprint,
+            sock_path.display()
+        );
 
-            let pubkey = pubkey_from_credential(&identity.credential);
-
-            let mut client = self.connect_upstream_agent(added_keys_sock).await?;
-            timeout(self.agent_timeout, client.add_identity(identity))
-                .await
-                .map_err(|_| {
-                    AgentError::Other(
-                        format!(
-                            "Add identity request timed out on upstream agent: {}",
-                            added_keys_sock.display()
-                        )
-                        .into(),
+        let mut client = self.connect_upstream_agent(&sock_path).await?;
+        self.note_keys_changed();
+        timeout(self.options.agent_timeout, client.remove_identity(identity))
+            .await
+            .map_err(|_| {
+                Metrics::increment(&self.metrics.upstream_timeouts);
+                AgentError::Other(
+                    format!(
+                        "Remove identity request timed out on upstream agent: {}",
+                        sock_path.display()
                     )
-                })??;
+                    .into(),
+                )
+            })??;
+        // Another agent may still list the key; the next refresh finds it
+        let mut known_keys = self.known_keys.lock().await;
+        if known_keys.get(&pubkey) == Some(&sock_path) {
+            known_keys.remove(&pubkey);
+        }
+        Ok(())
+    }
 
-            if let Some(pubkey) = pubkey {
-                let fingerprint = pubkey.fingerprint(Default::default());
-                log::debug!(
-                    "Caching added key {} -> <{}>",
-                    &fingerprint,
-                    added_keys_sock.display()
-                );
-                self.known_keys
-                    .lock()
-                    .await
-                    .insert(pubkey, added_keys_sock.clone());
-            }
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        log::trace!(session:% = self.session_id; "incoming: remove_all_identities");
+        self.check_allowed(Operation::RemoveIdentity)?;
+        self.note_keys_changed();
+        // An agent that fails to remove its keys doesn't keep the others' keys in place
+        let mut removed = false;
+        for agent in self.writable_agents() {
+            removed |= self.try_remove_all_identities(&agent.socket_path).await;
+        }
+        // Read-only agents keep their keys
+        let read_only: Vec<_> = self.agents.iter().filter(|a| a.read_only).collect();
+        self.known_keys
+            .lock()
+            .await
+            .retain(|_, sock_path| read_only.iter().any(|a| a.socket_path == *sock_path));
+        if !removed && self.writable_agents().next().is_some() {
+            return Err(AgentError::Failure);
+        }
+        Ok(())
+    }
+}
 
-            Ok(())
-        } else {
-            log::error!("add_identity requested but no added_keys socket configured");
-            Err(AgentError::Failure)
+/// Upstream agents answer a refused request with SSH_AGENT_FAILURE, which the protocol client
+/// reports as an unexpected response
+fn is_upstream_failure(error: &AgentError) -> bool {
+    match error {
+        AgentError::Failure
+        | AgentError::ExtensionFailure
+        | AgentError::Proto(ProtoError::UnexpectedResponse) => true,
+        // A listing agent's failure; the sign retry only asks again if the agent has since
+        // dropped the key, so that a denied prompt isn't shown twice
+        AgentError::Other(e) => e.is::<SignRefused>(),
+        _ => false,
+    }
+}
+
+/// An upstream agent that lists a key refused to sign with it, e.g. because a confirmation
+/// prompt was denied or a smartcard PIN was wrong
+#[derive(Debug)]
+struct SignRefused {
+    agent: String,
+    fingerprint: Fingerprint,
+    reason: AgentError,
+}
+
+impl std::fmt::Display for SignRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upstream agent {} refused to sign with key {}: {}",
+            self.agent, self.fingerprint, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SignRefused {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.reason)
+    }
+}
+
+/// Connecting to an upstream agent failed, e.g. because it was stopped; shown as the reason
+#[derive(Debug)]
+struct AgentUnreachable {
+    reason: AgentError,
+}
+
+impl std::fmt::Display for AgentUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.reason.fmt(f)
+    }
+}
+
+impl std::error::Error for AgentUnreachable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.reason)
+    }
+}
+
+/// The mux's own refusal of a request, shown as `policy denied (<tag>): <detail>`
+#[derive(Debug)]
+struct Denial {
+    reason: DenialReason,
+    detail: String,
+}
+
+impl Denial {
+    fn new(reason: DenialReason, detail: String) -> Self {
+        Self { reason, detail }
+    }
+
+    /// The reply to a request for one of the mux's own extensions refused with this
+    fn reply(&self) -> Denied {
+        Denied {
+            reason: self.reason.tag().into(),
+            detail: self.detail.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Denial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "policy denied ({}): {}", self.reason, self.detail)
+    }
+}
+
+/// Check that key data from an upstream agent is usable before making it routable; fingerprinting
+/// panics on key data that can't be encoded, and a nonsensical key would only fail later at sign
+fn validate_pubkey(pubkey: &PubKeyData) -> Result<(), String> {
+    pubkey
+        .encoded_len()
+        .map_err(|e| format!("key data can't be encoded: {}", e))?;
+    if let PubKeyData::Rsa(rsa) = pubkey {
+        if !rsa
+            .e
+            .as_positive_bytes()
+            .is_some_and(|e| e.iter().any(|b| *b != 0))
+        {
+            return Err("RSA public exponent is not a positive integer".into());
         }
+        let modulus_bits = match rsa.n.as_positive_bytes() {
+            Some([first, rest @ ..]) => rest.len() * 8 + (8 - first.leading_zeros() as usize),
+            _ => 0,
+        };
+        if modulus_bits < MIN_RSA_MODULUS_BITS {
+            return Err(format!(
+                "RSA modulus is {} bits; at least {} are required",
+                modulus_bits, MIN_RSA_MODULUS_BITS
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Expand the `{agent}` and `{fingerprint}` placeholders of a [`MuxOptions::default_comment`]
+/// template
+fn expand_comment_template(template: &str, agent: &str, pubkey: &PubKeyData) -> String {
+    template.replace("{agent}", agent).replace(
+        "{fingerprint}",
+        &pubkey.fingerprint(Default::default()).to_string(),
+    )
+}
+
+/// `comment` with the name of the agent holding its key appended, under
+/// [`MuxOptions::annotate_comments`]
+fn annotate_comment(comment: &str, agent: &str) -> String {
+    match comment {
+        "" => format!("[{}]", agent),
+        _ => format!("{} [{}]", comment, agent),
     }
 }
 
+/// Identities listed by each agent, in the order to offer them to clients: by the agents'
+/// [`UpstreamAgent::offer_rank`], then their configured order. A key listed by several agents is
+/// offered once, with the comment of the agent that ranks first.
+fn offer_order(mut lists: Vec<(&UpstreamAgent, Vec<Identity>)>) -> Vec<Identity> {
+    lists.sort_by_key(|(agent, _)| agent.offer_rank);
+    let mut offered = HashSet::new();
+    lists
+        .into_iter()
+        .flat_map(|(_, identities)| identities)
+        .filter(|id| offered.insert(id.pubkey.clone()))
+        .collect()
+}
+
 fn pubkey_from_credential(credential: &Credential) -> Option<PubKeyData> {
     match credential {
         Credential::Key { privkey, .. } => match PubKeyData::try_from(privkey) {
@@ -224,168 +798,2932 @@ fn pubkey_from_credential(credential: &Credential) -> Option<PubKeyData> {
     }
 }
 
-#[derive(Clone)]
-pub struct MuxAgent {
-    socket_paths: Vec<PathBuf>,
-    added_keys_sock: Option<PathBuf>,
-    known_keys: KnownPubKeys,
-    agent_timeout: Duration,
+/// Implementation of an upstream agent, for working around its quirks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpstreamKind {
+    /// An agent that follows the protocol, like OpenSSH's `ssh-agent`
+    #[default]
+    Standard,
+    /// gpg-agent's SSH agent socket. Compared to [`UpstreamKind::Standard`]:
+    /// - identities are requested on each connection before a sign request, since gpg-agent
+    ///   refuses to sign with keys that haven't been listed on the connection
+    /// - extension requests (e.g. `session-bind@openssh.com`) aren't forwarded to it, since it
+    ///   doesn't support them, and it isn't probed for loops back to the mux
+    /// - connecting to it is allowed to take at least 15 seconds, because it is often started on
+    ///   demand
+    GpgAgent,
+    /// An agent run by the mux itself, that holds keys added through the mux in its memory, so
+    /// that the mux can be used without any other agent. Its keys are lost when the mux stops or
+    /// reloads its configuration; it doesn't support certificates, key constraints, legacy
+    /// SHA-1 `ssh-rsa` signatures, or extensions. Create one with [`UpstreamAgent::builtin`].
+    Builtin,
 }
 
-impl MuxAgent {
-    /// Run a MuxAgent, listening for SSH agent protocol requests on `listen_sock`, forwarding
-    /// requests to the specified paths in `agent_socks`
-    pub async fn run<I, P>(
-        listen_sock: impl AsRef<Path>,
-        agent_socks: I,
-        added_keys_sock: Option<PathBuf>,
-        agent_timeout: Duration,
-    ) -> Result<(), AgentError>
-    where
-        I: IntoIterator<Item = P>,
-        P: AsRef<Path>,
-    {
-        let listen_sock = listen_sock.as_ref();
-        let socket_paths: Vec<_> = agent_socks
-            .into_iter()
-            .map(|p| p.as_ref().to_path_buf())
-            .collect();
-        if socket_paths.is_empty() {
-            log::warn!("Mux agent running but no upstream agents configured");
-        }
-        log::info!(
-            "Starting agent for {} upstream agents; listening on <{}>",
-            socket_paths.len(),
-            listen_sock.display()
-        );
-        log::debug!("Upstream agent sockets: {:?}", &socket_paths);
-        if let Some(ref added_keys) = added_keys_sock {
-            log::info!("add_identity requests will be forwarded to <{}>", added_keys.display());
-        }
+/// How an upstream agent is reached, from the form of its [`UpstreamAgent::socket_path`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamTransport<'a> {
+    /// The Unix socket at this path
+    Unix(&'a Path),
+    /// TCP, to this `host:port`
+    Tcp(&'a str),
+}
 
-        let listen_sock = match SelfDeletingUnixListener::bind(listen_sock) {
-            Ok(s) => s,
-            err => {
-                log::error!(
-                    "Failed to open listening socket at {}",
-                    listen_sock.display()
-                );
-                err?
-            }
+impl<'a> UpstreamTransport<'a> {
+    pub fn of(socket_path: &'a Path) -> Self {
+        let Some(path) = socket_path.to_str() else {
+            return Self::Unix(socket_path);
         };
-        let this = Self {
-            socket_paths,
-            added_keys_sock,
-            known_keys: Default::default(),
-            agent_timeout,
+        if let Some(address) = path.strip_prefix(TCP_SOCKET_PREFIX) {
+            Self::Tcp(address)
+        } else {
+            Self::Unix(socket_path)
+        }
+    }
+}
+
+/// Which of an upstream agent's keys are exposed through the mux
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyFilter {
+    /// Expose every key
+    #[default]
+    All,
+    /// Expose only keys with these fingerprints
+    Only(Vec<Fingerprint>),
+    /// Expose every key except those with these fingerprints
+    Except(Vec<Fingerprint>),
+}
+
+impl KeyFilter {
+    /// Whether `pubkey` is exposed
+    pub fn exposes(&self, pubkey: &PubKeyData) -> bool {
+        let matches = |fingerprints: &[Fingerprint]| {
+            fingerprints
+                .iter()
+                .any(|f| pubkey.fingerprint(f.algorithm()) == *f)
         };
-        agent::listen(listen_sock, this).await
+        match self {
+            KeyFilter::All => true,
+            KeyFilter::Only(fingerprints) => matches(fingerprints),
+            KeyFilter::Except(fingerprints) => !matches(fingerprints),
+        }
     }
+}
 
-    async fn connect_upstream_agent(
-        &self,
-        sock_path: impl AsRef<Path>,
-    ) -> Result<Box<dyn Session>, AgentError> {
-        let sock_path = sock_path.as_ref();
-        let stream = timeout(self.agent_timeout, tokio::net::UnixStream::connect(sock_path))
-            .await
-            .map_err(|_| {
-                AgentError::Other(
-                    format!(
-                        "Connection to upstream agent timed out: {}",
-                        sock_path.display()
-                    )
-                    .into(),
-                )
-            })?
-            .map_err(AgentError::IO)?;
-        let client = client::connect(stream.into_std()?.into()).map_err(|e| {
-            AgentError::Other(
-                format!(
-                    "Failed to connect to agent at {}: {}",
-                    sock_path.display(),
-                    e
-                )
-                .into(),
-            )
+/// Which keys are exposed through the mux, by the comment their upstream agent lists them with
+#[derive(Clone, Debug)]
+pub struct CommentFilter {
+    /// Matched anywhere in the comment, unless anchored with `^` and `$`
+    pub pattern: Regex,
+    /// Expose the keys whose comment doesn't match, instead of those whose comment does
+    pub invert: bool,
+}
+
+impl CommentFilter {
+    /// Whether a key listed with `comment` is exposed
+    pub fn exposes(&self, comment: &str) -> bool {
+        self.pattern.is_match(comment) != self.invert
+    }
+}
+
+impl PartialEq for CommentFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern.as_str() == other.pattern.as_str() && self.invert == other.invert
+    }
+}
+
+impl Eq for CommentFilter {}
+
+/// Which extension requests are forwarded to an upstream agent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExtensionFilter {
+    /// Forward every extension
+    #[default]
+    All,
+    /// Forward only extensions with these names
+    Only(Vec<String>),
+    /// Forward every extension except those with these names
+    Except(Vec<String>),
+}
+
+impl ExtensionFilter {
+    /// Whether the extension called `name` is forwarded
+    pub fn forwards(&self, name: &str) -> bool {
+        match self {
+            ExtensionFilter::All => true,
+            ExtensionFilter::Only(names) => names.iter().any(|n| n == name),
+            ExtensionFilter::Except(names) => !names.iter().any(|n| n == name),
+        }
+    }
+}
+
+/// An upstream agent whose keys are multiplexed
+#[derive(Clone, PartialEq)]
+pub struct UpstreamAgent {
+    /// Name used to identify the agent in logs and configuration
+    pub name: String,
+    /// Socket path of the agent, or the `host:port` it listens on for TCP connections after
+    /// [`TCP_SOCKET_PREFIX`], for agents that only speak the protocol over TCP (e.g. a remote agent
+    /// tunneled to a local port); see [`UpstreamTransport`]. The protocol isn't encrypted, so over
+    /// TCP anything on the path to the agent can read and replay its requests.
+    pub socket_path: PathBuf,
+    /// Tags that clients can select with the `select-tags@ssh-agent-mux` extension
+    pub tags: Vec<String>,
+    /// Passphrase to lock and unlock the agent with, instead of the one sent by the client
+    pub lock_passphrase: Option<Zeroizing<String>>,
+    /// Implementation of the agent
+    pub kind: UpstreamKind,
+    /// How long to keep retrying to connect the first time the agent is used, for agents whose
+    /// socket takes a moment to appear (e.g. that of a just-plugged-in hardware token)
+    pub startup_grace: Duration,
+    /// Which of the agent's keys are listed and signed with; others are treated as absent
+    pub key_filter: KeyFilter,
+    /// Ask the agent for its keys on every identity request, even when the mux would otherwise
+    /// answer from the keys it knows (see [`MuxOptions::lazy_connect`]), for agents whose keys
+    /// change often (e.g. a hardware token that's often unplugged)
+    pub always_refresh: bool,
+    /// Which extension requests (e.g. `session-bind@openssh.com`) are forwarded to the agent
+    pub extension_filter: ExtensionFilter,
+    /// Where the agent's keys are offered to clients: those of agents with a lower rank are
+    /// listed first, and agents of equal rank keep their configured order. It doesn't affect
+    /// which agent signs with a key.
+    pub offer_rank: i32,
+    /// Send the agent one request at a time, for agents that prompt for a PIN or lock up when
+    /// several clients use them at once (e.g. a PIV agent for a hardware token). A request waits
+    /// for the one before it to finish, including any prompt, before it even connects, so
+    /// concurrent clients are answered one after the other.
+    pub serialize: bool,
+    /// Program and arguments to start the agent with when its socket can't be connected to, e.g.
+    /// `["ssh-agent", "-D", "-a", "/run/user/1000/ssh-agent.sock"]`; empty to never start it. The
+    /// agent must stay in the foreground, so that the mux can tell whether it's still running, and
+    /// stop it when the mux stops. Connecting waits up to [`MuxOptions::agent_timeout`] for the
+    /// started agent to listen; an agent that keeps stopping is started again less and less
+    /// often, up to once a minute.
+    pub command: Vec<String>,
+    /// Environment variables to start the agent with, on top of the mux's own, e.g. `SSH_ASKPASS`
+    /// for a PIN prompt; the agent is restarted, like for a changed `command`, when they change
+    pub env: BTreeMap<String, String>,
+    /// Ask the user to approve each sign request before forwarding it to the agent, with
+    /// [`MuxOptions::confirm_command`]
+    pub confirm: bool,
+    /// Keep the agent's keys as they are: it isn't locked or unlocked with the mux, keys aren't
+    /// added to it or removed from it, and it's only asked to list its keys and sign
+    pub read_only: bool,
+}
+
+impl std::fmt::Debug for UpstreamAgent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamAgent")
+            .field("name", &self.name)
+            .field("socket_path", &self.socket_path)
+            .field("tags", &self.tags)
+            .field("kind", &self.kind)
+            .field("startup_grace", &self.startup_grace)
+            .field("key_filter", &self.key_filter)
+            .field("always_refresh", &self.always_refresh)
+            .field("extension_filter", &self.extension_filter)
+            .field("offer_rank", &self.offer_rank)
+            .field("serialize", &self.serialize)
+            .field("command", &self.command)
+            // Values may be secrets, e.g. a PIN for a helper
+            .field("env", &self.env.keys().collect::<Vec<_>>())
+            .field("confirm", &self.confirm)
+            .field("read_only", &self.read_only)
+            .field(
+                "lock_passphrase",
+                &self.lock_passphrase.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl UpstreamAgent {
+    pub fn new(name: impl Into<String>, socket_path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            socket_path: socket_path.into(),
+            tags: Vec::new(),
+            lock_passphrase: None,
+            kind: UpstreamKind::Standard,
+            startup_grace: Duration::ZERO,
+            key_filter: KeyFilter::All,
+            always_refresh: false,
+            extension_filter: ExtensionFilter::All,
+            offer_rank: 0,
+            serialize: false,
+            command: Vec::new(),
+            env: BTreeMap::new(),
+            confirm: false,
+            read_only: false,
+        }
+    }
+
+    /// How the agent is reached, from the form of its `socket_path`
+    pub fn transport(&self) -> UpstreamTransport<'_> {
+        UpstreamTransport::of(&self.socket_path)
+    }
+
+    /// An agent of [`UpstreamKind::Builtin`]; it has no socket, so its `socket_path` is only a
+    /// unique name for it, `builtin:<name>`
+    pub fn builtin(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            kind: UpstreamKind::Builtin,
+            ..Self::new(name.clone(), format!("builtin:{}", name))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MuxAgent {
+    agents: Vec<UpstreamAgent>,
+    added_keys_socks: Vec<PathBuf>,
+    known_keys: KnownPubKeys,
+    metrics: Arc<Metrics>,
+    options: MuxOptions,
+    /// Tags selected by the client of this session; `None` means all agents are in scope
+    selected_tags: Option<Vec<String>>,
+    /// Passphrase the mux was last locked with, shared by all sessions
+    lock_passphrase: Arc<Mutex<Option<Zeroizing<String>>>>,
+    /// Known keys have come from the cache and not yet been refreshed, so are only hints; a failed
+    /// sign with them is always retried after a refresh
+    routing_from_cache: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>,
+    session_id: SessionId,
+    /// Socket paths of agents that have been connected to since startup
+    used_agents: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    refreshes: Arc<std::sync::Mutex<Refreshes>>,
+    /// Outcomes of the latest identity request to each upstream agent, by socket path
+    agent_outcomes: Arc<std::sync::Mutex<HashMap<PathBuf, AgentOutcome>>>,
+    /// Keys added through the mux since startup
+    added_keys: Arc<std::sync::Mutex<HashMap<PubKeyData, AddedKey>>>,
+    /// Key stores of the builtin upstream agents, by socket path
+    builtin_agents: Arc<HashMap<PathBuf, BuiltinAgent>>,
+    /// Idle upstream agent connections, under [`MuxOptions::reuse_connections`]
+    connection_pool: Arc<ConnectionPool>,
+    /// Held by the request in flight to each agent under [`UpstreamAgent::serialize`], by socket
+    /// path
+    agent_turns: Arc<HashMap<PathBuf, Arc<Mutex<()>>>>,
+    /// Upstream agents started with their [`UpstreamAgent::command`]; only a running mux starts
+    /// them, not e.g. [`MuxAgent::route`]
+    spawned_agents: Option<SpawnedAgents>,
+    /// Under [`MuxOptions::once`], handed to the only session; the sender closes once that
+    /// session and every clone of it are dropped, i.e. the connection has been served
+    once_served: Option<Arc<oneshot::Sender<()>>>,
+    /// Client process of this session, for [`MuxOptions::policy`]
+    peer: Option<Peer>,
+    /// Identifies this mux to the others in a chain, to find upstream agents that lead back to it
+    instance_id: InstanceId,
+    /// Whether each upstream agent probed with [`MuxIdentity`] leads back to this mux through
+    /// other muxes, by socket path; those that do aren't connected to again
+    loop_checks: Arc<std::sync::Mutex<HashMap<PathBuf, bool>>>,
+}
+
+/// Where the mux sends a request to sign with a key, as found by [`MuxAgent::route`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// The named upstream agent lists the key
+    Agent(String),
+    /// No upstream agent lists the key, so the named default agent is asked
+    DefaultAgent(String),
+    /// The key would go to the named agent, but key filters hide it, so signing is refused
+    Hidden(String),
+    /// The named agents all list the key, and [`DuplicateKeyPolicy::Error`] refuses to sign with
+    /// it
+    Duplicate(Vec<String>),
+    /// No upstream agent lists the key, and there's no default agent
+    Unlisted,
+}
+
+/// The keys the upstream agents list, as found by [`MuxAgent::inventory`]
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    /// Each key listed by each agent, in configured agent order
+    pub keys: Vec<InventoryKey>,
+    /// Names of the agents that couldn't be asked for their keys
+    pub unreachable: Vec<String>,
+}
+
+/// A key listed by an upstream agent
+#[derive(Clone, Debug)]
+pub struct InventoryKey {
+    /// Name of the agent listing the key
+    pub agent: String,
+    pub identity: Identity,
+    /// Whether the mux sends requests to sign with the key to this agent; when several agents
+    /// list a key, only one of them gets them
+    pub routed: bool,
+}
+
+/// How an upstream agent answered a request for its identities, as found by
+/// [`MuxAgent::check_upstreams`]
+#[derive(Clone, Debug)]
+pub struct UpstreamCheck {
+    pub name: String,
+    pub socket_path: PathBuf,
+    pub outcome: RefreshOutcome,
+    /// Number of keys the agent listed, after key filters
+    pub keys: usize,
+    /// Reason for a failure, or empty
+    pub detail: String,
+    /// Time taken to connect to the agent and get its answer, or to give up
+    pub latency: Duration,
+}
+
+/// A category of client request, for [`MuxOptions::allowed_operations`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    RequestIdentities,
+    Sign,
+    AddIdentity,
+    RemoveIdentity,
+    Lock,
+    Unlock,
+    /// Every extension, including the mux's own
+    Extension,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 7] = [
+        Operation::RequestIdentities,
+        Operation::Sign,
+        Operation::AddIdentity,
+        Operation::RemoveIdentity,
+        Operation::Lock,
+        Operation::Unlock,
+        Operation::Extension,
+    ];
+}
+
+/// What to do when asked to add a key that the `add_identity` target agent already holds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddIfPresent {
+    /// Add it again, replacing the existing key and its constraints
+    #[default]
+    Replace,
+    /// Leave the existing key as it is, and report success
+    Skip,
+    /// Leave the existing key as it is, and report failure
+    Error,
+}
+
+/// Which upstream agent signs with a key that several of them list
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// The last configured agent that lists it, then the others from the last when it fails
+    #[default]
+    LastWins,
+    /// The first configured agent that lists it, then the others from the first when it fails
+    FirstWins,
+    /// None: the mux refuses to sign with it, logging an error
+    Error,
+}
+
+impl DuplicateKeyPolicy {
+    /// Whether the agent configured at `slot` routes a key instead of the one at `owner_slot`
+    fn prefers(self, slot: usize, owner_slot: usize) -> bool {
+        match self {
+            DuplicateKeyPolicy::LastWins => slot > owner_slot,
+            DuplicateKeyPolicy::FirstWins | DuplicateKeyPolicy::Error => slot < owner_slot,
+        }
+    }
+}
+
+/// Which keys the mux refuses to sign with unless they were added through it with a lifetime or
+/// confirmation constraint
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RequireConstraints {
+    /// Sign with any key
+    #[default]
+    Off,
+    /// Refuse keys added through the mux without a constraint; keys it didn't see added can
+    /// still be signed with
+    AddedKeys,
+    /// Also refuse keys the mux didn't see added, e.g. those added to upstream agents directly or
+    /// before the mux started
+    AllKeys,
+}
+
+/// What the mux records of each sign request in the audit log, under [`AUDIT_LOG_TARGET`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuditVerbosity {
+    /// Record nothing
+    #[default]
+    Off,
+    /// Record the key and the outcome: the agent that signed, or the error
+    Outcome,
+    /// Also record the agents that listed the key, each agent asked to sign and its answer, and
+    /// any fallback to the default agent or retry
+    Routing,
+}
+
+/// Tunable behavior of a [`MuxAgent`]
+#[derive(Clone, Debug)]
+pub struct MuxOptions {
+    /// Timeout for each operation on an upstream agent
+    pub agent_timeout: Duration,
+    /// Retry a sign request once, after finding which agent now holds the key, if the owning
+    /// agent can't be reached, or fails it and no longer lists the key
+    pub retry_sign: bool,
+    /// Refresh identities when asked to sign with a key no upstream agent is known to have;
+    /// otherwise such requests fail (or go to the default agent) without querying any agent
+    pub refresh_on_sign_miss: bool,
+    /// After a refresh that every upstream agent answered, how long to answer sign requests for
+    /// keys no agent listed without refreshing again; zero refreshes on every such request.
+    /// Identity requests still refresh.
+    pub cache_ttl: Duration,
+    /// Serve activity counters over HTTP at `/metrics` on this address, with `/healthz` and
+    /// `/readyz` probes (requires the `http-metrics` feature)
+    pub metrics_http: Option<SocketAddr>,
+    /// Upstream agent socket to send sign requests to when no upstream agent lists the key
+    pub default_agent_sock: Option<PathBuf>,
+    /// When no upstream agent lists a key to sign with, and there's no default agent, ask every
+    /// agent to sign with it in turn, for agents that sign with keys they don't list (e.g. some
+    /// smartcard middleware). Off by default, as it sends the data to be signed to agents that
+    /// may not be meant to see it.
+    pub sign_fallback_broadcast: bool,
+    /// Upstream agent sockets to send sign requests for keys with these fingerprints to, whichever
+    /// agents list them, without falling back to other agents; the first match applies. A route
+    /// to an agent outside a session's selected tags is ignored.
+    pub key_routes: Vec<(Fingerprint, PathBuf)>,
+    /// File to load known keys from at startup, and save them to at shutdown
+    pub known_keys_cache: Option<PathBuf>,
+    /// Ignore a known keys cache written longer ago than this at startup, as its keys may have
+    /// moved between agents since
+    pub known_keys_cache_max_age: Duration,
+    /// Extension names to advertise in `query` responses, in addition to the built-in ones
+    pub extra_extensions: Vec<String>,
+    /// What to do when asked to add a key that the `add_identity` target already holds
+    pub add_if_present: AddIfPresent,
+    /// Add each key to every `add_identity` target, instead of the first that accepts it; adding
+    /// succeeds if any of them accepts it
+    pub add_to_all: bool,
+    /// Which agent signs with a key listed by several of them
+    pub duplicate_key_policy: DuplicateKeyPolicy,
+    /// Timeout for forwarding a key to the `add_identity` target, instead of `agent_timeout`, as
+    /// adding a large key or one on a smartcard can take longer than other operations
+    pub add_timeout: Option<Duration>,
+    /// Times to retry forwarding a key that timed out, each on a new connection
+    pub add_retries: u32,
+    /// Which keys clients can see and sign with, on top of each agent's [`KeyFilter`]
+    pub visible_keys: KeyFilter,
+    /// Types of the keys clients can see and sign with (e.g. [`Algorithm::Ed25519`]), for
+    /// servers that reject some of them; empty for keys of every type
+    pub allowed_key_types: Vec<Algorithm>,
+    /// Answer identity requests with the known keys, from the cache or an earlier refresh,
+    /// instead of connecting to every upstream agent; identities are refreshed only when no keys
+    /// are known, after a change through the mux, when signing with an unknown key, or every few
+    /// minutes. Keys added to upstream agents outside the mux aren't listed until then.
+    pub lazy_connect: bool,
+    /// Keep connections to upstream agents open after a request and send later requests on them,
+    /// reconnecting when an agent closes one, instead of connecting for every request. A connection
+    /// that carried an extension isn't reused, since some agents tie state to it.
+    pub reuse_connections: bool,
+    /// Answer the [`SignCheck`] debugging extension, which makes every upstream agent holding a
+    /// key sign with it
+    pub sign_check: bool,
+    /// Prepended to the comment of every identity listed to clients, to tell the mux's keys apart
+    /// from other agents'
+    pub comment_prefix: String,
+    /// Comment for identities listed without one, which may contain the `{agent}` (name of the
+    /// agent holding the key) and `{fingerprint}` placeholders; applied before `comment_prefix`
+    pub default_comment: Option<String>,
+    /// Append the name of the agent holding each key to the comment of every identity listed to
+    /// clients, e.g. `user@host [yubikey]`; applied after `default_comment` and before
+    /// `comment_prefix`. Only listed comments change, not how keys are routed.
+    pub annotate_comments: bool,
+    /// Which keys clients can see, by their upstream comment, before `default_comment` and the
+    /// other changes to it; `None` for every key. Sign requests for a hidden key fail, as the mux
+    /// only routes keys it saw listed with a matching comment, or loaded from a known keys cache
+    /// written under the same filter; a sign request for a key no agent lists still goes to the
+    /// default agent, but isn't broadcast.
+    pub comment_filter: Option<CommentFilter>,
+    /// Refresh identities from every upstream agent at this interval, besides when clients need
+    /// them. While no agent is reachable, the interval doubles after each refresh, up to 10
+    /// minutes (or the interval, if longer).
+    pub background_refresh: Option<Duration>,
+    /// Client requests to handle; others fail without reaching any upstream agent
+    pub allowed_operations: Vec<Operation>,
+    /// What to record of each sign request in the audit log; never the data signed or the
+    /// signature
+    pub audit: AuditVerbosity,
+    /// Which keys to refuse to sign with for lacking a lifetime or confirmation constraint
+    pub require_constraints: RequireConstraints,
+    /// Ask the user to approve every sign request before forwarding it, as if every agent had
+    /// [`UpstreamAgent::confirm`]
+    pub confirm_sign: bool,
+    /// Program and arguments to ask the user to approve a sign request with, which is run with a
+    /// prompt naming the key and agent as its last argument and `SSH_ASKPASS_PROMPT=confirm`, as
+    /// ssh-agent runs `ssh-askpass` for keys added with `ssh-add -c`; the request is forwarded if
+    /// it exits successfully. This only guards requests through the mux: clients that reach an
+    /// upstream agent's own socket aren't asked.
+    pub confirm_command: Vec<String>,
+    /// How long to wait for the user to answer [`confirm_command`](Self::confirm_command) before
+    /// refusing the request
+    pub confirm_timeout: Duration,
+    /// Accept a single client connection, and stop once it closes, e.g. to serve a single `ssh`
+    /// command
+    pub once: bool,
+    /// Embedder's policy for allowing sign and add requests, on top of the other options
+    pub policy: Arc<dyn RequestPolicy>,
+    /// Keep what the mux learns of the upstream agents' keys here, and start from what an earlier
+    /// mux kept, if it had exactly the same upstream agents, key filters and
+    /// [`duplicate_key_policy`](Self::duplicate_key_policy); e.g. to pass to every mux run across
+    /// configuration reloads, so that a reload that doesn't change them doesn't make the new mux
+    /// ask every agent for its keys again
+    pub identity_cache: Option<IdentityCache>,
+    /// Keep the upstream agents the mux starts (see [`UpstreamAgent::command`]) here; e.g. to pass
+    /// to every mux run across configuration reloads, so that they keep running. Without it, they
+    /// are stopped once the mux stops.
+    pub spawned_agents: Option<SpawnedAgents>,
+    /// Accept connections from processes of any user, instead of closing those from users other
+    /// than the mux's own and root, as ssh-agent does
+    pub allow_any_peer: bool,
+    /// Permissions of the listening socket file, e.g. `0o660` to let a group use the mux (which
+    /// also needs [`allow_any_peer`](Self::allow_any_peer))
+    pub listen_mode: u32,
+}
+
+/// Where a mux keeps the keys it knows, when and how it last refreshed them, and each agent's
+/// latest outcome, for a later mux with the same upstream agents and key filters; see
+/// [`MuxOptions::identity_cache`]
+#[derive(Clone, Default)]
+pub struct IdentityCache(Arc<std::sync::Mutex<Option<CachedIdentities>>>);
+
+impl std::fmt::Debug for IdentityCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IdentityCache")
+    }
+}
+
+/// The shared state of the mux that last used an [`IdentityCache`]
+struct CachedIdentities {
+    agents: Vec<UpstreamAgent>,
+    key_options: KeyOptions,
+    known_keys: KnownPubKeys,
+    routing_from_cache: Arc<AtomicBool>,
+    refreshes: Arc<std::sync::Mutex<Refreshes>>,
+    agent_outcomes: Arc<std::sync::Mutex<HashMap<PathBuf, AgentOutcome>>>,
+}
+
+/// The options that decide which keys a mux knows and which agent each is routed to, so that a
+/// mux with different ones doesn't start from the keys an earlier mux kept
+#[derive(Debug, PartialEq)]
+struct KeyOptions {
+    duplicate_key_policy: DuplicateKeyPolicy,
+    visible_keys: KeyFilter,
+    allowed_key_types: Vec<Algorithm>,
+    comment_filter: Option<CommentFilter>,
+}
+
+impl KeyOptions {
+    fn of(options: &MuxOptions) -> Self {
+        Self {
+            duplicate_key_policy: options.duplicate_key_policy,
+            visible_keys: options.visible_keys.clone(),
+            allowed_key_types: options.allowed_key_types.clone(),
+            comment_filter: options.comment_filter.clone(),
+        }
+    }
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        Self {
+            agent_timeout: Duration::from_secs(5),
+            retry_sign: true,
+            refresh_on_sign_miss: true,
+            cache_ttl: Duration::ZERO,
+            metrics_http: None,
+            default_agent_sock: None,
+            sign_fallback_broadcast: false,
+            key_routes: Vec::new(),
+            known_keys_cache: None,
+            known_keys_cache_max_age: Duration::from_secs(300),
+            extra_extensions: Vec::new(),
+            add_if_present: AddIfPresent::Replace,
+            add_to_all: false,
+            duplicate_key_policy: DuplicateKeyPolicy::LastWins,
+            add_timeout: None,
+            add_retries: 0,
+            visible_keys: KeyFilter::All,
+            allowed_key_types: Vec::new(),
+            lazy_connect: false,
+            reuse_connections: false,
+            sign_check: false,
+            comment_prefix: String::new(),
+            default_comment: None,
+            annotate_comments: false,
+            comment_filter: None,
+            background_refresh: None,
+            allowed_operations: Operation::ALL.to_vec(),
+            audit: AuditVerbosity::Off,
+            require_constraints: RequireConstraints::Off,
+            confirm_sign: false,
+            confirm_command: Vec::new(),
+            confirm_timeout: Duration::from_secs(30),
+            once: false,
+            policy: Arc::new(policy::AllowAll),
+            identity_cache: None,
+            spawned_agents: None,
+            allow_any_peer: false,
+            listen_mode: 0o600,
+        }
+    }
+}
+
+impl MuxAgent {
+    /// Handle `message` with the matching [`Session`] method, as [`Session::handle`] does by
+    /// default
+    async fn dispatch(&mut self, message: Request) -> Result<Response, AgentError> {
+        match message {
+            Request::RequestIdentities => {
+                return Ok(Response::IdentitiesAnswer(self.request_identities().await?))
+            }
+            Request::SignRequest(request) => {
+                return Ok(Response::SignResponse(self.sign(request).await?))
+            }
+            Request::AddIdentity(identity) => self.add_identity(identity).await?,
+            Request::RemoveIdentity(identity) => self.remove_identity(identity).await?,
+            Request::RemoveAllIdentities => self.remove_all_identities().await?,
+            Request::AddSmartcardKey(key) => self.add_smartcard_key(key).await?,
+            Request::RemoveSmartcardKey(key) => self.remove_smartcard_key(key).await?,
+            Request::Lock(key) => self.lock(key).await?,
+            Request::Unlock(key) => self.unlock(key).await?,
+            Request::AddIdConstrained(identity) => self.add_identity_constrained(identity).await?,
+            Request::AddSmartcardKeyConstrained(key) => {
+                self.add_smartcard_key_constrained(key).await?
+            }
+            Request::Extension(extension) => {
+                return match self.extension(extension).await? {
+                    Some(response) => Ok(Response::ExtensionResponse(response)),
+                    None => Ok(Response::Success),
+                }
+            }
+        }
+        Ok(Response::Success)
+    }
+
+    fn new(
+        agents: Vec<UpstreamAgent>,
+        added_keys_socks: Vec<PathBuf>,
+        options: MuxOptions,
+    ) -> Self {
+        let builtin_agents = agents
+            .iter()
+            .filter(|a| a.kind == UpstreamKind::Builtin)
+            .map(|a| (a.socket_path.clone(), BuiltinAgent::default()))
+            .collect();
+        let agent_turns = agents
+            .iter()
+            .filter(|a| a.serialize)
+            .map(|a| (a.socket_path.clone(), Default::default()))
+            .collect();
+        Self {
+            agents,
+            added_keys_socks,
+            known_keys: Default::default(),
+            metrics: Default::default(),
+            options,
+            selected_tags: None,
+            lock_passphrase: Default::default(),
+            routing_from_cache: Default::default(),
+            clock: Arc::new(SystemClock),
+            session_id: Default::default(),
+            used_agents: Default::default(),
+            refreshes: Default::default(),
+            agent_outcomes: Default::default(),
+            added_keys: Default::default(),
+            builtin_agents: Arc::new(builtin_agents),
+            connection_pool: Default::default(),
+            agent_turns: Arc::new(agent_turns),
+            spawned_agents: None,
+            once_served: None,
+            peer: None,
+            instance_id: InstanceId::new(),
+            loop_checks: Default::default(),
+        }
+    }
+
+    /// Where a freshly started mux would send a request to sign with `pubkey`, found by listing
+    /// the identities of the upstream `agents` just as it would, without listening for clients
+    pub async fn route(
+        agents: impl IntoIterator<Item = UpstreamAgent>,
+        options: MuxOptions,
+        pubkey: &PubKeyData,
+    ) -> Result<Route, AgentError> {
+        let mut this = Self::new(agents.into_iter().collect(), vec![], options);
+        {
+            let mut known_keys = this.known_keys.clone().lock_owned().await;
+            this.refresh_identities(&mut known_keys).await?;
+        }
+        // Already refreshed
+        this.options.refresh_on_sign_miss = false;
+        let Some(sock_path) = this.get_agent_sock_for_pubkey(pubkey).await? else {
+            return Ok(Route::Unlisted);
+        };
+        let name = this.agent_name(&sock_path);
+        Ok(if this.is_hidden(pubkey, &sock_path) {
+            Route::Hidden(name)
+        } else if this.pinned_agent(pubkey).is_some() {
+            Route::Agent(name)
+        } else if let Some(listing) = this.refused_duplicate(pubkey) {
+            Route::Duplicate(listing)
+        } else if this.known_keys.lock().await.contains_key(pubkey) {
+            Route::Agent(name)
+        } else {
+            Route::DefaultAgent(name)
+        })
+    }
+
+    /// Every key the upstream `agents` list, after key filters, and which of them the mux would
+    /// route sign requests for each key to
+    pub async fn inventory(
+        agents: impl IntoIterator<Item = UpstreamAgent>,
+        options: MuxOptions,
+    ) -> Result<Inventory, AgentError> {
+        let this = Self::new(agents.into_iter().collect(), vec![], options);
+        let mut known_keys = this.known_keys.clone().lock_owned().await;
+        this.refresh_identities(&mut known_keys).await?;
+
+        let refreshes = this.refreshes();
+        let mut inventory = Inventory {
+            keys: vec![],
+            unreachable: vec![],
+        };
+        for agent in &this.agents {
+            let Some(listed) = refreshes.listed.get(&agent.socket_path) else {
+                inventory.unreachable.push(agent.name.clone());
+                continue;
+            };
+            inventory
+                .keys
+                .extend(listed.iter().map(|identity| InventoryKey {
+                    agent: agent.name.clone(),
+                    identity: identity.clone(),
+                    routed: known_keys.get(&identity.pubkey) == Some(&agent.socket_path),
+                }));
+        }
+        Ok(inventory)
+    }
+
+    /// Ask each of the upstream `agents` for its identities, all at once, and report how and how
+    /// quickly each answered, in configured order
+    pub async fn check_upstreams(
+        agents: impl IntoIterator<Item = UpstreamAgent>,
+        options: MuxOptions,
+    ) -> Vec<UpstreamCheck> {
+        let this = Self::new(agents.into_iter().collect(), vec![], options);
+        let mut checks = tokio::task::JoinSet::new();
+        for (slot, agent) in this.agents.iter().enumerate() {
+            let (this, agent) = (this.clone(), agent.clone());
+            checks.spawn(async move {
+                let started = this.clock.now();
+                this.query_identities(&agent).await;
+                (slot, this.clock.now() - started)
+            });
+        }
+        let mut latencies = vec![Duration::ZERO; this.agents.len()];
+        while let Some(result) = checks.join_next().await {
+            match result {
+                Ok((slot, latency)) => latencies[slot] = latency,
+                Err(e) => log::error!("Identity request task failed: {}", e),
+            }
+        }
+
+        let outcomes = this
+            .agent_outcomes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        this.agents
+            .iter()
+            .zip(latencies)
+            .map(|(agent, latency)| {
+                let outcome = outcomes.get(&agent.socket_path);
+                UpstreamCheck {
+                    name: agent.name.clone(),
+                    socket_path: agent.socket_path.clone(),
+                    outcome: outcome.map_or(RefreshOutcome::NotRefreshed, |o| o.outcome),
+                    keys: outcome.map_or(0, |o| o.keys),
+                    detail: outcome.map(|o| o.detail.clone()).unwrap_or_default(),
+                    latency,
+                }
+            })
+            .collect()
+    }
+
+    /// Run a MuxAgent, listening for SSH agent protocol requests on `listen_sock`, forwarding
+    /// requests to the specified upstream `agents`, and keys added by clients to the first of
+    /// `added_keys_socks` that accepts them (or to each, under [`MuxOptions::add_to_all`]).
+    ///
+    /// If the process was started by systemd socket activation, it listens on the socket systemd
+    /// passed instead, and leaves it in place on exit; every run in the process reuses it.
+    pub async fn run(
+        listen_sock: impl AsRef<Path>,
+        agents: impl IntoIterator<Item = UpstreamAgent>,
+        mut added_keys_socks: Vec<PathBuf>,
+        options: MuxOptions,
+    ) -> Result<(), AgentError> {
+        let listen_sock = listen_sock.as_ref();
+        let agents: Vec<_> = agents.into_iter().collect();
+        added_keys_socks.retain(|sock_path| {
+            let read_only = agents
+                .iter()
+                .any(|a| a.read_only && a.socket_path == *sock_path);
+            if read_only {
+                log::warn!(
+                    "Not forwarding add_identity requests to read-only upstream agent <{}>",
+                    sock_path.display()
+                );
+            }
+            !read_only
+        });
+        if agents.is_empty() {
+            log::warn!("Mux agent running but no upstream agents configured");
+        }
+        log::info!(
+            "Starting agent for {} upstream agents; listening on <{}>",
+            agents.len(),
+            listen_sock.display()
+        );
+        log::debug!("Upstream agents: {:?}", &agents);
+        for (i, added_keys) in added_keys_socks.iter().enumerate() {
+            if options.add_to_all {
+                log::info!(
+                    "add_identity requests will be forwarded to <{}>",
+                    added_keys.display()
+                );
+            } else {
+                log::info!(
+                    "add_identity requests will be forwarded to <{}> (choice {})",
+                    added_keys.display(),
+                    i + 1
+                );
+            }
+        }
+
+        let metrics = Arc::new(Metrics::with_agents(agents.iter().map(|a| a.name.clone())));
+        // Started before binding the agent socket, so the endpoint is up by the time clients can
+        // connect to the agent. Held until the agent stops listening, so a configuration reload
+        // rebinds it.
+        let _metrics_server = match options.metrics_http {
+            Some(addr) => {
+                Self::spawn_metrics_http(addr, metrics.clone(), &agents, &options).await?
+            }
+            None => None,
+        };
+
+        let bound = match activation_socket() {
+            Some(fd) => SelfDeletingUnixListener::inherit(fd).inspect_err(|_| {
+                log::error!("Failed to listen on the socket passed by systemd");
+            }),
+            None => {
+                SelfDeletingUnixListener::bind(listen_sock, options.listen_mode).inspect_err(|_| {
+                    log::error!(
+                        "Failed to open listening socket at {}",
+                        listen_sock.display()
+                    )
+                })
+            }
+        };
+        let mut listen_sock = bound?;
+        // Once bound, the socket exists, so an agent socket path that resolves to it can be found;
+        // under socket activation, that's the socket systemd passed, not `listen_sock`
+        if let Some(agent) = Self::agent_at(&listen_sock.path, &agents) {
+            log::error!(
+                "Upstream agent {} is the mux's own socket <{}>",
+                agent.name,
+                listen_sock.path.display()
+            );
+            return Err(AgentError::Other(
+                format!("Upstream agent {} would loop back to the mux", agent.name).into(),
+            ));
+        }
+
+        if !options.allow_any_peer {
+            listen_sock.peer_uids = Some(PeerUids::own());
+        }
+
+        let kept = options.identity_cache.as_ref().and_then(|cache| {
+            let kept = cache
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()?;
+            if kept.agents != agents {
+                log::debug!("Upstream agents changed; not keeping the previous mux's known keys");
+                return None;
+            }
+            if kept.key_options != KeyOptions::of(&options) {
+                log::debug!("Key filters or duplicate-key-policy changed; not keeping the previous mux's known keys");
+                return None;
+            }
+            log::debug!("Upstream agents unchanged; keeping the previous mux's known keys");
+            Some(kept)
+        });
+        let known_keys = kept
+            .as_ref()
+            .map_or_else(Default::default, |kept| kept.known_keys.clone());
+        let routing_from_cache = kept.as_ref().map_or_else(
+            || Arc::new(AtomicBool::new(false)),
+            |kept| kept.routing_from_cache.clone(),
+        );
+        let _cache = options.known_keys_cache.as_ref().map(|path| {
+            if kept.is_none() {
+                let cached = cache::load(
+                    path,
+                    &agents,
+                    options.comment_filter.as_ref(),
+                    options.known_keys_cache_max_age,
+                );
+                routing_from_cache.store(!cached.is_empty(), Ordering::Relaxed);
+                // Nothing else holds the lock yet
+                *known_keys.try_lock().expect("known keys unlocked") = cached;
+            }
+            cache::SaveOnDrop {
+                path: path.clone(),
+                agents: agents.clone(),
+                comment_filter: options.comment_filter.clone(),
+                known_keys: known_keys.clone(),
+            }
+        });
+
+        let refreshes = match &kept {
+            Some(kept) => kept.refreshes.clone(),
+            None => Default::default(),
+        };
+
+        let mut this = Self {
+            known_keys,
+            metrics,
+            routing_from_cache,
+            refreshes,
+            ..Self::new(agents, added_keys_socks, options)
+        };
+        match kept {
+            Some(kept) => this.agent_outcomes = kept.agent_outcomes,
+            // Cached keys count as a refresh, for lazy connection
+            None => {
+                if this.routing_from_cache.load(Ordering::Relaxed) {
+                    this.refreshes().swept_at = Some(this.clock.now());
+                }
+            }
+        }
+        // Not set before, so this can't fail
+        let _ = this.metrics.known_keys.set(this.known_keys.clone());
+        let spawned_agents = this.options.spawned_agents.clone().unwrap_or_default();
+        spawned_agents.retain(&this.agents).await;
+        this.spawned_agents = Some(spawned_agents);
+        this.start_missing_agents().await;
+        if let Some(cache) = &this.options.identity_cache {
+            *cache.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(CachedIdentities {
+                agents: this.agents.clone(),
+                key_options: KeyOptions::of(&this.options),
+                known_keys: this.known_keys.clone(),
+                routing_from_cache: this.routing_from_cache.clone(),
+                refreshes: this.refreshes.clone(),
+                agent_outcomes: this.agent_outcomes.clone(),
+            });
+        }
+        let _background_refresh = this
+            .options
+            .background_refresh
+            .map(|interval| AbortOnDrop(tokio::spawn(this.clone().background_refresh(interval))));
+        log::debug!("Mux instance id: {}", this.instance_id);
+        if !this.options.once {
+            return agent::listen(listen_sock, this).await;
+        }
+
+        // Only after the background refresh got its clone, so that the session gets the sender
+        let (served_tx, served) = oneshot::channel();
+        this.once_served = Some(Arc::new(served_tx));
+        listen_sock.once = true;
+        // Stopping drops the listener, which removes the socket, as on any other shutdown
+        tokio::select! {
+            res = agent::listen(listen_sock, this) => res,
+            _ = served => {
+                log::info!("Served one client connection; stopping");
+                Ok(())
+            }
+        }
+    }
+
+    /// The upstream agent whose socket is the one at `path`, if any
+    fn agent_at<'a>(path: &Path, agents: &'a [UpstreamAgent]) -> Option<&'a UpstreamAgent> {
+        let path = fs::canonicalize(path).ok()?;
+        agents.iter().find(|a| match a.transport() {
+            UpstreamTransport::Unix(sock_path) => {
+                fs::canonicalize(sock_path).is_ok_and(|p| p == path)
+            }
+            _ => false,
+        })
+    }
+
+    /// Probe the agent at `sock_path` with [`MuxIdentity`] on `client`, on the first connection
+    /// to it, before any request is forwarded; `true` if it leads back to this mux through other
+    /// muxes, and `None` if the probe timed out, which may leave its answer on the connection
+    async fn leads_back(&self, sock_path: &Path, client: &mut dyn Session) -> Option<bool> {
+        if let Some(&looping) = self.loop_checks().get(sock_path) {
+            return Some(looping);
+        }
+        let own = self.instance_id.to_string();
+        let request = Extension::new_message(MuxIdentity {
+            chain: vec![own.clone()],
+        })
+        .ok()?;
+        let looping = match timeout(self.options.agent_timeout, client.extension(request)).await {
+            Err(_) => None,
+            // Agents other than muxes fail the probe
+            Ok(Ok(Some(response))) => match response.parse_message::<MuxIdentity>() {
+                Ok(Some(reply)) if reply.cycle_at() == Some(own.as_str()) => {
+                    log::error!(
+                        session:% = self.session_id;
+                        "Upstream agent {} leads back to this mux through other muxes \
+                         (instances {}); not using it",
+                        self.agent_name(sock_path),
+                        reply.chain.join(" -> ")
+                    );
+                    Some(true)
+                }
+                _ => Some(false),
+            },
+            Ok(_) => Some(false),
+        };
+        self.loop_checks()
+            .insert(sock_path.to_path_buf(), looping.unwrap_or_default());
+        looping
+    }
+
+    /// Answer a [`MuxIdentity`] probe that has passed through the muxes in `chain`
+    async fn answer_mux_identity(&self, mut chain: Vec<String>) -> MuxIdentity {
+        let own = self.instance_id.to_string();
+        let seen = chain.contains(&own);
+        chain.push(own);
+        if seen || chain.len() >= MUX_CHAIN_MAX {
+            return MuxIdentity { chain };
+        }
+        let checks = self.loop_checks().clone();
+        let unchecked_or_not_looping = |a: &&UpstreamAgent| {
+            a.kind != UpstreamKind::Builtin && checks.get(&a.socket_path) != Some(&true)
+        };
+        for agent in self.agents.iter().filter(unchecked_or_not_looping) {
+            let probe = self.probe_mux_identity(&agent.socket_path, chain.clone());
+            if let Some(reply) = probe.await.filter(|reply| reply.cycle_at().is_some()) {
+                return reply;
+            }
+        }
+        MuxIdentity { chain }
+    }
+
+    /// Pass a [`MuxIdentity`] probe that has passed through the muxes in `chain` on to the agent
+    /// at `sock_path`; `None` if it isn't a mux, or doesn't answer in time
+    async fn probe_mux_identity(
+        &self,
+        sock_path: &Path,
+        chain: Vec<String>,
+    ) -> Option<MuxIdentity> {
+        let probe = async {
+            let stream = UpstreamStream::connect(sock_path).await.ok()?;
+            let mut client = stream.into_client().ok()?;
+            let request = Extension::new_message(MuxIdentity { chain }).ok()?;
+            let response = client.extension(request).await.ok()??;
+            response.parse_message::<MuxIdentity>().ok()?
+        };
+        timeout(self.options.agent_timeout, probe).await.ok()?
+    }
+
+    fn loop_checks(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, bool>> {
+        self.loop_checks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start the agents with a command whose socket can't be connected to, without waiting for
+    /// them to listen
+    async fn start_missing_agents(&self) {
+        let Some(spawned_agents) = &self.spawned_agents else {
+            return;
+        };
+        for agent in self.agents.iter().filter(|a| !a.command.is_empty()) {
+            let connect = UpstreamStream::connect(&agent.socket_path);
+            if !matches!(
+                timeout(self.options.agent_timeout, connect).await,
+                Ok(Ok(_))
+            ) {
+                spawned_agents.start(agent).await;
+            }
+        }
+    }
+
+    /// Refresh identities every `interval`, backing off while no upstream agent is reachable
+    async fn background_refresh(self, interval: Duration) {
+        let mut delay = interval;
+        loop {
+            tokio::time::sleep(delay).await;
+            let reachable = {
+                let mut known_keys = self.known_keys.clone().lock_owned().await;
+                match self.refresh_identities(&mut known_keys).await {
+                    Ok(_) => self.refreshes().reachable,
+                    Err(_) => 0,
+                }
+            };
+            let next = background_refresh_delay(interval, delay, reachable);
+            if next > delay {
+                log::debug!(
+                    "No upstream agent reachable; next background refresh in {:?}",
+                    next
+                );
+            } else if next < delay {
+                log::debug!(
+                    "Upstream agents reachable again; background refresh every {:?}",
+                    next
+                );
+            }
+            delay = next;
+        }
+    }
+
+    #[cfg(feature = "http-metrics")]
+    async fn spawn_metrics_http(
+        addr: SocketAddr,
+        metrics: Arc<Metrics>,
+        agents: &[UpstreamAgent],
+        options: &MuxOptions,
+    ) -> Result<Option<AbortOnDrop>, AgentError> {
+        if !addr.ip().is_loopback() {
+            log::warn!(
+                "Metrics endpoint bound to non-loopback address {}; counters will be visible to other hosts",
+                addr
+            );
+        }
+        let listener = match metrics::http::bind(addr) {
+            Ok(l) => l,
+            Err(e) => {
+                log::error!("Failed to open metrics endpoint at {}", addr);
+                return Err(AgentError::IO(e));
+            }
+        };
+        log::info!("Serving metrics on http://{}/metrics", addr);
+        // A builtin agent is always reachable
+        let readiness = metrics::http::Readiness::new(
+            agents.iter().map(|a| a.socket_path.clone()).collect(),
+            agents.iter().any(|a| a.kind == UpstreamKind::Builtin),
+            options.agent_timeout,
+        );
+        Ok(Some(AbortOnDrop(tokio::spawn(metrics::http::serve(
+            listener,
+            metrics,
+            Arc::new(readiness),
+        )))))
+    }
+
+    #[cfg(not(feature = "http-metrics"))]
+    async fn spawn_metrics_http(
+        addr: SocketAddr,
+        _metrics: Arc<Metrics>,
+        _agents: &[UpstreamAgent],
+        _options: &MuxOptions,
+    ) -> Result<Option<AbortOnDrop>, AgentError> {
+        log::warn!(
+            "Metrics endpoint {} requested, but {} was built without the http-metrics feature",
+            addr,
+            env!("CARGO_PKG_NAME")
+        );
+        Ok(None)
+    }
+
+    async fn connect_upstream_agent(
+        &self,
+        sock_path: impl AsRef<Path>,
+    ) -> Result<Box<dyn Session>, AgentError> {
+        let sock_path = sock_path.as_ref();
+        let Some(turn) = self.agent_turns.get(sock_path) else {
+            return self.open_upstream_client(sock_path).await;
+        };
+        let turn = match turn.clone().try_lock_owned() {
+            Ok(turn) => turn,
+            Err(_) => {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Waiting for the request in flight to upstream agent <{}> to finish",
+                    sock_path.display()
+                );
+                turn.clone().lock_owned().await
+            }
+        };
+        let client = self.open_upstream_client(sock_path).await?;
+        Ok(Box::new(SerializedClient::new(client, turn)))
+    }
+
+    async fn open_upstream_client(&self, sock_path: &Path) -> Result<Box<dyn Session>, AgentError> {
+        if let Some(agent) = self.builtin_agents.get(sock_path) {
+            return Ok(Box::new(agent.clone()));
+        }
+        let leads_back_error = || {
+            AgentError::Other(
+                format!(
+                    "Upstream agent leads back to this mux through other muxes: {}",
+                    sock_path.display()
+                )
+                .into(),
+            )
+        };
+        if self.loop_checks().get(sock_path) == Some(&true) {
+            return Err(leads_back_error());
+        }
+        if self.options.reuse_connections {
+            if let Some(connection) = self.connection_pool.take(sock_path) {
+                log::trace!(
+                    session:% = self.session_id;
+                    "Reusing connection to upstream agent on socket: {}",
+                    sock_path.display()
+                );
+                return Ok(self.pooled(sock_path, connection));
+            }
+        }
+        let (mut client, pool_handle) = self.connect_client(sock_path).await?;
+        let looping = match self.upstream_kind(sock_path) {
+            // Not a mux, and extensions aren't sent to it
+            UpstreamKind::GpgAgent => Some(false),
+            UpstreamKind::Standard | UpstreamKind::Builtin => {
+                self.leads_back(sock_path, &mut *client).await
+            }
+        };
+        let (client, pool_handle) = match looping {
+            Some(false) => (client, pool_handle),
+            Some(true) => return Err(leads_back_error()),
+            // The probe's answer may still arrive, so the connection can't be used; the agent
+            // was recorded as not a mux, so the new one isn't probed
+            None => self.connect_client(sock_path).await?,
+        };
+        Ok(match pool_handle {
+            Some(socket) => self.pooled(sock_path, Connection::new(client, socket)),
+            None => client,
+        })
+    }
+
+    /// Open a new connection to the agent at `sock_path`, and a handle to its socket to pool it
+    /// with under [`MuxOptions::reuse_connections`]
+    async fn connect_client(
+        &self,
+        sock_path: &Path,
+    ) -> Result<(Box<dyn Session>, Option<std::os::fd::OwnedFd>), AgentError> {
+        let connect_timeout = match self.upstream_kind(sock_path) {
+            UpstreamKind::GpgAgent => self
+                .options
+                .agent_timeout
+                .max(GPG_AGENT_MIN_CONNECT_TIMEOUT),
+            UpstreamKind::Standard | UpstreamKind::Builtin => self.options.agent_timeout,
+        };
+        let mut deadline = self.startup_grace_deadline(sock_path);
+        let mut started = false;
+        let stream = loop {
+            let result = timeout(connect_timeout, UpstreamStream::connect(sock_path))
+                .await
+                .map_err(|_| {
+                    Metrics::increment(&self.metrics.upstream_timeouts);
+                    AgentError::Other(
+                        format!(
+                            "Connection to upstream agent timed out: {}",
+                            sock_path.display()
+                        )
+                        .into(),
+                    )
+                })?;
+            if result.is_err() && !started && self.start_upstream_agent(sock_path).await {
+                started = true;
+                let ready_by = self.clock.now() + self.options.agent_timeout;
+                deadline = deadline.max(Some(ready_by));
+            }
+            match (result, deadline) {
+                (Err(e), Some(deadline)) if self.clock.now() < deadline => {
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Waiting for upstream agent socket {} to become ready: {}",
+                        sock_path.display(),
+                        e
+                    );
+                    tokio::time::sleep(STARTUP_GRACE_POLL).await;
+                }
+                (result, _) => break result.map_err(AgentError::IO)?,
+            }
+        };
+        let pool_handle = if self.options.reuse_connections {
+            Some(stream.try_clone_fd()?)
+        } else {
+            None
+        };
+        let client = stream.into_client().map_err(|e| {
+            AgentError::Other(
+                format!(
+                    "Failed to connect to agent at {}: {}",
+                    sock_path.display(),
+                    e
+                )
+                .into(),
+            )
         })?;
         log::trace!(
+            session:% = self.session_id;
             "Connected to upstream agent on socket: {}",
             sock_path.display()
         );
-        Ok(client)
+        Ok((client, pool_handle))
+    }
+
+    /// Start the agent at `sock_path` with its command, if it has one; whether it's running
+    async fn start_upstream_agent(&self, sock_path: &Path) -> bool {
+        let (Some(spawned_agents), Some(agent)) =
+            (&self.spawned_agents, self.upstream_agent(sock_path))
+        else {
+            return false;
+        };
+        spawned_agents.start(agent).await
+    }
+
+    /// `connection`, to be returned to the pool once the caller is done with it
+    fn pooled(&self, sock_path: &Path, connection: Connection) -> Box<dyn Session> {
+        let pool = self.connection_pool.clone();
+        Box::new(PooledClient::new(connection, sock_path.to_path_buf(), pool))
+    }
+
+    /// Deadline for connecting to the agent at `sock_path`, if it has a startup grace period and
+    /// this is its first use
+    fn startup_grace_deadline(&self, sock_path: &Path) -> Option<Instant> {
+        let agent = self.upstream_agent(sock_path)?;
+        if agent.startup_grace.is_zero() {
+            return None;
+        }
+        let first_use = self
+            .used_agents
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(sock_path.to_path_buf());
+        first_use.then(|| self.clock.now() + agent.startup_grace)
+    }
+
+    /// Add `identity` to the first add target that accepts it, or to every one under
+    /// [`MuxOptions::add_to_all`], with its constraints, if any
+    async fn add_to_targets(&mut self, identity: AddIdentityConstrained) -> Result<(), AgentError> {
+        if let Some(pubkey) = pubkey_from_credential(&identity.identity.credential) {
+            let fingerprint = pubkey.fingerprint(Default::default());
+            let decision = self
+                .options
+                .policy
+                .allow_add(&fingerprint, self.peer.as_ref())
+                .await;
+            if let Decision::Deny(detail) = decision {
+                let denial = Denial::new(DenialReason::Policy, detail);
+                return Err(self.refuse(&format!("to add key {}", fingerprint), &denial));
+            }
+        }
+        // The upstream agents can't be enabled or disabled while the mux runs: a configuration
+        // reload starts a new mux, so the add targets checked at load are still enabled here
+        let Some((last, preferred)) = self.added_keys_socks.split_last() else {
+            log::error!(
+                session:% = self.session_id;
+                "add_identity requested but no added_keys socket configured"
+            );
+            return Err(AgentError::Failure);
+        };
+        if self.options.add_to_all {
+            return self.add_to_every_target(identity).await;
+        }
+        for added_keys_sock in preferred {
+            match self
+                .add_identity_to(added_keys_sock, identity.clone())
+                .await
+            {
+                Ok(result) => return result,
+                Err(e) => log::warn!(
+                    session:% = self.session_id;
+                    "Failed to add key to upstream agent <{}>, trying the next one: {}",
+                    added_keys_sock.display(),
+                    e
+                ),
+            }
+        }
+        self.add_identity_to(last, identity).await?
+    }
+
+    /// Add `identity` to each add target in turn; succeeds if any of them accepts it
+    async fn add_to_every_target(
+        &self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        let mut result = Err(AgentError::Failure);
+        for added_keys_sock in &self.added_keys_socks {
+            match self
+                .add_identity_to(added_keys_sock, identity.clone())
+                .await
+            {
+                Ok(Ok(())) => result = Ok(()),
+                Ok(Err(e)) | Err(e) => {
+                    log::warn!(
+                        session:% = self.session_id;
+                        "Failed to add key to upstream agent <{}>: {}",
+                        added_keys_sock.display(),
+                        e
+                    );
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Add `identity` to the add target at `added_keys_sock`, as set by
+    /// [`MuxOptions::add_if_present`]; the outer error is a failure of the agent to add it, for
+    /// which the next target is tried, the inner one the mux's own refusal
+    async fn add_identity_to(
+        &self,
+        added_keys_sock: &Path,
+        identity: AddIdentityConstrained,
+    ) -> Result<Result<(), AgentError>, AgentError> {
+        log::info!(
+            session:% = self.session_id, socket:% = added_keys_sock.display();
+            "Forwarding add_identity request to upstream agent <{}>",
+            added_keys_sock.display()
+        );
+
+        let pubkey = pubkey_from_credential(&identity.identity.credential);
+        let constrained = identity
+            .constraints
+            .iter()
+            .any(|c| matches!(c, KeyConstraint::Lifetime(_) | KeyConstraint::Confirm));
+
+        let mut client = self.connect_upstream_agent(added_keys_sock).await?;
+        let present = match (&pubkey, self.options.add_if_present) {
+            (_, AddIfPresent::Replace) | (None, _) => false,
+            (Some(pubkey), _) => {
+                let identities = timeout(self.options.agent_timeout, client.request_identities())
+                    .await
+                    .map_err(|_| {
+                        Metrics::increment(&self.metrics.upstream_timeouts);
+                        AgentError::Other(
+                            format!(
+                                "Request identities timed out on upstream agent: {}",
+                                added_keys_sock.display()
+                            )
+                            .into(),
+                        )
+                    })??;
+                identities.iter().any(|id| &id.pubkey == pubkey)
+            }
+        };
+        match (present, self.options.add_if_present) {
+            // The key keeps the constraints it's listed with, so the ignored request's aren't
+            // recorded
+            (true, AddIfPresent::Skip) => {
+                log::info!(
+                    session:% = self.session_id;
+                    "Key already present in upstream agent <{}>; not adding it again",
+                    added_keys_sock.display()
+                );
+                return Ok(Ok(()));
+            }
+            (true, AddIfPresent::Error) => {
+                log::warn!(
+                    session:% = self.session_id;
+                    "Refusing to add key already present in upstream agent <{}>",
+                    added_keys_sock.display()
+                );
+                return Ok(Err(AgentError::Failure));
+            }
+            _ => {
+                self.note_keys_changed();
+                self.forward_add_identity(client, added_keys_sock, identity)
+                    .await?;
+            }
+        }
+
+        if let Some(pubkey) = pubkey {
+            let fingerprint = pubkey.fingerprint(Default::default());
+            log::debug!(
+                session:% = self.session_id;
+                "Caching added key {} -> <{}>",
+                &fingerprint,
+                added_keys_sock.display()
+            );
+            self.added_keys()
+                .insert(pubkey.clone(), AddedKey { constrained });
+            self.known_keys
+                .lock()
+                .await
+                .insert(pubkey, added_keys_sock.to_path_buf());
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Send `identity` to the add target at `sock_path` over `client`, under
+    /// [`MuxOptions::add_timeout`] and retrying after timeouts up to [`MuxOptions::add_retries`]
+    /// times
+    async fn forward_add_identity(
+        &self,
+        mut client: Box<dyn Session>,
+        sock_path: &Path,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        let add_timeout = self
+            .options
+            .add_timeout
+            .unwrap_or(self.options.agent_timeout);
+        let mut retries = 0;
+        loop {
+            let add = if identity.constraints.is_empty() {
+                client.add_identity(identity.identity.clone())
+            } else {
+                client.add_identity_constrained(identity.clone())
+            };
+            let Ok(result) = timeout(add_timeout, add).await else {
+                Metrics::increment(&self.metrics.upstream_timeouts);
+                if retries == self.options.add_retries {
+                    return Err(AgentError::Other(
+                        format!(
+                            "Add identity request timed out on upstream agent: {}",
+                            sock_path.display()
+                        )
+                        .into(),
+                    ));
+                }
+                retries += 1;
+                log::warn!(
+                    session:% = self.session_id;
+                    "Add identity request timed out on upstream agent <{}>; retrying ({}/{})",
+                    sock_path.display(),
+                    retries,
+                    self.options.add_retries
+                );
+                // The timed out request may still be answered on this connection, which must be
+                // closed first to give up the agent's turn under `serialize`
+                drop(client);
+                client = self.connect_upstream_agent(sock_path).await?;
+                continue;
+            };
+            return result;
+        }
+    }
+
+    /// Lock or unlock the agent at `sock_path` with `passphrase`
+    async fn forward_lock(
+        &self,
+        sock_path: &Path,
+        passphrase: &Zeroizing<String>,
+        lock: bool,
+    ) -> Result<(), AgentError> {
+        let (request, done) = if lock {
+            ("Lock", "Locked")
+        } else {
+            ("Unlock", "Unlocked")
+        };
+        let mut client = self.connect_upstream_agent(sock_path).await?;
+        let passphrase = passphrase.to_string();
+        let response = if lock {
+            timeout(self.options.agent_timeout, client.lock(passphrase)).await
+        } else {
+            timeout(self.options.agent_timeout, client.unlock(passphrase)).await
+        };
+        response.map_err(|_| {
+            Metrics::increment(&self.metrics.upstream_timeouts);
+            AgentError::Other(
+                format!(
+                    "{} request timed out on upstream agent: {}",
+                    request,
+                    sock_path.display()
+                )
+                .into(),
+            )
+        })??;
+        log::info!(
+            session:% = self.session_id;
+            "{} upstream agent <{}>",
+            done, sock_path.display()
+        );
+        Ok(())
+    }
+
+    /// Lock or unlock the agent at `sock_path` like [`Self::forward_lock`], logging a failure
+    /// instead of returning it, so that the remaining agents are still handled; returns whether
+    /// it succeeded
+    async fn try_forward_lock(
+        &self,
+        sock_path: &Path,
+        passphrase: &Zeroizing<String>,
+        lock: bool,
+    ) -> bool {
+        let request = if lock { "lock" } else { "unlock" };
+        match self.forward_lock(sock_path, passphrase, lock).await {
+            Ok(()) => true,
+            // e.g. an agent without lock support, or a wrong passphrase
+            Err(e) if is_upstream_failure(&e) => {
+                log::warn!(
+                    session:% = self.session_id;
+                    "Upstream agent <{}> refused to {}; skipping it",
+                    sock_path.display(),
+                    request
+                );
+                false
+            }
+            Err(e) => {
+                log::error!(
+                    session:% = self.session_id;
+                    "Unexpected error on socket <{}> when requesting {}; skipping it: {}",
+                    sock_path.display(),
+                    request,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Send an extension request the mux doesn't handle itself (e.g. `ecdh@joyent.com`) to each
+    /// agent in scope that it's forwarded to, in configured order, until one answers it
+    async fn forward_extension(
+        &self,
+        request: &Extension,
+    ) -> Result<Option<Extension>, AgentError> {
+        for agent in self.agents.iter().filter(|a| self.agent_in_scope(a)) {
+            if agent.kind == UpstreamKind::GpgAgent
+                || !agent.extension_filter.forwards(&request.name)
+            {
+                continue;
+            }
+            let sock_path = &agent.socket_path;
+            let Ok(mut client) = self.connect_upstream_agent(sock_path).await else {
+                continue;
+            };
+            match timeout(
+                self.options.agent_timeout,
+                client.extension(request.clone()),
+            )
+            .await
+            {
+                Ok(Ok(response)) => {
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Upstream agent <{}> answered extension {}",
+                        sock_path.display(),
+                        request.name
+                    );
+                    return Ok(response);
+                }
+                // Most agents support few extensions, if any
+                Ok(Err(e)) if is_upstream_failure(&e) => {}
+                Ok(Err(e)) => log::error!(
+                    session:% = self.session_id;
+                    "Unexpected error on socket <{}> when requesting {} extension: {}",
+                    sock_path.display(),
+                    request.name,
+                    e
+                ),
+                Err(_) => {
+                    Metrics::increment(&self.metrics.upstream_timeouts);
+                    log::warn!(
+                        session:% = self.session_id;
+                        "Extension request timed out on upstream agent: {}",
+                        sock_path.display()
+                    );
+                }
+            }
+        }
+        log::debug!(
+            session:% = self.session_id;
+            "No upstream agent answered extension {}",
+            request.name
+        );
+        Err(AgentError::Failure)
+    }
+
+    /// Ask the agent at `sock_path` to remove all its keys, logging a failure; whether it did
+    async fn try_remove_all_identities(&self, sock_path: &Path) -> bool {
+        let result = match self.connect_upstream_agent(sock_path).await {
+            Ok(mut client) => timeout(self.options.agent_timeout, client.remove_all_identities())
+                .await
+                .unwrap_or_else(|_| {
+                    Metrics::increment(&self.metrics.upstream_timeouts);
+                    Err(AgentError::Other(
+                        format!(
+                            "Remove all identities request timed out on upstream agent: {}",
+                            sock_path.display()
+                        )
+                        .into(),
+                    ))
+                }),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) if is_upstream_failure(&e) => {
+                log::warn!(
+                    session:% = self.session_id;
+                    "Upstream agent <{}> refused to remove all identities; skipping it",
+                    sock_path.display()
+                );
+                false
+            }
+            Err(e) => {
+                log::error!(
+                    session:% = self.session_id;
+                    "Unexpected error on socket <{}> when removing all identities; skipping it: {}",
+                    sock_path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    fn upstream_agent(&self, sock_path: &Path) -> Option<&UpstreamAgent> {
+        self.agents.iter().find(|a| a.socket_path == sock_path)
+    }
+
+    /// Agents whose keys the mux may change, lock or unlock: those not [`UpstreamAgent::read_only`]
+    fn writable_agents(&self) -> impl Iterator<Item = &UpstreamAgent> {
+        self.agents.iter().filter(|a| !a.read_only)
+    }
+
+    /// Name of the agent at `sock_path`, or the path if it isn't a configured agent
+    fn agent_name(&self, sock_path: &Path) -> String {
+        self.upstream_agent(sock_path)
+            .map(|a| a.name.clone())
+            .unwrap_or_else(|| sock_path.display().to_string())
+    }
+
+    /// Names of the agents in scope that listed `pubkey` at their latest identity request
+    fn listing_agents(&self, pubkey: &PubKeyData) -> Vec<String> {
+        let refreshes = self.refreshes();
+        self.agents
+            .iter()
+            .filter(|a| self.agent_in_scope(a))
+            .filter(|a| {
+                refreshes
+                    .listed
+                    .get(&a.socket_path)
+                    .is_some_and(|ids| ids.iter().any(|id| id.pubkey == *pubkey))
+            })
+            .map(|a| a.name.clone())
+            .collect()
+    }
+
+    /// Names of the agents listing `pubkey`, if there are several and
+    /// [`MuxOptions::duplicate_key_policy`] refuses to sign with it
+    fn refused_duplicate(&self, pubkey: &PubKeyData) -> Option<Vec<String>> {
+        if self.options.duplicate_key_policy != DuplicateKeyPolicy::Error {
+            return None;
+        }
+        Some(self.listing_agents(pubkey)).filter(|listing| listing.len() > 1)
+    }
+
+    /// Socket paths of the agents in scope, other than `owner`, that listed and expose `pubkey`
+    /// at their latest identity request, in routing preference under
+    /// [`MuxOptions::duplicate_key_policy`]
+    fn fallback_agents(&self, pubkey: &PubKeyData, owner: &Path) -> Vec<PathBuf> {
+        let refreshes = self.refreshes();
+        let mut fallbacks: Vec<_> = self
+            .agents
+            .iter()
+            .filter(|a| a.socket_path != owner && self.agent_in_scope(a))
+            .filter(|a| a.key_filter.exposes(pubkey))
+            .filter(|a| {
+                refreshes
+                    .listed
+                    .get(&a.socket_path)
+                    .is_some_and(|ids| ids.iter().any(|id| id.pubkey == *pubkey))
+            })
+            .map(|a| a.socket_path.clone())
+            .collect();
+        if self.options.duplicate_key_policy == DuplicateKeyPolicy::LastWins {
+            fallbacks.reverse();
+        }
+        fallbacks
+    }
+
+    /// Record a sign request with the key `fingerprint` in the audit log, as
+    /// [`MuxOptions::audit`] asks
+    fn audit_sign(
+        &self,
+        fingerprint: &Fingerprint,
+        trace: &SignTrace,
+        result: Result<(), &AgentError>,
+    ) {
+        let outcome = match (result, &trace.agent) {
+            (Ok(()), Some(agent)) => format!("signed by upstream agent {}", agent),
+            (Ok(()), None) => "signed".into(),
+            (Err(e), _) => format!("failed: {}", e),
+        };
+        // Also as fields, for records written as JSON, e.g. to an audit file
+        let pid = self.peer.and_then(|p| p.pid).map(|pid| pid.to_string());
+        let pid = pid.as_deref().unwrap_or("unknown");
+        let agent = trace.agent.as_deref().unwrap_or("none");
+        let result = if result.is_ok() { "success" } else { "failure" };
+        match self.options.audit {
+            AuditVerbosity::Off => {}
+            AuditVerbosity::Outcome => log::info!(
+                target: AUDIT_LOG_TARGET,
+                session:% = self.session_id, pid, fingerprint:% = fingerprint, agent, result;
+                "Sign with key {}: {}",
+                fingerprint,
+                outcome
+            ),
+            AuditVerbosity::Routing => {
+                let candidates = match trace.candidates.as_deref() {
+                    Some([]) | None => "none".into(),
+                    Some(names) => names.join(", "),
+                };
+                log::info!(
+                    target: AUDIT_LOG_TARGET,
+                    session:% = self.session_id, pid, fingerprint:% = fingerprint, agent, result;
+                    "Sign with key {}: {}; listed by: {}; steps: {}",
+                    fingerprint,
+                    outcome,
+                    candidates,
+                    trace.steps.join("; ")
+                )
+            }
+        }
+    }
+
+    fn upstream_kind(&self, sock_path: &Path) -> UpstreamKind {
+        self.upstream_agent(sock_path)
+            .map(|a| a.kind)
+            .unwrap_or_default()
+    }
+
+    async fn get_agent_sock_for_pubkey(
+        &mut self,
+        pubkey: &PubKeyData,
+    ) -> Result<Option<PathBuf>, AgentError> {
+        if let Some(pinned) = self.pinned_agent(pubkey) {
+            log::debug!(
+                session:% = self.session_id;
+                "Key is pinned to upstream agent <{}> by a route",
+                pinned.display()
+            );
+            return Ok(Some(pinned.clone()));
+        }
+        // Refresh available identities if the public key isn't found (unless disabled);
+        // hold lock for duration of signing operation
+        let generation = self.refresh_generation();
+        let mut known_keys = self.known_keys.clone().lock_owned().await;
+        let in_scope = |path: &&PathBuf| self.socket_in_scope(path);
+        if known_keys.get(pubkey).filter(in_scope).is_none() {
+            if self.refreshed_within_ttl() {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Key not found; not re-requesting keys, as the last refresh is within \
+                     cache-ttl"
+                );
+            } else if self.options.refresh_on_sign_miss {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Key not found, re-requesting keys from upstream agents"
+                );
+                let _ = self
+                    .refresh_identities_once(&mut known_keys, generation)
+                    .await?;
+            } else {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Key not found; not re-requesting keys, as refresh-on-sign-miss is disabled"
+                );
+            }
+        }
+        let maybe_agent = known_keys.get(pubkey).filter(in_scope).cloned();
+        if maybe_agent.is_none() {
+            if let Some(default_agent) = self.options.default_agent_sock.as_ref().filter(in_scope) {
+                log::debug!(
+                    session:% = self.session_id;
+                    "Key not listed by any upstream agent; falling back to default agent <{}>",
+                    default_agent.display()
+                );
+                return Ok(Some(default_agent.clone()));
+            }
+        }
+        Ok(maybe_agent)
+    }
+
+    /// Whether a refresh with this session's scope that every agent answered completed less than
+    /// [`MuxOptions::cache_ttl`] ago
+    fn refreshed_within_ttl(&self) -> bool {
+        let refreshes = self.refreshes();
+        refreshes.answered_at.as_ref().is_some_and(|(tags, at)| {
+            *tags == self.selected_tags && self.clock.now() - *at < self.options.cache_ttl
+        })
+    }
+
+    /// Socket path of the agent in scope that [`MuxOptions::key_routes`] pins `pubkey` to
+    fn pinned_agent(&self, pubkey: &PubKeyData) -> Option<&PathBuf> {
+        self.options
+            .key_routes
+            .iter()
+            .find(|(fingerprint, _)| pubkey.fingerprint(fingerprint.algorithm()) == *fingerprint)
+            .map(|(_, sock_path)| sock_path)
+            .filter(|sock_path| self.socket_in_scope(sock_path))
+    }
+
+    fn agent_in_scope(&self, agent: &UpstreamAgent) -> bool {
+        match &self.selected_tags {
+            None => true,
+            Some(tags) => agent.tags.iter().any(|t| tags.contains(t)),
+        }
+    }
+
+    fn socket_in_scope(&self, sock_path: &Path) -> bool {
+        self.agents
+            .iter()
+            .any(|a| a.socket_path == sock_path && self.agent_in_scope(a))
+    }
+
+    /// Whether `name` is one of the mux's own extensions, whether or not it's enabled
+    fn is_native_extension(name: &str) -> bool {
+        [
+            SelectTags::NAME,
+            RefreshStatus::NAME,
+            ListUpstreams::NAME,
+            MuxInfo::NAME,
+            MuxIdentity::NAME,
+            SignCheck::NAME,
+        ]
+        .contains(&name)
+    }
+
+    /// Names of the mux's own extensions it answers
+    fn native_extensions(&self) -> Vec<String> {
+        let mut extensions = vec![
+            SelectTags::NAME,
+            RefreshStatus::NAME,
+            ListUpstreams::NAME,
+            MuxInfo::NAME,
+            MuxIdentity::NAME,
+        ];
+        if self.options.sign_check {
+            extensions.push(SignCheck::NAME);
+        }
+        extensions.into_iter().map(String::from).collect()
+    }
+
+    fn check_allowed(&self, operation: Operation) -> Result<(), AgentError> {
+        match self.operation_denial(operation) {
+            None => Ok(()),
+            Some(denial) => Err(self.refuse(&format!("{:?} request", operation), &denial)),
+        }
+    }
+
+    fn operation_denial(&self, operation: Operation) -> Option<Denial> {
+        (!self.options.allowed_operations.contains(&operation)).then(|| {
+            Denial::new(
+                DenialReason::OperationNotAllowed,
+                format!("{:?} isn't an allowed operation", operation),
+            )
+        })
+    }
+
+    /// Log the mux's own refusal of `request`, tagged with its reason, and make the error to
+    /// answer the client with
+    fn refuse(&self, request: &str, denial: &Denial) -> AgentError {
+        log::warn!(session:% = self.session_id; "Refusing {}: {}", request, denial);
+        AgentError::Failure
+    }
+
+    /// Refuse an extension request like [`Self::refuse`], but answer one of the mux's own
+    /// extensions with a [`Denied`] reply, since its clients can read the reason from it
+    fn refuse_extension(
+        &self,
+        request: &Extension,
+        denial: &Denial,
+    ) -> Result<Option<Extension>, AgentError> {
+        let e = self.refuse(&format!("{} extension request", request.name), denial);
+        if Self::is_native_extension(&request.name) {
+            Ok(Some(Extension::new_message(denial.reply())?))
+        } else {
+            Err(e)
+        }
+    }
+
+    /// Why the mux itself would refuse to sign with `pubkey`, if it would
+    async fn sign_denial(&self, pubkey: &PubKeyData, fingerprint: &Fingerprint) -> Option<Denial> {
+        if let Some(denial) = self.operation_denial(Operation::Sign) {
+            return Some(denial);
+        }
+        if let Some(denial) = self.constraint_denial(pubkey) {
+            return Some(denial);
+        }
+        match self
+            .options
+            .policy
+            .allow_sign(fingerprint, self.peer.as_ref())
+            .await
+        {
+            Decision::Allow => None,
+            Decision::Deny(detail) => Some(Denial::new(DenialReason::Policy, detail)),
+        }
+    }
+
+    /// Ask the user to approve forwarding a sign request with the key `fingerprint` to the agent
+    /// at `sock_path`, if [`MuxOptions::confirm_sign`] or the agent's [`UpstreamAgent::confirm`]
+    /// asks for it and they haven't answered for this request yet; why not, if they didn't
+    async fn confirm_denial(
+        &self,
+        sock_path: &Path,
+        fingerprint: &Fingerprint,
+        trace: &mut SignTrace,
+    ) -> Option<Denial> {
+        let agent = self.upstream_agent(sock_path);
+        if !self.options.confirm_sign && !agent.is_some_and(|a| a.confirm) {
+            return None;
+        }
+        let detail = match trace.confirmed {
+            Some(true) => return None,
+            Some(false) => "the user didn't approve the request".to_string(),
+            None => {
+                let prompt = format!(
+                    "Allow use of key {} through upstream agent {}?",
+                    fingerprint,
+                    self.agent_name(sock_path)
+                );
+                let command = &self.options.confirm_command;
+                match confirm::ask(command, &prompt, self.options.confirm_timeout).await {
+                    Ok(true) => {
+                        trace.confirmed = Some(true);
+                        trace.steps.push("approved by the user".into());
+                        return None;
+                    }
+                    Ok(false) => "the user didn't approve the request".to_string(),
+                    Err(reason) => {
+                        format!("couldn't ask the user to approve the request: {}", reason)
+                    }
+                }
+            }
+        };
+        trace.confirmed = Some(false);
+        Some(Denial::new(DenialReason::NotConfirmed, detail))
+    }
+
+    fn added_keys(&self) -> std::sync::MutexGuard<'_, HashMap<PubKeyData, AddedKey>> {
+        self.added_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Refuse to sign with `pubkey` if [`MuxOptions::require_constraints`] requires a constraint
+    /// it wasn't added with
+    fn constraint_denial(&self, pubkey: &PubKeyData) -> Option<Denial> {
+        let allowed = match (
+            self.options.require_constraints,
+            self.added_keys().get(pubkey),
+        ) {
+            (RequireConstraints::Off, _) => true,
+            (_, Some(added)) => added.constrained,
+            (RequireConstraints::AddedKeys, None) => true,
+            (RequireConstraints::AllKeys, None) => false,
+        };
+        (!allowed).then(|| {
+            Denial::new(
+                DenialReason::ConstraintRequired,
+                "the key wasn't added through the mux with a lifetime or confirmation constraint"
+                    .into(),
+            )
+        })
+    }
+
+    /// Whether `pubkey` is exposed by the mux's own key filters, [`MuxOptions::visible_keys`] and
+    /// [`MuxOptions::allowed_key_types`], whichever agent lists it
+    fn exposes(&self, pubkey: &PubKeyData) -> bool {
+        self.options.visible_keys.exposes(pubkey)
+            && (self.options.allowed_key_types.is_empty()
+                || self.options.allowed_key_types.contains(&pubkey.algorithm()))
+    }
+
+    /// Whether key filters hide `pubkey`, routed to the agent at `sock_path`; a hidden key can
+    /// still be routed through the default agent, or from the known keys cache. The comment filter
+    /// isn't checked here: the known keys only ever hold keys listed with a comment it exposes.
+    fn is_hidden(&self, pubkey: &PubKeyData, sock_path: &Path) -> bool {
+        !self.exposes(pubkey)
+            || self
+                .upstream_agent(sock_path)
+                .is_some_and(|a| !a.key_filter.exposes(pubkey))
+    }
+
+    /// Send `request` to the agent at `sock_path`; the outer error is a failure to reach the agent
+    /// or a timeout, the inner one the agent's answer
+    async fn sign_with_agent(
+        &self,
+        sock_path: &Path,
+        request: &SignRequest,
+    ) -> Result<Result<Signature, AgentError>, AgentError> {
+        let result = self.request_upstream_sign(sock_path, request).await;
+        let signed = matches!(result, Ok(Ok(_)));
+        self.metrics
+            .count_upstream_sign(&self.agent_name(sock_path), signed);
+        result
+    }
+
+    async fn request_upstream_sign(
+        &self,
+        sock_path: &Path,
+        request: &SignRequest,
+    ) -> Result<Result<Signature, AgentError>, AgentError> {
+        let mut client = self
+            .connect_upstream_agent(sock_path)
+            .await
+            .map_err(|reason| AgentError::Other(Box::new(AgentUnreachable { reason })))?;
+        if self.upstream_kind(sock_path) == UpstreamKind::GpgAgent {
+            // gpg-agent only signs with keys it has listed on the same connection
+            timeout(self.options.agent_timeout, client.request_identities())
+                .await
+                .map_err(|_| {
+                    Metrics::increment(&self.metrics.upstream_timeouts);
+                    AgentError::Other(
+                        format!(
+                            "Request identities timed out on upstream agent: {}",
+                            sock_path.display()
+                        )
+                        .into(),
+                    )
+                })??;
+        }
+        timeout(self.options.agent_timeout, client.sign(request.clone()))
+            .await
+            .map_err(|_| {
+                Metrics::increment(&self.metrics.upstream_timeouts);
+                AgentError::Other(
+                    format!(
+                        "Sign request timed out on upstream agent: {}",
+                        sock_path.display()
+                    )
+                    .into(),
+                )
+            })
+    }
+
+    async fn route_and_sign(
+        &mut self,
+        request: &SignRequest,
+        trace: &mut SignTrace,
+    ) -> Result<Signature, AgentError> {
+        let fingerprint = request.pubkey.fingerprint(Default::default());
+
+        let maybe_agent = self.get_agent_sock_for_pubkey(&request.pubkey).await;
+        if self.options.audit == AuditVerbosity::Routing && trace.candidates.is_none() {
+            trace.candidates = Some(self.listing_agents(&request.pubkey));
+        }
+        if let Some(mut agent_sock_path) = maybe_agent? {
+            let mut agent = self.agent_name(&agent_sock_path);
+            let owner = self.known_keys.lock().await.get(&request.pubkey).cloned();
+            let pinned = self.pinned_agent(&request.pubkey).is_some();
+            if pinned {
+                trace.steps.push(format!("pinned to {} by a route", agent));
+            } else if owner.as_ref() == Some(&agent_sock_path) {
+                trace.steps.push(format!("routed to owner {}", agent));
+            } else {
+                trace
+                    .steps
+                    .push(format!("routed to default agent {}", agent));
+            }
+            if self.is_hidden(&request.pubkey, &agent_sock_path) {
+                trace.steps.push("hidden by key filters".into());
+                log::warn!(
+                    session:% = self.session_id, fingerprint:% = fingerprint,
+                    socket:% = agent_sock_path.display();
+                    "Refusing to sign with key {} hidden by key filters (upstream agent <{}>)",
+                    &fingerprint,
+                    agent_sock_path.display()
+                );
+                return Err(AgentError::Failure);
+            }
+            if let Some(listing) = self.refused_duplicate(&request.pubkey).filter(|_| !pinned) {
+                trace.steps.push("listed by several agents".into());
+                log::error!(
+                    session:% = self.session_id;
+                    "Refusing to sign with key {} listed by several upstream agents ({}), as \
+                     duplicate-key-policy is error",
+                    &fingerprint,
+                    listing.join(", ")
+                );
+                return Err(AgentError::Failure);
+            }
+
+            // Other agents listing the key are tried in turn if one is unreachable, times out
+            // (e.g. wedged waiting for a PIN), or refuses, unless a route pins the key to one
+            let fallbacks = match pinned {
+                true => vec![],
+                false => self.fallback_agents(&request.pubkey, &agent_sock_path),
+            };
+            let mut fallbacks = fallbacks.into_iter();
+            let result = loop {
+                log::info!(
+                    session:% = self.session_id;
+                    "Requesting signature with key {} from upstream agent <{}>",
+                    &fingerprint,
+                    agent_sock_path.display()
+                );
+                let denial = self
+                    .confirm_denial(&agent_sock_path, &fingerprint, trace)
+                    .await;
+                if let Some(denial) = denial {
+                    trace.steps.push(denial.to_string());
+                    return Err(self.refuse(&format!("to sign with key {}", fingerprint), &denial));
+                }
+                trace.agent = Some(agent.clone());
+                let result = self.sign_with_agent(&agent_sock_path, request).await;
+                let failed = match result {
+                    Ok(Ok(_)) => {
+                        trace.steps.push(format!("{} signed", agent));
+                        false
+                    }
+                    Ok(Err(ref e)) => {
+                        trace.steps.push(format!("{} failed: {}", agent, e));
+                        is_upstream_failure(e)
+                    }
+                    Err(ref e) => {
+                        trace.steps.push(format!("{} unreachable: {}", agent, e));
+                        true
+                    }
+                };
+                let Some(next) = fallbacks.next().filter(|_| failed) else {
+                    break result;
+                };
+                let next_agent = self.agent_name(&next);
+                log::warn!(
+                    session:% = self.session_id;
+                    "Upstream agent {} failed to sign with key {}; trying upstream agent {}, which \
+                     also lists it",
+                    agent,
+                    &fingerprint,
+                    next_agent
+                );
+                trace.steps.push(format!("trying {} instead", next_agent));
+                (agent, agent_sock_path) = (next_agent, next);
+            }?;
+            if let Ok(ref signature) = result {
+                // e.g. to tell rsa-sha2-256 from the legacy ssh-rsa some servers reject
+                log::debug!(
+                    session:% = self.session_id;
+                    "Upstream agent <{}> signed with key {} using algorithm {} (flags {:#x})",
+                    agent_sock_path.display(),
+                    &fingerprint,
+                    signature.algorithm(),
+                    request.flags
+                );
+            }
+            // Distinguish an agent that has the key but won't use it from one that's missing
+            result.map_err(|reason| {
+                if !is_upstream_failure(&reason) {
+                    return reason;
+                }
+                let refused = SignRefused {
+                    agent,
+                    fingerprint,
+                    reason,
+                };
+                log::warn!(session:% = self.session_id; "{}", refused);
+                AgentError::Other(Box::new(refused))
+            })
+        } else {
+            trace.steps.push("no upstream agent lists the key".into());
+            if self.options.sign_fallback_broadcast {
+                if let Some(result) = self.broadcast_sign(request, &fingerprint, trace).await {
+                    return result;
+                }
+            }
+            log::error!(
+                session:% = self.session_id;
+                "No upstream agent found for public key {}",
+                &fingerprint
+            );
+            log::trace!(session:% = self.session_id; "Known keys:\n{:#?}", self.known_keys);
+            Err(AgentError::Other(
+                format!("No agent found for public key: {}", &fingerprint).into(),
+            ))
+        }
+    }
+
+    /// Ask each agent in scope whose key filters expose the key to sign `request`, in configured
+    /// order, under [`MuxOptions::sign_fallback_broadcast`]; `None` if every agent failed or was
+    /// unreachable
+    async fn broadcast_sign(
+        &self,
+        request: &SignRequest,
+        fingerprint: &Fingerprint,
+        trace: &mut SignTrace,
+    ) -> Option<Result<Signature, AgentError>> {
+        if !self.exposes(&request.pubkey) {
+            trace.steps.push("hidden by key filters".into());
+            return None;
+        }
+        // No agent listed the key, so there's no comment to check it against
+        if self.options.comment_filter.is_some() {
+            trace
+                .steps
+                .push("not broadcasting under comment-filter".into());
+            return None;
+        }
+        let exposing =
+            |a: &&UpstreamAgent| self.agent_in_scope(a) && a.key_filter.exposes(&request.pubkey);
+        for agent in self.agents.iter().filter(exposing) {
+            log::info!(
+                session:% = self.session_id;
+                "Asking upstream agent {} to sign with unlisted key {} (sign-fallback-broadcast)",
+                agent.name,
+                fingerprint
+            );
+            let denial = self
+                .confirm_denial(&agent.socket_path, fingerprint, trace)
+                .await;
+            if let Some(denial) = denial {
+                trace.steps.push(denial.to_string());
+                let e = self.refuse(&format!("to sign with key {}", fingerprint), &denial);
+                return Some(Err(e));
+            }
+            trace.agent = Some(agent.name.clone());
+            match self.sign_with_agent(&agent.socket_path, request).await {
+                Ok(Ok(signature)) => {
+                    trace.steps.push(format!("{} signed", agent.name));
+                    return Some(Ok(signature));
+                }
+                Ok(Err(e)) if is_upstream_failure(&e) => {
+                    trace.steps.push(format!("{} failed: {}", agent.name, e));
+                }
+                Ok(Err(e)) => {
+                    trace.steps.push(format!("{} failed: {}", agent.name, e));
+                    return Some(Err(e));
+                }
+                Err(e) => trace
+                    .steps
+                    .push(format!("{} unreachable: {}", agent.name, e)),
+            }
+        }
+        None
+    }
+
+    fn refreshes(&self) -> std::sync::MutexGuard<'_, Refreshes> {
+        self.refreshes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Generation of the latest refresh; read before waiting on the known keys lock, to pass to
+    /// [`Self::refresh_identities_once`]
+    fn refresh_generation(&self) -> u64 {
+        self.refreshes().generation
+    }
+
+    /// Record a change to upstream agents' keys, so no refresh from before it is reused
+    fn note_keys_changed(&self) {
+        let mut refreshes = self.refreshes();
+        refreshes.changes += 1;
+        refreshes.latest = None;
+        refreshes.swept_at = None;
+        refreshes.answered_at = None;
+    }
+
+    /// Identities to advertise without connecting to upstream agents, under
+    /// [`MuxOptions::lazy_connect`]; `None` if they need refreshing first
+    fn lazy_identities(&self, known_keys: &KnownPubKeysMap) -> Option<Vec<Identity>> {
+        let refreshes = self.refreshes();
+        let swept_at = refreshes.swept_at?;
+        if known_keys.is_empty() || self.clock.now() - swept_at >= LAZY_CONNECT_REFRESH_INTERVAL {
+            return None;
+        }
+
+        let mut lists = vec![];
+        for agent in self.agents.iter().filter(|a| self.agent_in_scope(a)) {
+            if let Some(listed) = refreshes.listed.get(&agent.socket_path) {
+                lists.push((agent, listed.clone()));
+                continue;
+            }
+            // Keys from the cache, which doesn't record their comments or order, and may have been
+            // written before the key filters changed
+            let mut cached: Vec<_> = known_keys
+                .iter()
+                .filter(|(pubkey, sock_path)| {
+                    **sock_path == agent.socket_path && !self.is_hidden(pubkey, sock_path)
+                })
+                .map(|(pubkey, _)| Identity {
+                    pubkey: pubkey.clone(),
+                    comment: String::new(),
+                })
+                .collect();
+            cached.sort_by_cached_key(|id| id.pubkey.fingerprint(Default::default()).to_string());
+            lists.push((agent, cached));
+        }
+        let identities = offer_order(lists);
+        log::debug!(
+            session:% = self.session_id;
+            "Advertising {} known identities without refreshing (lazy-connect)",
+            identities.len()
+        );
+        Some(identities)
+    }
+
+    /// Refresh the identities of the agents in scope with [`UpstreamAgent::always_refresh`] set,
+    /// leaving those known from other agents alone; returns whether there were any
+    async fn refresh_volatile_agents(&self, known_keys: &mut KnownPubKeysMap) -> bool {
+        let mut queries = tokio::task::JoinSet::new();
+        for (slot, agent) in self.agents.iter().enumerate() {
+            if agent.always_refresh && self.agent_in_scope(agent) {
+                let (this, agent) = (self.clone(), agent.clone());
+                queries.spawn(async move { (slot, this.query_identities(&agent).await) });
+            }
+        }
+        if queries.is_empty() {
+            return false;
+        }
+        log::debug!(
+            session:% = self.session_id;
+            "Refreshing identities of {} always-refresh agents",
+            queries.len()
+        );
+
+        while let Some(result) = queries.join_next().await {
+            let (slot, agent_identities) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!(session:% = self.session_id; "Identity request task failed: {}", e);
+                    continue;
+                }
+            };
+            let sock_path = &self.agents[slot].socket_path;
+            known_keys.retain(|_, owner| owner != sock_path);
+            let mut refreshes = self.refreshes();
+            let Some(agent_identities) = agent_identities else {
+                refreshes.listed.remove(sock_path);
+                continue;
+            };
+            for id in &agent_identities {
+                self.claim_key(known_keys, &id.pubkey, &self.agents[slot]);
+            }
+            refreshes.listed.insert(sock_path.clone(), agent_identities);
+        }
+        true
     }
 
-    async fn get_agent_sock_for_pubkey(
-        &mut self,
+    /// Record `agent`, which lists `pubkey`, as the key's owner, unless another agent that lists
+    /// it too keeps it under [`MuxOptions::duplicate_key_policy`]
+    fn claim_key(
+        &self,
+        known_keys: &mut KnownPubKeysMap,
         pubkey: &PubKeyData,
-    ) -> Result<Option<PathBuf>, AgentError> {
-        // Refresh available identities if the public key isn't found;
-        // hold lock for duration of signing operation
-        let mut known_keys = self.known_keys.clone().lock_owned().await;
-        if !known_keys.contains_key(pubkey) {
-            log::debug!("Key not found, re-requesting keys from upstream agents");
-            let _ = self.refresh_identities(&mut known_keys).await?;
+        agent: &UpstreamAgent,
+    ) {
+        let slot_of =
+            |sock_path: &Path| self.agents.iter().position(|a| a.socket_path == sock_path);
+        let contested = known_keys
+            .get(pubkey)
+            .filter(|owner| **owner != agent.socket_path)
+            .and_then(|owner| Some((slot_of(owner)?, self.agent_name(owner))));
+        if let Some((owner_slot, owner)) = contested {
+            let policy = self.options.duplicate_key_policy;
+            let takes_over =
+                slot_of(&agent.socket_path).is_some_and(|slot| policy.prefers(slot, owner_slot));
+            let outcome = match (policy, takes_over) {
+                (DuplicateKeyPolicy::Error, _) => "refusing to sign with it".into(),
+                (_, true) => format!("routing it to {}", agent.name),
+                (_, false) => format!("routing it to {}", owner),
+            };
+            log::info!(
+                session:% = self.session_id;
+                "Key {} is listed by both upstream agents {} and {}; {} (duplicate-key-policy)",
+                pubkey.fingerprint(Default::default()),
+                owner,
+                agent.name,
+                outcome
+            );
+            if !takes_over {
+                return;
+            }
         }
-        let maybe_agent = known_keys.get(pubkey).cloned();
-        Ok(maybe_agent)
+        known_keys.insert(pubkey.clone(), agent.socket_path.clone());
     }
 
-    // Factored out so that the known_keys lock can be held across a total request that includes a
-    // refresh of keys from upstream agents
-    async fn refresh_identities(
-        &mut self,
+    /// Refresh identities, unless a refresh with the same scope completed since
+    /// `seen_generation`, i.e. while this session waited on the known keys lock: then take its
+    /// result
+    async fn refresh_identities_once(
+        &self,
         known_keys: &mut OwnedMutexGuard<KnownPubKeysMap>,
+        seen_generation: u64,
     ) -> Result<Vec<Identity>, AgentError> {
-        let mut identities = vec![];
-        known_keys.clear();
-
-        log::debug!("Refreshing identities");
-        for sock_path in &self.socket_paths {
-            let mut client = match self.connect_upstream_agent(sock_path).await {
-                Ok(c) => c,
-                Err(_) => {
-                    log::warn!(
-                        "Ignoring missing upstream agent socket: {}",
-                        sock_path.display()
+        {
+            let refreshes = self.refreshes();
+            if refreshes.generation != seen_generation {
+                if let Some((_, identities)) = refreshes
+                    .latest
+                    .as_ref()
+                    .filter(|(tags, _)| *tags == self.selected_tags)
+                {
+                    log::debug!(
+                        session:% = self.session_id;
+                        "Reusing {} identities from a concurrent refresh",
+                        identities.len()
                     );
-                    continue;
+                    return Ok(identities.clone());
                 }
-            };
-            let agent_identities: Vec<Identity> = match timeout(
-                self.agent_timeout,
-                client.request_identities(),
-            )
-            .await
-            {
+            }
+        }
+        self.refresh_identities(known_keys).await
+    }
+
+    /// Identities listed by `agent` that are usable and exposed, or `None` if it can't be reached
+    async fn query_identities(&self, agent: &UpstreamAgent) -> Option<Vec<Identity>> {
+        let sock_path = &agent.socket_path;
+        let mut client = match self.connect_upstream_agent(sock_path).await {
+            Ok(c) => c,
+            Err(e) => {
+                log::warn!(
+                    session:% = self.session_id;
+                    "Ignoring missing upstream agent socket: {}",
+                    sock_path.display()
+                );
+                self.record_outcome(sock_path, RefreshOutcome::ConnectFailed, 0, e.to_string());
+                return None;
+            }
+        };
+        let agent_identities: Vec<Identity> =
+            match timeout(self.options.agent_timeout, client.request_identities()).await {
                 Ok(Ok(ids)) => ids,
                 Ok(Err(e)) => {
                     log::warn!(
+                        session:% = self.session_id;
                         "Failed to request identities from upstream agent socket <{}>: {}",
                         sock_path.display(),
                         e
                     );
-                    continue;
+                    self.record_outcome(sock_path, RefreshOutcome::RequestFailed, 0, e.to_string());
+                    return None;
                 }
                 Err(_) => {
+                    Metrics::increment(&self.metrics.upstream_timeouts);
                     log::warn!(
+                        session:% = self.session_id;
                         "Request identities timed out on upstream agent: {}",
                         sock_path.display()
                     );
-                    continue;
+                    self.record_outcome(sock_path, RefreshOutcome::TimedOut, 0, String::new());
+                    return None;
+                }
+            };
+        // Skip malformed identities, so that one buggy agent can't pollute the key map, then
+        // those the agent's key filter hides
+        let agent_identities: Vec<Identity> = agent_identities
+            .into_iter()
+            .filter(|id| match validate_pubkey(&id.pubkey) {
+                Ok(()) => true,
+                Err(reason) => {
+                    log::warn!(
+                        session:% = self.session_id;
+                        "Ignoring malformed identity {:?} from upstream agent {}: {}",
+                        id.comment,
+                        agent.name,
+                        reason
+                    );
+                    false
+                }
+            })
+            .filter(|id| {
+                let exposed = agent.key_filter.exposes(&id.pubkey)
+                    && self.exposes(&id.pubkey)
+                    && self
+                        .options
+                        .comment_filter
+                        .as_ref()
+                        .map_or(true, |f| f.exposes(&id.comment));
+                if !exposed {
+                    log::trace!(
+                        session:% = self.session_id;
+                        "Hiding key {} of upstream agent {} by key filters",
+                        id.pubkey.fingerprint(Default::default()),
+                        agent.name
+                    );
                 }
+                exposed
+            })
+            .collect();
+        log::trace!(
+            session:% = self.session_id;
+            "Got {} identities from {}",
+            agent_identities.len(),
+            sock_path.display()
+        );
+        self.record_outcome(
+            sock_path,
+            RefreshOutcome::Ok,
+            agent_identities.len(),
+            String::new(),
+        );
+        Some(agent_identities)
+    }
+
+    fn record_outcome(
+        &self,
+        sock_path: &Path,
+        outcome: RefreshOutcome,
+        keys: usize,
+        detail: String,
+    ) {
+        let outcome = AgentOutcome {
+            at: self.clock.now(),
+            outcome,
+            keys,
+            detail,
+        };
+        self.agent_outcomes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(sock_path.to_path_buf(), outcome);
+    }
+
+    /// Response to `refresh-status@ssh-agent-mux`
+    fn refresh_status(&self) -> RefreshStatus {
+        let outcomes = self
+            .agent_outcomes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = self.clock.now();
+        let agents = self
+            .agents
+            .iter()
+            .map(|agent| match outcomes.get(&agent.socket_path) {
+                Some(o) => AgentRefreshStatus {
+                    name: agent.name.clone(),
+                    outcome: o.outcome,
+                    keys: o.keys.try_into().unwrap_or(u32::MAX),
+                    detail: o.detail.clone(),
+                    age_secs: (now - o.at).as_secs().try_into().unwrap_or(u32::MAX),
+                },
+                None => AgentRefreshStatus {
+                    name: agent.name.clone(),
+                    outcome: RefreshOutcome::NotRefreshed,
+                    keys: 0,
+                    detail: String::new(),
+                    age_secs: 0,
+                },
+            })
+            .collect();
+        RefreshStatus { agents }
+    }
+
+    /// Response to `list-upstreams@ssh-agent-mux`, after refreshing identities
+    async fn list_upstreams(&self) -> Result<ListUpstreams, AgentError> {
+        let mut known_keys = self.known_keys.clone().lock_owned().await;
+        self.refresh_identities(&mut known_keys).await?;
+        let refreshes = self.refreshes();
+        let agents = self
+            .agents
+            .iter()
+            .filter(|a| self.agent_in_scope(a))
+            .map(|agent| UpstreamKeys {
+                name: agent.name.clone(),
+                socket_path: agent.socket_path.display().to_string(),
+                fingerprints: refreshes
+                    .listed
+                    .get(&agent.socket_path)
+                    .into_iter()
+                    .flatten()
+                    .filter(|id| known_keys.get(&id.pubkey) == Some(&agent.socket_path))
+                    .map(|id| id.pubkey.fingerprint(Default::default()).to_string())
+                    .collect(),
+            })
+            .collect();
+        Ok(ListUpstreams { agents })
+    }
+
+    /// Whether the agent at `sock_path` currently lists `pubkey`, with a comment
+    /// [`MuxOptions::comment_filter`] exposes
+    async fn agent_lists_key(&self, sock_path: &Path, pubkey: &PubKeyData) -> bool {
+        let Ok(mut client) = self.connect_upstream_agent(sock_path).await else {
+            return false;
+        };
+        let exposed = |id: &Identity| {
+            &id.pubkey == pubkey
+                && self
+                    .options
+                    .comment_filter
+                    .as_ref()
+                    .map_or(true, |f| f.exposes(&id.comment))
+        };
+        timeout(self.options.agent_timeout, client.request_identities())
+            .await
+            .is_ok_and(|ids| ids.is_ok_and(|ids| ids.iter().any(exposed)))
+    }
+
+    /// Response to `sign-check@ssh-agent-mux`: every in-scope agent that lists and exposes
+    /// `pubkey` signs with it, one after another so that their timings are independent, once the
+    /// user approves it as for a sign request. The signatures aren't counted in the upstream sign
+    /// metrics.
+    async fn sign_check(&self, pubkey: &PubKeyData) -> SignCheckResults {
+        let fingerprint = pubkey.fingerprint(Default::default());
+        log::info!(
+            session:% = self.session_id;
+            "Checking signing with key {} on every upstream agent that lists it",
+            &fingerprint
+        );
+        let request = SignRequest {
+            pubkey: pubkey.clone(),
+            data: SIGN_CHECK_DATA.to_vec(),
+            flags: match pubkey.algorithm() {
+                Algorithm::Rsa { .. } => signature::RSA_SHA2_256,
+                _ => 0,
+            },
+        };
+
+        let mut agents = vec![];
+        if let Some(denial) = self.sign_denial(pubkey, &fingerprint).await {
+            log::info!(
+                session:% = self.session_id;
+                "Sign check with key {}: {}",
+                &fingerprint,
+                denial
+            );
+            let denied = denial.to_string();
+            return SignCheckResults { agents, denied };
+        }
+        if !self.exposes(pubkey) {
+            return SignCheckResults {
+                agents,
+                denied: String::new(),
             };
+        }
+        let mut trace = SignTrace::default();
+        for agent in self.agents.iter().filter(|a| self.agent_in_scope(a)) {
+            if !agent.key_filter.exposes(pubkey)
+                || !self.agent_lists_key(&agent.socket_path, pubkey).await
             {
-                for id in &agent_identities {
-                    known_keys.insert(id.pubkey.clone(), sock_path.clone());
+                continue;
+            }
+            let denial = self
+                .confirm_denial(&agent.socket_path, &fingerprint, &mut trace)
+                .await;
+            if let Some(denial) = denial {
+                agents.push(AgentSignCheck {
+                    name: agent.name.clone(),
+                    ok: false,
+                    detail: denial.to_string(),
+                    millis: 0,
+                });
+                continue;
+            }
+            let started = self.clock.now();
+            let result = self
+                .request_upstream_sign(&agent.socket_path, &request)
+                .await;
+            let elapsed = self.clock.now() - started;
+            let (ok, detail) = match result {
+                Ok(Ok(_)) => (true, String::new()),
+                Ok(Err(e)) | Err(e) => (false, e.to_string()),
+            };
+            log::debug!(
+                session:% = self.session_id;
+                "Sign check with key {} on upstream agent {:?}: {} in {:?}",
+                &fingerprint,
+                agent.name,
+                if ok { "ok" } else { &detail },
+                elapsed
+            );
+            agents.push(AgentSignCheck {
+                name: agent.name.clone(),
+                ok,
+                detail,
+                millis: elapsed.as_millis().try_into().unwrap_or(u32::MAX),
+            });
+        }
+        SignCheckResults {
+            agents,
+            denied: String::new(),
+        }
+    }
+
+    /// Ask every upstream agent in scope whether it lists `pubkey`, and record the one that does
+    /// as its owner (the one [`MuxOptions::duplicate_key_policy`] prefers, as in a refresh);
+    /// cheaper than a full refresh when only this key's recorded owner is stale
+    async fn relocate_key(&self, pubkey: &PubKeyData) {
+        let mut queries = tokio::task::JoinSet::new();
+        for (slot, agent) in self.agents.iter().enumerate() {
+            if self.agent_in_scope(agent) {
+                let (this, agent, pubkey) = (self.clone(), agent.clone(), pubkey.clone());
+                queries.spawn(async move {
+                    let listed = this.query_identities(&agent).await;
+                    (
+                        slot,
+                        listed.is_some_and(|ids| ids.iter().any(|id| id.pubkey == pubkey)),
+                    )
+                });
+            }
+        }
+        let mut owner_slot = None;
+        while let Some(result) = queries.join_next().await {
+            match result {
+                Ok((slot, true)) => {
+                    let policy = self.options.duplicate_key_policy;
+                    if owner_slot.map_or(true, |owner_slot| policy.prefers(slot, owner_slot)) {
+                        owner_slot = Some(slot);
+                    }
                 }
+                Ok((_, false)) => {}
+                Err(e) => log::error!(
+                    session:% = self.session_id;
+                    "Identity request task failed: {}",
+                    e
+                ),
             }
-            log::trace!(
-                "Got {} identities from {}",
-                agent_identities.len(),
-                sock_path.display()
+        }
+
+        let owner = owner_slot.map(|slot| self.agents[slot].socket_path.clone());
+        let previous = {
+            let mut known_keys = self.known_keys.lock().await;
+            let previous = known_keys.remove(pubkey);
+            if let Some(ref owner) = owner {
+                known_keys.insert(pubkey.clone(), owner.clone());
+            }
+            previous
+        };
+        if previous != owner {
+            log::info!(
+                session:% = self.session_id;
+                "Key {} moved from upstream agent <{}> to <{}>",
+                pubkey.fingerprint(Default::default()),
+                previous.as_deref().unwrap_or(Path::new("none")).display(),
+                owner.as_deref().unwrap_or(Path::new("none")).display()
             );
-            identities.extend(agent_identities);
+            self.note_keys_changed();
+        }
+    }
+
+    // Factored out so that the known_keys lock can be held across a total request that includes a
+    // refresh of keys from upstream agents
+    async fn refresh_identities(
+        &self,
+        known_keys: &mut OwnedMutexGuard<KnownPubKeysMap>,
+    ) -> Result<Vec<Identity>, AgentError> {
+        // Only agents in this session's scope are queried, so keep other agents' keys routable
+        // for other sessions
+        known_keys.retain(|_, sock_path| !self.socket_in_scope(sock_path));
+        self.routing_from_cache.store(false, Ordering::Relaxed);
+
+        log::debug!(session:% = self.session_id; "Refreshing identities");
+        let changes = self.refreshes().changes;
+        let started = self.clock.now();
+        Metrics::increment(&self.metrics.identity_refreshes);
+        // Query agents concurrently, but keep their identities in offer order: clients offer keys
+        // in the order listed, and may run out of authentication attempts before a key from a
+        // later agent
+        let mut queries = tokio::task::JoinSet::new();
+        for (slot, agent) in self.agents.iter().enumerate() {
+            if self.agent_in_scope(agent) {
+                let (this, agent) = (self.clone(), agent.clone());
+                queries.spawn(async move { (slot, this.query_identities(&agent).await) });
+            }
+        }
+        let queried = queries.len();
+        let mut slots = vec![None; self.agents.len()];
+        while let Some(result) = queries.join_next().await {
+            match result {
+                Ok((slot, agent_identities)) => slots[slot] = agent_identities,
+                Err(e) => log::error!(
+                    session:% = self.session_id;
+                    "Identity request task failed: {}",
+                    e
+                ),
+            }
+        }
+        let reachable = slots.iter().filter(|s| s.is_some()).count();
+        let mut listed = HashMap::new();
+        let mut lists = vec![];
+        for (agent, agent_identities) in self.agents.iter().zip(slots) {
+            let Some(agent_identities) = agent_identities else {
+                continue;
+            };
+            for id in &agent_identities {
+                self.claim_key(known_keys, &id.pubkey, agent);
+            }
+            listed.insert(agent.socket_path.clone(), agent_identities.clone());
+            lists.push((agent, agent_identities));
+        }
+        let identities = offer_order(lists);
+        log::debug!(
+            session:% = self.session_id;
+            "Refreshed {} identities in {:?}",
+            identities.len(),
+            self.clock.now() - started
+        );
+
+        {
+            let mut refreshes = self.refreshes();
+            refreshes.generation += 1;
+            let unchanged = refreshes.changes == changes;
+            refreshes.latest = unchanged.then(|| (self.selected_tags.clone(), identities.clone()));
+            // Agents that failed this refresh are asked again on the next one
+            for agent in self.agents.iter().filter(|a| self.agent_in_scope(a)) {
+                refreshes.listed.remove(&agent.socket_path);
+            }
+            refreshes.listed.extend(listed);
+            refreshes.swept_at = unchanged.then(|| self.clock.now());
+            refreshes.answered_at = (unchanged && reachable == queried)
+                .then(|| (self.selected_tags.clone(), self.clock.now()));
+            refreshes.reachable = reachable;
         }
 
         Ok(identities)
@@ -396,9 +3734,113 @@ impl Agent<SelfDeletingUnixListener> for MuxAgent {
     #[doc = "Create new session object when a new socket is accepted."]
     fn new_session(
         &mut self,
-        _socket: &<SelfDeletingUnixListener as ListeningSocket>::Stream,
+        socket: &<SelfDeletingUnixListener as ListeningSocket>::Stream,
     ) -> impl Session {
-        self.clone()
+        // Only the first session of a `once` mux gets it; the listener accepts no others
+        let once_served = self.once_served.take();
+        let peer = socket
+            .peer_cred()
+            .inspect_err(|e| log::warn!("Failed to read client credentials: {}", e))
+            .ok()
+            .map(|cred| Peer {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            });
+        let session = Self {
+            session_id: SessionId::new(),
+            once_served,
+            peer,
+            ..self.clone()
+        };
+        log::debug!(session:% = session.session_id; "Accepted client connection");
+        session
+    }
+}
+
+/// Name of the kind of `request`, for logs; unlike its `Debug` form, it has no key material
+fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::RequestIdentities => "request_identities",
+        Request::SignRequest(_) => "sign",
+        Request::AddIdentity(_) => "add_identity",
+        Request::RemoveIdentity(_) => "remove_identity",
+        Request::RemoveAllIdentities => "remove_all_identities",
+        Request::AddSmartcardKey(_) => "add_smartcard_key",
+        Request::RemoveSmartcardKey(_) => "remove_smartcard_key",
+        Request::Lock(_) => "lock",
+        Request::Unlock(_) => "unlock",
+        Request::AddIdConstrained(_) => "add_identity_constrained",
+        Request::AddSmartcardKeyConstrained(_) => "add_smartcard_key_constrained",
+        Request::Extension(_) => "extension",
+    }
+}
+
+/// Resolves to `None` instead of panicking if the wrapped future panics
+struct CatchUnwind<F>(F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.0).poll(cx))) {
+            Ok(poll) => poll.map(Some),
+            Err(_) => Poll::Ready(None),
+        }
+    }
+}
+
+/// Short random identifier of a client connection, included in its log lines so that those of
+/// concurrent connections can be told apart
+#[derive(Clone, Copy, Debug, Default)]
+struct SessionId(u32);
+
+impl SessionId {
+    fn new() -> Self {
+        // Each RandomState is seeded differently, so hashing anything yields a fresh random value
+        Self(RandomState::new().hash_one(()) as u32 & 0xff_ffff)
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:06x}", self.0)
+    }
+}
+
+/// Random identifier of a running mux, for [`MuxIdentity`] probes
+#[derive(Clone, Copy, Debug)]
+struct InstanceId(u64);
+
+impl InstanceId {
+    fn new() -> Self {
+        Self(RandomState::new().hash_one(()))
+    }
+}
+
+impl std::fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Aborts a background task when dropped, tying its lifetime to the owner's
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+/// Wait before the next background refresh, after one that waited `delay` and reached
+/// `reachable` agents: double it while none are reachable, up to a cap, and otherwise return to
+/// `interval`
+fn background_refresh_delay(interval: Duration, delay: Duration, reachable: usize) -> Duration {
+    if reachable > 0 {
+        interval
+    } else {
+        (delay * 2).min(BACKGROUND_REFRESH_MAX_BACKOFF.max(interval))
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
     }
 }
 
@@ -406,24 +3848,226 @@ impl Agent<SelfDeletingUnixListener> for MuxAgent {
 /// A wrapper for UnixListener that keeps the socket path around so it can be deleted
 struct SelfDeletingUnixListener {
     path: PathBuf,
+    /// Device and inode of the socket file this listener created, so that it only deletes that
+    /// file, not one a restarted mux created at the same path after removing this one
+    file_id: Option<(u64, u64)>,
     listener: UnixListener,
+    /// Accept only one connection
+    once: bool,
+    accepted: bool,
+    /// Close connections from other clients right away; `None` accepts any
+    peer_uids: Option<PeerUids>,
+    /// Passed by systemd, which owns the socket file, so it isn't deleted
+    inherited: bool,
+    _registration: ListenPathRegistration,
+}
+
+// sd_listen_fds(3)
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// Take the listening socket systemd passed through `LISTEN_FDS` and `LISTEN_PID`, if any, for
+/// [`MuxAgent::run`] to listen on instead of its listen path; `run` takes it on first use
+/// otherwise. The variables are left set: a caller unsetting them, as `sd_listen_fds` does so
+/// that they don't reach child processes, calls this first, while the process has a single
+/// thread, since changing the environment isn't safe once other threads run.
+pub fn init_socket_activation() {
+    activation_socket();
+}
+
+/// The listening socket passed by systemd socket activation, if any; taken from the environment
+/// on first use and kept open, so that each listener on it can be dropped, closing its own copy
+fn activation_socket() -> Option<&'static std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    static SOCKET: std::sync::OnceLock<Option<std::os::fd::OwnedFd>> = std::sync::OnceLock::new();
+    SOCKET
+        .get_or_init(|| {
+            let pid = std::env::var("LISTEN_PID").ok()?;
+            // Otherwise meant for another process, e.g. a parent that didn't unset them
+            if pid.parse() != Ok(std::process::id()) {
+                return None;
+            }
+            let fds = std::env::var("LISTEN_FDS").ok()?;
+            match fds.parse::<u32>() {
+                Ok(0) | Err(_) => return None,
+                Ok(1) => {}
+                Ok(n) => log::warn!("systemd passed {} sockets; only listening on the first", n),
+            }
+            // SAFETY: systemd passes the sockets as the descriptors starting at
+            // SD_LISTEN_FDS_START, and nothing else in the process takes them over
+            Some(unsafe { std::os::fd::OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) })
+        })
+        .as_ref()
+}
+
+/// Users whose processes may connect to the mux
+#[derive(Debug, Clone, Copy)]
+struct PeerUids {
+    own: u32,
+}
+
+impl PeerUids {
+    fn own() -> Self {
+        // SAFETY: geteuid has no preconditions and can't fail
+        let own = unsafe { libc::geteuid() };
+        Self { own }
+    }
+
+    /// Like ssh-agent, also lets root connect, since it could take over the mux's socket anyway
+    fn allows(&self, uid: u32) -> bool {
+        uid == self.own || uid == 0
+    }
+}
+
+/// Listen paths of the muxes running in this process
+static LISTEN_PATHS: std::sync::Mutex<Vec<PathBuf>> = std::sync::Mutex::new(Vec::new());
+
+/// A path in [`LISTEN_PATHS`], removed when dropped
+#[derive(Debug)]
+struct ListenPathRegistration(PathBuf);
+
+impl ListenPathRegistration {
+    /// Register `path`, unless another mux in this process already listens on it. Its socket
+    /// file would make binding fail anyway, but with an error that suggests another process.
+    fn register(path: &Path) -> std::io::Result<Self> {
+        let path = std::path::absolute(path)?;
+        let mut paths = LISTEN_PATHS.lock().unwrap_or_else(PoisonError::into_inner);
+        if paths.contains(&path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!(
+                    "another mux in this process already listens on {}",
+                    path.display()
+                ),
+            ));
+        }
+        paths.push(path.clone());
+        Ok(Self(path))
+    }
+}
+
+impl Drop for ListenPathRegistration {
+    fn drop(&mut self) {
+        LISTEN_PATHS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|p| *p != self.0);
+    }
+}
+
+fn socket_file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|m| (m.dev(), m.ino()))
 }
 
 impl SelfDeletingUnixListener {
-    fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
+    /// Bind a socket at `path`, with permissions `mode` regardless of the umask
+    fn bind(path: impl AsRef<Path>, mode: u32) -> std::io::Result<Self> {
         let path = path.as_ref().to_path_buf();
 
         // Create parent directories if they don't exist
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
+        let parent = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot create socket directory {}: {}; check permissions",
+                    parent.display(),
+                    e
+                ),
+            )
+        })?;
+
+        let registration = ListenPathRegistration::register(&path)?;
+        let listener = UnixListener::bind(&path)
+            .map(|listener| Self {
+                file_id: socket_file_id(&path),
+                path: path.clone(),
+                listener,
+                once: false,
+                accepted: false,
+                peer_uids: None,
+                inherited: false,
+                _registration: registration,
+            })
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::PermissionDenied => std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "cannot create socket in directory {}: {}; check permissions",
+                        parent.display(),
+                        e
+                    ),
+                ),
+                _ => e,
+            })?;
+        // Set after binding, not through the umask, which is process-wide and so would also apply
+        // to files other threads create meanwhile; until then, clients of other users are still
+        // rejected on accept, unless allow-any-peer is set
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("cannot set permissions of socket {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(listener)
+    }
 
-        UnixListener::bind(&path).map(|listener| Self { path, listener })
+    /// Listen on a copy of `fd`, an already listening socket passed by systemd
+    fn inherit(fd: &std::os::fd::OwnedFd) -> std::io::Result<Self> {
+        let listener = std::os::unix::net::UnixListener::from(fd.try_clone()?);
+        let not_unix = |e: std::io::Error| {
+            std::io::Error::new(
+                e.kind(),
+                format!("the socket passed by systemd isn't a Unix socket: {}", e),
+            )
+        };
+        let addr = listener.local_addr().map_err(not_unix)?;
+        let path = addr.as_pathname().map(Path::to_path_buf).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the socket passed by systemd has no path",
+            )
+        })?;
+        log::info!("Listening on socket {}, passed by systemd", path.display());
+        let registration = ListenPathRegistration::register(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            file_id: None,
+            path,
+            listener: UnixListener::from_std(listener)?,
+            once: false,
+            accepted: false,
+            peer_uids: None,
+            inherited: true,
+            _registration: registration,
+        })
     }
 }
 
 impl Drop for SelfDeletingUnixListener {
     fn drop(&mut self) {
+        if self.inherited {
+            log::debug!(
+                "Leaving socket {} to systemd, which created it",
+                self.path.display()
+            );
+            return;
+        }
+        // There's no way to unlink a path only if it's still a given file, so a socket created
+        // between this check and the removal is still removed; that window is much shorter than
+        // the time a stopping mux takes to get here
+        if self.file_id.is_none() || socket_file_id(&self.path) != self.file_id {
+            log::debug!(
+                "Not cleaning up socket {}; it was replaced since the mux created it",
+                self.path.display()
+            );
+            return;
+        }
         log::debug!("Cleaning up socket {}", self.path.display());
         let _ = std::fs::remove_file(&self.path);
     }
@@ -434,8 +4078,240 @@ impl ListeningSocket for SelfDeletingUnixListener {
     type Stream = tokio::net::UnixStream;
 
     async fn accept(&mut self) -> std::io::Result<Self::Stream> {
-        UnixListener::accept(&self.listener)
-            .await
-            .map(|(s, _addr)| s)
+        if self.once && self.accepted {
+            return std::future::pending().await;
+        }
+        loop {
+            let stream = UnixListener::accept(&self.listener).await?.0;
+            if let Some(peer_uids) = self.peer_uids {
+                // Dropping the stream closes the connection before the client can send anything
+                match stream.peer_cred() {
+                    Ok(cred) if peer_uids.allows(cred.uid()) => {}
+                    Ok(cred) => {
+                        log::warn!(
+                            "Rejecting connection from uid {} (pid {}), which isn't the mux's \
+                             own uid {}; set allow-any-peer to accept it",
+                            cred.uid(),
+                            cred.pid().map_or("unknown".into(), |pid| pid.to_string()),
+                            peer_uids.own
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Rejecting connection whose client credentials couldn't be read: {}",
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            self.accepted = true;
+            return Ok(stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_agent_lib::client;
+
+    use super::*;
+
+    #[test]
+    fn test_background_refresh_backoff() {
+        let interval = Duration::from_secs(60);
+        let mut delay = interval;
+        let mut delays = vec![];
+        for _ in 0..6 {
+            delay = background_refresh_delay(interval, delay, 0);
+            delays.push(delay.as_secs());
+        }
+        assert_eq!(delays, [120, 240, 480, 600, 600, 600]);
+        assert_eq!(background_refresh_delay(interval, delay, 1), interval);
+
+        // A configured interval above the cap isn't shortened
+        let interval = Duration::from_secs(3600);
+        assert_eq!(background_refresh_delay(interval, interval, 0), interval);
+    }
+
+    #[test]
+    fn test_cache_ttl_expires() {
+        let clock = Arc::new(clock::TestClock::new());
+        let options = MuxOptions {
+            cache_ttl: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut agent = MuxAgent::new(vec![], vec![], options);
+        agent.clock = clock.clone();
+        assert!(!agent.refreshed_within_ttl());
+
+        agent.refreshes().answered_at = Some((None, clock.now()));
+        clock.advance(Duration::from_secs(59));
+        assert!(agent.refreshed_within_ttl());
+        // Only a refresh with the session's own scope counts
+        agent.selected_tags = Some(vec!["work".into()]);
+        assert!(!agent.refreshed_within_ttl());
+        agent.selected_tags = None;
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!agent.refreshed_within_ttl());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_session_keeps_shared_state_usable() {
+        assert_eq!(CatchUnwind(Box::pin(async { 1 })).await, Some(1));
+        assert_eq!(
+            CatchUnwind(Box::pin(async { panic!("session bug") })).await,
+            None::<()>
+        );
+
+        // Shared state a panicking session held locked stays usable by other sessions
+        let mux = MuxAgent::new(vec![], vec![], MuxOptions::default());
+        let session = mux.clone();
+        let _ = CatchUnwind(Box::pin(async move {
+            let _refreshes = session.refreshes();
+            panic!("session bug");
+        }))
+        .await;
+        assert!(mux.refreshes.is_poisoned());
+        mux.note_keys_changed();
+        assert_eq!(mux.refreshes().changes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_listener_only_removes_its_own_socket() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mux.sock");
+
+        // A restarted mux removes the old socket and binds its own before the old one is dropped
+        let old = SelfDeletingUnixListener::bind(&path, 0o600)?;
+        std::fs::remove_file(&path)?;
+        let _new = std::os::unix::net::UnixListener::bind(&path)?;
+        drop(old);
+        assert!(path.exists());
+        tokio::net::UnixStream::connect(&path).await?;
+
+        // The path is free to bind again right away once its socket is gone
+        std::fs::remove_file(&path)?;
+        let rebound = SelfDeletingUnixListener::bind(&path, 0o600)?;
+        drop(rebound);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listener_socket_mode() -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mux.sock");
+        for mode in [0o600, 0o660] {
+            let listener = SelfDeletingUnixListener::bind(&path, mode)?;
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, mode);
+            drop(listener);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_second_mux_on_same_listen_path_fails() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mux.sock");
+        let run = || MuxAgent::run(path.clone(), vec![], vec![], MuxOptions::default());
+
+        let first = tokio::spawn(run());
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let err = run().await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("another mux in this process already listens on"),
+            "{}",
+            err
+        );
+        // The first mux's socket is left alone
+        tokio::net::UnixStream::connect(&path).await?;
+
+        first.abort();
+        let _ = first.await;
+        let again = tokio::spawn(run());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!again.is_finished());
+        again.abort();
+        Ok(())
+    }
+
+    /// Denies every sign request, recording what it was asked
+    #[derive(Debug, Default)]
+    struct DenySign(std::sync::Mutex<Vec<(Fingerprint, Option<Peer>)>>);
+
+    #[policy::async_trait]
+    impl RequestPolicy for DenySign {
+        async fn allow_sign(&self, fingerprint: &Fingerprint, peer: Option<&Peer>) -> Decision {
+            self.0.lock().unwrap().push((*fingerprint, peer.copied()));
+            Decision::Deny("test policy".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_denies_sign() -> Result<(), AgentError> {
+        use ssh_agent_lib::ssh_key::public::Ed25519PublicKey;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mux.sock");
+        let policy = Arc::new(DenySign::default());
+        let options = MuxOptions {
+            policy: policy.clone(),
+            sign_check: true,
+            ..Default::default()
+        };
+        let mux = AbortOnDrop(tokio::spawn(async move {
+            let _ = MuxAgent::run(&path, vec![], vec![], options).await;
+        }));
+        let path = dir.path().join("mux.sock");
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let stream = tokio::net::UnixStream::connect(&path).await?;
+        let mut client = client::connect(stream.into_std()?.into())
+            .map_err(|e| AgentError::Other(e.to_string().into()))?;
+        let pubkey = PubKeyData::Ed25519(Ed25519PublicKey([7; 32]));
+        let request = SignRequest {
+            pubkey: pubkey.clone(),
+            data: b"data".to_vec(),
+            flags: 0,
+        };
+        assert!(client.sign(request).await.is_err());
+        let response = client
+            .extension(Extension::new_message(SignCheck {
+                pubkey: pubkey.clone(),
+            })?)
+            .await?
+            .expect("sign check has a response");
+        let results = response.parse_message::<SignCheckResults>()?.unwrap();
+        assert_eq!(results.denied, "policy denied (policy): test policy");
+
+        let asked = policy.0.lock().unwrap().clone();
+        assert_eq!(asked.len(), 2);
+        assert_eq!(asked[0].0, pubkey.fingerprint(Default::default()));
+        assert_eq!(
+            asked[0].1.map(|p| p.uid),
+            Some(std::fs::metadata(&path)?.uid())
+        );
+        drop(mux);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_id_is_six_hex_digits() {
+        for _ in 0..100 {
+            let id = SessionId::new().to_string();
+            assert_eq!(id.len(), 6, "{}", id);
+            assert!(id.chars().all(|c| c.is_ascii_hexdigit()), "{}", id);
+        }
     }
 }
@@ -0,0 +1,400 @@
+//! Activity counters for a [`MuxAgent`](crate::MuxAgent), with an optional HTTP endpoint that
+//! serves them in the Prometheus text exposition format, alongside health and readiness probes
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+};
+
+use crate::KnownPubKeys;
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub sign_requests: AtomicU64,
+    pub sign_failures: AtomicU64,
+    pub identity_refreshes: AtomicU64,
+    pub upstream_timeouts: AtomicU64,
+    /// Outcomes of the sign requests sent to each upstream agent, by agent name
+    upstream_signs: Mutex<BTreeMap<String, UpstreamSigns>>,
+    /// The mux's known keys, once it has them, to report how many there are
+    pub known_keys: OnceLock<KnownPubKeys>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct UpstreamSigns {
+    successes: u64,
+    failures: u64,
+}
+
+impl Metrics {
+    /// Counters for the agents called `agent_names`, so that each is reported from the start
+    pub fn with_agents(agent_names: impl IntoIterator<Item = String>) -> Self {
+        let upstream_signs = agent_names
+            .into_iter()
+            .map(|name| (name, Default::default()));
+        Self {
+            upstream_signs: Mutex::new(upstream_signs.collect()),
+            ..Default::default()
+        }
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a sign request sent to the upstream agent called `agent`, and whether it signed
+    pub fn count_upstream_sign(&self, agent: &str, signed: bool) {
+        let mut upstream_signs = self
+            .upstream_signs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let counts = upstream_signs.entry(agent.to_owned()).or_default();
+        if signed {
+            counts.successes += 1;
+        } else {
+            counts.failures += 1;
+        }
+    }
+
+    /// Render all counters in the Prometheus text format, along with the number of known keys, if
+    /// given
+    #[cfg_attr(not(feature = "http-metrics"), allow(dead_code))]
+    pub fn render(&self, known_keys: Option<usize>) -> String {
+        let counters = [
+            (
+                "sign_requests_total",
+                "Sign requests received from clients",
+                &self.sign_requests,
+            ),
+            (
+                "sign_failures_total",
+                "Sign requests that returned an error to the client",
+                &self.sign_failures,
+            ),
+            (
+                "identity_refreshes_total",
+                "Refreshes of identities from upstream agents",
+                &self.identity_refreshes,
+            ),
+            (
+                "upstream_timeouts_total",
+                "Operations on upstream agents that timed out",
+                &self.upstream_timeouts,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            let name = format!("ssh_agent_mux_{name}");
+            // Writing to a String can't fail
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let upstream_signs = self
+            .upstream_signs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut per_agent = |name: &str, help: &str, value: fn(&UpstreamSigns) -> u64| {
+            let name = format!("ssh_agent_mux_{name}");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (agent, counts) in upstream_signs.iter() {
+                let agent = label_value(agent);
+                let _ = writeln!(out, "{name}{{agent=\"{agent}\"}} {}", value(counts));
+            }
+        };
+        per_agent(
+            "upstream_sign_successes_total",
+            "Sign requests sent to an upstream agent that it signed",
+            |counts| counts.successes,
+        );
+        per_agent(
+            "upstream_sign_failures_total",
+            "Sign requests sent to an upstream agent that it refused, failed, or timed out on",
+            |counts| counts.failures,
+        );
+
+        if let Some(known_keys) = known_keys {
+            let name = "ssh_agent_mux_known_keys";
+            let _ = writeln!(
+                out,
+                "# HELP {name} Keys the mux knows the upstream agent of"
+            );
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {known_keys}");
+        }
+        out
+    }
+}
+
+/// `value` escaped for a label value in the Prometheus text format
+#[cfg_attr(not(feature = "http-metrics"), allow(dead_code))]
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(feature = "http-metrics")]
+pub(crate) mod http {
+    use std::{
+        io,
+        net::SocketAddr,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpSocket, TcpStream},
+        sync::Mutex,
+        time::timeout,
+    };
+
+    use super::Metrics;
+    use crate::socket::UpstreamStream;
+
+    // Requests larger than this are rejected; scrapers send only a short request line and headers
+    const MAX_REQUEST_LEN: usize = 8192;
+
+    /// How long a readiness check result is reused, so that frequent probes don't each connect to
+    /// every upstream agent
+    const READINESS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Readiness of the mux: whether any upstream agent's socket accepts connections, or the mux
+    /// has a builtin agent
+    pub struct Readiness {
+        agent_socks: Vec<PathBuf>,
+        has_builtin: bool,
+        connect_timeout: Duration,
+        /// Time and result of the latest check; held while checking, so that concurrent probes
+        /// share one check
+        latest: Mutex<Option<(Instant, bool)>>,
+    }
+
+    impl Readiness {
+        pub fn new(
+            agent_socks: Vec<PathBuf>,
+            has_builtin: bool,
+            connect_timeout: Duration,
+        ) -> Self {
+            Self {
+                agent_socks,
+                has_builtin,
+                connect_timeout,
+                latest: Default::default(),
+            }
+        }
+
+        async fn ready(&self) -> bool {
+            let mut latest = self.latest.lock().await;
+            if let Some((at, ready)) = *latest {
+                if at.elapsed() < READINESS_CHECK_INTERVAL {
+                    return ready;
+                }
+            }
+            let ready = self.check().await;
+            *latest = Some((Instant::now(), ready));
+            ready
+        }
+
+        /// Only connects, rather than making a request, to keep the check cheap for the agents
+        async fn check(&self) -> bool {
+            if self.has_builtin {
+                return true;
+            }
+            for sock in &self.agent_socks {
+                match timeout(self.connect_timeout, UpstreamStream::connect(sock)).await {
+                    Ok(Ok(_)) => return true,
+                    Ok(Err(e)) => log::debug!("Readiness: <{}> unreachable: {}", sock.display(), e),
+                    Err(_) => log::debug!("Readiness: <{}> timed out", sock.display()),
+                }
+            }
+            false
+        }
+    }
+
+    /// Bind a listener on `addr` that a restarted or reloaded mux can rebind right away, even
+    /// while connections to the previous listener linger in `TIME_WAIT`.
+    ///
+    /// `SO_REUSEPORT` is deliberately not set: it would let any other process running as the same
+    /// user bind the same port alongside the mux and receive a share of its connections.
+    pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        socket.listen(128)
+    }
+
+    /// Serve `GET /metrics`, `/healthz`, and `/readyz` on `listener` until the task is aborted
+    pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, readiness: Arc<Readiness>) {
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            let readiness = readiness.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(&mut stream, &metrics, &readiness).await {
+                    log::debug!("Error serving metrics to {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        stream: &mut TcpStream,
+        metrics: &Metrics,
+        readiness: &Readiness,
+    ) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "incomplete or oversized HTTP request",
+                ));
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let request_line = String::from_utf8_lossy(&request);
+        let mut parts = request_line.split_whitespace();
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                let known_keys = match metrics.known_keys.get() {
+                    Some(known_keys) => Some(known_keys.lock().await.len()),
+                    None => None,
+                };
+                (
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    metrics.render(known_keys),
+                )
+            }
+            // The mux is serving this request, so it's alive
+            (Some("GET"), Some("/healthz")) => ("200 OK", "text/plain", "ok\n".into()),
+            (Some("GET"), Some("/readyz")) => match readiness.ready().await {
+                true => ("200 OK", "text/plain", "ready\n".into()),
+                false => (
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "no upstream agent reachable\n".into(),
+                ),
+            },
+            (Some("GET"), _) => ("404 Not Found", "text/plain", "Not Found\n".into()),
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain",
+                "Method Not Allowed\n".into(),
+            ),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_rebind_after_connection_in_time_wait() -> io::Result<()> {
+            let listener = bind("127.0.0.1:0".parse().unwrap())?;
+            let addr = listener.local_addr()?;
+
+            let client = TcpStream::connect(addr).await?;
+            let (mut server_side, _) = listener.accept().await?;
+            // Closing the server side first leaves its end of the connection in TIME_WAIT
+            server_side.shutdown().await?;
+            drop(server_side);
+            drop(client);
+            drop(listener);
+
+            bind(addr).map(drop)
+        }
+
+        #[test]
+        fn test_render_upstream_signs() {
+            let metrics = Metrics::with_agents(["idle".to_owned(), "say \"hi\"".to_owned()]);
+            metrics.count_upstream_sign("say \"hi\"", true);
+            metrics.count_upstream_sign("new", false);
+
+            let rendered = metrics.render(Some(3));
+            let successes = "ssh_agent_mux_upstream_sign_successes_total";
+            assert!(rendered.contains(&format!("\n{successes}{{agent=\"idle\"}} 0\n")));
+            assert!(rendered.contains(&format!("\n{successes}{{agent=\"say \\\"hi\\\"\"}} 1\n")));
+            assert!(
+                rendered.contains("_failures_total{agent=\"new\"} 1\n"),
+                "{}",
+                rendered
+            );
+            assert!(
+                rendered.ends_with("\nssh_agent_mux_known_keys 3\n"),
+                "{}",
+                rendered
+            );
+            assert!(!metrics.render(None).contains("known_keys"));
+        }
+
+        #[tokio::test]
+        async fn test_readiness_needs_a_reachable_agent() -> io::Result<()> {
+            let dir = tempfile::tempdir()?;
+            let missing = dir.path().join("missing.sock");
+            let present = dir.path().join("present.sock");
+            let _listener = tokio::net::UnixListener::bind(&present)?;
+            let timeout = Duration::from_secs(1);
+
+            assert!(!Readiness::new(vec![], false, timeout).ready().await);
+            assert!(
+                !Readiness::new(vec![missing.clone()], false, timeout)
+                    .ready()
+                    .await
+            );
+            assert!(
+                Readiness::new(vec![missing.clone(), present], false, timeout)
+                    .ready()
+                    .await
+            );
+            assert!(Readiness::new(vec![missing], true, timeout).ready().await);
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_readiness_is_cached() -> io::Result<()> {
+            let dir = tempfile::tempdir()?;
+            let sock = dir.path().join("agent.sock");
+            let readiness = Readiness::new(vec![sock.clone()], false, Duration::from_secs(1));
+            assert!(!readiness.ready().await);
+
+            // Reachable now, but the earlier result still stands
+            let _listener = tokio::net::UnixListener::bind(&sock)?;
+            assert!(!readiness.ready().await);
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Hook for embedders to allow or deny client requests with their own policy, e.g. by asking an
+//! external authorization service; see [`MuxOptions::policy`](crate::MuxOptions::policy)
+
+use std::fmt;
+
+pub use ssh_agent_lib::async_trait;
+use ssh_agent_lib::ssh_key::Fingerprint;
+
+/// The client process on the other end of a connection to the mux, from its socket credentials
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not available on every platform
+    pub pid: Option<i32>,
+}
+
+/// Whether to handle a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Fail the request without reaching any upstream agent, logging the reason
+    Deny(String),
+}
+
+/// Why the mux itself refused a request, without asking any upstream agent. Every refusal is
+/// logged as `policy denied (<tag>): <detail>`, and reported in the same form in
+/// [`SignCheckResults::denied`](crate::extensions::SignCheckResults::denied), or as a
+/// [`Denied`](crate::extensions::Denied) reply to the mux's own extensions; the tags are stable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenialReason {
+    /// `operation-not-allowed`: the request isn't one of
+    /// [`MuxOptions::allowed_operations`](crate::MuxOptions::allowed_operations)
+    OperationNotAllowed,
+    /// `constraint-required`: the key wasn't added with a constraint that
+    /// [`MuxOptions::require_constraints`](crate::MuxOptions::require_constraints) requires
+    ConstraintRequired,
+    /// `policy`: the [`RequestPolicy`] denied it
+    Policy,
+    /// `not-confirmed`: the user didn't approve the request when asked with
+    /// [`MuxOptions::confirm_command`](crate::MuxOptions::confirm_command)
+    NotConfirmed,
+    /// `not-enabled`: the mux's own extension isn't enabled, like `sign-check@ssh-agent-mux`
+    /// without [`MuxOptions::sign_check`](crate::MuxOptions::sign_check)
+    NotEnabled,
+}
+
+impl DenialReason {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::OperationNotAllowed => "operation-not-allowed",
+            Self::ConstraintRequired => "constraint-required",
+            Self::Policy => "policy",
+            Self::NotConfirmed => "not-confirmed",
+            Self::NotEnabled => "not-enabled",
+        }
+    }
+}
+
+impl fmt::Display for DenialReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+/// Policy consulted before the mux handles a client request, after its own checks (such as
+/// [`MuxOptions::allowed_operations`](crate::MuxOptions::allowed_operations)) have passed. Every
+/// method allows by default. `peer` is `None` if the client's credentials couldn't be read.
+///
+/// Implement it with [`async_trait`]:
+///
+/// ```
+/// use ssh_agent_mux::policy::{async_trait, Decision, Peer, RequestPolicy};
+/// use ssh_agent_lib::ssh_key::Fingerprint;
+///
+/// /// Only lets processes of one user sign
+/// #[derive(Debug)]
+/// struct OnlyUser(u32);
+///
+/// #[async_trait]
+/// impl RequestPolicy for OnlyUser {
+///     async fn allow_sign(&self, _fingerprint: &Fingerprint, peer: Option<&Peer>) -> Decision {
+///         match peer {
+///             Some(peer) if peer.uid == self.0 => Decision::Allow,
+///             _ => Decision::Deny(format!("client isn't user {}", self.0)),
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait RequestPolicy: fmt::Debug + Send + Sync {
+    /// Whether to sign with the key with `fingerprint`
+    async fn allow_sign(&self, fingerprint: &Fingerprint, peer: Option<&Peer>) -> Decision {
+        let _ = (fingerprint, peer);
+        Decision::Allow
+    }
+
+    /// Whether to add the key with `fingerprint` to the upstream agents keys are added to
+    async fn allow_add(&self, fingerprint: &Fingerprint, peer: Option<&Peer>) -> Decision {
+        let _ = (fingerprint, peer);
+        Decision::Allow
+    }
+}
+
+/// The default policy, which allows every request
+#[derive(Debug, Default)]
+pub struct AllowAll;
+
+impl RequestPolicy for AllowAll {}
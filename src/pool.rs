@@ -0,0 +1,205 @@
+//! Connections to upstream agents kept open between requests; see
+//! [`MuxOptions::reuse_connections`](crate::MuxOptions::reuse_connections)
+
+use std::{
+    collections::HashMap,
+    os::fd::{AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use ssh_agent_lib::{
+    agent::Session,
+    error::AgentError,
+    proto::{
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+        RemoveIdentity, SignRequest, SmartcardKey,
+    },
+    ssh_key::Signature,
+};
+
+/// Idle connections kept per upstream agent; a burst of concurrent requests opens more, which are
+/// closed once done
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// An open connection to an upstream agent
+pub(crate) struct Connection {
+    client: Box<dyn Session>,
+    /// Another handle on the client's socket, to check that the agent hasn't closed it while idle
+    socket: OwnedFd,
+}
+
+impl Connection {
+    pub(crate) fn new(client: Box<dyn Session>, socket: OwnedFd) -> Self {
+        Self { client, socket }
+    }
+
+    /// Whether the connection can take a request: an idle connection to an agent has nothing to
+    /// read, unless the agent closed it
+    fn is_idle(&self) -> bool {
+        let mut poll_fd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: polls the one descriptor, which the socket keeps open, without waiting
+        unsafe { libc::poll(&mut poll_fd, 1, 0) == 0 }
+    }
+}
+
+/// Idle connections to the upstream agents, by socket path. Each is handed to one request at a
+/// time, so sessions never share a client.
+#[derive(Default)]
+pub(crate) struct ConnectionPool {
+    idle: Mutex<HashMap<PathBuf, Vec<Connection>>>,
+}
+
+impl ConnectionPool {
+    /// An idle connection to the agent at `sock_path`, if one is still open
+    pub(crate) fn take(&self, sock_path: &Path) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let connections = idle.get_mut(sock_path)?;
+        while let Some(connection) = connections.pop() {
+            if connection.is_idle() {
+                return Some(connection);
+            }
+            log::debug!(
+                "Upstream agent closed an idle connection, or sent unexpected data on it: {}",
+                sock_path.display()
+            );
+        }
+        None
+    }
+
+    fn put(&self, sock_path: PathBuf, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let connections = idle.entry(sock_path).or_default();
+        if connections.len() < MAX_IDLE_CONNECTIONS {
+            connections.push(connection);
+        }
+    }
+}
+
+/// A connection checked out of a [`ConnectionPool`], returned to it when dropped if it's still
+/// usable
+pub(crate) struct PooledClient {
+    connection: Option<Connection>,
+    sock_path: PathBuf,
+    pool: Arc<ConnectionPool>,
+    /// Not after an error that may have broken the connection, a request abandoned before its
+    /// response came (e.g. on a timeout), or an extension, which may have bound the connection to
+    /// one client, as `session-bind@openssh.com` does
+    reusable: bool,
+}
+
+impl PooledClient {
+    pub(crate) fn new(
+        connection: Connection,
+        sock_path: PathBuf,
+        pool: Arc<ConnectionPool>,
+    ) -> Self {
+        Self {
+            connection: Some(connection),
+            sock_path,
+            pool,
+            reusable: true,
+        }
+    }
+
+    /// The client, marked unusable until the request being sent on it gets its response
+    fn client(&mut self) -> &mut Box<dyn Session> {
+        self.reusable = false;
+        &mut self
+            .connection
+            .as_mut()
+            .expect("connection is only taken on drop")
+            .client
+    }
+
+    fn finish<T>(&mut self, result: Result<T, AgentError>) -> Result<T, AgentError> {
+        // The agent refusing a request leaves the connection as it was
+        self.reusable = matches!(
+            result,
+            Ok(_) | Err(AgentError::Failure) | Err(AgentError::ExtensionFailure)
+        );
+        result
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take().filter(|_| self.reusable) {
+            self.pool
+                .put(std::mem::take(&mut self.sock_path), connection);
+        }
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for PooledClient {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        let result = self.client().request_identities().await;
+        self.finish(result)
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let result = self.client().sign(request).await;
+        self.finish(result)
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        let result = self.client().add_identity(identity).await;
+        self.finish(result)
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        let result = self.client().add_identity_constrained(identity).await;
+        self.finish(result)
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        let result = self.client().remove_identity(identity).await;
+        self.finish(result)
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        let result = self.client().remove_all_identities().await;
+        self.finish(result)
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        let result = self.client().add_smartcard_key(key).await;
+        self.finish(result)
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        let result = self.client().add_smartcard_key_constrained(key).await;
+        self.finish(result)
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        let result = self.client().remove_smartcard_key(key).await;
+        self.finish(result)
+    }
+
+    async fn lock(&mut self, key: String) -> Result<(), AgentError> {
+        let result = self.client().lock(key).await;
+        self.finish(result)
+    }
+
+    async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
+        let result = self.client().unlock(key).await;
+        self.finish(result)
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        // Never reused, whatever the result, as the connection may now carry this client's state
+        self.client().extension(extension).await
+    }
+}
@@ -0,0 +1,86 @@
+//! One request at a time to upstream agents that can't take concurrent ones; see
+//! [`UpstreamAgent::serialize`](crate::UpstreamAgent::serialize)
+
+use ssh_agent_lib::{
+    agent::Session,
+    error::AgentError,
+    proto::{
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+        RemoveIdentity, SignRequest, SmartcardKey,
+    },
+    ssh_key::Signature,
+};
+use tokio::sync::OwnedMutexGuard;
+
+/// A client holding its agent's turn: no other connection to the agent is opened until it's
+/// dropped
+pub(crate) struct SerializedClient {
+    client: Box<dyn Session>,
+    _turn: OwnedMutexGuard<()>,
+}
+
+impl SerializedClient {
+    pub(crate) fn new(client: Box<dyn Session>, turn: OwnedMutexGuard<()>) -> Self {
+        Self {
+            client,
+            _turn: turn,
+        }
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for SerializedClient {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.client.request_identities().await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.client.sign(request).await
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.client.add_identity(identity).await
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.client.add_identity_constrained(identity).await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        self.client.remove_identity(identity).await
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        self.client.remove_all_identities().await
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.client.add_smartcard_key(key).await
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.client.add_smartcard_key_constrained(key).await
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.client.remove_smartcard_key(key).await
+    }
+
+    async fn lock(&mut self, key: String) -> Result<(), AgentError> {
+        self.client.lock(key).await
+    }
+
+    async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
+        self.client.unlock(key).await
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        self.client.extension(extension).await
+    }
+}
@@ -0,0 +1,50 @@
+//! Connections to upstream agents over their Unix socket, or over TCP for agents whose socket
+//! path is a `tcp://` address; see [`UpstreamTransport`]
+
+use std::{
+    io,
+    net::TcpStream,
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    path::Path,
+};
+
+use ssh_agent_lib::{agent::Session, client};
+
+use crate::UpstreamTransport;
+
+/// An open connection to an upstream agent, before a client is made of it
+pub(crate) enum UpstreamStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl UpstreamStream {
+    pub(crate) async fn connect(sock_path: &Path) -> io::Result<Self> {
+        Ok(match UpstreamTransport::of(sock_path) {
+            UpstreamTransport::Unix(path) => {
+                Self::Unix(tokio::net::UnixStream::connect(path).await?.into_std()?)
+            }
+            UpstreamTransport::Tcp(address) => {
+                let stream = tokio::net::TcpStream::connect(address).await?;
+                // Requests and responses are small and sent one at a time
+                stream.set_nodelay(true)?;
+                Self::Tcp(stream.into_std()?)
+            }
+        })
+    }
+
+    /// Another handle on the socket, e.g. to poll it while the client holds the stream
+    pub(crate) fn try_clone_fd(&self) -> io::Result<OwnedFd> {
+        match self {
+            Self::Unix(stream) => stream.try_clone().map(OwnedFd::from),
+            Self::Tcp(stream) => stream.try_clone().map(OwnedFd::from),
+        }
+    }
+
+    pub(crate) fn into_client(self) -> Result<Box<dyn Session>, Box<dyn std::error::Error>> {
+        match self {
+            Self::Unix(stream) => client::connect(stream.into()),
+            Self::Tcp(stream) => client::connect(stream.into()),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Upstream agents started by the mux when their socket can't be connected to; see
+//! [`UpstreamAgent::command`](crate::UpstreamAgent::command)
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::UpstreamAgent;
+
+/// Wait after the first start of an agent before starting it again, doubled on each start after
+/// which the agent soon stopped again
+const RESPAWN_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Longest wait between starts of an agent; one that ran for this long gets the shortest wait
+const RESPAWN_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// How long a stopping agent gets to exit on SIGTERM before it's killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Spawned {
+    command: Vec<String>,
+    env: BTreeMap<String, String>,
+    child: Option<Child>,
+    started_at: Instant,
+    backoff: Duration,
+}
+
+impl Spawned {
+    /// Whether this is the agent started for `agent`, rather than with an earlier configuration
+    fn started_as(&self, agent: &UpstreamAgent) -> bool {
+        self.command == agent.command && self.env == agent.env
+    }
+
+    fn is_running(&mut self) -> bool {
+        let Some(child) = &mut self.child else {
+            return false;
+        };
+        match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                log::warn!("Upstream agent started by the mux exited: {}", status);
+                self.child = None;
+                false
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to check on upstream agent started by the mux: {}",
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Ask the agent to exit, killing it if it doesn't soon; blocks for up to [`STOP_TIMEOUT`]
+fn stop(mut child: Child) {
+    let Ok(pid) = libc::pid_t::try_from(child.id()) else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return;
+    };
+    // SAFETY: signals the child, which hasn't been waited for, so its pid can't be reused
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while matches!(child.try_wait(), Ok(None)) {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "Upstream agent (pid {}) didn't exit on SIGTERM; killing it",
+                pid
+            );
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Stop `children` on a blocking thread, so that waiting for them doesn't hold up the runtime
+async fn stop_children(children: Vec<Child>) {
+    if children.is_empty() {
+        return;
+    }
+    let stopping = tokio::task::spawn_blocking(move || children.into_iter().for_each(stop));
+    if let Err(e) = stopping.await {
+        log::warn!("Failed to stop upstream agents started by the mux: {}", e);
+    }
+}
+
+#[derive(Default)]
+struct Children(Mutex<HashMap<PathBuf, Spawned>>);
+
+impl Children {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Spawned>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Children {
+    fn drop(&mut self) {
+        for spawned in self.lock().values_mut() {
+            if let Some(child) = spawned.child.take() {
+                stop(child);
+            }
+        }
+    }
+}
+
+/// Upstream agents the mux started, by socket path; they're stopped once the last clone is
+/// dropped. Pass the same one to every mux run across configuration reloads, so that a reload
+/// doesn't restart them, and with them lose the keys added to them.
+#[derive(Clone, Default)]
+pub struct SpawnedAgents(Arc<Children>);
+
+impl std::fmt::Debug for SpawnedAgents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SpawnedAgents")
+    }
+}
+
+impl SpawnedAgents {
+    /// Start `agent` with its command, unless it's running already; whether it's running, and so
+    /// worth waiting for. An agent that keeps stopping is only started again after a backoff.
+    pub(crate) async fn start(&self, agent: &UpstreamAgent) -> bool {
+        let Some((program, args)) = agent.command.split_first() else {
+            return false;
+        };
+        loop {
+            // An agent started with another command or environment is stopped before starting
+            // this one, which may need its socket, without holding the lock while it exits
+            let stale = {
+                let mut children = self.0.lock();
+                let Some(spawned) = children.get_mut(&agent.socket_path) else {
+                    return Self::spawn(&mut children, agent, program, args);
+                };
+                if spawned.started_as(agent) && spawned.is_running() {
+                    return true;
+                }
+                match spawned.child.take() {
+                    Some(child) => child,
+                    None => return Self::spawn(&mut children, agent, program, args),
+                }
+            };
+            stop_children(vec![stale]).await;
+        }
+    }
+
+    /// Start `agent` with `program` and `args`, its command, which isn't running, unless it's
+    /// waiting out its backoff
+    fn spawn(
+        children: &mut HashMap<PathBuf, Spawned>,
+        agent: &UpstreamAgent,
+        program: &str,
+        args: &[String],
+    ) -> bool {
+        let now = Instant::now();
+        let backoff = match children.get(&agent.socket_path) {
+            Some(spawned) if spawned.started_as(agent) => {
+                let ran_for = now.saturating_duration_since(spawned.started_at);
+                if ran_for < spawned.backoff {
+                    log::debug!(
+                        "Not starting upstream agent {} again until {:?} after its last start",
+                        agent.name,
+                        spawned.backoff
+                    );
+                    return false;
+                }
+                if ran_for >= RESPAWN_BACKOFF_MAX {
+                    RESPAWN_BACKOFF_MIN
+                } else {
+                    (spawned.backoff * 2).min(RESPAWN_BACKOFF_MAX)
+                }
+            }
+            _ => RESPAWN_BACKOFF_MIN,
+        };
+        // Tracked even if it fails to start, for the backoff
+        let spawned = children
+            .entry(agent.socket_path.clone())
+            .or_insert(Spawned {
+                command: agent.command.clone(),
+                env: agent.env.clone(),
+                child: None,
+                started_at: now,
+                backoff,
+            });
+        spawned.command = agent.command.clone();
+        spawned.env = agent.env.clone();
+        spawned.started_at = now;
+        spawned.backoff = backoff;
+        // The agent's own output would only be noise in the mux's; its errors are kept
+        let child = Command::new(program)
+            .args(args)
+            .envs(&agent.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        match child {
+            Ok(child) => {
+                log::info!(
+                    "Started upstream agent {} (pid {}): {:?}",
+                    agent.name,
+                    child.id(),
+                    agent.command
+                );
+                spawned.child = Some(child);
+                true
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to start upstream agent {}: {:?}: {}",
+                    agent.name,
+                    program,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Stop the agents started for `socket_path`s that no agent in `agents` has, with the same
+    /// command and environment
+    pub(crate) async fn retain(&self, agents: &[UpstreamAgent]) {
+        let still_configured = |sock_path: &Path, spawned: &Spawned| {
+            agents
+                .iter()
+                .any(|a| a.socket_path == sock_path && spawned.started_as(a))
+        };
+        let mut stopping = Vec::new();
+        self.0.lock().retain(|sock_path, spawned| {
+            let keep = still_configured(sock_path, spawned);
+            if !keep {
+                stopping.extend(spawned.child.take());
+            }
+            keep
+        });
+        stop_children(stopping).await;
+    }
+}
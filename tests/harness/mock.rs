@@ -0,0 +1,291 @@
+//! Scripted in-process upstream agents, for upstream behavior a real `ssh-agent` can't produce
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::net::{UnixListener as StdUnixListener, UnixStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use ssh_agent_lib::{
+    agent::{self, Agent, Session},
+    error::AgentError,
+    proto::{extension::MessageExtension, AddIdentity, Extension, Identity, SignRequest},
+    ssh_key::{Algorithm, PublicKey, Signature},
+};
+use ssh_agent_mux::extensions::MuxIdentity;
+use tempfile::TempPath;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+pub struct MockAgent {
+    pub sock_path: TempPath,
+    /// Number of connections accepted
+    pub connections: Arc<AtomicUsize>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockAgent {
+    /// Serve `session`, cloned for each connection, on a new temporary socket
+    pub fn start<S>(session: S) -> io::Result<Self>
+    where
+        S: Session + Clone,
+    {
+        Self::start_at(super::temp_sock_path("mock_")?, session)
+    }
+
+    /// Serve `session`, cloned for each connection, on `sock_path`
+    pub fn start_at<S>(sock_path: TempPath, session: S) -> io::Result<Self>
+    where
+        S: Session + Clone,
+    {
+        // Bind before returning, so the socket accepts connections as soon as the mock exists
+        let listener = StdUnixListener::bind(&sock_path)?;
+        listener.set_nonblocking(true)?;
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let connections = Arc::<AtomicUsize>::default();
+        let accepted = connections.clone();
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build mock agent runtime");
+            runtime.block_on(async move {
+                let listener = tokio::net::UnixListener::from_std(listener)
+                    .expect("failed to register mock agent listener");
+                let session = CountingAgent { session, accepted };
+                tokio::select! {
+                    res = agent::listen(listener, session) => {
+                        if let Err(e) = res {
+                            println!("Mock agent stopped: {e}");
+                        }
+                    }
+                    _ = shutdown_rx => {}
+                }
+            });
+        });
+
+        Ok(Self {
+            sock_path,
+            connections,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Serves a clone of `session` for each connection, counting them
+struct CountingAgent<S> {
+    session: S,
+    accepted: Arc<AtomicUsize>,
+}
+
+impl<S: Session + Clone> Agent<tokio::net::UnixListener> for CountingAgent<S> {
+    fn new_session(&mut self, _socket: &tokio::net::UnixStream) -> impl Session {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.session.clone()
+    }
+}
+
+impl Drop for MockAgent {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A socket that answers every request with a message of an unknown type, like a program that
+/// doesn't speak the agent protocol; it serves until the test process exits
+pub fn start_garbage_agent() -> io::Result<TempPath> {
+    let sock_path = super::temp_sock_path("garbage_")?;
+    let listener = StdUnixListener::bind(&sock_path)?;
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut len = [0; 4];
+            while stream.read_exact(&mut len).is_ok() {
+                let mut request = vec![0; u32::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut request).is_err()
+                    || stream.write_all(&[0, 0, 0, 1, 0xff]).is_err()
+                {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(sock_path)
+}
+
+/// A loopback TCP port relaying each connection to the agent at `sock_path`, like a tunnel to a
+/// remote agent; it serves until the test process exits
+pub fn start_tcp_relay(sock_path: &Path) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let sock_path = sock_path.to_path_buf();
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let Ok(agent) = UnixStream::connect(&sock_path) else {
+                continue;
+            };
+            let (Ok(mut client_reader), Ok(mut agent_writer)) =
+                (client.try_clone(), agent.try_clone())
+            else {
+                continue;
+            };
+            thread::spawn(move || io::copy(&mut client_reader, &mut agent_writer));
+            let (mut agent_reader, mut client_writer) = (agent, client);
+            thread::spawn(move || io::copy(&mut agent_reader, &mut client_writer));
+        }
+    });
+    Ok(addr)
+}
+
+/// A mock agent that lists a fixed set of identities and signs with any of them
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedAgent {
+    pub identities: Vec<Identity>,
+    /// Also sign for keys that aren't listed, like a hardware token that only exposes keys on use
+    pub sign_unlisted: bool,
+    /// Number of identity requests received, across all connections
+    pub identity_requests: Arc<AtomicUsize>,
+    /// Number of keys added, across all connections; added keys aren't listed
+    pub adds: Arc<AtomicUsize>,
+    /// Refuse every sign request, like an agent whose confirmation prompt was denied
+    pub refuse_sign: bool,
+    /// How long to take to list identities, like an agent backed by a slow token
+    pub list_delay: Duration,
+    /// How long to take to add a key
+    pub add_delay: Duration,
+    /// How long to take to sign, like an agent waiting for a PIN
+    pub sign_delay: Duration,
+    /// Number of sign requests received, across all connections
+    pub sign_requests: Arc<AtomicUsize>,
+    /// Number of sign requests being answered, across all connections
+    pub signs_in_flight: Arc<AtomicUsize>,
+    /// Most sign requests that were ever being answered at once
+    pub max_signs_in_flight: Arc<AtomicUsize>,
+    /// Accept `session-bind@openssh.com` requests, instead of failing them as unsupported
+    pub accept_session_bind: bool,
+    /// Number of extension requests received, across all connections, besides the mux's probes
+    /// for loops
+    pub extension_requests: Arc<AtomicUsize>,
+    /// Names of extensions to answer, with a response of the same name and details as the request
+    pub echo_extensions: Vec<String>,
+    /// Number of the mux's probes for loops received, across all connections
+    pub mux_probes: Arc<AtomicUsize>,
+    /// How long to take to fail a probe for loops
+    pub probe_delay: Duration,
+}
+
+impl ScriptedAgent {
+    pub fn with_keys(public_keys: &[&str]) -> Self {
+        Self {
+            identities: public_keys.iter().map(|k| identity(k)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for ScriptedAgent {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.identity_requests.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(self.list_delay).await;
+        Ok(self.identities.clone())
+    }
+
+    async fn add_identity(&mut self, _identity: AddIdentity) -> Result<(), AgentError> {
+        tokio::time::sleep(self.add_delay).await;
+        self.adds.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.sign_requests.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.signs_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_signs_in_flight
+            .fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(self.sign_delay).await;
+        self.signs_in_flight.fetch_sub(1, Ordering::SeqCst);
+        if self.refuse_sign {
+            return Err(AgentError::Failure);
+        }
+        if self.sign_unlisted || self.identities.iter().any(|id| id.pubkey == request.pubkey) {
+            Ok(dummy_signature())
+        } else {
+            Err(AgentError::Failure)
+        }
+    }
+
+    async fn extension(&mut self, request: Extension) -> Result<Option<Extension>, AgentError> {
+        if request.name == MuxIdentity::NAME {
+            self.mux_probes.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.probe_delay).await;
+            return Err(AgentError::Failure);
+        }
+        self.extension_requests.fetch_add(1, Ordering::SeqCst);
+        if self.accept_session_bind && request.name == "session-bind@openssh.com" {
+            Ok(None)
+        } else if self.echo_extensions.contains(&request.name) {
+            Ok(Some(request))
+        } else {
+            Err(AgentError::Failure)
+        }
+    }
+}
+
+/// A mock agent that, like gpg-agent, only signs with keys it has listed on the same connection
+#[derive(Clone, Debug, Default)]
+pub struct ListBeforeSignAgent {
+    pub inner: ScriptedAgent,
+    listed: bool,
+}
+
+impl ListBeforeSignAgent {
+    pub fn with_keys(public_keys: &[&str]) -> Self {
+        Self {
+            inner: ScriptedAgent::with_keys(public_keys),
+            listed: false,
+        }
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for ListBeforeSignAgent {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.listed = true;
+        self.inner.request_identities().await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        if !self.listed {
+            return Err(AgentError::Failure);
+        }
+        self.inner.sign(request).await
+    }
+}
+
+/// Build an identity from an OpenSSH-format public key line
+pub fn identity(public_key: &str) -> Identity {
+    let key = PublicKey::from_openssh(public_key).expect("invalid test public key");
+    Identity {
+        pubkey: key.key_data().clone(),
+        comment: key.comment().into(),
+    }
+}
+
+/// A well-formed signature that won't verify; the mux forwards signatures without checking them
+pub fn dummy_signature() -> Signature {
+    Signature::new(Algorithm::Ed25519, vec![0; 64]).expect("valid ed25519 signature length")
+}
@@ -2,17 +2,31 @@ use std::{
     ffi::{OsStr, OsString},
     fs,
     io::{self, Write},
+    iter::Peekable,
     os::unix::fs::PermissionsExt,
     path::Path,
+    str::Chars,
     time::{Duration, Instant},
 };
 
 use duct::{cmd, unix::HandleExt, Handle};
+use ssh_agent_lib::{
+    agent::Session,
+    client,
+    error::AgentError,
+    proto::SignRequest,
+    ssh_key::{PublicKey, Signature},
+};
 use tempfile::TempPath;
 
+pub mod mock;
+
 const AGENT_TIMEOUT: Duration = Duration::from_secs(2);
 const AGENT_POLL: Duration = Duration::from_micros(100);
+const SIGHUP: std::ffi::c_int = 1;
 const SIGTERM: std::ffi::c_int = 15;
+// ssh-key can't decode legacy SHA-1 `ssh-rsa` signatures, so ask for SHA-2 like modern clients do
+const SSH_AGENT_RSA_SHA2_256: u32 = 0x02;
 
 pub enum SshAgentType {
     OpenSsh,
@@ -37,20 +51,26 @@ fn map_binary_notfound_error(binary_name: &str, err: io::Error) -> io::Error {
     }
 }
 
+/// A unique path for a socket that doesn't exist yet
+pub fn temp_sock_path(prefix: &str) -> io::Result<TempPath> {
+    let sock_path = tempfile::Builder::new()
+        .prefix(prefix)
+        .suffix(".sock")
+        // we don't use CARGO_TARGET_TMPDIR because the filepath in nix can get quite long, and
+        // there is a limit on macOS of 104 characters.
+        .tempfile_in(std::env::temp_dir())?
+        .into_temp_path();
+    fs::remove_file(&sock_path)?;
+    Ok(sock_path)
+}
+
 impl SshAgentInstance {
     pub fn new<I, A>(agent_type: SshAgentType, args: I) -> io::Result<Self>
     where
         I: IntoIterator<Item = A> + Clone + Send + Sync + 'static,
         A: AsRef<OsStr>,
     {
-        let sock_path = tempfile::Builder::new()
-            .prefix("agent_")
-            .suffix(".sock")
-            // we don't use CARGO_TARGET_TMPDIR because the filepath in nix can get quite long, and
-            // there is a limit on macOS of 104 characters.
-            .tempfile_in(std::env::temp_dir())?
-            .into_temp_path();
-        fs::remove_file(&sock_path)?;
+        let sock_path = temp_sock_path("agent_")?;
 
         let cmd = match agent_type {
             SshAgentType::OpenSsh => cmd!("ssh-agent", "-d", "-a", &sock_path),
@@ -73,7 +93,8 @@ impl SshAgentInstance {
             })
             .start()?;
         let agent_start_time = Instant::now();
-        while !sock_path.exists() {
+        // The socket file appears before the agent starts listening on it
+        while std::os::unix::net::UnixStream::connect(&sock_path).is_err() {
             std::thread::sleep(AGENT_POLL);
             if agent_start_time.elapsed() >= AGENT_TIMEOUT {
                 return Err(io::Error::new(
@@ -109,6 +130,18 @@ impl SshAgentInstance {
             .map_err(|e| map_binary_notfound_error(env!("CARGO_BIN_EXE_ssh-agent-mux"), e))
     }
 
+    /// Ask the agent to reload its configuration
+    pub fn reload(&self) -> io::Result<()> {
+        self.handle.send_signal(SIGHUP)
+    }
+
+    /// Stop the agent, returning its output
+    pub fn stop(&self) -> io::Result<String> {
+        self.handle.send_signal(SIGTERM)?;
+        let output = self.handle.wait()?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
     pub fn add(&self, key: &str) -> io::Result<()> {
         // Add an ssh-key from stdin
         cmd!("ssh-add", "-q", "--", "-")
@@ -120,6 +153,71 @@ impl SshAgentInstance {
         Ok(())
     }
 
+    /// Add an ssh-key from stdin, to be deleted after `lifetime` seconds
+    pub fn add_with_lifetime(&self, key: &str, lifetime: u32) -> io::Result<()> {
+        cmd!("ssh-add", "-q", "-t", lifetime.to_string(), "--", "-")
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdin_bytes(key)
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, pubkey: &str) -> io::Result<()> {
+        // Remove an ssh-key by public key from stdin
+        cmd!("ssh-add", "-q", "-d", "-")
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdin_bytes(pubkey)
+            .stdout_capture()
+            .stderr_capture()
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    pub fn remove_all(&self) -> io::Result<()> {
+        cmd!("ssh-add", "-q", "-D")
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdout_capture()
+            .stderr_capture()
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    /// Connect an in-process protocol client to the agent and run `f` with it
+    pub fn with_client<F, Fut, T>(&self, f: F) -> Result<T, AgentError>
+    where
+        F: FnOnce(Box<dyn Session>) -> Fut,
+        Fut: std::future::Future<Output = Result<T, AgentError>>,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let stream = tokio::net::UnixStream::connect(&self.sock_path).await?;
+            let client = client::connect(stream.into_std()?.into())
+                .map_err(|e| AgentError::Other(e.to_string().into()))?;
+            f(client).await
+        })
+    }
+
+    pub fn sign(&self, pubkey: &str) -> Result<Signature, AgentError> {
+        let pubkey = PublicKey::from_openssh(pubkey).map_err(AgentError::other)?;
+        self.with_client(|mut client| async move {
+            client
+                .sign(SignRequest {
+                    pubkey: pubkey.key_data().clone(),
+                    data: b"ssh-agent-mux test data".to_vec(),
+                    flags: SSH_AGENT_RSA_SHA2_256,
+                })
+                .await
+        })
+    }
+
     fn make_askpass_script(passphrase: &str) -> io::Result<tempfile::TempPath> {
         let mut script = tempfile::Builder::new()
             .prefix("askpass_")
@@ -205,3 +303,65 @@ impl Drop for SshAgentInstance {
         );
     }
 }
+
+/// The fields of `text` if it's a JSON object whose values are all strings, like the mux's JSON
+/// log records; `None` if it isn't valid JSON, or has other values
+pub fn parse_json_fields(text: &str) -> Option<Vec<(String, String)>> {
+    fn skip_whitespace(chars: &mut Peekable<Chars>) {
+        while chars
+            .next_if(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+    }
+
+    let mut chars = text.chars().peekable();
+    skip_whitespace(&mut chars);
+    (chars.next()? == '{').then_some(())?;
+    skip_whitespace(&mut chars);
+    let mut fields = vec![];
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_whitespace(&mut chars);
+            let key = parse_json_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            (chars.next()? == ':').then_some(())?;
+            skip_whitespace(&mut chars);
+            fields.push((key, parse_json_string(&mut chars)?));
+            skip_whitespace(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    chars.next().is_none().then_some(fields)
+}
+
+fn parse_json_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    (chars.next()? == '"').then_some(())?;
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => string.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                '/' => '/',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let hex: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c if c.is_control() => return None,
+            c => string.push(c),
+        }
+    }
+}
@@ -1,6 +1,50 @@
-use std::{ffi::OsString, io};
+#[cfg(feature = "http-metrics")]
+use std::net::{TcpListener, TcpStream};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{fs::PermissionsExt, net::UnixStream, process::CommandExt},
+    },
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use harness::SshAgentInstance;
+use harness::{
+    mock::{self, ListBeforeSignAgent, MockAgent, ScriptedAgent},
+    SshAgentInstance, SshAgentType,
+};
+use rsa::signature::Verifier;
+use ssh_agent_lib::{
+    agent::Session,
+    client,
+    proto::{
+        extension::{MessageExtension, QueryResponse},
+        Extension, Identity, SignRequest,
+    },
+    ssh_key::{
+        self,
+        public::{KeyData, RsaPublicKey},
+        Fingerprint, Mpint, PublicKey,
+    },
+};
+use ssh_agent_mux::{
+    extensions::{
+        Denied, ListUpstreams, MuxIdentity, MuxInfo, RefreshOutcome, RefreshStatus, SelectTags,
+        SignCheck, SignCheckResults,
+    },
+    policy::{async_trait, Decision, Peer, RequestPolicy},
+    MuxAgent, MuxOptions, UpstreamAgent,
+};
+use tempfile::TempPath;
 
 mod harness;
 mod keys;
@@ -20,7 +64,11 @@ fn make_openssh_agent_with_keys() -> io::Result<SshAgentInstance> {
 
 fn assert_no_keys_in_agent(agent: &SshAgentInstance) -> TestResult {
     let keys_in_agent = agent.list()?;
-    assert!(keys_in_agent.is_empty(), "Expected no keys, got: {:?}", keys_in_agent);
+    assert!(
+        keys_in_agent.is_empty(),
+        "Expected no keys, got: {:?}",
+        keys_in_agent
+    );
     Ok(())
 }
 
@@ -70,6 +118,160 @@ socket-path = "{}""##,
     Ok(())
 }
 
+#[test]
+fn mux_accepts_own_user() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    for allow_any_peer in [false, true] {
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                "allow-any-peer = {}\n[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+                allow_any_peer,
+                openssh_agent.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+        assert_all_keys_in_agent(&mux_agent)?;
+        let output = mux_agent.stop()?;
+        assert!(!output.contains("Rejecting connection"), "{output}");
+    }
+
+    Ok(())
+}
+
+/// Start the mux as systemd does with socket activation, passing it `listener`, which is already
+/// bound and listening
+fn start_activated_mux(
+    listener: &std::os::unix::net::UnixListener,
+    config_path: &Path,
+    listen_path: &Path,
+) -> io::Result<duct::Handle> {
+    let listener_fd = listener.as_raw_fd();
+    // The shell's pid is the mux's once it execs it
+    duct::cmd!(
+        "sh",
+        "-c",
+        r#"LISTEN_PID=$$ LISTEN_FDS=1 exec "$0" "$@""#,
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--log-level",
+        "trace",
+        "--listen-path",
+        listen_path,
+        format!("--config={}", config_path.display())
+    )
+    .unchecked()
+    .stderr_to_stdout()
+    .stdout_capture()
+    .before_spawn(move |cmd| {
+        // SAFETY: only calls async-signal-safe functions
+        unsafe {
+            cmd.pre_exec(move || {
+                // The copy at fd 3 is inherited, unlike the close-on-exec original
+                let result = match listener_fd {
+                    3 => libc::fcntl(3, libc::F_SETFD, 0),
+                    fd => libc::dup2(fd, 3),
+                };
+                match result {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            })
+        };
+        Ok(())
+    })
+    .start()
+}
+
+#[test]
+fn mux_systemd_socket_activation() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let spawned_sock = harness::temp_sock_path("spawned_")?;
+    let seen_path = scratch.path().join("seen");
+    fs::write(
+        &config_path,
+        format!(
+            r##"[[agents]]
+name = "upstream"
+socket-path = "{}"
+
+[[agents]]
+name = "spawned"
+socket-path = "{1}"
+command = ["sh", "-c", 'printf "%s" "$${{LISTEN_PID-}}$${{LISTEN_FDS-}}" > {2}; exec ssh-agent -D -a {1}']"##,
+            openssh_agent.sock_path.display(),
+            spawned_sock.display(),
+            seen_path.display()
+        ),
+    )?;
+    let unused_path = scratch.path().join("unused.sock");
+    // Bound and listening before the mux starts, as systemd does
+    let sock_path = harness::temp_sock_path("activated_")?;
+    let listener = std::os::unix::net::UnixListener::bind(&sock_path)?;
+
+    let handle = start_activated_mux(&listener, &config_path, &unused_path)?;
+    let mux_agent = SshAgentInstance { handle, sock_path };
+    assert_all_keys_in_agent(&mux_agent)?;
+    // The variables are unset, so they don't reach the agents the mux starts
+    let started = Instant::now();
+    while !seen_path.exists() && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read_to_string(&seen_path)?, "");
+
+    // A reload listens on the same socket again
+    mux_agent.reload()?;
+    thread::sleep(Duration::from_millis(500));
+    assert_all_keys_in_agent(&mux_agent)?;
+
+    let output = mux_agent.stop()?;
+    assert!(output.contains("passed by systemd"), "{output}");
+    // The socket belongs to systemd, and the listen path isn't used
+    assert!(mux_agent.sock_path.exists());
+    assert!(!unused_path.exists());
+    drop(listener);
+
+    Ok(())
+}
+
+#[test]
+fn mux_systemd_socket_activation_refuses_loop() -> TestResult {
+    let scratch = tempfile::tempdir()?;
+    let sock_path = harness::temp_sock_path("activated_")?;
+    let listener = std::os::unix::net::UnixListener::bind(&sock_path)?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[[agents]]\nname = \"itself\"\nsocket-path = \"{}\"\n",
+            sock_path.display()
+        ),
+    )?;
+
+    // The agent is the socket systemd passed, though not the listen path
+    let unused_path = scratch.path().join("unused.sock");
+    let handle = start_activated_mux(&listener, &config_path, &unused_path)?;
+    let started = Instant::now();
+    let output = loop {
+        if let Some(output) = handle.try_wait()? {
+            break output.clone();
+        }
+        if started.elapsed() > Duration::from_secs(5) {
+            handle.kill()?;
+            panic!("The mux kept running");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(!output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.contains("Upstream agent itself would loop back to the mux"),
+        "{output}"
+    );
+
+    Ok(())
+}
+
 #[test]
 fn mux_with_three_agents() -> TestResult {
     let agent_rsa = SshAgentInstance::new_openssh()?;
@@ -141,6 +343,112 @@ socket-path = "{}""##,
     Ok(())
 }
 
+#[test]
+fn mux_add_identity_lifetime_enforced_upstream() -> TestResult {
+    let target_agent = SshAgentInstance::new_openssh()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = "target"
+
+[[agents]]
+name = "target"
+socket-path = "{}""##,
+            target_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The constraint is forwarded as is, so the upstream agent expires the key itself
+    mux_agent.add_with_lifetime(keys::TEST_KEY_ED25519, 2)?;
+    assert_eq!(target_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let signature = mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    let pubkey = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?;
+    pubkey
+        .key_data()
+        .verify(b"ssh-agent-mux test data", &signature)?;
+    thread::sleep(Duration::from_millis(3100));
+    assert!(target_agent.list()?.is_empty());
+    assert_no_keys_in_agent(&mux_agent)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_require_constraints_for_sign() -> TestResult {
+    let target_agent = SshAgentInstance::new_openssh()?;
+    let new_mux = |policy: &str| {
+        SshAgentInstance::new_mux(
+            &format!(
+                r##"add-new-keys-to = "target"
+require-constraints-for-sign = "{}"
+
+[[agents]]
+name = "target"
+socket-path = "{}""##,
+                policy,
+                target_agent.sock_path.display()
+            ),
+            None::<OsString>,
+        )
+    };
+    let mux_agent = new_mux("added")?;
+
+    // Added through the mux without a constraint
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    // Added through the mux with a lifetime, which is forwarded
+    mux_agent.add_with_lifetime(keys::TEST_KEY_RSA, 600)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_RSA_PUB).is_ok());
+
+    // Added out of band: allowed, unless every key must have been added with a constraint
+    target_agent.add(keys::TEST_KEY_ECDSA)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ECDSA_PUB).is_ok());
+    let strict_mux = new_mux("all")?;
+    assert!(strict_mux.sign(keys::TEST_KEY_ECDSA_PUB).is_err());
+    strict_mux.add_with_lifetime(keys::TEST_KEY_ECDSA, 600)?;
+    assert!(strict_mux.sign(keys::TEST_KEY_ECDSA_PUB).is_ok());
+
+    let output = mux_agent.stop()?;
+    let fingerprint =
+        PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?.fingerprint(Default::default());
+    assert!(
+        output.contains(&format!(
+            "Refusing to sign with key {fingerprint}: policy denied (constraint-required)"
+        )),
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_require_constraints_skipped_add() -> TestResult {
+    let target_agent = SshAgentInstance::new_openssh()?;
+    target_agent.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = "target"
+add-if-present = "skip"
+require-constraints-for-sign = "all"
+
+[[agents]]
+name = "target"
+socket-path = "{}""##,
+            target_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The key is already present without a lifetime, so the one requested isn't applied and the
+    // key stays unconstrained
+    mux_agent.add_with_lifetime(keys::TEST_KEY_ED25519, 600)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
 #[test]
 fn mux_lock_unlock() -> TestResult {
     let openssh_agent = make_openssh_agent_with_keys()?;
@@ -204,3 +512,3608 @@ socket-path = "{}""##,
 
     Ok(())
 }
+
+#[test]
+fn mux_lock_skips_read_only_agents() -> TestResult {
+    let writable = SshAgentInstance::new_openssh()?;
+    writable.add(keys::TEST_KEY_RSA)?;
+    let read_only = SshAgentInstance::new_openssh()?;
+    read_only.add(keys::TEST_KEY_ED25519)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "writable"
+socket-path = "{}"
+
+[[agents]]
+name = "read-only"
+socket-path = "{}"
+read-only = true"##,
+            writable.sock_path.display(),
+            read_only.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.lock("test-passphrase")?;
+    assert_no_keys_in_agent(&writable)?;
+    // The read-only agent wasn't locked, so its keys are still listed
+    assert_eq!(read_only.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    mux_agent.unlock("test-passphrase")?;
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_lock_unlock_skips_failing_agents() -> TestResult {
+    let healthy = SshAgentInstance::new_openssh()?;
+    healthy.add(keys::TEST_KEY_RSA)?;
+    // The mock agent doesn't support locking
+    let unsupported = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let erroring = mock::start_garbage_agent()?;
+    // Nothing listens on it
+    let missing = harness::temp_sock_path("missing_")?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "missing"
+socket-path = "{}"
+
+[[agents]]
+name = "unsupported"
+socket-path = "{}"
+
+[[agents]]
+name = "erroring"
+socket-path = "{}"
+
+[[agents]]
+name = "healthy"
+socket-path = "{}""##,
+            missing.display(),
+            unsupported.sock_path.display(),
+            erroring.display(),
+            healthy.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.lock("test-passphrase")?;
+    assert_no_keys_in_agent(&healthy)?;
+
+    mux_agent.unlock("test-passphrase")?;
+    assert_eq!(healthy.list()?, [keys::TEST_KEY_RSA_PUB]);
+
+    // Without any agent that can be locked, locking fails
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "unsupported"
+socket-path = "{}"
+
+[[agents]]
+name = "erroring"
+socket-path = "{}""##,
+            unsupported.sock_path.display(),
+            erroring.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert!(mux_agent.lock("test-passphrase").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_lock_unlock_agent_passphrase_override() -> TestResult {
+    let agent_shared = SshAgentInstance::new_openssh()?;
+    agent_shared.add(keys::TEST_KEY_RSA)?;
+    let agent_own = SshAgentInstance::new_openssh()?;
+    agent_own.add(keys::TEST_KEY_ED25519)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "shared"
+socket-path = "{}"
+
+[[agents]]
+name = "own"
+socket-path = "{}"
+lock-passphrase = "own-passphrase""##,
+            agent_shared.sock_path.display(),
+            agent_own.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.lock("test-passphrase")?;
+    assert_no_keys_in_agent(&mux_agent)?;
+    // The agent with an override was locked with its own passphrase
+    assert!(agent_own.unlock("test-passphrase").is_err());
+
+    assert!(mux_agent.unlock("wrong-passphrase").is_err());
+    assert_no_keys_in_agent(&agent_own)?;
+
+    mux_agent.unlock("test-passphrase")?;
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_unlock_agent_passphrase_override_unverified() -> TestResult {
+    let agent_own = SshAgentInstance::new_openssh()?;
+    agent_own.add(keys::TEST_KEY_ED25519)?;
+    agent_own.lock("own-passphrase")?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "own"
+socket-path = "{}"
+lock-passphrase = "own-passphrase""##,
+            agent_own.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Nothing vouches for an arbitrary passphrase when every agent has its own
+    assert!(mux_agent.unlock("any-passphrase").is_err());
+    assert_no_keys_in_agent(&agent_own)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    for key in keys::PUBLIC {
+        mux_agent.sign(key)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_retries_after_key_moves() -> TestResult {
+    let agent_a = SshAgentInstance::new_openssh()?;
+    agent_a.add(keys::TEST_KEY_ED25519)?;
+    let agent_b = SshAgentInstance::new_openssh()?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "a"
+socket-path = "{}"
+
+[[agents]]
+name = "b"
+socket-path = "{}""##,
+            agent_a.sock_path.display(),
+            agent_b.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Populate the mux's routing table with the key owned by agent A
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // Move the key to agent B behind the mux's back
+    agent_a.remove(keys::TEST_KEY_ED25519_PUB)?;
+    agent_b.add(keys::TEST_KEY_ED25519)?;
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+
+    // The retry recorded B as the owner, so signing no longer depends on A
+    drop(agent_a);
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_remove_identity() -> TestResult {
+    let agent_a = SshAgentInstance::new_openssh()?;
+    agent_a.add(keys::TEST_KEY_ED25519)?;
+    let agent_b = SshAgentInstance::new_openssh()?;
+    agent_b.add(keys::TEST_KEY_RSA)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "a"
+socket-path = "{}"
+
+[[agents]]
+name = "b"
+socket-path = "{}""##,
+            agent_a.sock_path.display(),
+            agent_b.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Removed only from the agent holding it
+    mux_agent.remove(keys::TEST_KEY_RSA_PUB)?;
+    assert!(agent_b.list()?.is_empty());
+    assert_eq!(agent_a.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert!(mux_agent.sign(keys::TEST_KEY_RSA_PUB).is_err());
+
+    // No agent holds it any more
+    assert!(mux_agent.remove(keys::TEST_KEY_RSA_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_remove_all_identities() -> TestResult {
+    let agent_a = SshAgentInstance::new_openssh()?;
+    agent_a.add(keys::TEST_KEY_ED25519)?;
+    let agent_b = SshAgentInstance::new_openssh()?;
+    agent_b.add(keys::TEST_KEY_RSA)?;
+    agent_b.add(keys::TEST_KEY_ECDSA)?;
+    // Fails every request, and is skipped
+    let missing = harness::temp_sock_path("missing_")?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "a"
+socket-path = "{}"
+
+[[agents]]
+name = "missing"
+socket-path = "{}"
+
+[[agents]]
+name = "b"
+socket-path = "{}""##,
+            agent_a.sock_path.display(),
+            missing.display(),
+            agent_b.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?.len(), 3);
+
+    mux_agent.remove_all()?;
+    assert!(agent_a.list()?.is_empty());
+    assert!(agent_b.list()?.is_empty());
+    assert_no_keys_in_agent(&mux_agent)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_falls_back_when_owner_stalls() -> TestResult {
+    let responsive = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    // Listed last, so it's the one sign requests are routed to
+    let stalled = MockAgent::start(ScriptedAgent {
+        sign_delay: Duration::from_secs(30),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    })?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent-timeout = 1
+
+[[agents]]
+name = "responsive"
+socket-path = "{}"
+
+[[agents]]
+name = "stalled"
+socket-path = "{}""##,
+            responsive.sock_path.display(),
+            stalled.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let started = Instant::now();
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert!(started.elapsed() < Duration::from_secs(10));
+    let output = mux_agent.stop()?;
+    assert!(
+        output.contains("Upstream agent stalled failed to sign with key"),
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_retries_after_owner_disappears() -> TestResult {
+    let owner = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    // Not running yet when the mux first lists identities
+    let successor_sock = harness::temp_sock_path("successor_")?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "successor"
+socket-path = "{}"
+
+[[agents]]
+name = "owner"
+socket-path = "{}""##,
+            successor_sock.display(),
+            owner.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    let successor = MockAgent::start_at(
+        successor_sock,
+        ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]),
+    )?;
+    drop(owner);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+
+    // Without any agent left, the single retry fails too
+    drop(successor);
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    let output = mux_agent.stop()?;
+    assert_eq!(
+        output.matches("refreshing identities and retrying").count(),
+        2,
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_without_retry_fails_after_key_moves() -> TestResult {
+    let agent_a = SshAgentInstance::new_openssh()?;
+    agent_a.add(keys::TEST_KEY_ED25519)?;
+    let agent_b = SshAgentInstance::new_openssh()?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"retry-sign = false
+
+[[agents]]
+name = "a"
+socket-path = "{}"
+
+[[agents]]
+name = "b"
+socket-path = "{}""##,
+            agent_a.sock_path.display(),
+            agent_b.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    agent_a.remove(keys::TEST_KEY_ED25519_PUB)?;
+    agent_b.add(keys::TEST_KEY_ED25519)?;
+
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_duplicate_key_policy() -> TestResult {
+    for (policy, signs, expected) in [
+        ("last-wins", true, [0, 1]),
+        ("first-wins", true, [1, 0]),
+        ("error", false, [0, 0]),
+    ] {
+        let first = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+        let second = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+        let sign_requests = [first.sign_requests.clone(), second.sign_requests.clone()];
+        let first = MockAgent::start(first)?;
+        let second = MockAgent::start(second)?;
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"duplicate-key-policy = "{policy}"
+
+[[agents]]
+name = "first"
+socket-path = "{}"
+
+[[agents]]
+name = "second"
+socket-path = "{}""##,
+                first.sock_path.display(),
+                second.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+
+        assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB], "{policy}");
+        assert_eq!(
+            mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_ok(),
+            signs,
+            "{policy}"
+        );
+        let sign_requests = sign_requests.each_ref().map(|n| n.load(Ordering::SeqCst));
+        assert_eq!(sign_requests, expected, "{policy}");
+        let output = mux_agent.stop()?;
+        assert!(
+            output.contains("is listed by both upstream agents first and second"),
+            "{policy}: {output}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_route_pins_key() -> TestResult {
+    let fingerprint = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+    let pinned = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let discovered = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let sign_requests = [
+        pinned.sign_requests.clone(),
+        discovered.sign_requests.clone(),
+    ];
+    let pinned = MockAgent::start(pinned)?;
+    // Listed last, so it's the owner the mux would find on its own
+    let discovered = MockAgent::start(discovered)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "pinned"
+socket-path = "{}"
+
+[[agents]]
+name = "discovered"
+socket-path = "{}"
+
+[[routes]]
+fingerprint = "{fingerprint}"
+agent = "pinned""##,
+            pinned.sock_path.display(),
+            discovered.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    for _ in 0..2 {
+        assert_eq!(
+            mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+            mock::dummy_signature()
+        );
+    }
+    let signs = || sign_requests.each_ref().map(|n| n.load(Ordering::SeqCst));
+    assert_eq!(signs(), [2, 0]);
+
+    // Once the pinned agent is gone, the key isn't signed with elsewhere
+    drop(pinned);
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    assert_eq!(signs(), [2, 0]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_fallback_broadcast() -> TestResult {
+    for broadcast in [false, true] {
+        let refusing = ScriptedAgent::with_keys(&[keys::TEST_KEY_ECDSA_PUB]);
+        let unlisting = ScriptedAgent {
+            sign_unlisted: true,
+            ..ScriptedAgent::with_keys(&[])
+        };
+        let sign_requests = [
+            refusing.sign_requests.clone(),
+            unlisting.sign_requests.clone(),
+        ];
+        let refusing = MockAgent::start(refusing)?;
+        let unlisting = MockAgent::start(unlisting)?;
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"sign-fallback-broadcast = {broadcast}
+
+[[agents]]
+name = "refusing"
+socket-path = "{}"
+
+[[agents]]
+name = "unlisting"
+socket-path = "{}""##,
+                refusing.sock_path.display(),
+                unlisting.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+
+        assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ECDSA_PUB]);
+        let signed = mux_agent.sign(keys::TEST_KEY_ED25519_PUB);
+        let signs = sign_requests.each_ref().map(|n| n.load(Ordering::SeqCst));
+        if broadcast {
+            assert_eq!(signed?, mock::dummy_signature());
+            // The agent listing another key is asked first, and its failure skipped
+            assert_eq!(signs, [1, 1]);
+        } else {
+            assert!(signed.is_err());
+            assert_eq!(signs, [0, 0]);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_falls_back_to_default_agent() -> TestResult {
+    let agent_listed = SshAgentInstance::new_openssh()?;
+    agent_listed.add(keys::TEST_KEY_ED25519)?;
+    let token = MockAgent::start(ScriptedAgent {
+        sign_unlisted: true,
+        ..Default::default()
+    })?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"default-agent = "token"
+
+[[agents]]
+name = "listed"
+socket-path = "{}"
+
+[[agents]]
+name = "token"
+socket-path = "{}""##,
+            agent_listed.sock_path.display(),
+            token.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    // Listed keys still go to the agent that has them
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    // Unknown keys go to the default agent
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_RSA_PUB)?,
+        mock::dummy_signature()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_agent_key_filters() -> TestResult {
+    let fingerprint = |key: &str| -> Result<String, Box<dyn std::error::Error>> {
+        Ok(PublicKey::from_openssh(key)?
+            .fingerprint(Default::default())
+            .to_string())
+    };
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+
+    for (filter, exposed, hidden) in [
+        (
+            "expose",
+            keys::TEST_KEY_ED25519_PUB,
+            keys::TEST_KEY_ECDSA_PUB,
+        ),
+        ("hide", keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB),
+    ] {
+        // The agent is also the default agent, so hidden keys could be routed to it as unknown
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"default-agent = "filtered"
+
+[[agents]]
+name = "filtered"
+socket-path = "{}"
+{filter}-fingerprints = ["{}"]"##,
+                mock_agent.sock_path.display(),
+                fingerprint(keys::TEST_KEY_ED25519_PUB)?
+            ),
+            None::<OsString>,
+        )?;
+
+        assert_eq!(mux_agent.list()?, [exposed], "{filter}");
+        assert_eq!(mux_agent.sign(exposed)?, mock::dummy_signature());
+        assert!(
+            mux_agent.sign(hidden).is_err(),
+            "{filter}: hidden key signed"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_concurrent_refreshes_share_one_sweep() -> TestResult {
+    let upstream = ScriptedAgent {
+        list_delay: Duration::from_secs(1),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "slow"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let requests_before = identity_requests.load(Ordering::SeqCst);
+    // Every client connects while the first one's refresh is still waiting on the slow agent
+    let listed = thread::scope(|scope| {
+        let clients: Vec<_> = (0..5).map(|_| scope.spawn(|| mux_agent.list())).collect();
+        clients
+            .into_iter()
+            .map(|c| c.join().expect("client thread panicked"))
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+
+    for client_keys in listed {
+        assert_eq!(client_keys, [keys::TEST_KEY_ED25519_PUB]);
+    }
+    assert_eq!(
+        identity_requests.load(Ordering::SeqCst) - requests_before,
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_serialize_agent() -> TestResult {
+    let serialized = ScriptedAgent {
+        sign_delay: Duration::from_millis(500),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let serialized_max = serialized.max_signs_in_flight.clone();
+    let concurrent = ScriptedAgent {
+        sign_delay: Duration::from_millis(500),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ECDSA_PUB])
+    };
+    let concurrent_max = concurrent.max_signs_in_flight.clone();
+    let serialized = MockAgent::start(serialized)?;
+    let concurrent = MockAgent::start(concurrent)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "token"
+socket-path = "{}"
+serialize = true
+
+[[agents]]
+name = "concurrent"
+socket-path = "{}""##,
+            serialized.sock_path.display(),
+            concurrent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    thread::scope(|scope| {
+        let clients: Vec<_> = [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_ECDSA_PUB]
+            .into_iter()
+            .flat_map(|key| [key, key])
+            .map(|key| scope.spawn(|| mux_agent.sign(key)))
+            .collect();
+        clients
+            .into_iter()
+            .map(|c| c.join().expect("client thread panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    assert_eq!(serialized_max.load(Ordering::SeqCst), 1);
+    assert_eq!(concurrent_max.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_starts_agent_with_command() -> TestResult {
+    let sock_path = harness::temp_sock_path("spawned_")?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = "spawned"
+
+[[agents]]
+name = "spawned"
+socket-path = "{0}"
+command = ["ssh-agent", "-D", "-a", "{0}"]"##,
+            sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    assert!(UnixStream::connect(&sock_path).is_ok());
+
+    // ssh-agent removes its socket when it's stopped along with the mux
+    let output = mux_agent.stop()?;
+    assert!(
+        output.contains("Started upstream agent spawned"),
+        "{}",
+        output
+    );
+    assert!(!sock_path.exists(), "{}", output);
+
+    Ok(())
+}
+
+#[test]
+fn mux_starts_agent_with_env() -> TestResult {
+    let sock_path = harness::temp_sock_path("spawned_")?;
+    let seen_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "spawned"
+socket-path = "{0}"
+command = ["sh", "-c", 'printf "%s" "$$MUX_TEST_ENV" > {1}; exec ssh-agent -D -a {0}']
+env = {{ MUX_TEST_ENV = "$HOME/configured" }}"##,
+            sock_path.display(),
+            seen_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Only listed once the agent is up, after it wrote what it saw
+    assert!(mux_agent.list()?.is_empty());
+    assert!(UnixStream::connect(&sock_path).is_ok());
+    // Expanded like other settings, and set on top of the mux's environment, which has PATH
+    assert_eq!(
+        fs::read_to_string(&seen_path)?,
+        format!("{}/configured", std::env::var("HOME")?)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_tcp_upstream_agent() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_ECDSA)?;
+    let relay = mock::start_tcp_relay(&upstream.sock_path)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = "tunnel"
+reuse-connections = true
+
+[[agents]]
+name = "tunnel"
+socket-path = "tcp://{}""##,
+            relay
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ECDSA_PUB]);
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(upstream.list()?.len(), 2);
+    // Twice, the second time over a pooled connection
+    for _ in 0..2 {
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+        mux_agent.sign(keys::TEST_KEY_ECDSA_PUB)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_identities_in_configured_order() -> TestResult {
+    // The first agent answers last
+    let slow_agent = MockAgent::start(ScriptedAgent {
+        list_delay: Duration::from_millis(500),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ECDSA_PUB])
+    })?;
+    let fast_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "slow"
+socket-path = "{}"
+
+[[agents]]
+name = "fast"
+socket-path = "{}""##,
+            slow_agent.sock_path.display(),
+            fast_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    for _ in 0..3 {
+        assert_eq!(mux_agent.list()?, keys::PUBLIC);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_reuse_connections() -> TestResult {
+    let start_agent = |sock_path| {
+        MockAgent::start_at(
+            sock_path,
+            ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]),
+        )
+    };
+    let use_mux = |mux_agent: &SshAgentInstance| -> TestResult {
+        for _ in 0..3 {
+            assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+            mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+        }
+        Ok(())
+    };
+
+    for reuse_connections in [false, true] {
+        let upstream = start_agent(harness::temp_sock_path("mock_")?)?;
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                "reuse-connections = {}\n[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+                reuse_connections,
+                upstream.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+        use_mux(&mux_agent)?;
+        let connections = upstream.connections.load(Ordering::Relaxed);
+        if !reuse_connections {
+            assert_eq!(connections, 6);
+            continue;
+        }
+        assert_eq!(connections, 1);
+
+        // A restarted agent closed the idle connection, so the mux connects again
+        let sock_path = upstream.sock_path.to_path_buf();
+        drop(upstream);
+        let upstream = start_agent(TempPath::from_path(sock_path))?;
+        use_mux(&mux_agent)?;
+        assert_eq!(upstream.connections.load(Ordering::Relaxed), 1);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_miss_without_refresh() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"refresh-on-sign-miss = false
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The key is only usable once an identity listing has found it
+    let requests_before = identity_requests.load(Ordering::SeqCst);
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    assert_eq!(identity_requests.load(Ordering::SeqCst), requests_before);
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_cache_ttl() -> TestResult {
+    for (cache_ttl, refreshes_per_miss) in [(0, 1), (60, 0)] {
+        let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+        let identity_requests = upstream.identity_requests.clone();
+        let mock_agent = MockAgent::start(upstream)?;
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"cache-ttl = {cache_ttl}
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+                mock_agent.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+
+        assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+        let requests_before = identity_requests.load(Ordering::SeqCst);
+        for _ in 0..2 {
+            assert!(mux_agent.sign(keys::TEST_KEY_ECDSA_PUB).is_err());
+        }
+        assert_eq!(
+            identity_requests.load(Ordering::SeqCst) - requests_before,
+            2 * refreshes_per_miss,
+            "cache-ttl = {cache_ttl}"
+        );
+        // Listing always asks the agent
+        assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+        assert_eq!(
+            identity_requests.load(Ordering::SeqCst) - requests_before,
+            2 * refreshes_per_miss + 1,
+            "cache-ttl = {cache_ttl}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_default_visibility_none() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let config = |visible: &str| {
+        format!(
+            r##"default-visibility = "none"
+visible-fingerprints = [{visible}]
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        )
+    };
+
+    let mux_agent = SshAgentInstance::new_mux(&config(""), None::<OsString>)?;
+    assert!(mux_agent.list()?.is_empty());
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    drop(mux_agent);
+
+    let fingerprint =
+        PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?.fingerprint(Default::default());
+    let mux_agent =
+        SshAgentInstance::new_mux(&config(&format!("\"{fingerprint}\"")), None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert!(mux_agent.sign(keys::TEST_KEY_ECDSA_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_allowed_key_types() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ED25519_PUB]);
+    let sign_requests = upstream.sign_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"allowed-key-types = ["ssh-ed25519"]
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    // The RSA key isn't known to the mux, so it isn't forwarded
+    assert!(mux_agent.sign(keys::TEST_KEY_RSA_PUB).is_err());
+    assert_eq!(sign_requests.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn mux_comment_filter() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let cache_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    fs::remove_file(&cache_path)?;
+    let config = |settings: &str| {
+        format!(
+            r##"known-keys-cache = "{}"
+{settings}
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            cache_path.display(),
+            mock_agent.sock_path.display()
+        )
+    };
+    let filter = "comment-filter = \"-ed25519$\"";
+
+    // Caches both keys
+    let mux_agent = SshAgentInstance::new_mux(&config(""), None::<OsString>)?;
+    assert_eq!(mux_agent.list()?.len(), 2);
+    drop(mux_agent);
+
+    // Neither the cache nor broadcasting lets a hidden key sign
+    let mux_agent = SshAgentInstance::new_mux(&config(filter), None::<OsString>)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ECDSA_PUB).is_err());
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    drop(mux_agent);
+    let mux_agent = SshAgentInstance::new_mux(
+        &config(&format!("{filter}\nsign-fallback-broadcast = true")),
+        None::<OsString>,
+    )?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ECDSA_PUB).is_err());
+    drop(mux_agent);
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &config(&format!("{filter}\ncomment-filter-invert = true")),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ECDSA_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_known_keys_cache() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let other_agent = SshAgentInstance::new_openssh()?;
+    let cache_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    fs::remove_file(&cache_path)?;
+
+    let config = format!(
+        r##"known-keys-cache = "{}"
+
+[[agents]]
+name = "mock"
+socket-path = "{}""##,
+        cache_path.display(),
+        mock_agent.sock_path.display()
+    );
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    drop(mux_agent);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+    let key_base64 = keys::TEST_KEY_ED25519_PUB
+        .split_whitespace()
+        .nth(1)
+        .unwrap();
+    assert!(fs::read_to_string(&cache_path)?.contains(key_base64));
+
+    // Routed from the cache, without refreshing
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    drop(mux_agent);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+
+    // Adding an agent invalidates the cache
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"{config}
+
+[[agents]]
+name = "other"
+socket-path = "{}""##,
+            other_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_known_keys_cache_max_age() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let cache_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    fs::remove_file(&cache_path)?;
+    let config = format!(
+        r##"known-keys-cache = "{}"
+known-keys-cache-max-age = 60
+
+[[agents]]
+name = "mock"
+socket-path = "{}""##,
+        cache_path.display(),
+        mock_agent.sock_path.display()
+    );
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    drop(mux_agent);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+
+    // Backdate the cache past the maximum age: signing then needs a refresh
+    let cache = fs::read_to_string(&cache_path)?;
+    let written_at = cache
+        .lines()
+        .find(|line| line.starts_with("written-at = "))
+        .expect("cache has a timestamp");
+    fs::write(&cache_path, cache.replace(written_at, "written-at = 1"))?;
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    drop(mux_agent);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+
+    // The cache written at that shutdown is fresh again
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_lazy_connect() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let cache_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    fs::remove_file(&cache_path)?;
+    let config = format!(
+        r##"lazy-connect = true
+known-keys-cache = "{}"
+add-new-keys-to = "mock"
+
+[[agents]]
+name = "mock"
+socket-path = "{}""##,
+        cache_path.display(),
+        mock_agent.sock_path.display()
+    );
+
+    // Only the first listing, with no keys known yet, asks the upstream agent
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+
+    // A change through the mux means the known keys need refreshing
+    mux_agent.add(keys::TEST_KEY_ECDSA)?;
+    mux_agent.list()?;
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+    drop(mux_agent);
+
+    // After a restart, the cached keys are listed, without their comments
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    let key_base64 = keys::TEST_KEY_ED25519_PUB
+        .split_whitespace()
+        .nth(1)
+        .unwrap();
+    let listed = mux_agent.list()?;
+    assert_eq!(listed.len(), 1, "{:?}", listed);
+    assert!(listed[0].contains(key_base64), "{:?}", listed);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+    drop(mux_agent);
+
+    // Unless the key filters now hide them
+    let fingerprint =
+        PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?.fingerprint(Default::default());
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!("{config}\nhide-fingerprints = [\"{fingerprint}\"]"),
+        None::<OsString>,
+    )?;
+    assert!(mux_agent.list()?.is_empty());
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_lazy_connect_always_refresh() -> TestResult {
+    let stable = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let stable_requests = stable.identity_requests.clone();
+    let stable = MockAgent::start(stable)?;
+    let volatile = ScriptedAgent::with_keys(&[keys::TEST_KEY_ECDSA_PUB]);
+    let volatile_requests = volatile.identity_requests.clone();
+    let volatile = MockAgent::start(volatile)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"lazy-connect = true
+
+[[agents]]
+name = "stable"
+socket-path = "{}"
+
+[[agents]]
+name = "volatile"
+socket-path = "{}"
+always-refresh = true"##,
+            stable.sock_path.display(),
+            volatile.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let listed = [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_ECDSA_PUB];
+    assert_eq!(mux_agent.list()?, listed);
+    assert_eq!(mux_agent.list()?, listed);
+    assert_eq!(mux_agent.list()?, listed);
+    // Only the flagged agent is asked again once the keys are known
+    assert_eq!(stable_requests.load(Ordering::SeqCst), 1);
+    assert_eq!(volatile_requests.load(Ordering::SeqCst), 3);
+
+    // Its keys disappear once it's gone, while the other agent's are still listed
+    drop(volatile);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(stable_requests.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+/// Send an extension request with no details, and return the type of the response message; the
+/// protocol client can't tell failure responses apart
+fn extension_response_type(sock_path: &Path, name: &str) -> io::Result<u8> {
+    const SSH_AGENTC_EXTENSION: u8 = 27;
+    let mut message = vec![SSH_AGENTC_EXTENSION];
+    message.extend((name.len() as u32).to_be_bytes());
+    message.extend(name.as_bytes());
+
+    let mut stream = UnixStream::connect(sock_path)?;
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(&message)?;
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    response
+        .first()
+        .copied()
+        .ok_or_else(|| io::Error::other("empty response"))
+}
+
+#[test]
+fn mux_extension_failure_codes() -> TestResult {
+    const SSH_AGENT_FAILURE: u8 = 5;
+    const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+    // The mock agent doesn't support session-bind@openssh.com
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let response_type = |name| extension_response_type(&mux_agent.sock_path, name);
+    assert_eq!(response_type("unknown@example.com")?, SSH_AGENT_FAILURE);
+    // Supported, but the upstream agent fails it
+    assert_eq!(
+        response_type("session-bind@openssh.com")?,
+        SSH_AGENT_EXTENSION_FAILURE
+    );
+    // Supported, but the request has no tag list
+    assert_eq!(
+        response_type(SelectTags::NAME)?,
+        SSH_AGENT_EXTENSION_FAILURE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_session_bind_skips_agents_blocking_it() -> TestResult {
+    let scripted = || ScriptedAgent {
+        accept_session_bind: true,
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let (team, personal) = (scripted(), scripted());
+    let (team_requests, personal_requests) = (
+        team.extension_requests.clone(),
+        personal.extension_requests.clone(),
+    );
+    let team_agent = MockAgent::start(team)?;
+    let personal_agent = MockAgent::start(personal)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "team"
+socket-path = "{}"
+no-forward-extensions = ["session-bind@openssh.com"]
+
+[[agents]]
+name = "personal"
+socket-path = "{}""##,
+            team_agent.sock_path.display(),
+            personal_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let session_bind = || Extension {
+        name: "session-bind@openssh.com".into(),
+        details: Vec::new().into(),
+    };
+    let response = mux_agent
+        .with_client(|mut client| async move { client.extension(session_bind()).await })?;
+    assert!(response.is_none());
+    assert_eq!(team_requests.load(Ordering::SeqCst), 0);
+    assert_eq!(personal_requests.load(Ordering::SeqCst), 1);
+
+    // With every agent blocking it, nothing can bind the session
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "team"
+socket-path = "{}"
+forward-extensions = ["other@example.com"]"##,
+            team_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let result =
+        mux_agent.with_client(|mut client| async move { client.extension(session_bind()).await });
+    assert!(result.is_err());
+    assert_eq!(team_requests.load(Ordering::SeqCst), 0);
+
+    Ok(())
+}
+
+#[test]
+fn mux_forwards_other_extensions() -> TestResult {
+    let unsupporting = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let unsupporting_requests = unsupporting.extension_requests.clone();
+    let supporting = ScriptedAgent {
+        echo_extensions: vec!["ecdh@joyent.com".into()],
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB])
+    };
+    let supporting_requests = supporting.extension_requests.clone();
+    let unsupporting_agent = MockAgent::start(unsupporting)?;
+    let supporting_agent = MockAgent::start(supporting)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "unsupporting"
+socket-path = "{}"
+
+[[agents]]
+name = "supporting"
+socket-path = "{}""##,
+            unsupporting_agent.sock_path.display(),
+            supporting_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let request = Extension {
+        name: "ecdh@joyent.com".into(),
+        details: b"request details".to_vec().into(),
+    };
+    let response = mux_agent.with_client(|mut client| {
+        let request = request.clone();
+        async move { client.extension(request).await }
+    })?;
+    assert_eq!(response, Some(request));
+    assert_eq!(unsupporting_requests.load(Ordering::SeqCst), 1);
+    assert_eq!(supporting_requests.load(Ordering::SeqCst), 1);
+
+    // No agent answers it
+    let result = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: "unknown@example.com".into(),
+                details: Vec::new().into(),
+            })
+            .await
+    });
+    assert!(result.is_err());
+    assert_eq!(supporting_requests.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_query_advertises_extra_extensions() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux(
+        r#"advertise-extensions = ["routing-table@ssh-agent-mux", "session-bind@openssh.com"]"#,
+        None::<OsString>,
+    )?;
+
+    let response = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: "query".into(),
+                details: Vec::new().into(),
+            })
+            .await
+    })?;
+    let QueryResponse { extensions } = response
+        .expect("query has a response")
+        .parse_message::<QueryResponse>()?
+        .expect("query response");
+
+    assert_eq!(
+        extensions,
+        [
+            "session-bind@openssh.com",
+            SelectTags::NAME,
+            RefreshStatus::NAME,
+            ListUpstreams::NAME,
+            MuxInfo::NAME,
+            MuxIdentity::NAME,
+            "routing-table@ssh-agent-mux"
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_info_extension() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux("sign-check = true", None::<OsString>)?;
+
+    let response = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: MuxInfo::NAME.into(),
+                details: Vec::new().into(),
+            })
+            .await
+    })?;
+    let info = response
+        .expect("info has a response")
+        .parse_message::<MuxInfo>()?
+        .expect("info response");
+
+    assert_eq!(info.name, "ssh-agent-mux");
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        info.extensions,
+        [
+            SelectTags::NAME,
+            RefreshStatus::NAME,
+            ListUpstreams::NAME,
+            MuxInfo::NAME,
+            MuxIdentity::NAME,
+            SignCheck::NAME
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_list_upstreams_extension() -> TestResult {
+    let first = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let second = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_RSA_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let mut config = String::new();
+    for (name, sock_path) in [
+        ("first", &first.sock_path),
+        ("second", &second.sock_path),
+        ("missing", &missing_sock),
+    ] {
+        config += &format!(
+            "[[agents]]\nname = \"{}\"\nsocket-path = \"{}\"\n",
+            name,
+            sock_path.display()
+        );
+    }
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+
+    // Nothing has listed identities yet, so the extension has to refresh them itself
+    let response = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: ListUpstreams::NAME.into(),
+                details: Vec::new().into(),
+            })
+            .await
+    })?;
+    let ListUpstreams { agents } = response
+        .expect("list upstreams has a response")
+        .parse_message::<ListUpstreams>()?
+        .expect("list upstreams response");
+
+    let fingerprint =
+        |key| PublicKey::from_openssh(key).map(|k| k.fingerprint(Default::default()).to_string());
+    let agents: Vec<_> = agents
+        .into_iter()
+        .map(|a| (a.name, a.socket_path, a.fingerprints))
+        .collect();
+    // The key both agents list is routed to the last one
+    assert_eq!(
+        agents,
+        [
+            (
+                "first".into(),
+                first.sock_path.display().to_string(),
+                vec![fingerprint(keys::TEST_KEY_ED25519_PUB)?]
+            ),
+            (
+                "second".into(),
+                second.sock_path.display().to_string(),
+                vec![
+                    fingerprint(keys::TEST_KEY_RSA_PUB)?,
+                    fingerprint(keys::TEST_KEY_ECDSA_PUB)?
+                ]
+            ),
+            ("missing".into(), missing_sock.display().to_string(), vec![]),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_gpg_agent_lists_before_sign() -> TestResult {
+    let gpg_agent = MockAgent::start(ListBeforeSignAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+    ]))?;
+    let config = |kind: &str| {
+        format!(
+            r##"retry-sign = false
+
+[[agents]]
+name = "gpg"
+socket-path = "{}"
+kind = "{kind}""##,
+            gpg_agent.sock_path.display()
+        )
+    };
+
+    let mux_agent = SshAgentInstance::new_mux(&config("ssh-agent"), None::<OsString>)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    drop(mux_agent);
+
+    let mux_agent = SshAgentInstance::new_mux(&config("gpg-agent"), None::<OsString>)?;
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_startup_grace_waits_for_late_agent() -> TestResult {
+    let config = |sock_path: &TempPath, grace: &str| {
+        format!(
+            r##"[[agents]]
+name = "late"
+socket-path = "{}"
+{grace}"##,
+            sock_path.display()
+        )
+    };
+    let start_late = |sock_path: TempPath| {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            MockAgent::start_at(
+                sock_path,
+                ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]),
+            )
+        })
+    };
+
+    // Without a grace period, the agent is skipped until it appears
+    let sock_path = harness::temp_sock_path("late_")?;
+    let mux_agent = SshAgentInstance::new_mux(&config(&sock_path, ""), None::<OsString>)?;
+    let late_agent = start_late(sock_path);
+    assert!(mux_agent.list()?.is_empty());
+    let _late_agent = late_agent.join().expect("mock agent thread panicked")?;
+    drop(mux_agent);
+
+    let sock_path = harness::temp_sock_path("late_")?;
+    let mux_agent =
+        SshAgentInstance::new_mux(&config(&sock_path, "startup-grace = 5"), None::<OsString>)?;
+    let late_agent = start_late(sock_path);
+    let listed = mux_agent.list()?;
+    let _late_agent = late_agent.join().expect("mock agent thread panicked")?;
+    assert_eq!(listed, [keys::TEST_KEY_ED25519_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_add_if_present() -> TestResult {
+    for (policy, adds_succeed, expected_adds) in
+        [("replace", true, 2), ("skip", true, 0), ("error", false, 0)]
+    {
+        let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+        let adds = upstream.adds.clone();
+        let mock_agent = MockAgent::start(upstream)?;
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"add-new-keys-to = "target"
+add-if-present = "{policy}"
+
+[[agents]]
+name = "target"
+socket-path = "{}""##,
+                mock_agent.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+
+        for _ in 0..2 {
+            assert_eq!(
+                mux_agent.add(keys::TEST_KEY_ED25519).is_ok(),
+                adds_succeed,
+                "add-if-present = {policy}"
+            );
+        }
+        assert_eq!(
+            adds.load(Ordering::SeqCst),
+            expected_adds,
+            "add-if-present = {policy}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_add_falls_back_to_next_target() -> TestResult {
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let fallback = ScriptedAgent::with_keys(&[]);
+    let adds = fallback.adds.clone();
+    let fallback = MockAgent::start(fallback)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = ["primary", "fallback"]
+
+[[agents]]
+name = "primary"
+socket-path = "{}"
+
+[[agents]]
+name = "fallback"
+socket-path = "{}""##,
+            missing_sock.display(),
+            fallback.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(adds.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn mux_add_to_all_targets() -> TestResult {
+    let disk_agent = SshAgentInstance::new_openssh()?;
+    let forwarded_agent = SshAgentInstance::new_openssh()?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"add-new-keys-to = ["disk", "missing", "forwarded"]
+add-to-all = true
+
+[[agents]]
+name = "disk"
+socket-path = "{}"
+
+[[agents]]
+name = "missing"
+socket-path = "{}"
+
+[[agents]]
+name = "forwarded"
+socket-path = "{}""##,
+            disk_agent.sock_path.display(),
+            missing_sock.display(),
+            forwarded_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The unreachable target doesn't fail the add
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(disk_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(forwarded_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let output = mux_agent.stop()?;
+    assert!(
+        output.contains("Failed to add key to upstream agent"),
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_builtin_agent() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux(
+        r##"add-new-keys-to = "memory"
+
+[[agents]]
+name = "memory"
+kind = "builtin""##,
+        None::<OsString>,
+    )?;
+    assert_no_keys_in_agent(&mux_agent)?;
+
+    mux_agent.add(keys::TEST_KEY_RSA)?;
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+    for pubkey in [keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ED25519_PUB] {
+        let signature = mux_agent.sign(pubkey)?;
+        let pubkey = PublicKey::from_openssh(pubkey)?;
+        pubkey
+            .key_data()
+            .verify(b"ssh-agent-mux test data", &signature)?;
+    }
+
+    // Constraints it can't enforce are refused, rather than ignored
+    assert!(mux_agent
+        .add_with_lifetime(keys::TEST_KEY_ECDSA, 60)
+        .is_err());
+
+    mux_agent.lock("test-passphrase")?;
+    assert_no_keys_in_agent(&mux_agent)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_RSA_PUB).is_err());
+    assert!(mux_agent.unlock("wrong-passphrase").is_err());
+    mux_agent.unlock("test-passphrase")?;
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+
+    mux_agent.remove(keys::TEST_KEY_RSA_PUB)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_offer_rank() -> TestResult {
+    let software = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_RSA_PUB,
+        keys::TEST_KEY_ED25519_PUB,
+    ]))?;
+    let hardware = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "software"
+socket-path = "{}"
+
+[[agents]]
+name = "hardware"
+socket-path = "{}"
+offer-rank = -1"##,
+            software.sock_path.display(),
+            hardware.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The key both agents hold is offered once, where the hardware agent's keys are
+    assert_eq!(
+        mux_agent.list()?,
+        [
+            keys::TEST_KEY_ED25519_PUB,
+            keys::TEST_KEY_ECDSA_PUB,
+            keys::TEST_KEY_RSA_PUB
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_add_timeout() -> TestResult {
+    let upstream = ScriptedAgent {
+        add_delay: Duration::from_millis(1500),
+        ..ScriptedAgent::with_keys(&[])
+    };
+    let mock_agent = MockAgent::start(upstream)?;
+    let config = |add_timeout: &str| {
+        format!(
+            r##"agent-timeout = 1
+add-new-keys-to = "slow"
+{add_timeout}
+
+[[agents]]
+name = "slow"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        )
+    };
+
+    // Slower than agent-timeout
+    let mux_agent = SshAgentInstance::new_mux(&config(""), None::<OsString>)?;
+    assert!(mux_agent.add(keys::TEST_KEY_ED25519).is_err());
+
+    let mux_agent = SshAgentInstance::new_mux(&config("add-timeout = 3"), None::<OsString>)?;
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_log_timestamps() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let log_lines = |timestamp: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"log-timestamp = "{}"
+
+[[agents]]
+name = "mock"
+socket-path = "{}""##,
+                timestamp,
+                mock_agent.sock_path.display()
+            ),
+            None::<OsString>,
+        )?;
+        mux_agent.list()?;
+        let output = mux_agent.stop()?;
+        let lines: Vec<_> = output
+            .lines()
+            .filter(|l| l.contains("Accepted client connection"))
+            .map(String::from)
+            .collect();
+        assert!(!lines.is_empty(), "{output}");
+        Ok(lines)
+    };
+
+    for line in log_lines("none")? {
+        assert!(line.starts_with("DEBUG ["), "{line}");
+    }
+    for line in log_lines("rfc3339")? {
+        // e.g. 2024-05-01T12:34:56.789Z
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert_eq!(timestamp.len(), 24, "{line}");
+        assert_eq!(timestamp.as_bytes()[10], b'T', "{line}");
+        assert!(timestamp.ends_with('Z'), "{line}");
+        assert!(rest.starts_with("DEBUG ["), "{line}");
+    }
+    for line in log_lines("local")? {
+        assert!(!line.starts_with("DEBUG"), "{line}");
+        assert!(line.contains(" DEBUG ["), "{line}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mux_audit_log_routing_trace() -> TestResult {
+    let first = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let second = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let fallback = MockAgent::start(ScriptedAgent {
+        sign_unlisted: true,
+        ..Default::default()
+    })?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"audit-verbosity = "routing"
+default-agent = "fallback"
+
+[[agents]]
+name = "first"
+socket-path = "{}"
+
+[[agents]]
+name = "second"
+socket-path = "{}"
+
+[[agents]]
+name = "fallback"
+socket-path = "{}""##,
+            first.sock_path.display(),
+            second.sock_path.display(),
+            fallback.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    mux_agent.sign(keys::TEST_KEY_ECDSA_PUB)?;
+    let output = mux_agent.stop()?;
+    let audit: Vec<_> = output
+        .lines()
+        .filter(|l| l.contains("Sign with key"))
+        .collect();
+
+    let fingerprint =
+        |key| ssh_key::PublicKey::from_openssh(key).map(|k| k.fingerprint(Default::default()));
+    assert_eq!(audit.len(), 2, "{output}");
+    assert!(
+        audit[0].ends_with(&format!(
+            "Sign with key {}: signed by upstream agent second; listed by: first, second; \
+             steps: routed to owner second; second signed",
+            fingerprint(keys::TEST_KEY_ED25519_PUB)?
+        )),
+        "{}",
+        audit[0]
+    );
+    assert!(
+        audit[1].ends_with(&format!(
+            "Sign with key {}: signed by upstream agent fallback; listed by: none; \
+             steps: routed to default agent fallback; fallback signed",
+            fingerprint(keys::TEST_KEY_ECDSA_PUB)?
+        )),
+        "{}",
+        audit[1]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_json_log_format() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"log-format = "json"
+audit-verbosity = "outcome"
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    let output = mux_agent.stop()?;
+
+    let records = output
+        .lines()
+        .map(|line| harness::parse_json_fields(line).ok_or(line))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|line| format!("not a JSON log record: {line}"))?;
+    let field = |record: &[(String, String)], name: &str| {
+        record
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    for record in &records {
+        for name in ["timestamp", "level", "target", "message"] {
+            assert!(field(record, name).is_some(), "no {name} in {record:?}");
+        }
+    }
+    let fingerprint = ssh_key::PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+    let audit = records
+        .iter()
+        .find(|r| field(r, "target").as_deref() == Some("ssh_agent_mux::audit"))
+        .ok_or("no audit record")?;
+    assert_eq!(field(audit, "level").as_deref(), Some("INFO"));
+    assert_eq!(field(audit, "fingerprint"), Some(fingerprint));
+    assert!(field(audit, "session").is_some(), "{audit:?}");
+
+    Ok(())
+}
+
+#[test]
+fn mux_audit_file() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    // Its directory is created by the mux
+    let audit_path = scratch.path().join("audit").join("sign.log");
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"audit-file = "{}"
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            audit_path.display(),
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    // Flushed once the request is answered, before the mux stops
+    let audit = std::fs::read_to_string(&audit_path)?;
+    mux_agent.stop()?;
+
+    let lines: Vec<_> = audit.lines().collect();
+    assert_eq!(lines.len(), 1, "{audit}");
+    let record = harness::parse_json_fields(lines[0]).ok_or("not a JSON audit record")?;
+    let field = |name: &str| {
+        record
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let fingerprint = ssh_key::PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+    assert!(field("timestamp").is_some(), "{record:?}");
+    assert_eq!(field("fingerprint"), Some(fingerprint.as_str()));
+    assert_eq!(field("agent"), Some("upstream"));
+    assert_eq!(field("result"), Some("success"));
+    assert!(
+        field("pid").is_some_and(|pid| pid.parse::<u32>().is_ok()),
+        "{record:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_audit_file_under_rust_log() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let audit_path = scratch.path().join("sign.log");
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "audit-file = \"{}\"\n[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+            audit_path.display(),
+            openssh_agent.sock_path.display()
+        ),
+    )?;
+    let sock_path = harness::temp_sock_path("agent_")?;
+    // Directives that would filter out audit records like any other
+    let handle = duct::cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--listen-path",
+        &sock_path,
+        format!("--config={}", config_path.display())
+    )
+    .env("RUST_LOG", "off")
+    .unchecked()
+    .stderr_to_stdout()
+    .stdout_capture()
+    .start()?;
+    let started = Instant::now();
+    while UnixStream::connect(&sock_path).is_err() && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mux_agent = SshAgentInstance { handle, sock_path };
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    let audit = fs::read_to_string(&audit_path)?;
+    let output = mux_agent.stop()?;
+    assert_eq!(audit.lines().count(), 1, "{audit}");
+    // The log itself still follows the directives
+    assert!(!output.contains("Requesting signature"), "{output}");
+
+    Ok(())
+}
+
+#[test]
+fn mux_confirm_sign() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let prompts = scratch.path().join("prompts");
+    let approve = scratch.path().join("approve");
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"confirm-command = ["sh", "-c", 'printf "%s\n" "$$1" >> {}; test -e {}', "confirm"]
+
+[[agents]]
+name = "upstream"
+socket-path = "{}"
+confirm = true"##,
+            prompts.display(),
+            approve.display(),
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    std::fs::write(&approve, "")?;
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    std::fs::remove_file(&approve)?;
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    let fingerprint =
+        PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?.fingerprint(Default::default());
+    let output = mux_agent.stop()?;
+    // Asked once per request, even though the refused one is retried
+    let prompt = format!("Allow use of key {fingerprint} through upstream agent upstream?");
+    assert_eq!(
+        std::fs::read_to_string(&prompts)?,
+        format!("{prompt}\n{prompt}\n")
+    );
+    assert!(output.contains("policy denied (not-confirmed)"), "{output}");
+
+    Ok(())
+}
+
+#[test]
+fn mux_refuses_upstream_loop() -> TestResult {
+    let scratch = tempfile::tempdir()?;
+    // Made to point at the second mux once it's listening
+    let link = scratch.path().join("second.sock");
+    let first = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "second"
+socket-path = "{}""##,
+            link.display()
+        ),
+        None::<OsString>,
+    )?;
+    let second = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "first"
+socket-path = "{}""##,
+            first.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    std::os::unix::fs::symlink(&second.sock_path, &link)?;
+
+    // The second mux probes the first on connecting to it. Without stopping there, the request
+    // would go round until it timed out.
+    let started = Instant::now();
+    let _ = second.list();
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        started.elapsed()
+    );
+
+    let output = second.stop()?;
+    first.stop()?;
+    assert!(
+        output.contains("Upstream agent first leads back to this mux through other muxes"),
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_gpg_agent_not_probed() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let mux_probes = upstream.mux_probes.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let config = |kind: &str| {
+        format!(
+            r##"[[agents]]
+name = "gpg"
+socket-path = "{}"
+kind = "{kind}""##,
+            mock_agent.sock_path.display()
+        )
+    };
+
+    let mux_agent = SshAgentInstance::new_mux(&config("ssh-agent"), None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    drop(mux_agent);
+    assert_eq!(mux_probes.load(Ordering::SeqCst), 1);
+
+    // gpg-agent isn't sent extensions, so it isn't probed for loops either
+    let mux_agent = SshAgentInstance::new_mux(&config("gpg-agent"), None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert_eq!(mux_probes.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn mux_probe_timeout_reconnects() -> TestResult {
+    let upstream = ScriptedAgent {
+        probe_delay: Duration::from_secs(2),
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let mux_probes = upstream.mux_probes.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent-timeout = 1
+
+[[agents]]
+name = "slow-to-fail"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // The probe timed out, so the agent is asked for its keys on a new, unprobed connection
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+    assert_eq!(mux_probes.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_refused_by_owner() -> TestResult {
+    let upstream = ScriptedAgent {
+        refuse_sign: true,
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let sign_requests = upstream.sign_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"retry-sign = true
+
+[[agents]]
+name = "confirming"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+    // The agent still lists the key, so it isn't asked again, as that would prompt again
+    assert_eq!(sign_requests.load(Ordering::SeqCst), 1);
+
+    let fingerprint =
+        PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?.fingerprint(Default::default());
+    let output = mux_agent.stop()?;
+    assert!(
+        output.contains(&format!(
+            "upstream agent confirming refused to sign with key {fingerprint}"
+        )),
+        "{output}"
+    );
+    assert!(!output.contains("No upstream agent found"), "{output}");
+
+    Ok(())
+}
+
+/// Panics on the first sign request, as a bug in the mux would, and allows every later one
+#[derive(Debug, Default)]
+struct PanicsOnce(AtomicBool);
+
+#[async_trait]
+impl RequestPolicy for PanicsOnce {
+    async fn allow_sign(&self, _: &Fingerprint, _: Option<&Peer>) -> Decision {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            panic!("sign policy bug");
+        }
+        Decision::Allow
+    }
+}
+
+#[test]
+fn mux_survives_session_panic() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let listen_sock = harness::temp_sock_path("ssh-agent-mux_")?;
+    let agents = [UpstreamAgent::new(
+        "upstream",
+        mock_agent.sock_path.to_path_buf(),
+    )];
+    let options = MuxOptions {
+        policy: Arc::new(PanicsOnce::default()),
+        ..Default::default()
+    };
+    let request = SignRequest {
+        pubkey: PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+            .key_data()
+            .clone(),
+        data: b"ssh-agent-mux test data".to_vec(),
+        flags: 0,
+    };
+
+    // The mux runs in this process, as only a policy can make a session panic on purpose
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mux = tokio::spawn(MuxAgent::run(
+            listen_sock.to_path_buf(),
+            agents,
+            vec![],
+            options,
+        ));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !listen_sock.exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let connect = || async {
+            let stream = tokio::net::UnixStream::connect(&listen_sock).await?;
+            client::connect(stream.into_std()?.into()).map_err(|e| io::Error::other(e.to_string()))
+        };
+
+        let mut panicking: Box<dyn Session> = connect().await?;
+        assert!(panicking.sign(request.clone()).await.is_err());
+
+        // The listener, and even the connection whose session panicked, keep being served
+        let mut other: Box<dyn Session> = connect().await?;
+        assert_eq!(other.request_identities().await?.len(), 1);
+        assert_eq!(other.sign(request.clone()).await?, mock::dummy_signature());
+        assert_eq!(panicking.sign(request).await?, mock::dummy_signature());
+
+        mux.abort();
+        TestResult::Ok(())
+    })
+}
+
+#[test]
+fn mux_select_tags() -> TestResult {
+    let agent_work = SshAgentInstance::new_openssh()?;
+    agent_work.add(keys::TEST_KEY_ED25519)?;
+    let agent_personal = SshAgentInstance::new_openssh()?;
+    agent_personal.add(keys::TEST_KEY_RSA)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "work"
+socket-path = "{}"
+tags = ["work", "hardware"]
+
+[[agents]]
+name = "personal"
+socket-path = "{}"
+tags = ["personal"]"##,
+            agent_work.sock_path.display(),
+            agent_personal.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Without a selection, every agent is in scope
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    let work_key = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?;
+    let personal_key = PublicKey::from_openssh(keys::TEST_KEY_RSA_PUB)?;
+    let (scoped, personal_sign, reset) = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension::new_message(SelectTags {
+                tags: vec!["work".into()],
+            })?)
+            .await?;
+        let scoped = client.request_identities().await?;
+        let personal_sign = client
+            .sign(SignRequest {
+                pubkey: personal_key.key_data().clone(),
+                data: b"data".to_vec(),
+                flags: 0,
+            })
+            .await;
+
+        client
+            .extension(Extension::new_message(SelectTags { tags: vec![] })?)
+            .await?;
+        let reset = client.request_identities().await?;
+        Ok((scoped, personal_sign, reset))
+    })?;
+
+    assert_eq!(scoped.len(), 1);
+    assert_eq!(&scoped[0].pubkey, work_key.key_data());
+    assert!(
+        personal_sign.is_err(),
+        "Out-of-scope key should not be signed"
+    );
+    assert_eq!(reset.len(), 2);
+
+    // The selection only applies to the connection that made it
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_skips_malformed_identities() -> TestResult {
+    let mut upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    // An RSA key with a one-bit modulus decodes fine, but is unusable
+    upstream.identities.push(Identity {
+        pubkey: KeyData::Rsa(RsaPublicKey {
+            e: Mpint::from_positive_bytes(&[0x01, 0x00, 0x01])?,
+            n: Mpint::from_positive_bytes(&[0x01])?,
+        }),
+        comment: "malformed".into(),
+    });
+    let mock_agent = MockAgent::start(upstream)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"[[agents]]
+name = "buggy"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let identities =
+        mux_agent.with_client(|mut client| async move { client.request_identities().await })?;
+    assert_eq!(identities, [mock::identity(keys::TEST_KEY_ED25519_PUB)]);
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "http-metrics")]
+fn http_get(port: u16, path: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(feature = "http-metrics")]
+fn scrape_metrics(port: u16) -> io::Result<String> {
+    http_get(port, "/metrics")
+}
+
+#[cfg(feature = "http-metrics")]
+#[test]
+fn mux_metrics_http() -> TestResult {
+    // Reserve a free port for the mux's metrics endpoint
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"metrics-http = "127.0.0.1:{}"
+sign-check = true
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            port,
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let before = scrape_metrics(port)?;
+    assert!(before.starts_with("HTTP/1.1 200 OK"), "{}", before);
+    assert!(before.contains("\nssh_agent_mux_sign_requests_total 0\n"));
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+
+    let after = scrape_metrics(port)?;
+    assert!(after.contains("\nssh_agent_mux_sign_requests_total 1\n"));
+    assert!(after.contains("\nssh_agent_mux_sign_failures_total 0\n"));
+    assert!(
+        after.contains("_upstream_sign_successes_total{agent=\"upstream\"} 1\n"),
+        "{}",
+        after
+    );
+    assert!(
+        after.contains("_upstream_sign_failures_total{agent=\"upstream\"} 0\n"),
+        "{}",
+        after
+    );
+    assert!(
+        after.contains("\nssh_agent_mux_known_keys 3\n"),
+        "{}",
+        after
+    );
+
+    // Sign checks aren't counted as signs
+    let pubkey = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?;
+    let results = mux_agent.with_client(|mut client| async move {
+        let response = client
+            .extension(Extension::new_message(SignCheck {
+                pubkey: pubkey.key_data().clone(),
+            })?)
+            .await?
+            .expect("sign check has a response");
+        Ok(response
+            .parse_message::<SignCheckResults>()?
+            .expect("sign check response"))
+    })?;
+    assert!(results.agents[0].ok, "{:?}", results.agents);
+    let checked = scrape_metrics(port)?;
+    assert!(
+        checked.contains("_upstream_sign_successes_total{agent=\"upstream\"} 1\n"),
+        "{}",
+        checked
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "http-metrics")]
+#[test]
+fn mux_health_probes() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let mux_with_agent = |socket_path: &Path| -> io::Result<(u16, SshAgentInstance)> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let mux_agent = SshAgentInstance::new_mux(
+            &format!(
+                r##"metrics-http = "127.0.0.1:{}"
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+                port,
+                socket_path.display()
+            ),
+            None::<OsString>,
+        )?;
+        Ok((port, mux_agent))
+    };
+
+    let (port, _mux_agent) = mux_with_agent(&openssh_agent.sock_path)?;
+    let health = http_get(port, "/healthz")?;
+    assert!(health.starts_with("HTTP/1.1 200 OK"), "{}", health);
+    let ready = http_get(port, "/readyz")?;
+    assert!(ready.starts_with("HTTP/1.1 200 OK"), "{}", ready);
+
+    let (port, _mux_agent) = mux_with_agent(&missing_sock)?;
+    let health = http_get(port, "/healthz")?;
+    assert!(health.starts_with("HTTP/1.1 200 OK"), "{}", health);
+    let ready = http_get(port, "/readyz")?;
+    assert!(
+        ready.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "{}",
+        ready
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_unwritable_socket_directory() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555))?;
+    if fs::create_dir(dir.path().join("probe")).is_ok() {
+        println!("Skipping: permissions aren't enforced for this user");
+        return Ok(());
+    }
+
+    let listen_path = dir.path().join("state").join("agent.sock");
+    let output = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("--config=/nonexistent")
+        .arg("--listen-path")
+        .arg(&listen_path)
+        .output()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+
+    assert_eq!(output.status.code(), Some(77), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let expected = format!(
+        "cannot create socket directory {}",
+        listen_path.parent().unwrap().display()
+    );
+    assert!(
+        stderr.contains(&expected),
+        "unexpected error output:\n{stderr}"
+    );
+    assert!(
+        stderr.contains("check permissions"),
+        "unexpected error output:\n{stderr}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_exit_codes() -> TestResult {
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let run_mux = |listen_path: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+            .arg(format!("--config={}", config_path.display()))
+            .arg("--listen-path")
+            .arg(listen_path)
+            .output()
+    };
+
+    fs::write(&config_path, "add-new-keys-to = \"nonexistent\"")?;
+    let output = run_mux(&scratch.path().join("mux.sock"))?;
+    assert_eq!(output.status.code(), Some(78), "{:?}", output);
+    fs::write(&config_path, "agent-timeout = \"soon\"")?;
+    let output = run_mux(&scratch.path().join("mux.sock"))?;
+    assert_eq!(output.status.code(), Some(78), "{:?}", output);
+
+    // Another mux already listens on the socket
+    fs::write(&config_path, "")?;
+    let mux_agent = SshAgentInstance::new_mux("", None::<OsString>)?;
+    let output = run_mux(&mux_agent.sock_path)?;
+    assert_eq!(output.status.code(), Some(75), "{:?}", output);
+
+    Ok(())
+}
+
+#[test]
+fn mux_once() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let listen_path = scratch.path().join("mux.sock");
+    fs::write(
+        &config_path,
+        format!(
+            "[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+            openssh_agent.sock_path.display()
+        ),
+    )?;
+    // Not started with the harness, whose readiness check would be the one connection
+    let mut mux = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg(format!("--config={}", config_path.display()))
+        .arg("--listen-path")
+        .arg(&listen_path)
+        .arg("--once")
+        .spawn()?;
+    for _ in 0..100 {
+        if listen_path.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let output = Command::new("ssh-add")
+        .arg("-L")
+        .env("SSH_AUTH_SOCK", &listen_path)
+        .output()?;
+    let listed = String::from_utf8(output.stdout)?;
+    for key in keys::PUBLIC {
+        assert!(listed.contains(key), "{} not listed in:\n{}", key, listed);
+    }
+
+    let mut status = None;
+    for _ in 0..100 {
+        status = mux.try_wait()?;
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let Some(status) = status else {
+        mux.kill()?;
+        panic!("mux still running after serving its one connection");
+    };
+    assert!(status.success(), "{:?}", status);
+    assert!(!listen_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn mux_probe_only() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let listen_path = scratch.path().join("mux.sock");
+    let probe = |agents: &str| -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        fs::write(&config_path, agents)?;
+        Ok(Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+            .arg(format!("--config={}", config_path.display()))
+            .arg("--listen-path")
+            .arg(&listen_path)
+            .arg("--probe-only")
+            .output()?)
+    };
+    let reachable = format!(
+        "[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+        openssh_agent.sock_path.display()
+    );
+
+    let output = probe(&reachable)?;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stdout.is_empty());
+    assert!(!listen_path.exists());
+
+    let unreachable = format!(
+        "{reachable}\n[[agents]]\nname = \"gone\"\nsocket-path = \"{}\"\n",
+        scratch.path().join("gone.sock").display()
+    );
+    let output = probe(&unreachable)?;
+    assert_eq!(output.status.code(), Some(69), "{:?}", output);
+    assert!(String::from_utf8(output.stderr)?.contains("gone"));
+    assert!(!listen_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn mux_reload_with_bad_config() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let start_mux = |config: &str| -> io::Result<SshAgentInstance> {
+        fs::write(
+            &config_path,
+            format!(
+                "{}\n[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+                config,
+                openssh_agent.sock_path.display()
+            ),
+        )?;
+        SshAgentInstance::new(
+            SshAgentType::Mux,
+            [format!("--config={}", config_path.display())],
+        )
+    };
+    let reload_with = |mux_agent: &SshAgentInstance, config: &str| -> io::Result<()> {
+        fs::write(&config_path, config)?;
+        mux_agent.reload()?;
+        // Give the mux time to handle the signal
+        thread::sleep(Duration::from_millis(500));
+        Ok(())
+    };
+    let reload_bad_config =
+        |mux_agent: &SshAgentInstance| reload_with(mux_agent, "agent-timeout = \"soon\"");
+
+    // The previous configuration stays in use, whether the new one isn't valid TOML or fails
+    // validation
+    let mux_agent = start_mux("")?;
+    reload_bad_config(&mux_agent)?;
+    assert!(mux_agent.handle.try_wait()?.is_none());
+    assert_all_keys_in_agent(&mux_agent)?;
+    reload_with(&mux_agent, "[[agents]\nname = ")?;
+    assert!(mux_agent.handle.try_wait()?.is_none());
+    assert_all_keys_in_agent(&mux_agent)?;
+    let unknown_reference = format!(
+        "add-new-keys-to = \"missing\"\n[[agents]]\nname = \"other\"\nsocket-path = \"{}\"\n",
+        openssh_agent.sock_path.display()
+    );
+    reload_with(&mux_agent, &unknown_reference)?;
+    assert!(mux_agent.handle.try_wait()?.is_none());
+    assert_all_keys_in_agent(&mux_agent)?;
+    let output = mux_agent.stop()?;
+    assert_eq!(
+        output
+            .matches("Failed to reload configuration; still serving with the previous one")
+            .count(),
+        3,
+        "{output}"
+    );
+
+    let mux_agent = start_mux("strict-reload = true")?;
+    reload_bad_config(&mux_agent)?;
+    let output = mux_agent.handle.try_wait()?.expect("mux exited");
+    assert_eq!(output.status.code(), Some(78), "{:?}", output);
+
+    Ok(())
+}
+
+#[test]
+fn mux_watch_config() -> TestResult {
+    let agent_a = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let agent_b = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB]))?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let config = |agent: &MockAgent| {
+        format!(
+            "watch-config = true\n[[agents]]\nname = \"upstream\"\nsocket-path = \"{}\"\n",
+            agent.sock_path.display()
+        )
+    };
+    // Long enough to notice a change and let it settle
+    let settle = || thread::sleep(Duration::from_millis(2500));
+
+    fs::write(&config_path, config(&agent_a))?;
+    let mux_agent = SshAgentInstance::new(
+        SshAgentType::Mux,
+        [format!("--config={}", config_path.display())],
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // Replaced the way editors save, by writing a new file and renaming it over the old one
+    let new_path = scratch.path().join("ssh-agent-mux.toml.new");
+    fs::write(&new_path, config(&agent_b))?;
+    fs::rename(&new_path, &config_path)?;
+    settle();
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+
+    // A bad edit keeps the previous configuration
+    fs::write(&config_path, "agent-timeout = \"soon\"")?;
+    settle();
+    assert!(mux_agent.handle.try_wait()?.is_none());
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    let output = mux_agent.stop()?;
+    assert_eq!(
+        output
+            .matches("Configuration file changed; reloading it")
+            .count(),
+        2,
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_reload_keeps_known_keys() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let write_config = |settings: &str, agent: &str| {
+        fs::write(
+            &config_path,
+            format!(
+                "lazy-connect = true\n{}\n[[agents]]\nname = \"mock\"\nsocket-path = \"{}\"\n{}",
+                settings,
+                mock_agent.sock_path.display(),
+                agent
+            ),
+        )
+    };
+    let reload = |mux_agent: &SshAgentInstance| -> io::Result<()> {
+        mux_agent.reload()?;
+        // Give the mux time to handle the signal
+        thread::sleep(Duration::from_millis(500));
+        Ok(())
+    };
+
+    write_config("", "")?;
+    let mux_agent = SshAgentInstance::new(
+        SshAgentType::Mux,
+        [format!("--config={}", config_path.display())],
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+
+    // Only the log level changed, so the reloaded mux still knows the keys
+    write_config("log-level = \"debug\"", "")?;
+    reload(&mux_agent)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 1);
+
+    // A changed agent starts from scratch
+    write_config("log-level = \"debug\"", "tags = [\"work\"]")?;
+    reload(&mux_agent)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 2);
+
+    // So does a key filter that hides the known key, which can then no longer sign
+    write_config("comment-filter = \"no-such-comment\"", "tags = [\"work\"]")?;
+    reload(&mux_agent)?;
+    assert!(mux_agent.list()?.is_empty());
+    assert_eq!(identity_requests.load(Ordering::SeqCst), 3);
+    assert!(mux_agent.sign(keys::TEST_KEY_ED25519_PUB).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_import_suggests_agents() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let empty_home = tempfile::tempdir()?;
+    let sockets_dir = tempfile::tempdir()?;
+    // A socket file nothing listens on
+    drop(std::os::unix::net::UnixListener::bind(
+        sockets_dir.path().join("stale.sock"),
+    )?);
+    let config_path = empty_home.path().join("ssh-agent-mux.toml");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg(format!("--config={}", config_path.display()))
+        .arg("import")
+        .arg("--dir")
+        .arg(sockets_dir.path())
+        .arg("--write")
+        .env("SSH_AUTH_SOCK", &openssh_agent.sock_path)
+        .env("HOME", empty_home.path())
+        .env_remove("XDG_RUNTIME_DIR")
+        .env_remove("GPG_AGENT_INFO")
+        .output()?;
+    assert!(output.status.success(), "{:?}", output);
+
+    let suggested = String::from_utf8(output.stdout)?;
+    println!("{suggested}");
+    assert!(suggested.contains(&format!(
+        r#"# From SSH_AUTH_SOCK: reachable, {} identities
+[[agents]]
+name = "ssh-auth-sock"
+socket-path = "{}"
+"#,
+        keys::PRIVATE.len(),
+        openssh_agent.sock_path.display()
+    )));
+    assert!(suggested.contains("name = \"stale\""));
+    assert!(suggested.contains("enabled = false"));
+
+    // The suggestions were appended to the config, which the mux can then load
+    assert_eq!(fs::read_to_string(&config_path)?.trim(), suggested.trim());
+    let config = fs::read_to_string(&config_path)?;
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_all_keys_in_agent(&mux_agent)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_subcommand() -> TestResult {
+    let openssh_agent = SshAgentInstance::new_openssh()?;
+    openssh_agent.add(keys::TEST_KEY_ED25519)?;
+    let agents_config = format!(
+        r##"[[agents]]
+name = "openssh"
+socket-path = "{}""##,
+        openssh_agent.sock_path.display()
+    );
+    let mux_agent = SshAgentInstance::new_mux(&agents_config, None::<OsString>)?;
+
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "listen-path = \"{}\"\n{}",
+            mux_agent.sock_path.display(),
+            agents_config
+        ),
+    )?;
+    let data_path = scratch.path().join("data");
+    fs::write(&data_path, "data to sign")?;
+    let sign = |key: &str, output: Option<&std::path::Path>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"));
+        command
+            .arg(format!("--config={}", config_path.display()))
+            .args(["sign", "--key", key, "--data"])
+            .arg(&data_path);
+        if let Some(output) = output {
+            command.arg("--output").arg(output);
+        }
+        command.output()
+    };
+
+    let fingerprint = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+    let output = sign(&fingerprint, None)?;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8(output.stderr)?.contains("by upstream agent \"openssh\""),
+        "owning agent not reported"
+    );
+    assert!(!String::from_utf8(output.stdout)?.trim().is_empty());
+
+    let pubkey_path = scratch.path().join("id_ed25519.pub");
+    fs::write(&pubkey_path, keys::TEST_KEY_ED25519_PUB)?;
+    let signature_path = scratch.path().join("signature");
+    let output = sign(&pubkey_path.display().to_string(), Some(&signature_path))?;
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!fs::read(&signature_path)?.is_empty());
+
+    let rsa_fingerprint = PublicKey::from_openssh(keys::TEST_KEY_RSA_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+    assert!(!sign(&rsa_fingerprint, None)?.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn mux_route_subcommand() -> TestResult {
+    let first = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let second = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ED25519_PUB,
+        keys::TEST_KEY_ECDSA_PUB,
+    ]))?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r##"listen-path = "{}"
+
+[[agents]]
+name = "first"
+socket-path = "{}"
+
+[[agents]]
+name = "second"
+socket-path = "{}""##,
+            scratch.path().join("mux.sock").display(),
+            first.sock_path.display(),
+            second.sock_path.display()
+        ),
+    )?;
+    let route = |pubkey: &str| -> io::Result<std::process::Output> {
+        let pubkey_path = scratch.path().join("key.pub");
+        fs::write(&pubkey_path, pubkey)?;
+        Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+            .arg(format!("--config={}", config_path.display()))
+            .arg("route")
+            .arg(&pubkey_path)
+            .output()
+    };
+
+    // Both agents list the key; like the mux, the later one wins
+    let output = route(keys::TEST_KEY_ED25519_PUB)?;
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "second");
+
+    let output = route(keys::TEST_KEY_RSA_PUB)?;
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(String::from_utf8(output.stderr)?.contains("No agent holds key"));
+
+    Ok(())
+}
+
+#[test]
+fn mux_inventory_subcommand() -> TestResult {
+    let first = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let second = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ECDSA_PUB,
+        keys::TEST_KEY_ED25519_PUB,
+    ]))?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r##"listen-path = "{}"
+
+[[agents]]
+name = "first"
+socket-path = "{}"
+
+[[agents]]
+name = "second"
+socket-path = "{}"
+
+[[agents]]
+name = "missing"
+socket-path = "{}""##,
+            scratch.path().join("mux.sock").display(),
+            first.sock_path.display(),
+            second.sock_path.display(),
+            missing_sock.display()
+        ),
+    )?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg(format!("--config={}", config_path.display()))
+        .arg("inventory")
+        .output()?;
+    assert!(output.status.success(), "{:?}", output);
+    let inventory = String::from_utf8(output.stdout)?;
+    println!("{inventory}");
+    let entry = |agent: &str, pubkey: &str, routed: bool| -> ssh_key::Result<String> {
+        let key = PublicKey::from_openssh(pubkey)?;
+        Ok(format!(
+            concat!(
+                r#"{{"agent": "{}", "fingerprint": "{}", "type": "{}", "comment": "{}", "#,
+                r#""public-key": "{}", "routed": {}}}"#
+            ),
+            agent,
+            key.fingerprint(Default::default()),
+            key.algorithm().as_str(),
+            key.comment(),
+            PublicKey::new(key.key_data().clone(), "").to_openssh()?,
+            routed
+        ))
+    };
+    let lines: Vec<_> = inventory
+        .lines()
+        .map(|l| l.trim_end_matches(',').trim())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "[".to_string(),
+            entry("first", keys::TEST_KEY_ED25519_PUB, false)?,
+            entry("second", keys::TEST_KEY_ECDSA_PUB, true)?,
+            entry("second", keys::TEST_KEY_ED25519_PUB, true)?,
+            "]".to_string(),
+        ]
+    );
+    assert!(String::from_utf8(output.stderr)?.contains("upstream agent \"missing\""));
+
+    Ok(())
+}
+
+#[test]
+fn mux_doctor_subcommand() -> TestResult {
+    let first = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let second = MockAgent::start(ScriptedAgent::with_keys(&[
+        keys::TEST_KEY_ECDSA_PUB,
+        keys::TEST_KEY_RSA_PUB,
+    ]))?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let scratch = tempfile::tempdir()?;
+    let config_path = scratch.path().join("ssh-agent-mux.toml");
+    let doctor = |config: &str| -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        fs::write(&config_path, config)?;
+        Ok(Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+            .arg(format!("--config={}", config_path.display()))
+            .arg("doctor")
+            .output()?)
+    };
+    let agent = |name: &str, sock_path: &Path| {
+        format!(
+            "[[agents]]\nname = \"{}\"\nsocket-path = \"{}\"\n",
+            name,
+            sock_path.display()
+        )
+    };
+    let reachable = agent("first", &first.sock_path) + &agent("second", &second.sock_path);
+    // Disabled agents aren't checked
+    let disabled = agent("disabled", &missing_sock) + "enabled = false\n";
+
+    let output = doctor(&(reachable.clone() + &disabled))?;
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout)?;
+    let rows: Vec<Vec<_>> = stdout
+        .lines()
+        .map(|l| l.split_whitespace().collect())
+        .collect();
+    assert_eq!(rows.len(), 3, "{stdout}");
+    assert_eq!(rows[0], ["AGENT", "SOCKET", "KEYS", "LATENCY", "STATUS"]);
+    let (first_sock, second_sock) = (first.sock_path.display(), second.sock_path.display());
+    assert_eq!(rows[1][..3], ["first", &first_sock.to_string(), "1"]);
+    assert_eq!(rows[1][4..], ["reachable"]);
+    assert_eq!(rows[2][..3], ["second", &second_sock.to_string(), "2"]);
+    assert_eq!(rows[2][4..], ["reachable"]);
+
+    let output = doctor(&(reachable + &agent("missing", &missing_sock)))?;
+    assert_eq!(output.status.code(), Some(69), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout)?;
+    let missing = stdout
+        .lines()
+        .find(|l| l.starts_with("missing"))
+        .expect("missing agent row");
+    assert!(
+        missing.contains("unreachable (connect-failed): "),
+        "{stdout}"
+    );
+    assert!(String::from_utf8(output.stderr)?.contains("\"missing\""));
+
+    Ok(())
+}
+
+/// Name, outcome and key count of each agent
+type AgentOutcomes = Vec<(String, RefreshOutcome, u32)>;
+
+/// Outcomes in the mux's `refresh-status@ssh-agent-mux` response
+fn refresh_status(
+    mux_agent: &SshAgentInstance,
+) -> Result<AgentOutcomes, Box<dyn std::error::Error>> {
+    let response = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: RefreshStatus::NAME.into(),
+                details: Vec::new().into(),
+            })
+            .await
+    })?;
+    let RefreshStatus { agents } = response
+        .expect("refresh status has a response")
+        .parse_message::<RefreshStatus>()?
+        .expect("refresh status response");
+    Ok(agents
+        .into_iter()
+        .map(|a| (a.name, a.outcome, a.keys))
+        .collect())
+}
+
+#[test]
+fn mux_refresh_status() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let missing_sock = harness::temp_sock_path("missing_")?;
+    let config = format!(
+        r##"[[agents]]
+name = "present"
+socket-path = "{}"
+
+[[agents]]
+name = "missing"
+socket-path = "{}""##,
+        mock_agent.sock_path.display(),
+        missing_sock.display()
+    );
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(
+        refresh_status(&mux_agent)?,
+        [
+            ("present".into(), RefreshOutcome::NotRefreshed, 0),
+            ("missing".into(), RefreshOutcome::NotRefreshed, 0)
+        ]
+    );
+    mux_agent.list()?;
+    assert_eq!(
+        refresh_status(&mux_agent)?,
+        [
+            ("present".into(), RefreshOutcome::Ok, 1),
+            ("missing".into(), RefreshOutcome::ConnectFailed, 0)
+        ]
+    );
+
+    let config_path = tempfile::NamedTempFile::new()?.into_temp_path();
+    fs::write(
+        &config_path,
+        format!(
+            "listen-path = \"{}\"\n{}",
+            mux_agent.sock_path.display(),
+            config
+        ),
+    )?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg(format!("--config={}", config_path.display()))
+        .arg("status")
+        .output()?;
+    assert!(output.status.success(), "{:?}", output);
+    let status = String::from_utf8(output.stdout)?;
+    println!("{status}");
+    assert!(status.starts_with("present  ok, 1 key ("), "{status}");
+    assert!(status.contains("\nmissing  connect-failed ("), "{status}");
+
+    Ok(())
+}
+
+#[test]
+fn mux_sign_check() -> TestResult {
+    let signing = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let refusing = ScriptedAgent {
+        refuse_sign: true,
+        ..ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB])
+    };
+    let refusing_sign_requests = refusing.sign_requests.clone();
+    let refusing = MockAgent::start(refusing)?;
+    let other = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB]))?;
+    let config = |enabled: bool| {
+        format!(
+            r##"sign-check = {}
+
+[[agents]]
+name = "refusing"
+socket-path = "{}"
+
+[[agents]]
+name = "other"
+socket-path = "{}"
+
+[[agents]]
+name = "signing"
+socket-path = "{}""##,
+            enabled,
+            refusing.sock_path.display(),
+            other.sock_path.display(),
+            signing.sock_path.display()
+        )
+    };
+    let pubkey = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?;
+    let sign_check_response = |mux_agent: &SshAgentInstance| {
+        let pubkey = pubkey.clone();
+        mux_agent.with_client(|mut client| async move {
+            Ok(client
+                .extension(Extension::new_message(SignCheck {
+                    pubkey: pubkey.key_data().clone(),
+                })?)
+                .await?
+                .expect("sign check has a response"))
+        })
+    };
+    let sign_check =
+        |mux_agent: &SshAgentInstance| -> Result<SignCheckResults, Box<dyn std::error::Error>> {
+            Ok(sign_check_response(mux_agent)?
+                .parse_message::<SignCheckResults>()?
+                .expect("sign check response"))
+        };
+
+    // Disabled by default
+    let mux_agent = SshAgentInstance::new_mux(&config(false), None::<OsString>)?;
+    let denied = sign_check_response(&mux_agent)?
+        .parse_message::<Denied>()?
+        .expect("denied response");
+    assert_eq!(denied.reason, "not-enabled");
+    drop(mux_agent);
+
+    let mux_agent = SshAgentInstance::new_mux(&config(true), None::<OsString>)?;
+    let SignCheckResults { agents, denied } = sign_check(&mux_agent)?;
+    assert_eq!(denied, "");
+    let outcomes: Vec<_> = agents.iter().map(|a| (a.name.as_str(), a.ok)).collect();
+    assert_eq!(outcomes, [("refusing", false), ("signing", true)]);
+    assert!(!agents[0].detail.is_empty());
+    drop(mux_agent);
+
+    // Agents are only asked to sign once the user approves, as for a sign request
+    let sign_requests = refusing_sign_requests.load(Ordering::SeqCst);
+    let confirming = format!(
+        "confirm-sign = true\nconfirm-command = [\"false\"]\n{}",
+        config(true)
+    );
+    let mux_agent = SshAgentInstance::new_mux(&confirming, None::<OsString>)?;
+    let SignCheckResults { agents, denied } = sign_check(&mux_agent)?;
+    assert_eq!(denied, "");
+    let outcomes: Vec<_> = agents.iter().map(|a| (a.name.as_str(), a.ok)).collect();
+    assert_eq!(outcomes, [("refusing", false), ("signing", false)]);
+    assert!(
+        agents[1]
+            .detail
+            .starts_with("policy denied (not-confirmed): "),
+        "{:?}",
+        agents[1].detail
+    );
+    assert_eq!(refusing_sign_requests.load(Ordering::SeqCst), sign_requests);
+    drop(mux_agent);
+
+    // The mux's own refusal is reported instead of asking the agents
+    let strict = format!("require-constraints-for-sign = \"all\"\n{}", config(true));
+    let mux_agent = SshAgentInstance::new_mux(&strict, None::<OsString>)?;
+    let SignCheckResults { agents, denied } = sign_check(&mux_agent)?;
+    assert!(agents.is_empty());
+    assert!(
+        denied.starts_with("policy denied (constraint-required): "),
+        "{denied}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_comment_prefix() -> TestResult {
+    let mock_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"comment-prefix = "[mux] "
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let upstream_identity = mock::identity(keys::TEST_KEY_ED25519_PUB);
+    for _ in 0..2 {
+        // The prefix is added once, not again to identities that are reused
+        let identities =
+            mux_agent.with_client(|mut client| async move { client.request_identities().await })?;
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].pubkey, upstream_identity.pubkey);
+        assert_eq!(
+            identities[0].comment,
+            format!("[mux] {}", upstream_identity.comment)
+        );
+    }
+
+    // Signing still routes by key, whatever the comment
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_annotate_comments() -> TestResult {
+    let rsa_agent = MockAgent::start(ScriptedAgent::with_keys(&[keys::TEST_KEY_RSA_PUB]))?;
+    let mut uncommented = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    uncommented.identities[0].comment.clear();
+    let other_agent = MockAgent::start(uncommented)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"annotate-comments = true
+
+[[agents]]
+name = "rsa-agent"
+socket-path = "{}"
+
+[[agents]]
+name = "other"
+socket-path = "{}""##,
+            rsa_agent.sock_path.display(),
+            other_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let rsa_identity = mock::identity(keys::TEST_KEY_RSA_PUB);
+    for _ in 0..2 {
+        // Annotated once, however many times the identities are refreshed
+        let identities =
+            mux_agent.with_client(|mut client| async move { client.request_identities().await })?;
+        let comments: Vec<_> = identities.iter().map(|id| id.comment.as_str()).collect();
+        assert_eq!(
+            comments,
+            [
+                format!("{} [rsa-agent]", rsa_identity.comment),
+                "[other]".into()
+            ]
+        );
+        assert_eq!(identities[0].pubkey, rsa_identity.pubkey);
+    }
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_RSA_PUB)?,
+        mock::dummy_signature()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_default_comment() -> TestResult {
+    let mut upstream =
+        ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_ECDSA_PUB]);
+    upstream.identities[0].comment.clear();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"comment-prefix = "[mux] "
+default-comment = "{{agent}} {{fingerprint}}"
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let identities =
+        mux_agent.with_client(|mut client| async move { client.request_identities().await })?;
+    let uncommented = mock::identity(keys::TEST_KEY_ED25519_PUB);
+    let commented = mock::identity(keys::TEST_KEY_ECDSA_PUB);
+    assert_eq!(identities.len(), 2);
+    // The default is only for the key without a comment, and is prefixed like any other
+    assert_eq!(
+        identities[0].comment,
+        format!(
+            "[mux] upstream {}",
+            uncommented.pubkey.fingerprint(Default::default())
+        )
+    );
+    assert_eq!(
+        identities[1].comment,
+        format!("[mux] {}", commented.comment)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_background_refresh() -> TestResult {
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let identity_requests = upstream.identity_requests.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let _mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"background-refresh = 1
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    // Refreshed without any client requests
+    thread::sleep(Duration::from_millis(2500));
+    assert!(identity_requests.load(Ordering::SeqCst) >= 2);
+
+    Ok(())
+}
+
+#[test]
+fn mux_allowed_operations() -> TestResult {
+    const SSH_AGENT_FAILURE: u8 = 5;
+
+    let upstream = ScriptedAgent::with_keys(&[keys::TEST_KEY_ED25519_PUB]);
+    let adds = upstream.adds.clone();
+    let mock_agent = MockAgent::start(upstream)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"allowed-operations = ["request-identities", "sign"]
+add-new-keys-to = "upstream"
+
+[[agents]]
+name = "upstream"
+socket-path = "{}""##,
+            mock_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(
+        mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?,
+        mock::dummy_signature()
+    );
+
+    assert!(mux_agent.add(keys::TEST_KEY_ECDSA).is_err());
+    assert_eq!(adds.load(Ordering::SeqCst), 0);
+    assert!(mux_agent.lock("passphrase").is_err());
+    assert_eq!(
+        extension_response_type(&mux_agent.sock_path, "query")?,
+        SSH_AGENT_FAILURE
+    );
+    // The mux's own extensions are answered with the reason
+    let response = mux_agent.with_client(|mut client| async move {
+        client
+            .extension(Extension {
+                name: MuxInfo::NAME.into(),
+                details: Vec::new().into(),
+            })
+            .await
+    })?;
+    let denied = response
+        .expect("denied reply")
+        .parse_message::<Denied>()?
+        .expect("denied response");
+    assert_eq!(denied.reason, "operation-not-allowed");
+    assert_eq!(denied.detail, "Extension isn't an allowed operation");
+    let output = mux_agent.stop()?;
+    assert!(
+        output.contains("Refusing Lock request: policy denied (operation-not-allowed)"),
+        "{output}"
+    );
+    assert!(
+        output.contains(
+            "Refusing info@ssh-agent-mux extension request: policy denied (operation-not-allowed)"
+        ),
+        "{output}"
+    );
+
+    Ok(())
+}